@@ -1,9 +1,22 @@
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tracing::info;
+use tracing::{info, warn};
+use xdr_trace::ChaosDecision;
+
+pub mod response;
+pub mod rules;
+pub mod scenario;
+
+use response::ResponseFault;
+use rules::{ChaosRule, CompiledRule, RequestType, TargetMatcher, DEFAULT_ERROR_STATUSES};
+use scenario::{FaultKind, Scenario, ScenarioRun, ScenarioStatus};
+
+/// Most agents whose request numbers are kept at once
+pub const MAX_TRACKED_AGENTS: usize = 10_000;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChaosConfig {
@@ -14,6 +27,18 @@ pub struct ChaosConfig {
     pub rug_rate: f64,             // Payment succeeds, but request fails (Lost funds)
     pub min_latency_ms: u64,
     pub max_latency_ms: u64,
+    #[serde(default)]
+    pub reorg_rate: f64,           // Chance per mined block of a shallow reorg
+    #[serde(default = "default_reorg_depth")]
+    pub max_reorg_depth: u64,      // Deepest reorg (in blocks) the chaos engine triggers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<ChaosRule>,     // Targeted rates, rolled after the global ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response_faults: Vec<ResponseFault>, // Broken bodies/headers applied to upstream responses
+}
+
+fn default_reorg_depth() -> u64 {
+    2
 }
 
 impl Default for ChaosConfig {
@@ -26,19 +51,127 @@ impl Default for ChaosConfig {
             rug_rate: 0.0,
             min_latency_ms: 0,
             max_latency_ms: 0,
+            reorg_rate: 0.0,
+            max_reorg_depth: default_reorg_depth(),
+            rules: Vec::new(),
+            response_faults: Vec::new(),
+        }
+    }
+}
+
+impl ChaosConfig {
+    fn validate(&self) -> Result<(), String> {
+        for (field, rate) in [
+            ("global_failure_rate", self.global_failure_rate),
+            ("payment_failure_rate", self.payment_failure_rate),
+            ("rug_rate", self.rug_rate),
+            ("reorg_rate", self.reorg_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("{} must be between 0 and 1", field));
+            }
+        }
+        if self.min_latency_ms > self.max_latency_ms {
+            return Err("min_latency_ms is above max_latency_ms".to_string());
+        }
+        for fault in &self.response_faults {
+            fault.validate()?;
         }
+        Ok(())
     }
 }
 
+/// The request a chaos decision is about (for rules and faults aimed at part of the traffic).
+#[derive(Debug, Clone, Copy)]
+pub struct ChaosContext<'a> {
+    pub agent_id: &'a str,
+    pub host: &'a str,
+    pub path: &'a str,
+    pub method: &'a str,
+    pub request_type: RequestType,
+    /// The agent's request number (from `next_seq`), part of every decision key
+    pub seq: u64,
+    /// Where the decisions made for this request are collected for its trace
+    pub decisions: &'a DecisionLog,
+}
+
+/// The decisions made for one request.
+#[derive(Debug, Default)]
+pub struct DecisionLog(Mutex<Vec<ChaosDecision>>);
+
+impl DecisionLog {
+    fn push(&self, decision: ChaosDecision) {
+        self.0.lock().unwrap().push(decision);
+    }
+
+    pub fn take(&self) -> Vec<ChaosDecision> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// A decision's own RNG stream, keyed by (seed, agent, seq, fault). FNV-1a
+/// keeps the key-to-stream mapping stable across Rust versions and platforms.
+fn decision_rng(seed: u64, agent_id: &str, seq: u64, fault: &str) -> ChaCha8Rng {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let key = seed.to_le_bytes().into_iter()
+        .chain(agent_id.bytes())
+        .chain([0])
+        .chain(seq.to_le_bytes())
+        .chain(fault.bytes());
+    for byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    ChaCha8Rng::seed_from_u64(hash)
+}
+
+fn pick_status(statuses: &[u16], rng: &mut ChaCha8Rng) -> u16 {
+    let statuses = if statuses.is_empty() { &DEFAULT_ERROR_STATUSES[..] } else { statuses };
+    statuses[rng.gen_range(0..statuses.len())]
+}
+
 #[derive(Clone)]
 pub struct ChaosEngine {
-    // We use Mutex instead of RwLock because checking chaos MODIFIES the RNG state
     state: Arc<Mutex<ChaosState>>,
 }
 
 struct ChaosState {
     config: ChaosConfig,
-    rng: ChaCha8Rng,
+    /// `config.rules` with their globs compiled
+    rules: Vec<CompiledRule>,
+    /// `config.response_faults` with their targets compiled
+    response_faults: Vec<(ResponseFault, TargetMatcher)>,
+    /// Loaded scenario; replaces `config` while set
+    scenario: Option<ScenarioRun>,
+    /// Requests seen per agent since chaos was last configured, with the
+    /// `requests` count at each agent's latest one
+    seqs: HashMap<String, (u64, u64)>,
+    /// Requests numbered since chaos was last configured (all agents)
+    requests: u64,
+}
+
+impl ChaosState {
+    fn active(&self) -> bool {
+        self.config.enabled || self.scenario.is_some()
+    }
+
+    fn seed(&self) -> u64 {
+        self.scenario.as_ref().map_or(self.config.seed, |run| run.scenario().seed)
+    }
+
+    /// Asks the scenario whether a fault of `kind` fires for this request.
+    /// None when no scenario is loaded (the flat config decides).
+    fn scenario_fault(&mut self, kind: FaultKind, ctx: Option<&ChaosContext>, rng: &mut ChaCha8Rng) -> Option<Option<scenario::Fault>> {
+        self.scenario.as_mut().map(|run| run.fire(kind, ctx, rng))
+    }
+
+    /// Rolls each rule matching `ctx` that sets `rate`, in order. Returns the first that hits.
+    fn roll_rules(&self, ctx: &ChaosContext, rng: &mut ChaCha8Rng, rate: impl Fn(&ChaosRule) -> f64) -> Option<&ChaosRule> {
+        self.rules.iter()
+            .filter(|compiled| rate(&compiled.rule) > 0.0 && compiled.target.matches(Some(ctx)))
+            .find(|compiled| rng.gen_bool(rate(&compiled.rule)))
+            .map(|compiled| &compiled.rule)
+    }
 }
 
 impl Default for ChaosEngine {
@@ -52,17 +185,85 @@ impl ChaosEngine {
         Self {
             state: Arc::new(Mutex::new(ChaosState {
                 config: ChaosConfig::default(),
-                rng: ChaCha8Rng::seed_from_u64(0),
+                rules: Vec::new(),
+                response_faults: Vec::new(),
+                scenario: None,
+                seqs: HashMap::new(),
+                requests: 0,
             })),
         }
     }
 
-    pub fn set_config(&self, new_config: ChaosConfig) {
+    pub fn set_config(&self, new_config: ChaosConfig) -> Result<(), String> {
+        new_config.validate()?;
+        let rules = rules::compile_rules(&new_config.rules)?;
+        let response_faults = new_config.response_faults.iter()
+            .map(|fault| fault.target.compile().map(|target| (fault.clone(), target)))
+            .collect::<Result<Vec<_>, _>>()?;
         let mut state = self.state.lock().unwrap();
-        // Re-seed the RNG whenever config changes to ensure replayability from this point
-        state.rng = ChaCha8Rng::seed_from_u64(new_config.seed);
+        // Restart the request counts whenever config changes to ensure replayability from this point
+        state.seqs.clear();
+        state.requests = 0;
         state.config = new_config;
+        state.rules = rules;
+        state.response_faults = response_faults;
+        state.scenario = None;
         info!("Chaos Re-Seeded & Updated: {:?}", state.config);
+        Ok(())
+    }
+
+    /// Starts playing a scenario from its first phase (replacing any flat config).
+    pub fn load_scenario(&self, scenario: Scenario) -> Result<(), String> {
+        scenario.validate()?;
+        let mut state = self.state.lock().unwrap();
+        state.seqs.clear();
+        state.requests = 0;
+        info!("Chaos scenario loaded: {} ({} phases)", scenario.name.as_deref().unwrap_or("unnamed"), scenario.phases.len());
+        state.scenario = Some(ScenarioRun::start(scenario)?);
+        Ok(())
+    }
+
+    /// The loaded scenario, if any.
+    pub fn get_scenario(&self) -> Option<Scenario> {
+        self.state.lock().unwrap().scenario.as_ref().map(|run| run.scenario().clone())
+    }
+
+    /// Where the loaded scenario is on its timeline.
+    pub fn scenario_status(&self) -> Option<ScenarioStatus> {
+        self.state.lock().unwrap().scenario.as_mut().map(|run| run.status())
+    }
+
+    /// Counts a request against the scenario timeline. Returns the new phase
+    /// when this request starts one.
+    pub fn begin_request(&self) -> Option<ScenarioStatus> {
+        let mut state = self.state.lock().unwrap();
+        let run = state.scenario.as_mut()?;
+        if !run.on_request() {
+            return None;
+        }
+        let status = run.status();
+        info!("Chaos scenario: {}", status.label());
+        Some(status)
+    }
+
+    /// Numbers the agent's requests (from 1) for the decision keys. At most
+    /// `MAX_TRACKED_AGENTS` are counted; past that the idlest agent is
+    /// forgotten and starts again from 1.
+    pub fn next_seq(&self, agent_id: &str) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.requests += 1;
+        let now = state.requests;
+        if !state.seqs.contains_key(agent_id) && state.seqs.len() >= MAX_TRACKED_AGENTS {
+            let idlest = state.seqs.iter().min_by_key(|(_, (_, last))| *last).map(|(id, _)| id.clone());
+            if let Some(idlest) = idlest {
+                warn!("Chaos is tracking {} agents; restarting the request count of {}", MAX_TRACKED_AGENTS, idlest);
+                state.seqs.remove(&idlest);
+            }
+        }
+        let (seq, last) = state.seqs.entry(agent_id.to_string()).or_insert((0, 0));
+        *seq += 1;
+        *last = now;
+        *seq
     }
 
     /// Returns a clone of the current chaos configuration (for TUI display)
@@ -71,71 +272,212 @@ impl ChaosEngine {
         state.config.clone()
     }
 
-    /// Roll dice for generic network failure (503/429)
-    pub fn roll_network_failure(&self) -> Option<u16> {
+    /// Runs one decision on its own RNG stream and logs its key to `ctx.decisions`.
+    /// Nothing is rolled (or logged) while chaos is off.
+    fn decide<T>(
+        &self,
+        ctx: &ChaosContext,
+        fault: &str,
+        roll: impl FnOnce(&mut ChaosState, &mut ChaCha8Rng) -> Option<T>,
+    ) -> Option<T> {
         let mut state = self.state.lock().unwrap();
-        
-        // Extract boolean first
-        let enabled = state.config.enabled;
-        let rate = state.config.global_failure_rate;
-
-        if !enabled { return None; }
-
-        // Now mutate RNG
-        if state.rng.gen_bool(rate) {
-            let errors = [503, 429, 504];
-            let idx = state.rng.gen_range(0..errors.len());
-            return Some(errors[idx]);
+        if !state.active() {
+            return None;
         }
-        None
+        let seed = state.seed();
+        let mut rng = decision_rng(seed, ctx.agent_id, ctx.seq, fault);
+        let outcome = roll(&mut state, &mut rng);
+        ctx.decisions.push(ChaosDecision {
+            seed,
+            agent_id: ctx.agent_id.to_string(),
+            seq: ctx.seq,
+            fault: fault.to_string(),
+            hit: outcome.is_some(),
+        });
+        outcome
     }
 
-    /// Roll dice for payment processing failure (Payment Rejected)
-    pub fn roll_payment_failure(&self) -> bool {
-        let mut state = self.state.lock().unwrap();
-       // 1. EXTRACT VALUES (Read Borrow)
-        let enabled = state.config.enabled;
-        let rate = state.config.payment_failure_rate;
+    /// Roll dice for generic network failure (503/429)
+    pub fn roll_network_failure(&self, ctx: &ChaosContext) -> Option<u16> {
+        self.decide(ctx, FaultKind::NetworkFailure.label(), |state, rng| {
+            if let Some(fault) = state.scenario_fault(FaultKind::NetworkFailure, Some(ctx), rng) {
+                return fault.map(|f| pick_status(&f.status, rng));
+            }
+            if rng.gen_bool(state.config.global_failure_rate) {
+                return Some(pick_status(&[], rng));
+            }
 
-        if !enabled { return false; }
+            // Then the targeted rules
+            let rule = state.roll_rules(ctx, rng, |r| r.failure_rate)?;
+            let status = pick_status(&rule.status, rng);
+            info!("Chaos rule '{}' injected {}", rule.label(), status);
+            Some(status)
+        })
+    }
 
-        // 2. MUTATE RNG (Write Borrow)
-        // Now we pass the COPY 'rate', not the borrow 'state.config.rate'
-        state.rng.gen_bool(rate)
+    /// Roll dice for payment processing failure (Payment Rejected)
+    pub fn roll_payment_failure(&self, ctx: &ChaosContext) -> bool {
+        self.decide(ctx, FaultKind::PaymentFailure.label(), |state, rng| {
+            if let Some(fault) = state.scenario_fault(FaultKind::PaymentFailure, Some(ctx), rng) {
+                return fault.map(|_| ());
+            }
+            if rng.gen_bool(state.config.payment_failure_rate) {
+                return Some(());
+            }
+            state.roll_rules(ctx, rng, |r| r.payment_failure_rate)
+                .map(|rule| info!("Chaos rule '{}' failed a payment", rule.label()))
+        })
+        .is_some()
     }
 
     /// Roll dice for "Rug" (Payment Accepted -> Request Failed)
-    pub fn roll_rug_pull(&self) -> bool {
+    pub fn roll_rug_pull(&self, ctx: &ChaosContext) -> bool {
+        self.decide(ctx, FaultKind::RugPull.label(), |state, rng| {
+            if let Some(fault) = state.scenario_fault(FaultKind::RugPull, Some(ctx), rng) {
+                return fault.map(|_| ());
+            }
+            if rng.gen_bool(state.config.rug_rate) {
+                return Some(());
+            }
+            state.roll_rules(ctx, rng, |r| r.rug_rate)
+                .map(|rule| info!("Chaos rule '{}' pulled the rug", rule.label()))
+        })
+        .is_some()
+    }
+
+    /// Roll dice for each response fault matching the request. Every fault
+    /// that hits is returned, so they can stack (e.g. a slow body that then resets).
+    pub fn roll_response_faults(&self, ctx: &ChaosContext) -> Vec<ResponseFault> {
+        {
+            let state = self.state.lock().unwrap();
+            if state.response_faults.is_empty() || state.scenario.is_some() {
+                return Vec::new();
+            }
+        }
+        self.decide(ctx, "response", |state, rng| {
+            let hits: Vec<ResponseFault> = state.response_faults.iter()
+                .filter(|(fault, target)| fault.rate > 0.0 && target.matches(Some(ctx)))
+                .filter(|(fault, _)| rng.gen_bool(fault.rate))
+                .map(|(fault, _)| fault.clone())
+                .collect();
+            Some(hits).filter(|hits| !hits.is_empty())
+        })
+        .unwrap_or_default()
+    }
+
+    /// Roll dice for a chain reorg after block `height` is mined. Returns the depth.
+    /// Chain-wide, so the decision is keyed by height rather than by agent.
+    pub fn roll_reorg(&self, height: u64) -> Option<u64> {
         let mut state = self.state.lock().unwrap();
-        let enabled = state.config.enabled;
-        let rate = state.config.rug_rate;
-
-        if !enabled { return false; }
-
-        // 2. MUTATE RNG (Write Borrow)
-        state.rng.gen_bool(rate)
-    }
-
-    pub async fn inject_latency(&self) {
-        let (enabled, delay) = {
-            let mut state = self.state.lock().unwrap();
-            
-            // Extract values first (READ)
-            let enabled = state.config.enabled;
-            let min = state.config.min_latency_ms;
-            let max = state.config.max_latency_ms;
-
-            if !enabled || max == 0 {
-                (false, 0)
-            } else {
-                // Now mutate RNG (WRITE)
-                // We use the local 'min'/'max' copies, so we don't touch 'state.config' here
-                (true, state.rng.gen_range(min..=max))
+        if !state.active() {
+            return None;
+        }
+        let seed = state.seed();
+        let mut rng = decision_rng(seed, "chain", height, FaultKind::Reorg.label());
+        if let Some(fault) = state.scenario_fault(FaultKind::Reorg, None, &mut rng) {
+            let max_depth = fault?.max_depth.unwrap_or(1).max(1);
+            return Some(rng.gen_range(1..=max_depth));
+        }
+        let max_depth = state.config.max_reorg_depth.max(1);
+        if !rng.gen_bool(state.config.reorg_rate) {
+            return None;
+        }
+        let depth = rng.gen_range(1..=max_depth);
+        info!("Chaos reorg of {} block(s) at key {}/chain/{}/reorg", depth, seed, height);
+        Some(depth)
+    }
+
+    pub async fn inject_latency(&self, ctx: &ChaosContext<'_>) {
+        let delay = self.decide(ctx, FaultKind::Latency.label(), |state, rng| {
+            // The scenario's fault, or the flat config
+            if let Some(fault) = state.scenario_fault(FaultKind::Latency, Some(ctx), rng) {
+                return fault.map(|f| rng.gen_range(f.min_latency_ms..=f.max_latency_ms)).filter(|d| *d > 0);
+            }
+            let mut delay = 0;
+            if state.config.max_latency_ms > 0 {
+                delay = rng.gen_range(state.config.min_latency_ms..=state.config.max_latency_ms);
+            }
+
+            // Matching rules can only make it slower: the longest delay wins
+            for compiled in state.rules.iter().filter(|c| c.rule.max_latency_ms > 0 && c.target.matches(Some(ctx))) {
+                delay = delay.max(rng.gen_range(compiled.rule.min_latency_ms..=compiled.rule.max_latency_ms));
             }
-        };
+            Some(delay).filter(|d| *d > 0)
+        });
 
-        if enabled && delay > 0 {
+        if let Some(delay) = delay {
             tokio::time::sleep(Duration::from_millis(delay)).await;
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(seed: u64) -> ChaosEngine {
+        let engine = ChaosEngine::new();
+        let config = ChaosConfig { enabled: true, seed, global_failure_rate: 0.5, payment_failure_rate: 0.5, rug_rate: 0.5, ..Default::default() };
+        engine.set_config(config).unwrap();
+        engine
+    }
+
+    /// Rolls network and payment failures for `requests` and returns every decision.
+    fn run(engine: &ChaosEngine, requests: &[&str]) -> Vec<ChaosDecision> {
+        let log = DecisionLog::default();
+        for agent_id in requests {
+            let ctx = ChaosContext {
+                agent_id,
+                host: "api.openai.com",
+                path: "/v1/chat/completions",
+                method: "POST",
+                request_type: RequestType::AiInference,
+                seq: engine.next_seq(agent_id),
+                decisions: &log,
+            };
+            engine.roll_network_failure(&ctx);
+            engine.roll_payment_failure(&ctx);
+        }
+        log.take()
+    }
+
+    #[test]
+    fn same_seed_agent_and_order_decide_the_same() {
+        let requests = ["agent-a"; 16];
+        let first = run(&engine(42), &requests);
+        assert_eq!(first.len(), 32);
+        assert_eq!(first, run(&engine(42), &requests));
+        assert!(first.iter().any(|d| d.hit) && first.iter().any(|d| !d.hit));
+        assert_ne!(first.iter().map(|d| d.hit).collect::<Vec<_>>(), run(&engine(7), &requests).iter().map(|d| d.hit).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn other_agents_do_not_shift_an_agents_decisions() {
+        let alone = run(&engine(42), &["agent-a"; 8]);
+        let interleaved = run(&engine(42), &["agent-a", "agent-b"].repeat(8));
+        let own: Vec<_> = interleaved.into_iter().filter(|d| d.agent_id == "agent-a").collect();
+        assert_eq!(alone, own);
+    }
+
+    #[test]
+    fn reconfiguring_restarts_the_request_numbers() {
+        let engine = engine(42);
+        let first = run(&engine, &["agent-a"; 4]);
+        engine.set_config(engine.get_config()).unwrap();
+        assert_eq!(first, run(&engine, &["agent-a"; 4]));
+    }
+
+    #[test]
+    fn request_numbers_forget_the_idlest_agent_once_full() {
+        let engine = engine(42);
+        for i in 0..MAX_TRACKED_AGENTS {
+            engine.next_seq(&format!("agent-{}", i));
+        }
+        assert_eq!(engine.next_seq("agent-0"), 2);
+        assert_eq!(engine.next_seq("newcomer"), 1);
+        assert_eq!(engine.state.lock().unwrap().seqs.len(), MAX_TRACKED_AGENTS);
+        assert_eq!(engine.next_seq("agent-0"), 3);
+        // agent-1 had gone longest without a request, so it starts over
+        assert_eq!(engine.next_seq("agent-1"), 1);
+    }
+}
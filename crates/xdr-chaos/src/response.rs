@@ -0,0 +1,137 @@
+//! Response-side faults: the upstream answered, but what reaches the agent is
+//! broken. The engine only decides which faults hit; the proxy applies them to
+//! the upstream body as it streams through.
+
+use serde::{Deserialize, Serialize};
+
+use crate::rules::Target;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFaultKind {
+    /// Throttle the body to `bytes_per_sec`
+    SlowBody,
+    /// End the body cleanly after `after_bytes` (a short read, e.g. an SSE stream that just stops)
+    Truncate,
+    /// Corrupt the body so it no longer parses as JSON (buffers the body)
+    MalformedJson,
+    /// Answer 200 with a provider-style error body instead of the real response
+    ErrorBody,
+    /// Replace the Content-Type header
+    WrongContentType,
+    /// Drop the connection after `after_bytes`, without finishing the body
+    ConnectionReset,
+}
+
+impl ResponseFaultKind {
+    /// Name as written in config files.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::SlowBody => "slow_body",
+            Self::Truncate => "truncate",
+            Self::MalformedJson => "malformed_json",
+            Self::ErrorBody => "error_body",
+            Self::WrongContentType => "wrong_content_type",
+            Self::ConnectionReset => "connection_reset",
+        }
+    }
+}
+
+/// Body sent by `error_body` unless the fault sets its own (OpenAI's shape).
+pub const DEFAULT_ERROR_BODY: &str = r#"{"error":{"message":"The server had an error while processing your request. Sorry about that!","type":"server_error","param":null,"code":null}}"#;
+
+/// Content-Type set by `wrong_content_type` unless the fault sets its own.
+pub const DEFAULT_WRONG_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+const DEFAULT_TRUNCATE_BYTES: usize = 64;
+
+fn default_rate() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseFault {
+    pub kind: ResponseFaultKind,
+    /// Chance per response (default 1.0: every time)
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+    /// Only responses to requests matching these
+    #[serde(flatten)]
+    pub target: Target,
+    /// `slow_body`: throughput
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_per_sec: Option<u64>,
+    /// `truncate` (default 64) and `connection_reset` (default 0): bytes
+    /// delivered before the cut
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after_bytes: Option<usize>,
+    /// `error_body`: the body sent with the 200
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// `wrong_content_type`: the Content-Type sent instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+impl ResponseFault {
+    /// Bytes delivered before a `truncate` or `connection_reset` cut.
+    pub fn cut_after(&self) -> usize {
+        match self.kind {
+            ResponseFaultKind::Truncate => self.after_bytes.unwrap_or(DEFAULT_TRUNCATE_BYTES),
+            _ => self.after_bytes.unwrap_or(0),
+        }
+    }
+
+    /// Human-readable description for traces, e.g. "truncate after 64 bytes".
+    pub fn describe(&self) -> String {
+        let text = match self.kind {
+            ResponseFaultKind::SlowBody => format!("slow_body at {} B/s", self.bytes_per_sec.unwrap_or_default()),
+            ResponseFaultKind::Truncate | ResponseFaultKind::ConnectionReset => {
+                format!("{} after {} bytes", self.kind.label(), self.cut_after())
+            }
+            ResponseFaultKind::WrongContentType => format!(
+                "wrong_content_type ({})",
+                self.content_type.as_deref().unwrap_or(DEFAULT_WRONG_CONTENT_TYPE)
+            ),
+            kind => kind.label().to_string(),
+        };
+        text + &self.target.describe()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        let context = format!("response fault {}", self.kind.label());
+        if !(0.0..=1.0).contains(&self.rate) {
+            return Err(format!("{}: rate must be between 0 and 1", context));
+        }
+        if self.kind == ResponseFaultKind::SlowBody && self.bytes_per_sec.unwrap_or_default() == 0 {
+            return Err(format!("{}: bytes_per_sec is required", context));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fault(kind: ResponseFaultKind) -> ResponseFault {
+        ResponseFault { kind, rate: 1.0, target: Target::default(), bytes_per_sec: None, after_bytes: None, body: None, content_type: None }
+    }
+
+    #[test]
+    fn validate_checks_the_rate_and_slow_body_throughput() {
+        assert!(fault(ResponseFaultKind::Truncate).validate().is_ok());
+        assert!(ResponseFault { rate: 1.5, ..fault(ResponseFaultKind::Truncate) }.validate().is_err());
+        assert!(ResponseFault { rate: -0.1, ..fault(ResponseFaultKind::ErrorBody) }.validate().is_err());
+        assert!(fault(ResponseFaultKind::SlowBody).validate().is_err());
+        assert!(ResponseFault { bytes_per_sec: Some(0), ..fault(ResponseFaultKind::SlowBody) }.validate().is_err());
+        assert!(ResponseFault { bytes_per_sec: Some(100), ..fault(ResponseFaultKind::SlowBody) }.validate().is_ok());
+    }
+
+    #[test]
+    fn cuts_default_per_kind() {
+        assert_eq!(fault(ResponseFaultKind::Truncate).cut_after(), 64);
+        assert_eq!(fault(ResponseFaultKind::ConnectionReset).cut_after(), 0);
+        assert_eq!(ResponseFault { after_bytes: Some(10), ..fault(ResponseFaultKind::Truncate) }.describe(), "truncate after 10 bytes");
+    }
+}
@@ -0,0 +1,215 @@
+//! Targeted chaos: rules that only hit some requests. A `Target` matches on
+//! agent, upstream host, path, HTTP method and request type; fields left out
+//! match anything. Several rules can be active at once, on top of the flat
+//! `ChaosConfig` rates.
+
+use globset::{GlobBuilder, GlobMatcher};
+use serde::{Deserialize, Serialize};
+
+use crate::ChaosContext;
+
+/// Statuses a network failure picks from unless a rule or fault lists its own.
+pub(crate) const DEFAULT_ERROR_STATUSES: [u16; 3] = [503, 429, 504];
+
+/// What a request is for, as classified by the proxy from its upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestType {
+    AiInference,
+    Rpc,
+    Unknown,
+}
+
+impl RequestType {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::AiInference => "ai_inference",
+            Self::Rpc => "rpc",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+impl std::str::FromStr for RequestType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "_").as_str() {
+            "ai_inference" | "ai" => Ok(Self::AiInference),
+            "rpc" => Ok(Self::Rpc),
+            "unknown" => Ok(Self::Unknown),
+            other => Err(format!("Unknown request type '{}' (expected ai_inference, rpc or unknown)", other)),
+        }
+    }
+}
+
+/// Which requests a rule or scenario fault applies to. Globs on agent and
+/// host are case-insensitive.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Target {
+    /// Glob on the agent id, e.g. `swarm-*`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+    /// Glob on the upstream host; a plain domain also matches its subdomains
+    /// (`openai.com` matches `api.openai.com`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Glob on the request path, e.g. `/v1/chat/*`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// HTTP method, e.g. `POST`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_type: Option<RequestType>,
+}
+
+impl Target {
+    /// True when every request matches.
+    pub fn is_any(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// " for swarm-* on openai.com POST /v1/chat/* (ai_inference)"; empty for any request.
+    pub fn describe(&self) -> String {
+        let mut text = String::new();
+        if let Some(agent) = &self.agent {
+            text.push_str(&format!(" for {}", agent));
+        }
+        if let Some(host) = &self.host {
+            text.push_str(&format!(" on {}", host));
+        }
+        match (&self.method, &self.path) {
+            (Some(method), Some(path)) => text.push_str(&format!(" {} {}", method.to_ascii_uppercase(), path)),
+            (Some(method), None) => text.push_str(&format!(" {}", method.to_ascii_uppercase())),
+            (None, Some(path)) => text.push_str(&format!(" {}", path)),
+            (None, None) => {}
+        }
+        if let Some(request_type) = self.request_type {
+            text.push_str(&format!(" ({})", request_type.label()));
+        }
+        text
+    }
+
+    pub fn compile(&self) -> Result<TargetMatcher, String> {
+        let optional = |pattern: &Option<String>, field, case_insensitive| {
+            pattern.as_deref().map(|p| glob(p, field, case_insensitive)).transpose()
+        };
+        Ok(TargetMatcher {
+            any: self.is_any(),
+            agent: optional(&self.agent, "agent", true)?,
+            host: optional(&self.host, "host", true)?.map(|g| (self.host.clone().unwrap_or_default().to_ascii_lowercase(), g)),
+            path: optional(&self.path, "path", false)?,
+            method: self.method.clone(),
+            request_type: self.request_type,
+        })
+    }
+}
+
+/// Compiles a glob from a config file; `field` names it in the error. Shared
+/// with the proxy's pricing, rate-limit, approval and mock AI tables.
+pub fn glob(pattern: &str, field: &str, case_insensitive: bool) -> Result<GlobMatcher, String> {
+    GlobBuilder::new(pattern)
+        .case_insensitive(case_insensitive)
+        .build()
+        .map(|g| g.compile_matcher())
+        .map_err(|e| format!("Invalid {} glob '{}': {}", field, pattern, e))
+}
+
+/// A compiled `Target`.
+#[derive(Debug, Clone)]
+pub struct TargetMatcher {
+    any: bool,
+    agent: Option<GlobMatcher>,
+    /// Lowercased pattern (for the subdomain check) and its glob
+    host: Option<(String, GlobMatcher)>,
+    path: Option<GlobMatcher>,
+    method: Option<String>,
+    request_type: Option<RequestType>,
+}
+
+impl TargetMatcher {
+    /// Chain-wide checks (reorgs) have no request: only untargeted entries match.
+    pub(crate) fn matches(&self, ctx: Option<&ChaosContext>) -> bool {
+        let Some(ctx) = ctx else { return self.any };
+        self.matches_request(ctx.agent_id, ctx.host, ctx.path, ctx.method, ctx.request_type)
+    }
+
+    pub fn matches_request(&self, agent_id: &str, host: &str, path: &str, method: &str, request_type: RequestType) -> bool {
+        self.agent.as_ref().is_none_or(|g| g.is_match(agent_id))
+            && self.host.as_ref().is_none_or(|(domain, g)| {
+                g.is_match(host) || host.to_ascii_lowercase().ends_with(&format!(".{}", domain))
+            })
+            && self.path.as_ref().is_none_or(|g| g.is_match(path))
+            && self.method.as_deref().is_none_or(|m| m.eq_ignore_ascii_case(method))
+            && self.request_type.is_none_or(|t| t == request_type)
+    }
+}
+
+/// A set of fault rates that only applies to matching requests.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChaosRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub target: Target,
+    /// Chance of failing the request with an error status
+    #[serde(default)]
+    pub failure_rate: f64,
+    /// Statuses to pick from (default 503, 429, 504)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub status: Vec<u16>,
+    #[serde(default)]
+    pub payment_failure_rate: f64,
+    #[serde(default)]
+    pub rug_rate: f64,
+    #[serde(default)]
+    pub min_latency_ms: u64,
+    #[serde(default)]
+    pub max_latency_ms: u64,
+}
+
+impl ChaosRule {
+    /// The rule's name, or what it targets.
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None if self.target.is_any() => "all requests".to_string(),
+            None => self.target.describe().trim_start().to_string(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (field, rate) in [
+            ("failure_rate", self.failure_rate),
+            ("payment_failure_rate", self.payment_failure_rate),
+            ("rug_rate", self.rug_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("rule '{}': {} must be between 0 and 1", self.label(), field));
+            }
+        }
+        if self.min_latency_ms > self.max_latency_ms {
+            return Err(format!("rule '{}': min_latency_ms is above max_latency_ms", self.label()));
+        }
+        if let Some(code) = self.status.iter().find(|c| !(100..=599).contains(*c)) {
+            return Err(format!("rule '{}': invalid status {}", self.label(), code));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct CompiledRule {
+    pub(crate) rule: ChaosRule,
+    pub(crate) target: TargetMatcher,
+}
+
+pub(crate) fn compile_rules(rules: &[ChaosRule]) -> Result<Vec<CompiledRule>, String> {
+    rules.iter()
+        .map(|rule| {
+            rule.validate()?;
+            let target = rule.target.compile().map_err(|e| format!("rule '{}': {}", rule.label(), e))?;
+            Ok(CompiledRule { rule: rule.clone(), target })
+        })
+        .collect()
+}
@@ -0,0 +1,363 @@
+//! Scripted chaos: a scenario is a timeline of phases, each with its own set
+//! of faults. A phase ends after `duration_secs`, after `requests` requests,
+//! or whichever comes first; the last phase may run forever. While a scenario
+//! is loaded it replaces the flat `ChaosConfig` probabilities.
+//!
+//! The timeline is shared: a phase's `requests` count every agent's requests
+//! in the order they reach the proxy, so with agents running concurrently
+//! the request that switches phases depends on how they interleave. `on_call`
+//! counts per agent (reorgs count per block), so a fault aimed at one agent's
+//! Nth call fires on the same call however the others are scheduled.
+
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::rules::{Target, TargetMatcher};
+use crate::ChaosContext;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Seed for the fault dice (0 unless set)
+    #[serde(default)]
+    pub seed: u64,
+    /// Start over from the first phase after the last one ends
+    #[serde(default)]
+    pub repeat: bool,
+    pub phases: Vec<Phase>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Phase {
+    pub name: String,
+    /// The phase ends after this many seconds...
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+    /// ...or after this many requests, counted across all agents
+    #[serde(default)]
+    pub requests: Option<u64>,
+    /// Faults active during the phase (none: a clean phase)
+    #[serde(default)]
+    pub faults: Vec<Fault>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind {
+    /// Fail the request with an error status before it is processed
+    NetworkFailure,
+    /// Delay the request
+    Latency,
+    /// The payment transaction fails on-chain
+    PaymentFailure,
+    /// The payment settles but the request is dropped
+    RugPull,
+    /// Reorg the tip after a block is mined (chain-wide; can't be targeted)
+    Reorg,
+}
+
+impl FaultKind {
+    /// Name as written in scenario files.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::NetworkFailure => "network_failure",
+            Self::Latency => "latency",
+            Self::PaymentFailure => "payment_failure",
+            Self::RugPull => "rug_pull",
+            Self::Reorg => "reorg",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// Chance per check (default 1.0: every time)
+    #[serde(default)]
+    pub rate: Option<f64>,
+    /// Fire on the Nth matching check of each agent in the phase (e.g. its
+    /// 5th paid call for a rug pull) instead of rolling dice
+    #[serde(default)]
+    pub on_call: Option<u64>,
+    /// Only requests matching these (agent, host, path, method, request_type)
+    #[serde(flatten)]
+    pub target: Target,
+    /// `network_failure`: statuses to pick from (default 503, 429, 504)
+    #[serde(default)]
+    pub status: Vec<u16>,
+    /// `latency`: delay range in milliseconds
+    #[serde(default)]
+    pub min_latency_ms: u64,
+    #[serde(default)]
+    pub max_latency_ms: u64,
+    /// `reorg`: deepest reorg in blocks (default 1)
+    #[serde(default)]
+    pub max_depth: Option<u64>,
+}
+
+impl Fault {
+    /// Human-readable description for logs, e.g. "40% network_failure on openai.com".
+    pub fn describe(&self) -> String {
+        let text = match self.on_call {
+            Some(n) => format!("{} on call #{}", self.kind.label(), n),
+            None => format!("{}% {}", (self.rate.unwrap_or(1.0) * 100.0).round(), self.kind.label()),
+        };
+        text + &self.target.describe()
+    }
+}
+
+impl Scenario {
+    /// Rejects scenarios that would never run as written.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.phases.is_empty() {
+            return Err("scenario has no phases".to_string());
+        }
+        for (i, phase) in self.phases.iter().enumerate() {
+            let last = i + 1 == self.phases.len();
+            let bounded = phase.duration_secs.is_some() || phase.requests.is_some();
+            if !bounded && (!last || self.repeat) {
+                return Err(format!("phase '{}' needs duration_secs or requests (only the last phase of a non-repeating scenario can run forever)", phase.name));
+            }
+            if phase.duration_secs == Some(0) || phase.requests == Some(0) {
+                return Err(format!("phase '{}': duration_secs and requests must be greater than zero", phase.name));
+            }
+            for fault in &phase.faults {
+                let context = format!("phase '{}', {} fault", phase.name, fault.kind.label());
+                if fault.rate.is_some_and(|r| !(0.0..=1.0).contains(&r)) {
+                    return Err(format!("{}: rate must be between 0 and 1", context));
+                }
+                if fault.on_call == Some(0) {
+                    return Err(format!("{}: on_call counts from 1", context));
+                }
+                if fault.min_latency_ms > fault.max_latency_ms {
+                    return Err(format!("{}: min_latency_ms is above max_latency_ms", context));
+                }
+                if fault.kind == FaultKind::Latency && fault.max_latency_ms == 0 {
+                    return Err(format!("{}: max_latency_ms is required", context));
+                }
+                if let Some(code) = fault.status.iter().find(|c| !(100..=599).contains(*c)) {
+                    return Err(format!("{}: invalid status {}", context, code));
+                }
+                if fault.kind == FaultKind::Reorg && !fault.target.is_any() {
+                    return Err(format!("{}: reorgs are chain-wide and can't target requests", context));
+                }
+                fault.target.compile().map_err(|e| format!("{}: {}", context, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a running scenario is, for the control plane and TUI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScenarioStatus {
+    pub name: Option<String>,
+    /// 1-based
+    pub phase: usize,
+    pub phases: usize,
+    pub phase_name: String,
+    pub elapsed_secs: u64,
+    #[serde(default)]
+    pub phase_remaining_secs: Option<u64>,
+    #[serde(default)]
+    pub phase_remaining_requests: Option<u64>,
+    /// The last phase has ended (no faults are injected any more)
+    pub finished: bool,
+}
+
+impl ScenarioStatus {
+    /// "game-day 2/4 openai-brownout (42s left)"
+    pub fn label(&self) -> String {
+        let name = self.name.as_deref().unwrap_or("scenario");
+        if self.finished {
+            return format!("{} finished", name);
+        }
+        let remaining = match (self.phase_remaining_secs, self.phase_remaining_requests) {
+            (Some(secs), Some(reqs)) => format!(" ({}s / {} req left)", secs, reqs),
+            (Some(secs), None) => format!(" ({}s left)", secs),
+            (None, Some(reqs)) => format!(" ({} req left)", reqs),
+            (None, None) => String::new(),
+        };
+        format!("{} {}/{} {}{}", name, self.phase, self.phases, self.phase_name, remaining)
+    }
+}
+
+/// A scenario being played.
+pub(crate) struct ScenarioRun {
+    scenario: Scenario,
+    started: Instant,
+    phase: usize,
+    phase_started: Instant,
+    phase_requests: u64,
+    /// Matching checks per agent (None for chain-wide checks) and fault of
+    /// the current phase (for `on_call`)
+    calls: HashMap<Option<String>, Vec<u64>>,
+    /// Compiled targets, per phase and fault
+    targets: Vec<Vec<TargetMatcher>>,
+    finished: bool,
+}
+
+impl ScenarioRun {
+    pub(crate) fn start(scenario: Scenario) -> Result<Self, String> {
+        let targets = scenario.phases.iter()
+            .map(|phase| phase.faults.iter().map(|fault| fault.target.compile()).collect::<Result<Vec<_>, _>>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let now = Instant::now();
+        Ok(Self { scenario, started: now, phase: 0, phase_started: now, phase_requests: 0, calls: HashMap::new(), targets, finished: false })
+    }
+
+    pub(crate) fn scenario(&self) -> &Scenario {
+        &self.scenario
+    }
+
+    fn enter(&mut self, index: usize, at: Instant) {
+        let index = match index >= self.scenario.phases.len() {
+            true if self.scenario.repeat => 0,
+            true => {
+                self.finished = true;
+                return;
+            }
+            false => index,
+        };
+        self.phase = index;
+        self.phase_started = at;
+        self.phase_requests = 0;
+        self.calls.clear();
+    }
+
+    /// Moves past every phase whose time is up. Returns true if the phase changed.
+    fn advance_clock(&mut self, now: Instant) -> bool {
+        let mut changed = false;
+        while !self.finished {
+            let Some(duration) = self.scenario.phases[self.phase].duration_secs else { break };
+            let ends = self.phase_started + Duration::from_secs(duration);
+            if now < ends {
+                break;
+            }
+            self.enter(self.phase + 1, ends);
+            changed = true;
+        }
+        changed
+    }
+
+    /// Counts a new request against the timeline. Returns true if it starts a new phase.
+    pub(crate) fn on_request(&mut self) -> bool {
+        let now = Instant::now();
+        let mut changed = self.advance_clock(now);
+        if !self.finished && self.scenario.phases[self.phase].requests.is_some_and(|max| self.phase_requests >= max) {
+            self.enter(self.phase + 1, now);
+            changed = true;
+        }
+        if !self.finished {
+            self.phase_requests += 1;
+        }
+        changed
+    }
+
+    /// The first fault of `kind` in the current phase that applies to `ctx` and fires.
+    pub(crate) fn fire(&mut self, kind: FaultKind, ctx: Option<&ChaosContext>, rng: &mut ChaCha8Rng) -> Option<Fault> {
+        self.advance_clock(Instant::now());
+        if self.finished {
+            return None;
+        }
+        let faults = &self.scenario.phases[self.phase].faults;
+        let calls = self.calls.entry(ctx.map(|c| c.agent_id.to_string())).or_insert_with(|| vec![0; faults.len()]);
+        for (i, fault) in faults.iter().enumerate() {
+            if fault.kind != kind || !self.targets[self.phase][i].matches(ctx) {
+                continue;
+            }
+            calls[i] += 1;
+            let fires = match fault.on_call {
+                Some(n) => calls[i] == n,
+                None => rng.gen_bool(fault.rate.unwrap_or(1.0)),
+            };
+            if fires {
+                return Some(fault.clone());
+            }
+        }
+        None
+    }
+
+    pub(crate) fn status(&mut self) -> ScenarioStatus {
+        let now = Instant::now();
+        self.advance_clock(now);
+        let phase = &self.scenario.phases[self.phase];
+        let phase_elapsed = now.saturating_duration_since(self.phase_started).as_secs();
+        ScenarioStatus {
+            name: self.scenario.name.clone(),
+            phase: self.phase + 1,
+            phases: self.scenario.phases.len(),
+            phase_name: phase.name.clone(),
+            elapsed_secs: now.saturating_duration_since(self.started).as_secs(),
+            phase_remaining_secs: phase.duration_secs.map(|d| d.saturating_sub(phase_elapsed)),
+            phase_remaining_requests: phase.requests.map(|r| r.saturating_sub(self.phase_requests)),
+            finished: self.finished,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::RequestType;
+    use crate::DecisionLog;
+    use rand::SeedableRng;
+
+    fn run(json: &str) -> ScenarioRun {
+        let scenario: Scenario = serde_json::from_str(json).unwrap();
+        scenario.validate().unwrap();
+        ScenarioRun::start(scenario).unwrap()
+    }
+
+    fn rug(run: &mut ScenarioRun, agent_id: &str) -> bool {
+        let decisions = DecisionLog::default();
+        let ctx = ChaosContext {
+            agent_id,
+            host: "api.openai.com",
+            path: "/v1/chat/completions",
+            method: "POST",
+            request_type: RequestType::AiInference,
+            seq: 0,
+            decisions: &decisions,
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        run.fire(FaultKind::RugPull, Some(&ctx), &mut rng).is_some()
+    }
+
+    #[test]
+    fn on_call_counts_each_agents_checks() {
+        let mut run = run(r#"{ "phases": [{ "name": "rug", "faults": [{ "kind": "rug_pull", "on_call": 2 }] }] }"#);
+        // Interleaving doesn't matter: each agent is pulled on its own second call
+        assert!(!rug(&mut run, "agent-a"));
+        assert!(!rug(&mut run, "agent-b"));
+        assert!(rug(&mut run, "agent-b"));
+        assert!(rug(&mut run, "agent-a"));
+        assert!(!rug(&mut run, "agent-a"));
+        assert!(!rug(&mut run, "agent-b"));
+    }
+
+    #[test]
+    fn phase_requests_count_every_agent_in_arrival_order() {
+        let mut run = run(r#"{ "phases": [
+            { "name": "warmup", "requests": 2 },
+            { "name": "rug", "requests": 1, "faults": [{ "kind": "rug_pull", "on_call": 1 }] }
+        ] }"#);
+        assert!(!run.on_request());
+        assert!(!rug(&mut run, "agent-a"));
+        assert!(!run.on_request());
+        assert_eq!(run.status().phase_remaining_requests, Some(0));
+
+        // The third request of any agent opens the next phase
+        assert!(run.on_request());
+        assert_eq!(run.status().phase_name, "rug");
+        assert!(rug(&mut run, "agent-b"));
+
+        assert!(run.on_request());
+        assert!(run.status().finished);
+        assert!(!rug(&mut run, "agent-a"));
+    }
+}
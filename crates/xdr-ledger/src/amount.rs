@@ -0,0 +1,308 @@
+//! Fixed-point money.
+//!
+//! Amounts are held as integer base units with 6 decimals (1 USDC =
+//! 1_000_000 units), so balances never drift and budget checks are exact.
+//! Amounts are written as decimal strings (`"0.01"`), which round-trip
+//! exactly; both strings and JSON numbers (`0.01`) are accepted. Code that
+//! answers clients can write numbers instead for the length of a
+//! `with_format` call, which never covers what gets persisted.
+//!
+//! The `+` and `-` operators (and `Sum`) are for math that stays in range by
+//! construction (a refund of what was taken, a part of a whole) and panic if
+//! it doesn't, in release builds too: a balance that wrapped or silently
+//! stuck at zero would hide the bug. Math driven by input goes through
+//! `checked_add` / `checked_sub` and turns overflow into an error.
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::cell::Cell;
+use std::str::FromStr;
+
+/// Base units per whole token
+const SCALE: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    /// Decimal places (USDC on Cronos)
+    pub const DECIMALS: u32 = 6;
+
+    pub const fn from_units(units: u64) -> Self {
+        Self(units)
+    }
+
+    pub const fn from_whole(whole: u64) -> Self {
+        Self(whole * SCALE)
+    }
+
+    /// Base units (what an ERC-20 transfer carries).
+    pub const fn units(self) -> u64 {
+        self.0
+    }
+
+    /// Nearest amount to a float. Only for inputs that arrive as JSON numbers.
+    pub fn from_f64(value: f64) -> Result<Self, String> {
+        if !value.is_finite() || value < 0.0 {
+            return Err(format!("invalid amount {}", value));
+        }
+        let units = (value * SCALE as f64).round();
+        if units > u64::MAX as f64 {
+            return Err(format!("amount {} is too large", value));
+        }
+        Ok(Self(units as u64))
+    }
+
+    /// Lossy conversion for display math (gauges, percentages).
+    pub fn as_f64(self) -> f64 {
+        self.0 as f64 / SCALE as f64
+    }
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+
+    pub fn saturating_add(self, other: Self) -> Self {
+        Self(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Self) -> Self {
+        Self(self.0.saturating_sub(other.0))
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, other: Self) -> Self {
+        self.checked_add(other).expect("Amount overflow")
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, other: Self) -> Self {
+        self.checked_sub(other).expect("Amount underflow")
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, other: Self) {
+        *self = *self - other;
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::ZERO, Add::add)
+    }
+}
+
+/// At least two decimals, more only when they carry value: `100.00`, `0.01`, `0.000001`.
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let whole = self.0 / SCALE;
+        let frac = format!("{:06}", self.0 % SCALE);
+        let frac = frac.trim_end_matches('0');
+        write!(f, "{}.{:0<2}", whole, frac)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = String;
+
+    /// Parses a plain decimal ("12", "0.01", "1.500000") exactly.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid amount '{}'", s);
+        let (whole, frac) = s.trim().split_once('.').unwrap_or((s.trim(), ""));
+        if whole.is_empty() && frac.is_empty() {
+            return Err(invalid());
+        }
+        if !whole.chars().all(|c| c.is_ascii_digit()) || !frac.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        if frac.len() > Self::DECIMALS as usize {
+            return Err(format!("amount '{}' has more than {} decimal places", s, Self::DECIMALS));
+        }
+        let whole: u64 = if whole.is_empty() { 0 } else { whole.parse().map_err(|_| invalid())? };
+        let frac: u64 = format!("{:0<6}", frac).parse().map_err(|_| invalid())?;
+        whole
+            .checked_mul(SCALE)
+            .and_then(|w| w.checked_add(frac))
+            .map(Self)
+            .ok_or_else(|| format!("amount '{}' is too large", s))
+    }
+}
+
+/// How amounts are written in JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmountFormat {
+    /// `0.01`
+    Number,
+    /// `"0.01"` (exact for any client)
+    String,
+}
+
+impl FromStr for AmountFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "number" => Ok(Self::Number),
+            "string" => Ok(Self::String),
+            other => Err(format!("Unknown amount format '{}' (expected number or string)", other)),
+        }
+    }
+}
+
+thread_local! {
+    static FORMAT: Cell<AmountFormat> = const { Cell::new(AmountFormat::String) };
+}
+
+/// Runs `f` with every `Amount` it serializes on this thread written in
+/// `format`; outside of it amounts are always strings.
+pub fn with_format<R>(format: AmountFormat, f: impl FnOnce() -> R) -> R {
+    struct Restore(AmountFormat);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            FORMAT.set(self.0);
+        }
+    }
+    let _restore = Restore(FORMAT.replace(format));
+    f()
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match FORMAT.get() {
+            // Shortest float that round-trips, so 0.01 stays 0.01
+            AmountFormat::Number => serializer.serialize_f64(self.as_f64()),
+            AmountFormat::String => serializer.collect_str(self),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(AmountVisitor)
+    }
+}
+
+struct AmountVisitor;
+
+impl Visitor<'_> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a non-negative decimal amount, as a number or string")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Amount, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Amount, E> {
+        v.checked_mul(SCALE).map(Amount).ok_or_else(|| E::custom(format!("amount {} is too large", v)))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Amount, E> {
+        u64::try_from(v)
+            .map_err(|_| E::custom(format!("amount {} is negative", v)))
+            .and_then(|v| self.visit_u64(v))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Amount, E> {
+        Amount::from_f64(v).map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_decimals_exactly() {
+        assert_eq!("12".parse::<Amount>().unwrap(), Amount::from_whole(12));
+        assert_eq!("0.01".parse::<Amount>().unwrap(), Amount::from_units(10_000));
+        assert_eq!(".5".parse::<Amount>().unwrap(), Amount::from_units(500_000));
+        assert_eq!("1.".parse::<Amount>().unwrap(), Amount::from_whole(1));
+        assert_eq!(" 1.500000 ".parse::<Amount>().unwrap(), Amount::from_units(1_500_000));
+        assert_eq!("0.000001".parse::<Amount>().unwrap(), Amount::from_units(1));
+    }
+
+    #[test]
+    fn rejects_malformed_and_out_of_range_amounts() {
+        for bad in ["", ".", "-1", "1e3", "1.2.3", "abc", "0.0000001", "18446744073710"] {
+            assert!(bad.parse::<Amount>().is_err(), "{:?} should not parse", bad);
+        }
+        assert!(Amount::from_f64(-0.5).is_err());
+        assert!(Amount::from_f64(f64::NAN).is_err());
+        assert_eq!(Amount::from_f64(0.1).unwrap(), Amount::from_units(100_000));
+    }
+
+    #[test]
+    fn displays_at_least_two_decimals() {
+        assert_eq!(Amount::from_whole(100).to_string(), "100.00");
+        assert_eq!(Amount::from_units(10_000).to_string(), "0.01");
+        assert_eq!(Amount::from_units(1).to_string(), "0.000001");
+        assert_eq!(Amount::from_units(1_250_000).to_string(), "1.25");
+    }
+
+    #[test]
+    fn checked_math_reports_overflow() {
+        let max = Amount::from_units(u64::MAX);
+        let one = Amount::from_units(1);
+        assert_eq!(max.checked_add(one), None);
+        assert_eq!(Amount::ZERO.checked_sub(one), None);
+        assert_eq!(max.saturating_add(one), max);
+        assert_eq!(Amount::from_whole(3) - Amount::from_whole(1), Amount::from_whole(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount overflow")]
+    fn adding_past_the_largest_amount_panics() {
+        let _ = [Amount::from_units(u64::MAX), Amount::from_units(1)].into_iter().sum::<Amount>();
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount underflow")]
+    fn subtracting_below_zero_panics() {
+        let mut balance = Amount::from_units(1);
+        balance -= Amount::from_whole(1);
+    }
+
+    #[test]
+    fn deserializes_numbers_and_strings() {
+        let parsed: Vec<Amount> = serde_json::from_str(r#"[0.01, "0.01", 2, "2"]"#).unwrap();
+        assert_eq!(parsed, [Amount::from_units(10_000), Amount::from_units(10_000), Amount::from_whole(2), Amount::from_whole(2)]);
+        assert!(serde_json::from_str::<Amount>("-1").is_err());
+    }
+
+    #[test]
+    fn serializes_as_a_string_outside_with_format() {
+        let large = Amount::from_units(9_007_199_254_740_993);
+        assert_eq!(serde_json::to_string(&large).unwrap(), r#""9007199254.740993""#);
+        let number = with_format(AmountFormat::Number, || serde_json::to_string(&Amount::from_units(10_000)).unwrap());
+        assert_eq!(number, "0.01");
+        assert_eq!(serde_json::to_string(&large).unwrap(), r#""9007199254.740993""#);
+    }
+}
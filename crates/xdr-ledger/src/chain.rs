@@ -0,0 +1,149 @@
+//! Simulated block production.
+//!
+//! Payments enter a mempool and are included in the next mined block. With
+//! automine on (the default) every payment is mined straight away; otherwise
+//! blocks come from the chain clock or an explicit mine call. The most recent
+//! blocks are kept around so a shallow reorg can orphan them.
+
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use std::collections::VecDeque;
+
+use crate::{Transaction, GENESIS_BLOCK};
+
+/// How many recent blocks are kept (and can be reorged)
+pub const REORG_WINDOW: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxStatus {
+    /// In the mempool, waiting for the next block
+    Pending,
+    /// Included in a canonical block
+    Mined,
+    /// Its block was dropped by a reorg; the payment was reverted
+    Orphaned,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    pub number: u64,
+    pub hash: String,
+    pub parent_hash: String,
+    /// Unix seconds
+    pub timestamp: u64,
+    /// Tx hashes, in inclusion order
+    pub transactions: Vec<String>,
+}
+
+/// Current shape of the simulated chain (for the control plane and TUI).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainInfo {
+    pub height: u64,
+    pub head_hash: String,
+    pub automine: bool,
+    pub pending: usize,
+    pub reorgs: u64,
+}
+
+/// Outcome of a reorg: how deep it went and which payments it reverted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reorg {
+    pub depth: u64,
+    /// Height of the first replaced block
+    pub from_block: u64,
+    pub orphaned: Vec<Transaction>,
+}
+
+pub(crate) struct ChainState {
+    pub height: u64,
+    pub head_hash: String,
+    pub automine: bool,
+    pub mempool: Vec<String>,
+    pub recent: VecDeque<Block>,
+    pub reorgs: u64,
+}
+
+impl ChainState {
+    pub fn new() -> Self {
+        let genesis = Block {
+            number: GENESIS_BLOCK,
+            hash: block_hash("", GENESIS_BLOCK, 0),
+            parent_hash: format!("0x{}", "0".repeat(64)),
+            timestamp: 0,
+            transactions: Vec::new(),
+        };
+        Self {
+            height: genesis.number,
+            head_hash: genesis.hash.clone(),
+            automine: true,
+            mempool: Vec::new(),
+            recent: VecDeque::from([genesis]),
+            reorgs: 0,
+        }
+    }
+
+    /// Resumes from a persisted head. Older blocks are final, so the reorg
+    /// window starts again at the head.
+    pub fn resume(&mut self, head: Option<Block>) {
+        let automine = self.automine;
+        *self = Self::new();
+        self.automine = automine;
+        if let Some(head) = head {
+            self.height = head.number;
+            self.head_hash = head.hash.clone();
+            self.recent = VecDeque::from([head]);
+        }
+    }
+
+    pub fn next_block(&mut self, transactions: Vec<String>, timestamp: u64) -> Block {
+        let number = self.height + 1;
+        let block = Block {
+            number,
+            hash: block_hash(&self.head_hash, number, self.reorgs),
+            parent_hash: self.head_hash.clone(),
+            timestamp,
+            transactions,
+        };
+        self.height = number;
+        self.head_hash = block.hash.clone();
+        self.recent.push_back(block.clone());
+        if self.recent.len() > REORG_WINDOW {
+            self.recent.pop_front();
+        }
+        block
+    }
+
+    /// Drops up to `depth` blocks from the tip and returns them (newest first).
+    /// The oldest block in the window is never dropped so the new tip always
+    /// has a known parent.
+    pub fn rewind(&mut self, depth: u64) -> Vec<Block> {
+        let mut dropped = Vec::new();
+        while (dropped.len() as u64) < depth && self.recent.len() > 1 {
+            let block = self.recent.pop_back().expect("window is non-empty");
+            self.height = block.number - 1;
+            self.head_hash = block.parent_hash.clone();
+            dropped.push(block);
+        }
+        if !dropped.is_empty() {
+            self.reorgs += 1;
+        }
+        dropped
+    }
+
+    pub fn block(&self, number: u64) -> Option<Block> {
+        self.recent.iter().find(|b| b.number == number).cloned()
+    }
+
+    pub fn head(&self) -> Option<Block> {
+        self.recent.back().cloned()
+    }
+}
+
+/// Blocks carry no real content, so the hash commits to the parent, height and
+/// fork (reorg count) — replacement blocks get a different hash.
+pub fn block_hash(parent_hash: &str, number: u64, fork: u64) -> String {
+    let hash = Keccak256::digest(format!("xdr-block:{}:{}:{}", parent_hash, number, fork).as_bytes());
+    let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("0x{}", hex)
+}
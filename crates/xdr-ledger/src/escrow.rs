@@ -0,0 +1,123 @@
+//! Escrow holds for the `upto` payment scheme.
+//!
+//! The agent authorizes a maximum, which is transferred into escrow up front
+//! (and checked against balance, budget and policies like any payment). Once
+//! the request's real cost is known the hold is captured for that much, and
+//! the unused remainder goes back to the agent as a refund transaction. A
+//! hold can also be released whole when nothing was consumed. When the payee
+//! is another agent, the captured part is paid out to its wallet.
+
+use serde::{Deserialize, Serialize};
+
+use crate::Amount;
+
+/// Mock escrow contract that holds `upto` authorizations until capture
+pub const ESCROW_ADDRESS: &str = "0x00000000000000000000000000000000000e5c40";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldStatus {
+    /// Funds are in escrow, waiting for the final amount
+    Held,
+    /// Settled for `captured`; the rest was refunded
+    Captured,
+    /// Nothing consumed; everything was refunded
+    Released,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hold {
+    /// Same as the invoice the hold was reserved against
+    pub id: String,
+    pub agent_id: String,
+    /// Who is being paid (the upstream host)
+    pub payee: String,
+    /// Agent whose wallet the captured part is paid out to (agent-to-agent)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payee_agent: Option<String>,
+    pub currency: String,
+    /// The authorized maximum
+    pub amount: Amount,
+    pub captured: Amount,
+    pub refunded: Amount,
+    pub status: HoldStatus,
+    /// Transfer into escrow
+    pub tx_hash: String,
+    /// Transfer of the unused remainder back to the agent
+    #[serde(default)]
+    pub refund_tx: Option<String>,
+    /// Transfer of the captured part to the payee agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payout_tx: Option<String>,
+    /// Unix seconds
+    pub created_at: u64,
+    #[serde(default)]
+    pub settled_at: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Ledger, TxKind};
+
+    /// A ledger where agent-1 has 2 USDC held in escrow.
+    fn held() -> (Ledger, Hold) {
+        let ledger = Ledger::new();
+        ledger.register_or_get("agent-1");
+        let invoice = ledger.create_invoice("agent-1", Amount::from_whole(2), "USDC", "api.openai.com", None, None);
+        let (hold, _) = ledger.reserve(&invoice.id, "agent-1", "cronos-testnet", None).unwrap();
+        (ledger, hold)
+    }
+
+    fn usdc(ledger: &Ledger) -> Amount {
+        ledger.get_state("agent-1").unwrap().balance("USDC")
+    }
+
+    #[test]
+    fn capture_refunds_the_unspent_remainder() {
+        let (ledger, hold) = held();
+        assert_eq!(usdc(&ledger), Amount::from_whole(98));
+
+        let (hold, refund) = ledger.capture(&hold.id, "0.5".parse().unwrap()).unwrap();
+        let refund = refund.unwrap();
+        assert_eq!((hold.status, hold.captured, hold.refunded), (HoldStatus::Captured, "0.5".parse().unwrap(), "1.5".parse().unwrap()));
+        assert_eq!(hold.refund_tx.as_deref(), Some(refund.hash.as_str()));
+        assert_eq!((refund.kind, refund.from.as_str(), refund.amount), (TxKind::Refund, ESCROW_ADDRESS, "1.5".parse().unwrap()));
+        assert_eq!(usdc(&ledger), "99.5".parse().unwrap());
+        assert_eq!(ledger.get_state("agent-1").unwrap().total_spend, "0.5".parse().unwrap());
+    }
+
+    #[test]
+    fn capture_cannot_exceed_the_authorized_max() {
+        let (ledger, hold) = held();
+        let err = ledger.capture(&hold.id, "2.000001".parse().unwrap()).unwrap_err();
+        assert!(err.contains("only 2.00 was authorized"), "{}", err);
+        assert_eq!(ledger.get_hold(&hold.id).unwrap().status, HoldStatus::Held);
+        assert_eq!(usdc(&ledger), Amount::from_whole(98));
+
+        // The full maximum is fine, and leaves nothing to refund
+        let (hold, refund) = ledger.capture(&hold.id, Amount::from_whole(2)).unwrap();
+        assert_eq!(hold.refunded, Amount::ZERO);
+        assert!(refund.is_none());
+    }
+
+    #[test]
+    fn holds_settle_only_once() {
+        let (ledger, hold) = held();
+        ledger.capture(&hold.id, Amount::from_whole(1)).unwrap();
+        assert_eq!(ledger.capture(&hold.id, Amount::from_whole(1)).unwrap_err(), format!("hold {} is already captured", hold.id));
+        assert!(ledger.release(&hold.id).is_err());
+        assert_eq!(usdc(&ledger), Amount::from_whole(99));
+        assert!(ledger.capture("no-such-hold", Amount::ZERO).is_err());
+    }
+
+    #[test]
+    fn release_refunds_the_whole_hold() {
+        let (ledger, hold) = held();
+        let (hold, refund) = ledger.release(&hold.id).unwrap();
+        assert_eq!((hold.status, hold.captured), (HoldStatus::Released, Amount::ZERO));
+        assert_eq!(refund.unwrap().amount, Amount::from_whole(2));
+        assert_eq!(usdc(&ledger), Amount::from_whole(100));
+        assert_eq!(ledger.get_state("agent-1").unwrap().total_spend, Amount::ZERO);
+    }
+}
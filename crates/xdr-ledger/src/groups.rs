@@ -0,0 +1,144 @@
+//! Budget groups: shared wallets for swarms of agents.
+//!
+//! Groups form a tree (an org, its teams, their sub-teams). The top-level
+//! group holds the wallet every agent in the tree pays from; each group below
+//! it is an allocation, a USD cap on what its agents and subgroups may spend.
+//! A payment counts against the agent's own `budget_limit` and against every
+//! group from the agent's up to the root, and is refused if any level would
+//! go over.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::Amount;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BudgetGroup {
+    pub id: String,
+    /// None for a top-level group (which holds the shared wallet)
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// Shared wallet by token symbol (top-level groups only)
+    #[serde(default)]
+    pub balances: BTreeMap<String, Amount>,
+    /// USD cap on everything spent below this group (None: no cap of its own)
+    #[serde(default)]
+    pub budget_limit: Option<Amount>,
+    /// USD spent by the group's agents and subgroups
+    #[serde(default)]
+    pub total_spend: Amount,
+    #[serde(default)]
+    pub payment_count: u64,
+}
+
+impl BudgetGroup {
+    pub fn new(id: &str, parent: Option<&str>, budget_limit: Option<Amount>) -> Self {
+        Self {
+            id: id.to_string(),
+            parent: parent.map(str::to_string),
+            balances: BTreeMap::new(),
+            budget_limit,
+            total_spend: Amount::ZERO,
+            payment_count: 0,
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.parent.is_none()
+    }
+
+    /// Balance of one asset in the shared wallet (zero if it never held it).
+    pub fn balance(&self, symbol: &str) -> Amount {
+        self.balances.get(symbol).copied().unwrap_or_default()
+    }
+
+    /// USD left before the group's own cap (None when it has none).
+    pub fn remaining(&self) -> Option<Amount> {
+        self.budget_limit.map(|limit| limit.saturating_sub(self.total_spend))
+    }
+}
+
+/// Changes to make to a budget group. A field left as None keeps its value.
+#[derive(Debug, Clone, Default)]
+pub struct GroupUpdate {
+    /// `Some(None)` makes the group top-level, holding a wallet of its own
+    pub parent: Option<Option<String>>,
+    /// `Some(None)` lifts the group's cap
+    pub budget_limit: Option<Option<Amount>>,
+}
+
+impl GroupUpdate {
+    /// Sets both fields, as a full group record does.
+    pub fn replace(parent: Option<&str>, budget_limit: Option<Amount>) -> Self {
+        Self { parent: Some(parent.map(str::to_string)), budget_limit: Some(budget_limit) }
+    }
+}
+
+/// Ids of `id` and its ancestors, nearest first (the last is the wallet holder).
+/// Stops at a missing parent rather than failing, so a half-restored tree
+/// still pays from whatever root it reaches.
+pub(crate) fn path(groups: &BTreeMap<String, BudgetGroup>, id: &str) -> Vec<String> {
+    let mut path = Vec::new();
+    let mut next = Some(id.to_string());
+    while let Some(id) = next.take() {
+        let Some(group) = groups.get(&id) else { break };
+        if path.contains(&id) {
+            break;
+        }
+        next = group.parent.clone();
+        path.push(id);
+    }
+    path
+}
+
+/// Checks `group` can be stored: a sane id, an existing parent, no cycle, and
+/// no wallet on a group that has a parent.
+pub(crate) fn validate(groups: &BTreeMap<String, BudgetGroup>, group: &BudgetGroup) -> Result<(), String> {
+    let valid_id = !group.id.is_empty()
+        && group.id.len() <= 64
+        && group.id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid_id {
+        return Err(format!("Invalid group id '{}' (use up to 64 letters, digits, '-', '_' or '.')", group.id));
+    }
+    let Some(parent) = &group.parent else { return Ok(()) };
+    if !groups.contains_key(parent) {
+        return Err(format!("Unknown parent group: {}", parent));
+    }
+    if path(groups, parent).contains(&group.id) {
+        return Err(format!("Group {} can't be nested under {}: that would make a cycle", group.id, parent));
+    }
+    if group.balances.values().any(|b| !b.is_zero()) {
+        return Err(format!("Group {} holds a wallet; empty it before nesting it under {}", group.id, parent));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(groups: &[(&str, Option<&str>)]) -> BTreeMap<String, BudgetGroup> {
+        groups.iter().map(|(id, parent)| (id.to_string(), BudgetGroup::new(id, *parent, None))).collect()
+    }
+
+    #[test]
+    fn paths_run_from_the_group_to_its_root() {
+        let groups = tree(&[("org", None), ("team", Some("org")), ("squad", Some("team"))]);
+        assert_eq!(path(&groups, "squad"), ["squad", "team", "org"]);
+        assert_eq!(path(&groups, "org"), ["org"]);
+        assert!(path(&groups, "missing").is_empty());
+    }
+
+    #[test]
+    fn validate_refuses_cycles_unknown_parents_and_nested_wallets() {
+        let groups = tree(&[("org", None), ("team", Some("org")), ("squad", Some("team"))]);
+        assert!(validate(&groups, &BudgetGroup::new("org", Some("squad"), None)).unwrap_err().contains("cycle"));
+        assert!(validate(&groups, &BudgetGroup::new("ops", Some("nope"), None)).unwrap_err().contains("Unknown parent"));
+        assert!(validate(&groups, &BudgetGroup::new("bad id", None, None)).is_err());
+
+        let mut funded = BudgetGroup::new("ops", Some("org"), None);
+        funded.balances.insert("USDC".to_string(), Amount::from_whole(1));
+        assert!(validate(&groups, &funded).unwrap_err().contains("holds a wallet"));
+        assert!(validate(&groups, &BudgetGroup::new("ops", Some("org"), None)).is_ok());
+    }
+}
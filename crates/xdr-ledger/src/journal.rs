@@ -0,0 +1,210 @@
+//! Append-only journal of every balance change, for per-agent statements.
+//!
+//! `AgentState` only keeps running totals; the journal keeps the line items:
+//! faucet funding, payments, escrow holds, refunds and releases, income from
+//! other agents, and admin balance overrides. Entries are never edited. A
+//! payment orphaned by a reorg gets a reversing entry of the same kind (a
+//! credit where the original was a debit), so the running `balance_after`
+//! always matches the wallet.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Amount, Transaction};
+
+/// Default and largest page size for journal queries
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    /// Faucet balance a new agent starts with
+    Fund,
+    /// Payment for a request (exact scheme or usage charge)
+    Payment,
+    /// Unused part of an escrow hold returned after capture
+    Refund,
+    /// Balance overwritten through the control plane
+    AdminSet,
+    /// `upto` authorization moved into escrow
+    Hold,
+    /// Escrow hold returned in full (nothing captured)
+    Release,
+    /// Payment received from another agent (or a captured hold paid out to it)
+    Income,
+}
+
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fund => "fund",
+            Self::Payment => "payment",
+            Self::Refund => "refund",
+            Self::AdminSet => "admin_set",
+            Self::Hold => "hold",
+            Self::Release => "release",
+            Self::Income => "income",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Position in the journal, increasing across all agents
+    pub seq: u64,
+    /// Unix seconds
+    pub timestamp: u64,
+    /// Empty for admin overrides of a budget group's wallet
+    pub agent_id: String,
+    pub kind: EntryKind,
+    pub currency: String,
+    /// Amount taken from the wallet
+    pub debit: Amount,
+    /// Amount added to the wallet
+    pub credit: Amount,
+    /// Wallet balance in `currency` after this entry
+    pub balance_after: Amount,
+    /// USD value of the change at the token's mock rate
+    pub usd_value: Amount,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invoice_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payee: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+    /// Budget group whose shared wallet moved (`balance_after` is that wallet's)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wallet: Option<String>,
+}
+
+impl JournalEntry {
+    /// A change in `currency`; the ledger fills in the sequence number, time,
+    /// agent, balance and USD value when it posts the entry.
+    pub(crate) fn new(kind: EntryKind, currency: &str, debit: Amount, credit: Amount) -> Self {
+        Self {
+            seq: 0,
+            timestamp: 0,
+            agent_id: String::new(),
+            kind,
+            currency: currency.to_string(),
+            debit,
+            credit,
+            balance_after: Amount::ZERO,
+            usd_value: Amount::ZERO,
+            tx_hash: None,
+            invoice_id: None,
+            payee: None,
+            memo: None,
+            wallet: None,
+        }
+    }
+
+    /// A change caused by `tx` (in its currency, linked to its hash and invoice).
+    pub(crate) fn for_tx(kind: EntryKind, tx: &Transaction, debit: Amount, credit: Amount) -> Self {
+        Self {
+            tx_hash: Some(tx.hash.clone()),
+            invoice_id: Some(tx.invoice_id.clone()),
+            payee: Some(tx.payee.clone()),
+            ..Self::new(kind, &tx.currency, debit, credit)
+        }
+    }
+
+    pub(crate) fn with_memo(mut self, memo: impl Into<String>) -> Self {
+        self.memo = Some(memo.into());
+        self
+    }
+
+    /// The change hit a budget group's shared wallet rather than the agent's own.
+    pub(crate) fn in_wallet(mut self, group: &str, balance_after: Amount) -> Self {
+        self.wallet = Some(group.to_string());
+        self.balance_after = balance_after;
+        self
+    }
+}
+
+/// Filters for reading the journal. Entries come back oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct JournalQuery {
+    pub agent_id: Option<String>,
+    /// Only entries at or after this unix second
+    pub from: Option<u64>,
+    /// Only entries before this unix second
+    pub to: Option<u64>,
+    /// Cursor: only entries after this `seq` (the previous page's `next`)
+    pub after: Option<u64>,
+    /// Page size (defaults to `DEFAULT_PAGE_SIZE`, capped at `MAX_PAGE_SIZE`)
+    pub limit: Option<usize>,
+}
+
+impl JournalQuery {
+    fn matches(&self, entry: &JournalEntry) -> bool {
+        self.agent_id.as_ref().is_none_or(|id| entry.agent_id == *id)
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp < to)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalPage {
+    pub entries: Vec<JournalEntry>,
+    /// Entries matching the filters across all pages
+    pub total: usize,
+    /// Cursor for the next page, None on the last one
+    #[serde(default)]
+    pub next: Option<u64>,
+}
+
+/// One page of `entries` (in `seq` order) matching `query`.
+pub fn page(entries: &[JournalEntry], query: &JournalQuery) -> JournalPage {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let matching: Vec<&JournalEntry> = entries.iter().filter(|e| query.matches(e)).collect();
+    let start = query.after.map_or(0, |after| matching.partition_point(|e| e.seq <= after));
+    let page: Vec<JournalEntry> = matching[start..].iter().take(limit).map(|e| (*e).clone()).collect();
+    let next = (start + page.len() < matching.len()).then(|| page.last().map(|e| e.seq)).flatten();
+    JournalPage { entries: page, total: matching.len(), next }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<JournalEntry> {
+        (1..=7u64).map(|seq| JournalEntry {
+            seq,
+            timestamp: 1000 + seq,
+            agent_id: if seq % 2 == 0 { "agent-2" } else { "agent-1" }.to_string(),
+            ..JournalEntry::new(EntryKind::Payment, "USDC", Amount::from_whole(1), Amount::ZERO)
+        }).collect()
+    }
+
+    fn seqs(page: &JournalPage) -> Vec<u64> {
+        page.entries.iter().map(|e| e.seq).collect()
+    }
+
+    #[test]
+    fn pages_follow_the_cursor_to_the_end() {
+        let entries = entries();
+        let mut query = JournalQuery { agent_id: Some("agent-1".to_string()), limit: Some(3), ..Default::default() };
+        let first = page(&entries, &query);
+        assert_eq!((seqs(&first), first.total, first.next), (vec![1, 3, 5], 4, Some(5)));
+
+        query.after = first.next;
+        let last = page(&entries, &query);
+        assert_eq!((seqs(&last), last.total, last.next), (vec![7], 4, None));
+    }
+
+    #[test]
+    fn time_bounds_and_limits_are_applied() {
+        let entries = entries();
+        let window = JournalQuery { from: Some(1003), to: Some(1006), ..Default::default() };
+        assert_eq!(seqs(&page(&entries, &window)), vec![3, 4, 5]);
+
+        // A zero limit still returns one entry; the cap bounds huge ones
+        let tiny = page(&entries, &JournalQuery { limit: Some(0), ..Default::default() });
+        assert_eq!((seqs(&tiny), tiny.next), (vec![1], Some(1)));
+        let huge = page(&entries, &JournalQuery { limit: Some(usize::MAX), ..Default::default() });
+        assert_eq!((huge.entries.len(), huge.next), (7, None));
+    }
+}
@@ -1,64 +1,724 @@
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use uuid::Uuid;
-use rand::{distributions::Alphanumeric, Rng};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use sha3::{Digest, Keccak256};
 
-const DEFAULT_BUDGET: f64 = 10.0; 
+pub mod amount;
+pub mod chain;
+pub mod escrow;
+pub mod groups;
+pub mod journal;
+pub mod policy;
+pub mod storage;
+pub mod tokens;
+
+pub use amount::Amount;
+use chain::{Block, ChainInfo, ChainState, Reorg, TxStatus};
+use escrow::{Hold, HoldStatus, ESCROW_ADDRESS};
+use groups::{BudgetGroup, GroupUpdate};
+use journal::{EntryKind, JournalEntry, JournalPage, JournalQuery};
+use policy::{PolicyViolation, Spend, SpendingPolicy};
+use storage::{LedgerSnapshot, MemoryStorage, Storage, StorageRecord};
+use tokens::{Token, TokenRegistry};
+
+/// Spend cap in USD (across all assets, at the tokens' mock rates)
+const DEFAULT_BUDGET: Amount = Amount::from_whole(10);
+/// Height of the simulated chain before the first payment
+pub const GENESIS_BLOCK: u64 = 10_000_000;
+/// Mock settlement address (burned funds)
+pub const BURN_ADDRESS: &str = "0x000000000000000000000000000000000000dead";
+/// How long an invoice can be paid after it's issued
+pub const DEFAULT_INVOICE_TTL_SECS: u64 = 300;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AgentState {
     pub id: String,
-    pub balance_usdc: f64,
-    pub total_spend: f64,
+    /// Wallet balances by token symbol
+    #[serde(default)]
+    pub balances: BTreeMap<String, Amount>,
+    /// USD value of everything spent so far
+    pub total_spend: Amount,
     pub payment_count: u64,
-    pub budget_limit: f64,
+    pub budget_limit: Amount,
     pub is_active: bool,
+    /// On-chain identity: derived from the id until the agent signs an x402 payment
+    #[serde(default)]
+    pub address: String,
+    /// Rolling-window, rate, payee and asset rules on top of `budget_limit`
+    #[serde(default, skip_serializing_if = "SpendingPolicy::is_empty")]
+    pub policy: SpendingPolicy,
+    /// Budget group the agent pays through (its `budget_limit` is then a
+    /// sub-limit within the group's)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Owed by token symbol after a reorg took back income the agent had
+    /// already spent; withheld from its next income
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub debts: BTreeMap<String, Amount>,
+    /// Single-asset wallets from before multi-asset support (migrated on load)
+    #[serde(default, rename = "balance_usdc", skip_serializing)]
+    legacy_usdc: Option<Amount>,
 }
 
 impl AgentState {
-    fn new(id: String) -> Self {
+    /// A fresh wallet holding the faucet amount of every token.
+    fn new(id: String, tokens: &[Token]) -> Self {
         Self {
+            address: derive_address(&id),
             id,
-            balance_usdc: 100.0, // Free 100 mock USDC
-            total_spend: 0.0,
+            balances: tokens.iter().map(|t| (t.symbol.clone(), t.faucet)).collect(),
+            total_spend: Amount::ZERO,
             payment_count: 0,
             budget_limit: DEFAULT_BUDGET,
             is_active: true,
+            policy: SpendingPolicy::default(),
+            group: None,
+            debts: BTreeMap::new(),
+            legacy_usdc: None,
         }
     }
+
+    /// Records the part of a reversal (or of an unpaid usage charge) the
+    /// wallet couldn't cover as debt and notes it on the memo.
+    fn owe(&mut self, symbol: &str, shortfall: Amount, memo: String) -> String {
+        if shortfall.is_zero() {
+            return memo;
+        }
+        *self.debts.entry(symbol.to_string()).or_default() += shortfall;
+        warn!(target: "xdr_ledger", "Agent {} is {} {} short; recorded as debt", self.id, shortfall, symbol);
+        format!("{} ({} {} short, owed from future income)", memo, shortfall, symbol)
+    }
+
+    /// Clears up to `amount` of the debt in `symbol`; returns how much it cleared.
+    fn repay(&mut self, symbol: &str, amount: Amount) -> Amount {
+        let Some(owed) = self.debts.get_mut(symbol) else { return Amount::ZERO };
+        let repaid = (*owed).min(amount);
+        *owed -= repaid;
+        if owed.is_zero() {
+            self.debts.remove(symbol);
+        }
+        repaid
+    }
+
+    /// Balance of one asset (zero if the wallet never held it).
+    pub fn balance(&self, symbol: &str) -> Amount {
+        self.balances.get(symbol).copied().unwrap_or_default()
+    }
+}
+
+/// Stable mock address for an agent that hasn't presented its own key.
+pub fn derive_address(agent_id: &str) -> String {
+    let hash = Keccak256::digest(format!("xdr-agent:{}", agent_id).as_bytes());
+    let hex: String = hash[12..].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("0x{}", hex)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PaymentReceipt {
-    pub new_balance: f64,
+    /// Remaining balance of the asset that was paid
+    pub new_balance: Amount,
+    pub currency: String,
     pub tx_hash: String,
     pub chain_id: String,
-    pub block_height: u64,
+    /// None while the payment waits in the mempool
+    pub block_height: Option<u64>,
+}
+
+/// Why `pay_invoice` refused to settle.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaymentError {
+    InvoiceInvalid,
+    AlreadyPaid,
+    InvoiceExpired { expired_at: u64 },
+    InvoiceCancelled,
+    /// Invoice issued for a different request (method + URL)
+    ResourceMismatch { issued_for: String },
+    WrongAgent,
+    /// The invoice pays the agent's own service
+    SelfPayment,
+    UnsupportedCurrency(String),
+    AgentNotFound,
+    InsufficientFunds { balance: Amount, currency: String },
+    BudgetExceeded,
+    /// A budget group on the agent's path would go over its cap
+    GroupBudgetExceeded { group: String },
+    Policy(PolicyViolation),
+    /// A balance or spend total would go past the largest representable amount
+    AmountOverflow { currency: String },
+}
+
+impl PaymentError {
+    /// Stable error code for clients to branch on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvoiceInvalid => "invoice_invalid",
+            Self::AlreadyPaid => "invoice_already_paid",
+            Self::InvoiceExpired { .. } => "invoice_expired",
+            Self::InvoiceCancelled => "invoice_cancelled",
+            Self::ResourceMismatch { .. } => "invoice_resource_mismatch",
+            Self::WrongAgent => "invoice_wrong_agent",
+            Self::SelfPayment => "self_payment",
+            Self::UnsupportedCurrency(_) => "unsupported_currency",
+            Self::AgentNotFound => "agent_not_found",
+            Self::InsufficientFunds { .. } => "insufficient_funds",
+            Self::BudgetExceeded => "budget_exceeded",
+            Self::GroupBudgetExceeded { .. } => "group_budget_exceeded",
+            Self::Policy(violation) => violation.code(),
+            Self::AmountOverflow { .. } => "amount_overflow",
+        }
+    }
+}
+
+impl fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvoiceInvalid => f.write_str("Invoice invalid"),
+            Self::AlreadyPaid => f.write_str("Invoice already paid"),
+            Self::InvoiceExpired { expired_at } => write!(f, "Invoice expired (at {}); request a new one", expired_at),
+            Self::InvoiceCancelled => f.write_str("Invoice was cancelled"),
+            Self::ResourceMismatch { issued_for } => write!(f, "Invoice was issued for {}", issued_for),
+            Self::WrongAgent => f.write_str("Invoice belongs to another agent"),
+            Self::SelfPayment => f.write_str("An agent cannot pay its own service"),
+            Self::UnsupportedCurrency(currency) => write!(f, "Unsupported currency: {}", currency),
+            Self::AgentNotFound => f.write_str("Agent not found"),
+            Self::InsufficientFunds { balance, currency } => {
+                write!(f, "Wallet Exhausted: Insufficient funds ({} {})", balance, currency)
+            }
+            Self::BudgetExceeded => f.write_str("Safety Limit: Budget cap exceeded"),
+            Self::GroupBudgetExceeded { group } => write!(f, "Safety Limit: Budget cap of group {} exceeded", group),
+            Self::Policy(violation) => write!(f, "Policy: {}", violation),
+            Self::AmountOverflow { currency } => write!(f, "Amount overflow: the payment would exceed the largest {} amount", currency),
+        }
+    }
+}
+
+/// Why `remove_group` refused; each variant carries the group id first.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoveGroupError {
+    NotFound(String),
+    HasMember(String, String),
+    HasSubgroup(String, String),
+    /// The group's wallet still holds this balance
+    WalletNotEmpty(String, String, Amount),
+}
+
+impl fmt::Display for RemoveGroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(id) => write!(f, "Unknown group: {}", id),
+            Self::HasMember(id, agent) => write!(f, "Group {} still has member {}", id, agent),
+            Self::HasSubgroup(id, child) => write!(f, "Group {} still has subgroup {}", id, child),
+            Self::WalletNotEmpty(id, currency, amount) => {
+                write!(f, "Group {} still holds {} {}; set its balance to 0 first", id, amount, currency)
+            }
+        }
+    }
+}
+
+/// Why an agent couldn't join or leave a budget group.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GroupMemberError {
+    UnknownGroup(String),
+    /// The agent (first) isn't a member of the group (second)
+    NotAMember(String, String),
+}
+
+impl fmt::Display for GroupMemberError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownGroup(id) => write!(f, "Unknown group: {}", id),
+            Self::NotAMember(agent, id) => write!(f, "{} is not a member of group {}", agent, id),
+        }
+    }
+}
+
+/// Why `cancel_invoice` refused; each variant carries the invoice id.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CancelError {
+    NotFound(String),
+    AlreadyPaid(String),
+    AlreadyCancelled(String),
+}
+
+impl fmt::Display for CancelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(id) => write!(f, "Unknown invoice: {}", id),
+            Self::AlreadyPaid(id) => write!(f, "Invoice {} is already paid", id),
+            Self::AlreadyCancelled(id) => write!(f, "Invoice {} is already cancelled", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxKind {
+    /// Agent paying (or paying into escrow)
+    #[default]
+    Payment,
+    /// Unused part of an escrow hold going back to the agent
+    Refund,
+    /// Captured part of an escrow hold going to the payee agent
+    Payout,
+}
+
+impl TxKind {
+    fn is_payment(&self) -> bool {
+        *self == Self::Payment
+    }
+
+    /// Sent by the escrow contract rather than the agent.
+    fn sent_by_escrow(&self) -> bool {
+        matches!(self, Self::Refund | Self::Payout)
+    }
+}
+
+/// A payment, as the simulated chain sees it (an ERC-20 or native transfer).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "TxKind::is_payment")]
+    pub kind: TxKind,
+    pub status: TxStatus,
+    /// Set once the transaction is mined
+    pub block_height: Option<u64>,
+    pub block_hash: Option<String>,
+    pub from: String,
+    pub to: String,
+    pub amount: Amount,
+    pub currency: String,
+    /// What the payment counted against the budget cap
+    #[serde(default)]
+    pub usd_value: Amount,
+    /// Who was paid (the upstream host)
+    #[serde(default)]
+    pub payee: String,
+    pub agent_id: String,
+    pub invoice_id: String,
+    /// Unix seconds
+    pub timestamp: u64,
+    /// Budget group the payment went through (paid from, or refunded to, its shared wallet)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Agent whose wallet received the transfer (agent-to-agent payments)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payee_agent: Option<String>,
+}
+
+/// The request an invoice was issued for; it can't pay for any other.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InvoiceResource {
+    pub method: String,
+    pub url: String,
+}
+
+impl InvoiceResource {
+    pub fn new(method: &str, url: &str) -> Self {
+        Self { method: method.to_ascii_uppercase(), url: url.to_string() }
+    }
+}
+
+impl fmt::Display for InvoiceResource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.method, self.url)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvoiceStatus {
+    Open,
+    Paid,
+    Expired,
+    Cancelled,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Invoice {
     pub id: String,
-    pub amount: f64,
+    pub amount: Amount,
+    pub currency: String,
     pub is_paid: bool,
     pub agent_id: String,
+    /// Who is being paid (the upstream host)
+    #[serde(default)]
+    pub payee: String,
+    /// Agent selling the service; the payment goes to its wallet instead of being burned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payee_agent: Option<String>,
+    /// Unix seconds
+    #[serde(default)]
+    pub issued_at: u64,
+    /// Unix seconds; None never expires
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// None for invoices that can pay for anything (post-paid usage charges)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource: Option<InvoiceResource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancelled_at: Option<u64>,
+    /// Unix seconds; unset on invoices paid before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paid_at: Option<u64>,
+    /// Invoices issued with this one for the same request in other assets;
+    /// paying any of them cancels the rest
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternatives: Vec<String>,
 }
 
-#[derive(Clone, Default)]
+impl Invoice {
+    /// Key shared by an invoice and its alternatives (the lowest id of the set).
+    fn offer(&self) -> Option<&str> {
+        if self.alternatives.is_empty() {
+            return None;
+        }
+        self.alternatives.iter().map(String::as_str).chain([self.id.as_str()]).min()
+    }
+
+    pub fn status(&self, now: u64) -> InvoiceStatus {
+        if self.is_paid {
+            InvoiceStatus::Paid
+        } else if self.cancelled_at.is_some() {
+            InvoiceStatus::Cancelled
+        } else if self.expires_at.is_some_and(|at| now >= at) {
+            InvoiceStatus::Expired
+        } else {
+            InvoiceStatus::Open
+        }
+    }
+
+    /// Whether `agent_id` may pay this invoice for `resource` right now.
+    fn check(&self, agent_id: &str, resource: Option<&InvoiceResource>, now: u64) -> Result<(), PaymentError> {
+        match self.status(now) {
+            InvoiceStatus::Paid => return Err(PaymentError::AlreadyPaid),
+            InvoiceStatus::Cancelled => return Err(PaymentError::InvoiceCancelled),
+            InvoiceStatus::Expired => return Err(PaymentError::InvoiceExpired { expired_at: self.expires_at.unwrap_or_default() }),
+            InvoiceStatus::Open => {}
+        }
+        if self.agent_id != agent_id {
+            return Err(PaymentError::WrongAgent);
+        }
+        match (&self.resource, resource) {
+            (Some(issued_for), Some(requested)) if issued_for != requested => {
+                Err(PaymentError::ResourceMismatch { issued_for: issued_for.to_string() })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Ledger {
     store: Arc<DashMap<String, AgentState>>,
     invoices: Arc<DashMap<String, Invoice>>,
+    /// Spent EIP-3009 authorization nonces, keyed by "payer:nonce"
+    authorizations: Arc<DashMap<String, ()>>,
+    /// Payments by tx hash (pending, mined and orphaned)
+    transactions: Arc<DashMap<String, Transaction>>,
+    /// Hashes of the transactions each agent paid (or was refunded), oldest
+    /// first, so policy checks don't scan everyone's payments
+    agent_txs: Arc<DashMap<String, Vec<String>>>,
+    /// `upto` escrow holds by id
+    holds: Arc<DashMap<String, Hold>>,
+    /// Which invoice of a set of alternatives was paid, by the set's `offer` key.
+    /// Claimed while the payment settles, so two alternatives can't both be paid.
+    offers: Arc<DashMap<String, String>>,
+    /// Budget groups by id. One lock for the whole forest: a payment updates
+    /// every level of its tree at once. Taken after an agent's entry, never before.
+    groups: Arc<RwLock<BTreeMap<String, BudgetGroup>>>,
+    /// Held while an agent joins or leaves a group and while a group is
+    /// removed, so a group can't lose its last member check to a joining
+    /// agent. Taken before an agent's entry.
+    memberships: Arc<Mutex<()>>,
+    /// Lifetime of new invoices in seconds (None: they never expire)
+    invoice_ttl: Arc<RwLock<Option<u64>>>,
+    /// Append-only record of every balance change
+    journal: Arc<Mutex<Vec<JournalEntry>>>,
+    /// Simulated block production
+    chain: Arc<Mutex<ChainState>>,
+    /// Write-through persistence backend
+    storage: Arc<dyn Storage>,
+    /// Source of invoice ids and tx hashes (re-seedable for replay)
+    rng: Arc<Mutex<ChaCha8Rng>>,
+    /// Assets of the simulated network
+    tokens: Arc<RwLock<Vec<Token>>>,
+    /// Tokens registered at runtime (`add_token`). Persisted, and laid over
+    /// whatever list `set_tokens` installs. Taken after `tokens`, never before.
+    custom_tokens: Arc<RwLock<Vec<Token>>>,
+    /// First storage write or sync that failed
+    storage_error: Arc<Mutex<Option<String>>>,
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Ledger {
+    /// In-memory ledger; state is lost when the process exits.
     pub fn new() -> Self {
         Self {
             store: Arc::new(DashMap::new()),
             invoices: Arc::new(DashMap::new()),
+            authorizations: Arc::new(DashMap::new()),
+            transactions: Arc::new(DashMap::new()),
+            agent_txs: Arc::new(DashMap::new()),
+            holds: Arc::new(DashMap::new()),
+            offers: Arc::new(DashMap::new()),
+            groups: Arc::new(RwLock::new(BTreeMap::new())),
+            memberships: Arc::new(Mutex::new(())),
+            invoice_ttl: Arc::new(RwLock::new(Some(DEFAULT_INVOICE_TTL_SECS))),
+            journal: Arc::new(Mutex::new(Vec::new())),
+            chain: Arc::new(Mutex::new(ChainState::new())),
+            storage: Arc::new(MemoryStorage::new()),
+            rng: Arc::new(Mutex::new(ChaCha8Rng::from_entropy())),
+            tokens: Arc::new(RwLock::new(TokenRegistry::default().tokens("cronos-testnet"))),
+            custom_tokens: Arc::new(RwLock::new(Vec::new())),
+            storage_error: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Ledger backed by `storage`, resuming from whatever state it holds.
+    pub fn with_storage(storage: Arc<dyn Storage>) -> io::Result<Self> {
+        let initial = storage.load()?;
+        let ledger = Self {
+            store: Arc::new(DashMap::new()),
+            invoices: Arc::new(DashMap::new()),
+            authorizations: Arc::new(DashMap::new()),
+            transactions: Arc::new(DashMap::new()),
+            agent_txs: Arc::new(DashMap::new()),
+            holds: Arc::new(DashMap::new()),
+            offers: Arc::new(DashMap::new()),
+            groups: Arc::new(RwLock::new(BTreeMap::new())),
+            memberships: Arc::new(Mutex::new(())),
+            invoice_ttl: Arc::new(RwLock::new(Some(DEFAULT_INVOICE_TTL_SECS))),
+            journal: Arc::new(Mutex::new(Vec::new())),
+            chain: Arc::new(Mutex::new(ChainState::new())),
+            storage,
+            rng: Arc::new(Mutex::new(ChaCha8Rng::from_entropy())),
+            tokens: Arc::new(RwLock::new(TokenRegistry::default().tokens("cronos-testnet"))),
+            custom_tokens: Arc::new(RwLock::new(Vec::new())),
+            storage_error: Arc::new(Mutex::new(None)),
+        };
+        ledger.replace_state(initial);
+        Ok(ledger)
+    }
+
+    /// Makes invoice ids and tx hashes reproducible from this point on.
+    pub fn reseed(&self, seed: u64) {
+        *self.rng.lock().unwrap() = ChaCha8Rng::seed_from_u64(seed);
+    }
+
+    /// Writes a mutation through to storage. Called while the entry (or lock)
+    /// it changed is still held, so records of one key are logged in the
+    /// order they happened. A failure isn't the caller's to handle: it fails
+    /// every `commit` from then on, so nothing more is acknowledged.
+    fn persist(&self, record: StorageRecord) {
+        if let Err(e) = self.storage.record(&record) {
+            error!(target: "xdr_ledger", "Failed to persist ledger mutation: {}", e);
+            self.storage_error.lock().unwrap().get_or_insert_with(|| e.to_string());
+        }
+    }
+
+    /// Makes every mutation so far durable. A change only counts as
+    /// acknowledged once this returns Ok. Blocks on the disk, so call it with
+    /// no ledger lock held (and off the async runtime). Fails for good once
+    /// the storage couldn't be written.
+    pub fn commit(&self) -> io::Result<()> {
+        self.storage_error()?;
+        self.storage.sync().inspect_err(|e| {
+            error!(target: "xdr_ledger", "Failed to sync the ledger log: {}", e);
+            self.storage_error.lock().unwrap().get_or_insert_with(|| e.to_string());
+        })
+    }
+
+    /// Err once a mutation couldn't be written to storage. Doesn't touch the disk.
+    pub fn storage_error(&self) -> io::Result<()> {
+        match &*self.storage_error.lock().unwrap() {
+            Some(e) => Err(io::Error::other(format!("Ledger storage failed, no changes are being saved: {}", e))),
+            None => Ok(()),
+        }
+    }
+
+    /// Journals a change to one of `agent`'s balances (or to its group's
+    /// shared wallet, whose balance the entry already carries). Called while
+    /// the agent is still borrowed, so entries land in the order the balance moved.
+    fn post(&self, agent: &AgentState, mut entry: JournalEntry) {
+        entry.agent_id = agent.id.clone();
+        if entry.wallet.is_none() {
+            entry.balance_after = agent.balance(&entry.currency);
+        }
+        self.append(entry);
+    }
+
+    /// Numbers, values and stores an entry whose agent and balance are filled in.
+    fn append(&self, mut entry: JournalEntry) {
+        entry.timestamp = now_secs();
+        entry.usd_value = self.token(&entry.currency).map_or(Amount::ZERO, |t| t.usd_value(entry.debit + entry.credit));
+        let mut journal = self.journal.lock().unwrap();
+        entry.seq = journal.last().map_or(1, |last| last.seq + 1);
+        self.persist(StorageRecord::Journal(entry.clone()));
+        journal.push(entry);
+    }
+
+    /// Journals the faucet balances a new agent starts with.
+    fn post_funding(&self, agent: &AgentState) {
+        for (symbol, amount) in agent.balances.iter().filter(|(_, amount)| !amount.is_zero()) {
+            self.post(agent, JournalEntry::new(EntryKind::Fund, symbol, Amount::ZERO, *amount).with_memo("Faucet"));
         }
     }
 
+    /// One page of journal entries matching `query`, oldest first.
+    pub fn journal(&self, query: &JournalQuery) -> JournalPage {
+        journal::page(&self.journal.lock().unwrap(), query)
+    }
+
+    fn replace_state(&self, snapshot: LedgerSnapshot) {
+        self.store.clear();
+        self.invoices.clear();
+        self.authorizations.clear();
+        self.transactions.clear();
+        self.agent_txs.clear();
+        self.holds.clear();
+        self.offers.clear();
+        *self.journal.lock().unwrap() = snapshot.journal;
+        for mut agent in snapshot.agents {
+            if agent.address.is_empty() {
+                agent.address = derive_address(&agent.id);
+            }
+            if let Some(usdc) = agent.legacy_usdc.take() {
+                agent.balances.entry("USDC".to_string()).or_insert(usdc);
+            }
+            self.store.insert(agent.id.clone(), agent);
+        }
+        for invoice in snapshot.invoices {
+            if let Some(offer) = invoice.offer().filter(|_| invoice.is_paid) {
+                self.offers.insert(offer.to_string(), invoice.id.clone());
+            }
+            self.invoices.insert(invoice.id.clone(), invoice);
+        }
+        for key in snapshot.authorizations {
+            self.authorizations.insert(key, ());
+        }
+        for hold in snapshot.holds {
+            self.holds.insert(hold.id.clone(), hold);
+        }
+        *self.groups.write().unwrap() = snapshot.groups.into_iter().map(|g| (g.id.clone(), g)).collect();
+        {
+            let mut tokens = self.tokens.write().unwrap();
+            for token in &snapshot.custom_tokens {
+                tokens::upsert(&mut tokens, token.clone());
+            }
+            *self.custom_tokens.write().unwrap() = snapshot.custom_tokens;
+        }
+        let mut chain = self.chain.lock().unwrap();
+        chain.resume(snapshot.head);
+        let mut transactions = snapshot.transactions;
+        transactions.sort_by_key(|t| t.timestamp);
+        for tx in transactions {
+            if tx.status == TxStatus::Pending {
+                chain.mempool.push(tx.hash.clone());
+            }
+            self.agent_txs.entry(tx.agent_id.clone()).or_default().push(tx.hash.clone());
+            self.transactions.insert(tx.hash.clone(), tx);
+        }
+    }
+
+    /// Captures the full ledger state.
+    pub fn snapshot(&self) -> LedgerSnapshot {
+        LedgerSnapshot {
+            agents: self.store.iter().map(|r| r.value().clone()).collect(),
+            invoices: self.invoices.iter().map(|r| r.value().clone()).collect(),
+            authorizations: self.authorizations.iter().map(|r| r.key().clone()).collect(),
+            transactions: self.list_transactions(),
+            holds: self.holds.iter().map(|r| r.value().clone()).collect(),
+            journal: self.journal.lock().unwrap().clone(),
+            head: self.chain.lock().unwrap().head(),
+            groups: self.list_groups(),
+            custom_tokens: self.custom_tokens.read().unwrap().clone(),
+        }
+    }
+
+    /// Replaces the full ledger state (and logs the reset to storage).
+    pub fn restore(&self, snapshot: LedgerSnapshot) {
+        self.persist(StorageRecord::Reset(snapshot.clone()));
+        self.replace_state(snapshot);
+    }
+
+    /// Saves the current state under `name`.
+    pub fn save_snapshot(&self, name: &str) -> io::Result<LedgerSnapshot> {
+        let snapshot = self.snapshot();
+        self.storage.save_snapshot(name, &snapshot)?;
+        Ok(snapshot)
+    }
+
+    /// Restores the state saved under `name`.
+    pub fn load_snapshot(&self, name: &str) -> io::Result<LedgerSnapshot> {
+        let snapshot = self.storage.load_snapshot(name)?;
+        self.restore(snapshot.clone());
+        Ok(snapshot)
+    }
+
+    pub fn list_snapshots(&self) -> io::Result<Vec<String>> {
+        self.storage.list_snapshots()
+    }
+
+    /// Replaces the network's token list (new agents are funded from it).
+    /// Tokens registered at runtime stay on top of it.
+    pub fn set_tokens(&self, tokens: Vec<Token>) {
+        let mut current = self.tokens.write().unwrap();
+        *current = tokens;
+        for token in self.custom_tokens.read().unwrap().iter() {
+            tokens::upsert(&mut current, token.clone());
+        }
+    }
+
+    pub fn tokens(&self) -> Vec<Token> {
+        self.tokens.read().unwrap().clone()
+    }
+
+    /// Looks a token up by symbol (case-insensitive).
+    pub fn token(&self, symbol: &str) -> Option<Token> {
+        self.tokens.read().unwrap().iter().find(|t| t.symbol.eq_ignore_ascii_case(symbol)).cloned()
+    }
+
+    /// Looks an ERC-20 up by contract address (case-insensitive).
+    pub fn token_by_address(&self, address: &str) -> Option<Token> {
+        self.tokens.read().unwrap().iter()
+            .find(|t| t.address.as_deref().is_some_and(|a| a.eq_ignore_ascii_case(address)))
+            .cloned()
+    }
+
+    /// Registers a custom token, or replaces the one with the same symbol.
+    pub fn add_token(&self, token: Token) -> Result<(), String> {
+        token.validate()?;
+        let mut tokens = self.tokens.write().unwrap();
+        if token.is_native() && tokens.iter().any(|t| t.is_native() && !t.symbol.eq_ignore_ascii_case(&token.symbol)) {
+            return Err(format!("{}: the network already has a native coin", token.symbol));
+        }
+        if let Some(address) = &token.address {
+            let clash = tokens.iter().find(|t| {
+                !t.symbol.eq_ignore_ascii_case(&token.symbol)
+                    && t.address.as_deref().is_some_and(|a| a.eq_ignore_ascii_case(address))
+            });
+            if let Some(other) = clash {
+                return Err(format!("{}: address {} is already registered as {}", token.symbol, address, other.symbol));
+            }
+        }
+        tokens::upsert(&mut tokens, token.clone());
+        tokens::upsert(&mut self.custom_tokens.write().unwrap(), token.clone());
+        self.persist(StorageRecord::Token(token));
+        Ok(())
+    }
+
     /// Registers a new agent or returns existing state.
     /// Returns (AgentState, is_new) where is_new indicates first-time registration.
     pub fn register_or_get(&self, agent_id: &str) -> (AgentState, bool) {
@@ -67,10 +727,20 @@ impl Ledger {
             return (existing.value().clone(), false);
         }
         // New agent - create with initial funding
+        let mut is_new = false;
+        let tokens = self.tokens();
         let entry = self.store.entry(agent_id.to_string()).or_insert_with(|| {
-            AgentState::new(agent_id.to_string())
+            is_new = true;
+            AgentState::new(agent_id.to_string(), &tokens)
         });
-        (entry.value().clone(), true)
+        if is_new {
+            self.post_funding(&entry);
+        }
+        let agent = entry.value().clone();
+        if is_new {
+            self.persist(StorageRecord::Agent(agent.clone()));
+        }
+        (agent, is_new)
     }
 
     pub fn get_state(&self, agent_id: &str) -> Option<AgentState> {
@@ -82,35 +752,702 @@ impl Ledger {
         self.store.iter().map(|r| r.value().clone()).collect()
     }
 
-    /// Creates a new pending invoice
-    pub fn create_invoice(&self, agent_id: &str, amount: f64) -> Invoice {
-        let id = Uuid::new_v4().to_string();
+    /// Finds the agent whose wallet is `address` (case-insensitive).
+    pub fn find_agent_by_address(&self, address: &str) -> Option<AgentState> {
+        self.store.iter()
+            .find(|r| r.value().address.eq_ignore_ascii_case(address))
+            .map(|r| r.value().clone())
+    }
+
+    /// Records the key an agent actually pays with as its on-chain address.
+    pub fn bind_address(&self, agent_id: &str, address: &str) {
+        let Some(mut agent) = self.store.get_mut(agent_id) else { return };
+        if agent.address.eq_ignore_ascii_case(address) {
+            return;
+        }
+        agent.address = address.to_lowercase();
+        let agent = agent.value().clone();
+        self.persist(StorageRecord::Agent(agent));
+    }
+
+    /// Height of the latest mined block.
+    pub fn block_number(&self) -> u64 {
+        self.chain.lock().unwrap().height
+    }
+
+    pub fn chain_info(&self) -> ChainInfo {
+        let chain = self.chain.lock().unwrap();
+        ChainInfo {
+            height: chain.height,
+            head_hash: chain.head_hash.clone(),
+            automine: chain.automine,
+            pending: chain.mempool.len(),
+            reorgs: chain.reorgs,
+        }
+    }
+
+    /// With automine on, every payment is mined into its own block as it settles.
+    pub fn set_automine(&self, automine: bool) {
+        self.chain.lock().unwrap().automine = automine;
+    }
+
+    /// A recent block (within the reorg window) by height.
+    pub fn get_block(&self, number: u64) -> Option<Block> {
+        self.chain.lock().unwrap().block(number)
+    }
+
+    pub fn get_transaction(&self, hash: &str) -> Option<Transaction> {
+        self.transactions.get(&hash.to_lowercase()).map(|r| r.value().clone())
+    }
+
+    /// Blocks mined on top of the transaction's block, plus one (0 unless mined).
+    pub fn confirmations(&self, tx: &Transaction) -> u64 {
+        match (tx.status, tx.block_height) {
+            (TxStatus::Mined, Some(height)) => self.block_number().saturating_sub(height) + 1,
+            _ => 0,
+        }
+    }
+
+    /// All payments in chain order; pending ones last.
+    pub fn list_transactions(&self) -> Vec<Transaction> {
+        let mut txs: Vec<Transaction> = self.transactions.iter().map(|r| r.value().clone()).collect();
+        txs.sort_by_key(|t| (t.block_height.unwrap_or(u64::MAX), t.timestamp));
+        txs
+    }
+
+    /// Mines one block containing every pending payment. The payments are
+    /// marked mined before the chain lock is released, so a reorg of the new
+    /// block can't be overwritten by it.
+    pub fn mine_block(&self) -> Block {
+        let mut chain = self.chain.lock().unwrap();
+        let pending = std::mem::take(&mut chain.mempool);
+        let block = chain.next_block(pending, now_secs());
+
+        for hash in &block.transactions {
+            if let Some(mut tx) = self.transactions.get_mut(hash) {
+                tx.status = TxStatus::Mined;
+                tx.block_height = Some(block.number);
+                tx.block_hash = Some(block.hash.clone());
+                let tx = tx.value().clone();
+                self.persist(StorageRecord::Transaction(tx));
+            }
+        }
+        self.persist(StorageRecord::Block(block.clone()));
+        drop(chain);
+        block
+    }
+
+    /// Drops the last `depth` blocks and mines empty replacements at the same
+    /// heights. Payments in the dropped blocks are orphaned and reverted: the
+    /// payer is refunded and the invoice can be paid again.
+    pub fn reorg(&self, depth: u64) -> Reorg {
+        let mut chain = self.chain.lock().unwrap();
+        let dropped = chain.rewind(depth);
+        let from_block = chain.height + 1;
+        for _ in 0..dropped.len() {
+            let block = chain.next_block(Vec::new(), now_secs());
+            self.persist(StorageRecord::Block(block));
+        }
+        drop(chain);
+
+        let mut orphaned = Vec::new();
+        for hash in dropped.iter().rev().flat_map(|b| b.transactions.iter()) {
+            if let Some(tx) = self.revert_payment(hash) {
+                orphaned.push(tx);
+            }
+        }
+        Reorg { depth: dropped.len() as u64, from_block, orphaned }
+    }
+
+    fn revert_payment(&self, hash: &str) -> Option<Transaction> {
+        // Escrow refunds and payouts aren't the agent's to revert: the escrow rebroadcasts them
+        if self.transactions.get(hash).is_some_and(|tx| tx.kind.sent_by_escrow()) {
+            let tx = {
+                let mut tx = self.transactions.get_mut(hash)?;
+                if tx.status == TxStatus::Orphaned {
+                    return None;
+                }
+                tx.status = TxStatus::Pending;
+                tx.block_height = None;
+                tx.block_hash = None;
+                self.persist(StorageRecord::Transaction(tx.clone()));
+                tx.value().clone()
+            };
+            self.chain.lock().unwrap().mempool.push(tx.hash.clone());
+            return None;
+        }
+        let tx = {
+            let mut tx = self.transactions.get_mut(hash)?;
+            tx.status = TxStatus::Orphaned;
+            tx.block_height = None;
+            tx.block_hash = None;
+            self.persist(StorageRecord::Transaction(tx.clone()));
+            tx.value().clone()
+        };
+        if let Some(mut agent) = self.store.get_mut(&tx.agent_id) {
+            let kind = if tx.to == ESCROW_ADDRESS { EntryKind::Hold } else { EntryKind::Payment };
+            let entry = JournalEntry::for_tx(kind, &tx, Amount::ZERO, tx.amount).with_memo("Reversed: orphaned by reorg");
+            let wallet = tx.group.as_deref()
+                .and_then(|group| self.credit_group(group, &tx.currency, tx.amount, tx.usd_value, true));
+            let entry = match wallet {
+                Some((wallet, balance)) => entry.in_wallet(&wallet, balance),
+                None => {
+                    *agent.balances.entry(tx.currency.clone()).or_default() += tx.amount;
+                    entry
+                }
+            };
+            agent.total_spend = agent.total_spend.saturating_sub(tx.usd_value);
+            agent.payment_count = agent.payment_count.saturating_sub(1);
+            self.post(&agent, entry);
+            let agent = agent.value().clone();
+            self.persist(StorageRecord::Agent(agent));
+        }
+        if let Some(mut invoice) = self.invoices.get_mut(&tx.invoice_id) {
+            invoice.is_paid = false;
+            invoice.paid_at = None;
+            let invoice = invoice.value().clone();
+            self.persist(StorageRecord::Invoice(invoice));
+        }
+        if let Some(payee) = &tx.payee_agent {
+            self.settle_payee(payee, &tx, false, "Reversed: orphaned by reorg".to_string());
+        }
+        if tx.to == ESCROW_ADDRESS {
+            self.void_hold(&tx.invoice_id);
+        }
+        Some(tx)
+    }
+
+    /// Orphans a transfer out of escrow (and drops it from the mempool).
+    /// None if it already was.
+    fn orphan_escrow_tx(&self, hash: &str) -> Option<Transaction> {
+        let tx = {
+            let mut tx = self.transactions.get_mut(hash)?;
+            if tx.status == TxStatus::Orphaned {
+                return None;
+            }
+            tx.status = TxStatus::Orphaned;
+            tx.block_height = None;
+            tx.block_hash = None;
+            self.persist(StorageRecord::Transaction(tx.clone()));
+            tx.value().clone()
+        };
+        self.chain.lock().unwrap().mempool.retain(|pending| *pending != tx.hash);
+        Some(tx)
+    }
+
+    /// The transfer into escrow was orphaned, so the whole authorization is
+    /// already back with the agent: close the hold and take back any refund
+    /// it paid out, which would otherwise be credited twice, and any payout
+    /// to the payee agent, which was never funded.
+    fn void_hold(&self, hold_id: &str) {
+        let Some(mut hold) = self.holds.get_mut(hold_id) else { return };
+        if let Some(payout) = hold.payout_tx.as_deref().and_then(|hash| self.orphan_escrow_tx(hash)) {
+            if let Some(payee) = &payout.payee_agent {
+                self.settle_payee(payee, &payout, false, "Reversed: escrow transfer orphaned by reorg".to_string());
+            }
+        }
+        if let Some(refund) = hold.refund_tx.as_deref().and_then(|hash| self.orphan_escrow_tx(hash)) {
+            if let Some(mut agent) = self.store.get_mut(&refund.agent_id) {
+                let kind = if hold.status == HoldStatus::Released { EntryKind::Release } else { EntryKind::Refund };
+                let wallet = refund.group.as_deref()
+                    .and_then(|group| self.debit_group(group, &refund.currency, refund.amount, refund.usd_value));
+                let (wallet, shortfall) = match wallet {
+                    Some((wallet, balance, shortfall)) => (Some((wallet, balance)), shortfall),
+                    None => {
+                        let balance = agent.balances.entry(refund.currency.clone()).or_default();
+                        let taken = (*balance).min(refund.amount);
+                        *balance -= taken;
+                        (None, refund.amount - taken)
+                    }
+                };
+                let memo = agent.owe(&refund.currency, shortfall, "Reversed: escrow transfer orphaned by reorg".to_string());
+                let entry = JournalEntry::for_tx(kind, &refund, refund.amount - shortfall, Amount::ZERO).with_memo(memo);
+                let entry = match wallet {
+                    Some((wallet, balance)) => entry.in_wallet(&wallet, balance),
+                    None => entry,
+                };
+                agent.total_spend += refund.usd_value;
+                self.post(&agent, entry);
+                let agent = agent.value().clone();
+                self.persist(StorageRecord::Agent(agent));
+            }
+        }
+        hold.captured = Amount::ZERO;
+        hold.refunded = hold.amount;
+        hold.status = HoldStatus::Released;
+        hold.settled_at.get_or_insert(now_secs());
+        let hold = hold.value().clone();
+        self.persist(StorageRecord::Hold(hold));
+    }
+
+    /// Sets how long new invoices stay payable (None: forever).
+    pub fn set_invoice_ttl(&self, ttl_secs: Option<u64>) {
+        *self.invoice_ttl.write().unwrap() = ttl_secs;
+    }
+
+    pub fn invoice_ttl(&self) -> Option<u64> {
+        *self.invoice_ttl.read().unwrap()
+    }
+
+    /// Creates a new pending invoice, bound to `resource` when given. With a
+    /// `payee_agent` the payment is a transfer to that agent's wallet.
+    pub fn create_invoice(&self, agent_id: &str, amount: Amount, currency: &str, payee: &str, payee_agent: Option<&str>, resource: Option<InvoiceResource>) -> Invoice {
+        let id = uuid::Builder::from_random_bytes(self.rng.lock().unwrap().gen()).into_uuid().to_string();
+        let now = now_secs();
         let invoice = Invoice {
             id: id.clone(),
             amount,
+            currency: currency.to_string(),
             is_paid: false,
             agent_id: agent_id.to_string(),
+            payee: payee.to_string(),
+            payee_agent: payee_agent.map(str::to_string),
+            issued_at: now,
+            expires_at: self.invoice_ttl().map(|ttl| now + ttl),
+            resource,
+            cancelled_at: None,
+            paid_at: None,
+            alternatives: Vec::new(),
         };
         self.invoices.insert(id.clone(), invoice.clone());
+        self.persist(StorageRecord::Invoice(invoice.clone()));
         invoice
     }
 
-    fn generate_cronos_hash(&self) -> String {
-        let rng = rand::thread_rng();
-        let suffix: String = rng
-            .sample_iter(&Alphanumeric)
-            .take(64)
-            .map(char::from)
+    /// Marks invoices issued for the same request (one per accepted asset) as
+    /// alternatives: once one of them is paid the others are cancelled.
+    pub fn link_alternatives(&self, invoice_ids: &[String]) {
+        if invoice_ids.len() < 2 {
+            return;
+        }
+        for id in invoice_ids {
+            if let Some(mut invoice) = self.invoices.get_mut(id) {
+                invoice.alternatives = invoice_ids.iter().filter(|other| *other != id).cloned().collect();
+                self.persist(StorageRecord::Invoice(invoice.clone()));
+            }
+        }
+    }
+
+    /// Cancels the still-open alternatives of an invoice that was just paid.
+    fn cancel_alternatives(&self, paid: &Invoice) {
+        let now = now_secs();
+        for id in &paid.alternatives {
+            let Some(mut invoice) = self.invoices.get_mut(id) else { continue };
+            if matches!(invoice.status(now), InvoiceStatus::Open | InvoiceStatus::Expired) {
+                invoice.cancelled_at = Some(now);
+                self.persist(StorageRecord::Invoice(invoice.clone()));
+            }
+        }
+    }
+
+    pub fn get_invoice(&self, invoice_id: &str) -> Option<Invoice> {
+        self.invoices.get(invoice_id).map(|r| r.value().clone())
+    }
+
+    /// Invoices of one agent (or all), oldest first.
+    pub fn list_invoices(&self, agent_id: Option<&str>) -> Vec<Invoice> {
+        let mut invoices: Vec<Invoice> = self.invoices.iter()
+            .filter(|r| agent_id.is_none_or(|id| r.value().agent_id == id))
+            .map(|r| r.value().clone())
             .collect();
-        format!("0x{}", suffix.to_lowercase())
+        invoices.sort_by_key(|i| i.issued_at);
+        invoices
+    }
+
+    /// The invoice, if `agent_id` could pay it for `resource` right now. The
+    /// same checks `pay_invoice` makes before looking at the wallet.
+    pub fn validate_invoice(&self, invoice_id: &str, agent_id: &str, resource: Option<&InvoiceResource>) -> Result<Invoice, PaymentError> {
+        let invoice = self.get_invoice(invoice_id).ok_or(PaymentError::InvoiceInvalid)?;
+        invoice.check(agent_id, resource, now_secs())?;
+        Ok(invoice)
+    }
+
+    /// Withdraws an unpaid invoice; paying it afterwards fails with `invoice_cancelled`.
+    pub fn cancel_invoice(&self, invoice_id: &str) -> Result<Invoice, CancelError> {
+        let mut invoice = self.invoices.get_mut(invoice_id).ok_or_else(|| CancelError::NotFound(invoice_id.to_string()))?;
+        match invoice.status(now_secs()) {
+            InvoiceStatus::Open | InvoiceStatus::Expired => {}
+            InvoiceStatus::Paid => return Err(CancelError::AlreadyPaid(invoice_id.to_string())),
+            InvoiceStatus::Cancelled => return Err(CancelError::AlreadyCancelled(invoice_id.to_string())),
+        }
+        invoice.cancelled_at = Some(now_secs());
+        let invoice = invoice.value().clone();
+        self.persist(StorageRecord::Invoice(invoice.clone()));
+        Ok(invoice)
+    }
+
+    /// Cancels an invoice for something its agent was already served but
+    /// couldn't pay (a post-paid usage charge the ledger refused) and records
+    /// the amount as the agent's debt, owed from future income. Returns what
+    /// the agent now owes in that asset, or None when the invoice is no longer
+    /// open or its agent is unknown.
+    pub fn owe_invoice(&self, invoice_id: &str) -> Option<Amount> {
+        let agent_id = self.invoices.get(invoice_id)?.agent_id.clone();
+        if !self.store.contains_key(&agent_id) {
+            return None;
+        }
+        let invoice = self.cancel_invoice(invoice_id).ok()?;
+        let mut agent = self.store.get_mut(&agent_id)?;
+        agent.owe(&invoice.currency, invoice.amount, String::new());
+        let owed = agent.debts.get(&invoice.currency).copied().unwrap_or_default();
+        self.persist(StorageRecord::Agent(agent.value().clone()));
+        Some(owed)
+    }
+
+    /// Forgets invoices that were paid, expired or were cancelled more than
+    /// `retention` seconds ago (kept that long so late payers still get a
+    /// precise error). Returns how many were removed.
+    pub fn sweep_invoices(&self, retention: u64) -> usize {
+        let now = now_secs();
+        let stale: Vec<String> = self.invoices.iter()
+            .filter(|r| {
+                let invoice = r.value();
+                let closed_at = match invoice.is_paid {
+                    true => Some(invoice.paid_at.unwrap_or(invoice.issued_at)),
+                    false => invoice.cancelled_at.or(invoice.expires_at),
+                };
+                closed_at.is_some_and(|at| at.saturating_add(retention) <= now)
+            })
+            .map(|r| r.key().clone())
+            .collect();
+        for id in &stale {
+            self.invoices.remove(id);
+        }
+        let swept: HashSet<&String> = stale.iter().collect();
+        self.offers.retain(|_, paid| !swept.contains(paid));
+        if !stale.is_empty() {
+            self.persist(StorageRecord::InvoicesSwept { ids: stale.clone() });
+        }
+        stale.len()
+    }
+
+    /// Marks a signed authorization nonce as spent. Nonces are scoped to the
+    /// token contract (`asset`), as in EIP-3009.
+    /// Returns false if the payer already used this nonce on `asset` (replay).
+    pub fn claim_authorization(&self, asset: &str, payer: &str, nonce: &str) -> bool {
+        let key = authorization_key(asset, payer, nonce);
+        match self.authorizations.entry(key) {
+            dashmap::mapref::entry::Entry::Occupied(_) => false,
+            dashmap::mapref::entry::Entry::Vacant(v) => {
+                self.persist(StorageRecord::Authorization { key: v.key().clone(), used: true });
+                v.insert(());
+                true
+            }
+        }
+    }
+
+    /// Frees a nonce whose settlement failed, so the client can retry with it.
+    pub fn release_authorization(&self, asset: &str, payer: &str, nonce: &str) {
+        let key = authorization_key(asset, payer, nonce);
+        if let dashmap::mapref::entry::Entry::Occupied(o) = self.authorizations.entry(key) {
+            self.persist(StorageRecord::Authorization { key: o.key().clone(), used: false });
+            o.remove();
+        }
+    }
+
+    fn generate_cronos_hash(&self) -> String {
+        let bytes: [u8; 32] = self.rng.lock().unwrap().gen();
+        let suffix: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("0x{}", suffix)
     }
 
     // Admin function to force-set a balance (for testing exhaustion)
-    pub fn set_balance(&self, agent_id: &str, amount: f64) {
+    pub fn set_balance(&self, agent_id: &str, currency: &str, amount: Amount) -> Result<(), String> {
+        let token = self.token(currency).ok_or_else(|| format!("Unknown asset: {}", currency))?;
+        let tokens = self.tokens();
+        let mut is_new = false;
+        let mut entry = self.store.entry(agent_id.to_string()).or_insert_with(|| {
+            is_new = true;
+            AgentState::new(agent_id.to_string(), &tokens)
+        });
+        if is_new {
+            self.post_funding(&entry);
+        }
+        let previous = entry.balances.insert(token.symbol.clone(), amount).unwrap_or_default();
+        let (debit, credit) = match amount >= previous {
+            true => (Amount::ZERO, amount - previous),
+            false => (previous - amount, Amount::ZERO),
+        };
+        self.post(&entry, JournalEntry::new(EntryKind::AdminSet, &token.symbol, debit, credit)
+            .with_memo(format!("Balance set from {} to {}", previous, amount)));
+        self.persist(StorageRecord::Agent(entry.value().clone()));
+        Ok(())
+    }
+
+    /// Replaces an agent's spending policy (an empty policy clears it).
+    pub fn set_policy(&self, agent_id: &str, policy: SpendingPolicy) -> Result<(), String> {
+        policy.validate()?;
+        let tokens = self.tokens();
+        let mut is_new = false;
+        let mut entry = self.store.entry(agent_id.to_string()).or_insert_with(|| {
+            is_new = true;
+            AgentState::new(agent_id.to_string(), &tokens)
+        });
+        if is_new {
+            self.post_funding(&entry);
+        }
+        entry.policy = policy;
+        self.persist(StorageRecord::Agent(entry.value().clone()));
+        Ok(())
+    }
+
+    /// Creates a budget group, or moves/re-caps an existing one (its wallet and
+    /// spend are kept, and fields `update` leaves out keep their value). A new
+    /// group is top-level, with a wallet, unless `update` gives it a parent.
+    pub fn upsert_group(&self, id: &str, update: GroupUpdate) -> Result<BudgetGroup, String> {
+        let mut groups = self.groups.write().unwrap();
+        let existing = groups.get(id).cloned();
+        let mut group = existing.clone().unwrap_or_else(|| BudgetGroup::new(id, None, None));
+        if let Some(parent) = update.parent {
+            group.parent = parent;
+        }
+        if let Some(budget_limit) = update.budget_limit {
+            group.budget_limit = budget_limit;
+        }
+        groups::validate(&groups, &group)?;
+
+        // A moved group takes the spend under it along: off the ancestors it
+        // leaves, onto the ones it joins (shared ones keep theirs)
+        let old_parent = existing.and_then(|g| g.parent);
+        if old_parent != group.parent {
+            let ancestors = |parent: &Option<String>| parent.as_ref().map(|p| groups::path(&groups, p)).unwrap_or_default();
+            let (left, joined) = (ancestors(&old_parent), ancestors(&group.parent));
+            for ancestor in left.iter().filter(|a| !joined.contains(a)) {
+                let Some(ancestor) = groups.get_mut(ancestor) else { continue };
+                ancestor.total_spend = ancestor.total_spend.saturating_sub(group.total_spend);
+                ancestor.payment_count = ancestor.payment_count.saturating_sub(group.payment_count);
+                self.persist(StorageRecord::Group(ancestor.clone()));
+            }
+            for ancestor in joined.iter().filter(|a| !left.contains(a)) {
+                let Some(ancestor) = groups.get_mut(ancestor) else { continue };
+                ancestor.total_spend += group.total_spend;
+                ancestor.payment_count += group.payment_count;
+                self.persist(StorageRecord::Group(ancestor.clone()));
+            }
+        }
+        self.persist(StorageRecord::Group(group.clone()));
+        groups.insert(id.to_string(), group.clone());
+        Ok(group)
+    }
+
+    /// Deletes a group with no member agents, no subgroups and an empty
+    /// wallet, so no money leaves the ledger with it.
+    pub fn remove_group(&self, id: &str) -> Result<BudgetGroup, RemoveGroupError> {
+        // No agent can join while the members are counted and the group removed
+        let _memberships = self.memberships.lock().unwrap();
+        // Agents are looked at before the groups lock is taken (see `groups`)
+        if let Some(member) = self.store.iter().find(|r| r.value().group.as_deref() == Some(id)) {
+            return Err(RemoveGroupError::HasMember(id.to_string(), member.key().clone()));
+        }
+        let mut groups = self.groups.write().unwrap();
+        if let Some(child) = groups.values().find(|g| g.parent.as_deref() == Some(id)) {
+            return Err(RemoveGroupError::HasSubgroup(id.to_string(), child.id.clone()));
+        }
+        let group = groups.get(id).ok_or_else(|| RemoveGroupError::NotFound(id.to_string()))?;
+        if let Some((currency, amount)) = group.balances.iter().find(|(_, amount)| !amount.is_zero()) {
+            return Err(RemoveGroupError::WalletNotEmpty(id.to_string(), currency.clone(), *amount));
+        }
+        let group = groups.remove(id).ok_or_else(|| RemoveGroupError::NotFound(id.to_string()))?;
+        self.persist(StorageRecord::GroupRemoved { id: id.to_string() });
+        Ok(group)
+    }
+
+    /// Force-sets a balance of a top-level group's shared wallet.
+    pub fn set_group_balance(&self, id: &str, currency: &str, amount: Amount) -> Result<BudgetGroup, String> {
+        let token = self.token(currency).ok_or_else(|| format!("Unknown asset: {}", currency))?;
+        let mut groups = self.groups.write().unwrap();
+        let path = groups::path(&groups, id);
+        let root = path.last().ok_or_else(|| format!("Unknown group: {}", id))?;
+        if root != id {
+            return Err(format!("Group {} is an allocation within {}; fund {} instead", id, root, root));
+        }
+        let group = groups.get_mut(id).ok_or_else(|| format!("Unknown group: {}", id))?;
+        let previous = group.balances.insert(token.symbol.clone(), amount).unwrap_or_default();
+        let group = group.clone();
+        self.persist(StorageRecord::Group(group.clone()));
+        let (debit, credit) = match amount >= previous {
+            true => (Amount::ZERO, amount - previous),
+            false => (previous - amount, Amount::ZERO),
+        };
+        // No agent moved the money: the entry is the wallet's alone
+        self.append(JournalEntry::new(EntryKind::AdminSet, &token.symbol, debit, credit)
+            .with_memo(format!("Group balance set from {} to {}", previous, amount))
+            .in_wallet(id, amount));
+        Ok(group)
+    }
+
+    /// Moves an agent into a budget group (or out of one with None). Spend
+    /// already counted against the old group stays there.
+    pub fn assign_group(&self, agent_id: &str, group: Option<&str>) -> Result<AgentState, GroupMemberError> {
+        // The group can't be removed between the check and the move
+        let _memberships = self.memberships.lock().unwrap();
+        if let Some(id) = group {
+            if !self.groups.read().unwrap().contains_key(id) {
+                return Err(GroupMemberError::UnknownGroup(id.to_string()));
+            }
+        }
+        let tokens = self.tokens();
+        let mut is_new = false;
         let mut entry = self.store.entry(agent_id.to_string()).or_insert_with(|| {
-            AgentState::new(agent_id.to_string())
+            is_new = true;
+            AgentState::new(agent_id.to_string(), &tokens)
         });
-        entry.balance_usdc = amount;
+        if is_new {
+            self.post_funding(&entry);
+        }
+        entry.group = group.map(str::to_string);
+        let agent = entry.value().clone();
+        self.persist(StorageRecord::Agent(agent.clone()));
+        Ok(agent)
+    }
+
+    /// Takes an agent out of `group`, back to its own wallet. Refused if the
+    /// agent has meanwhile moved to another group (or never was in this one).
+    pub fn leave_group(&self, agent_id: &str, group: &str) -> Result<AgentState, GroupMemberError> {
+        let _memberships = self.memberships.lock().unwrap();
+        let not_a_member = || GroupMemberError::NotAMember(agent_id.to_string(), group.to_string());
+        let mut agent = self.store.get_mut(agent_id).ok_or_else(not_a_member)?;
+        if agent.group.as_deref() != Some(group) {
+            return Err(not_a_member());
+        }
+        agent.group = None;
+        let agent = agent.value().clone();
+        self.persist(StorageRecord::Agent(agent.clone()));
+        Ok(agent)
+    }
+
+    pub fn get_group(&self, id: &str) -> Option<BudgetGroup> {
+        self.groups.read().unwrap().get(id).cloned()
+    }
+
+    /// All budget groups, by id.
+    pub fn list_groups(&self) -> Vec<BudgetGroup> {
+        self.groups.read().unwrap().values().cloned().collect()
+    }
+
+    /// A group and its ancestors, nearest first; the last one holds the wallet.
+    pub fn group_path(&self, id: &str) -> Vec<BudgetGroup> {
+        let groups = self.groups.read().unwrap();
+        groups::path(&groups, id).iter().filter_map(|id| groups.get(id).cloned()).collect()
+    }
+
+    /// Adds `amount` to the shared wallet of `group`'s tree and takes
+    /// `usd_value` off the spend of every level (and one payment off the
+    /// counts when `reverses_payment`). Returns the wallet's group and its new
+    /// balance, or None when the group no longer exists.
+    fn credit_group(&self, group: &str, currency: &str, amount: Amount, usd_value: Amount, reverses_payment: bool) -> Option<(String, Amount)> {
+        let mut groups = self.groups.write().unwrap();
+        let path = groups::path(&groups, group);
+        let root = path.last()?.clone();
+        for id in &path {
+            let Some(group) = groups.get_mut(id) else { continue };
+            group.total_spend = group.total_spend.saturating_sub(usd_value);
+            if reverses_payment {
+                group.payment_count = group.payment_count.saturating_sub(1);
+            }
+        }
+        let wallet = groups.get_mut(&root)?;
+        *wallet.balances.entry(currency.to_string()).or_default() += amount;
+        let balance = wallet.balance(currency);
+        for group in path.iter().filter_map(|id| groups.get(id)) {
+            self.persist(StorageRecord::Group(group.clone()));
+        }
+        Some((root, balance))
+    }
+
+    /// Takes `amount` out of the shared wallet of `group`'s tree and puts
+    /// `usd_value` back on the spend of every level (undoing `credit_group`).
+    /// Returns the wallet's group, its new balance and the part of `amount`
+    /// it couldn't cover.
+    fn debit_group(&self, group: &str, currency: &str, amount: Amount, usd_value: Amount) -> Option<(String, Amount, Amount)> {
+        let mut groups = self.groups.write().unwrap();
+        let path = groups::path(&groups, group);
+        let root = path.last()?.clone();
+        for id in &path {
+            if let Some(group) = groups.get_mut(id) {
+                group.total_spend += usd_value;
+            }
+        }
+        let wallet = groups.get_mut(&root)?;
+        let balance = wallet.balances.entry(currency.to_string()).or_default();
+        let taken = (*balance).min(amount);
+        *balance -= taken;
+        let balance = *balance;
+        for group in path.iter().filter_map(|id| groups.get(id)) {
+            self.persist(StorageRecord::Group(group.clone()));
+        }
+        Some((root, balance, amount - taken))
+    }
+
+    /// On-chain address of an agent, registered or not.
+    fn agent_address(&self, agent_id: &str) -> String {
+        self.store.get(agent_id).map_or_else(|| derive_address(agent_id), |a| a.address.clone())
+    }
+
+    /// Balance of `currency` in the wallet an agent is paid into: its budget
+    /// group's shared wallet if it's in one, otherwise its own.
+    fn wallet_balance(&self, agent_id: &str, currency: &str) -> Amount {
+        let Some(agent) = self.store.get(agent_id) else { return Amount::ZERO };
+        if let Some(group) = &agent.group {
+            let groups = self.groups.read().unwrap();
+            if let Some(wallet) = groups::path(&groups, group).last().and_then(|root| groups.get(root)) {
+                return wallet.balance(currency);
+            }
+        }
+        agent.balance(currency)
+    }
+
+    /// Moves `tx`'s amount into (`credit`) or back out of the wallet of the
+    /// agent it paid: its budget group's shared wallet if it's in one,
+    /// otherwise its own. Journaled on the payee's statement as income.
+    /// Income first pays off the payee's debt in that asset; a reversal the
+    /// wallet can't cover becomes debt rather than being forgiven.
+    fn settle_payee(&self, payee: &str, tx: &Transaction, credit: bool, memo: String) {
+        let tokens = self.tokens();
+        let mut is_new = false;
+        let mut agent = self.store.entry(payee.to_string()).or_insert_with(|| {
+            is_new = true;
+            AgentState::new(payee.to_string(), &tokens)
+        });
+        if is_new {
+            self.post_funding(&agent);
+        }
+        let (amount, memo) = match credit {
+            true => match agent.repay(&tx.currency, tx.amount) {
+                repaid if repaid.is_zero() => (tx.amount, memo),
+                repaid => (tx.amount - repaid, format!("{} ({} {} withheld against debt)", memo, repaid, tx.currency)),
+            },
+            false => (tx.amount, memo),
+        };
+        let wallet = agent.group.clone().and_then(|group| match credit {
+            true => self.credit_group(&group, &tx.currency, amount, Amount::ZERO, false).map(|(wallet, balance)| (wallet, balance, Amount::ZERO)),
+            false => self.debit_group(&group, &tx.currency, amount, Amount::ZERO),
+        });
+        let (wallet, shortfall) = match wallet {
+            Some((wallet, balance, shortfall)) => (Some((wallet, balance)), shortfall),
+            None => {
+                let balance = agent.balances.entry(tx.currency.clone()).or_default();
+                if credit {
+                    *balance += amount;
+                    (None, Amount::ZERO)
+                } else {
+                    let taken = (*balance).min(amount);
+                    *balance -= taken;
+                    (None, amount - taken)
+                }
+            }
+        };
+        let memo = agent.owe(&tx.currency, shortfall, memo);
+        let entry = match credit {
+            true => JournalEntry::for_tx(EntryKind::Income, tx, Amount::ZERO, amount),
+            false => JournalEntry::for_tx(EntryKind::Income, tx, amount - shortfall, Amount::ZERO),
+        }.with_memo(memo);
+        let entry = match wallet {
+            Some((wallet, balance)) => entry.in_wallet(&wallet, balance),
+            None => entry,
+        };
+        self.post(&agent, entry);
+        self.persist(StorageRecord::Agent(agent.value().clone()));
     }
 
     /// Returns a snapshot of all registered agents (for TUI display)
@@ -118,43 +1455,707 @@ impl Ledger {
         self.store.iter().map(|r| r.value().clone()).collect()
     }
 
-    pub fn pay_invoice(&self, invoice_id: &str, agent_id: &str, network: &str) -> Result<PaymentReceipt, String> {
-        // 1. Validate Invoice
-        let mut invoice = self.invoices.get_mut(invoice_id).ok_or("Invoice invalid")?;
-        
-        if invoice.is_paid {
-            return Err("Invoice already paid".to_string());
+    /// Settled (pending or mined) payments of an agent. Read while the agent's
+    /// entry is held, so a concurrent payment of the same agent can't be missed.
+    fn payment_history(&self, agent_id: &str) -> Vec<Transaction> {
+        let Some(hashes) = self.agent_txs.get(agent_id).map(|r| r.value().clone()) else {
+            return Vec::new();
+        };
+        hashes.iter()
+            .filter_map(|hash| self.transactions.get(hash).map(|r| r.value().clone()))
+            .filter(|tx| tx.status != TxStatus::Orphaned)
+            .collect()
+    }
+
+    /// Adds a new transaction (and indexes it under the agent it belongs to).
+    fn insert_tx(&self, tx: &Transaction) {
+        let entry = self.transactions.entry(tx.hash.clone()).insert(tx.clone());
+        self.persist(StorageRecord::Transaction(tx.clone()));
+        drop(entry);
+        self.agent_txs.entry(tx.agent_id.clone()).or_default().push(tx.hash.clone());
+    }
+
+    /// Settles an invoice. With a `resource`, the invoice must have been
+    /// issued for that same request.
+    pub fn pay_invoice(&self, invoice_id: &str, agent_id: &str, network: &str, resource: Option<&InvoiceResource>) -> Result<PaymentReceipt, PaymentError> {
+        self.settle_invoice(invoice_id, agent_id, network, resource, BURN_ADDRESS)
+    }
+
+    /// Pays an invoice's full amount into escrow (`upto` scheme). The hold is
+    /// captured or released once the request's real cost is known.
+    pub fn reserve(&self, invoice_id: &str, agent_id: &str, network: &str, resource: Option<&InvoiceResource>) -> Result<(Hold, PaymentReceipt), PaymentError> {
+        let receipt = self.settle_invoice(invoice_id, agent_id, network, resource, ESCROW_ADDRESS)?;
+        let invoice = self.get_invoice(invoice_id).ok_or(PaymentError::InvoiceInvalid)?;
+        let hold = Hold {
+            id: invoice.id,
+            agent_id: agent_id.to_string(),
+            payee: invoice.payee,
+            payee_agent: invoice.payee_agent,
+            currency: receipt.currency.clone(),
+            amount: invoice.amount,
+            captured: Amount::ZERO,
+            refunded: Amount::ZERO,
+            status: HoldStatus::Held,
+            tx_hash: receipt.tx_hash.clone(),
+            refund_tx: None,
+            payout_tx: None,
+            created_at: now_secs(),
+            settled_at: None,
+        };
+        let entry = self.holds.entry(hold.id.clone()).insert(hold.clone());
+        self.persist(StorageRecord::Hold(hold.clone()));
+        drop(entry);
+        Ok((hold, receipt))
+    }
+
+    /// Settles a hold for `amount` (at most the authorized maximum) and
+    /// refunds the rest. Returns the hold and the refund, if any.
+    pub fn capture(&self, hold_id: &str, amount: Amount) -> Result<(Hold, Option<Transaction>), String> {
+        self.close_hold(hold_id, amount, HoldStatus::Captured)
+    }
+
+    /// Refunds a whole hold (nothing was consumed).
+    pub fn release(&self, hold_id: &str) -> Result<(Hold, Option<Transaction>), String> {
+        self.close_hold(hold_id, Amount::ZERO, HoldStatus::Released)
+    }
+
+    pub fn get_hold(&self, hold_id: &str) -> Option<Hold> {
+        self.holds.get(hold_id).map(|r| r.value().clone())
+    }
+
+    /// Holds of one agent (or all), oldest first.
+    pub fn list_holds(&self, agent_id: Option<&str>) -> Vec<Hold> {
+        let mut holds: Vec<Hold> = self.holds.iter()
+            .filter(|r| agent_id.is_none_or(|id| r.value().agent_id == id))
+            .map(|r| r.value().clone())
+            .collect();
+        holds.sort_by_key(|h| h.created_at);
+        holds
+    }
+
+    fn close_hold(&self, hold_id: &str, amount: Amount, status: HoldStatus) -> Result<(Hold, Option<Transaction>), String> {
+        let mut hold = self.holds.get_mut(hold_id).ok_or_else(|| format!("Unknown hold: {}", hold_id))?;
+        if hold.status != HoldStatus::Held {
+            return Err(format!("Hold {} is already {:?}", hold_id, hold.status).to_lowercase());
+        }
+        if amount > hold.amount {
+            return Err(format!("Cannot capture {} {}: only {} was authorized", amount, hold.currency, hold.amount));
+        }
+        let token = self.token(&hold.currency).ok_or_else(|| format!("Unknown asset: {}", hold.currency))?;
+        let remainder = hold.amount - amount;
+        let now = now_secs();
+
+        let mut refund = match remainder.is_zero() {
+            true => None,
+            false => {
+                let group = self.transactions.get(&hold.tx_hash).and_then(|tx| tx.group.clone());
+                let mut agent = self.store.get_mut(&hold.agent_id).ok_or_else(|| format!("Unknown agent: {}", hold.agent_id))?;
+                let usd_value = token.usd_value(remainder);
+                let wallet = group.as_deref()
+                    .and_then(|group| self.credit_group(group, &token.symbol, remainder, usd_value, false));
+                if wallet.is_none() {
+                    *agent.balances.entry(token.symbol.clone()).or_default() += remainder;
+                }
+                agent.total_spend = agent.total_spend.saturating_sub(usd_value);
+                let tx = Transaction {
+                    hash: self.generate_cronos_hash(),
+                    kind: TxKind::Refund,
+                    status: TxStatus::Pending,
+                    block_height: None,
+                    block_hash: None,
+                    from: ESCROW_ADDRESS.to_string(),
+                    to: agent.address.clone(),
+                    amount: remainder,
+                    currency: token.symbol.clone(),
+                    usd_value,
+                    payee: hold.payee.clone(),
+                    agent_id: hold.agent_id.clone(),
+                    invoice_id: hold.id.clone(),
+                    timestamp: now,
+                    group: wallet.as_ref().and(group),
+                    payee_agent: None,
+                };
+                let entry = match status {
+                    HoldStatus::Released => JournalEntry::for_tx(EntryKind::Release, &tx, Amount::ZERO, remainder)
+                        .with_memo(format!("Escrow hold of {} released", hold.amount)),
+                    _ => JournalEntry::for_tx(EntryKind::Refund, &tx, Amount::ZERO, remainder)
+                        .with_memo(format!("Captured {} of {} held", amount, hold.amount)),
+                };
+                let entry = match &wallet {
+                    Some((wallet, balance)) => entry.in_wallet(wallet, *balance),
+                    None => entry,
+                };
+                self.post(&agent, entry);
+                let agent = agent.value().clone();
+                self.persist(StorageRecord::Agent(agent));
+                Some(tx)
+            }
+        };
+        // Agent-to-agent: the captured part leaves escrow for the payee's wallet
+        let payout = match &hold.payee_agent {
+            Some(payee) if !amount.is_zero() => Some(Transaction {
+                hash: self.generate_cronos_hash(),
+                kind: TxKind::Payout,
+                status: TxStatus::Pending,
+                block_height: None,
+                block_hash: None,
+                from: ESCROW_ADDRESS.to_string(),
+                to: self.agent_address(payee),
+                amount,
+                currency: token.symbol.clone(),
+                usd_value: token.usd_value(amount),
+                payee: hold.payee.clone(),
+                agent_id: hold.agent_id.clone(),
+                invoice_id: hold.id.clone(),
+                timestamp: now,
+                group: None,
+                payee_agent: Some(payee.clone()),
+            }),
+            _ => None,
+        };
+        hold.captured = amount;
+        hold.refunded = remainder;
+        hold.status = status;
+        hold.settled_at = Some(now);
+        hold.refund_tx = refund.as_ref().map(|tx| tx.hash.clone());
+        hold.payout_tx = payout.as_ref().map(|tx| tx.hash.clone());
+        let closed = hold.value().clone();
+        self.persist(StorageRecord::Hold(closed.clone()));
+        drop(hold);
+
+        for tx in refund.iter().chain(&payout) {
+            self.insert_tx(tx);
+            self.chain.lock().unwrap().mempool.push(tx.hash.clone());
         }
-        if invoice.agent_id != agent_id {
-            return Err("Invoice belongs to another agent".to_string());
+        if let Some(payout) = &payout {
+            let payee = payout.payee_agent.as_deref().unwrap_or_default();
+            self.settle_payee(payee, payout, true, format!("Captured from {}'s escrow hold", closed.agent_id));
+        }
+        let automine = self.chain.lock().unwrap().automine;
+        if automine && (refund.is_some() || payout.is_some()) {
+            let block = self.mine_block();
+            if let Some(refund) = refund.as_mut() {
+                refund.status = TxStatus::Mined;
+                refund.block_height = Some(block.number);
+                refund.block_hash = Some(block.hash);
+            }
+        }
+        Ok((closed, refund))
+    }
+
+    fn settle_invoice(&self, invoice_id: &str, agent_id: &str, network: &str, resource: Option<&InvoiceResource>, to: &str) -> Result<PaymentReceipt, PaymentError> {
+        // 1. Validate Invoice (paid, cancelled, expired, owner, resource)
+        let mut invoice = self.invoices.get_mut(invoice_id).ok_or(PaymentError::InvoiceInvalid)?;
+        let now = now_secs();
+        invoice.check(agent_id, resource, now)?;
+        let token = self.token(&invoice.currency)
+            .ok_or_else(|| PaymentError::UnsupportedCurrency(invoice.currency.clone()))?;
+        let usd_value = token.usd_value(invoice.amount);
+        if invoice.payee_agent.as_deref() == Some(agent_id) {
+            return Err(PaymentError::SelfPayment);
+        }
+        // Paying another agent sends the funds to its wallet instead of burning them
+        // (escrowed payments reach it when the hold is captured)
+        let payee_agent = invoice.payee_agent.clone().filter(|_| to != ESCROW_ADDRESS);
+        let to = payee_agent.as_deref().map_or_else(|| to.to_string(), |payee| self.agent_address(payee));
+        // Read before the payer is locked: the payee may share its DashMap shard
+        if let Some(payee) = &payee_agent {
+            if self.wallet_balance(payee, &token.symbol).checked_add(invoice.amount).is_none() {
+                return Err(PaymentError::AmountOverflow { currency: token.symbol });
+            }
         }
 
         // 2. Validate Funds & Safety
-        let mut agent = self.store.get_mut(agent_id).ok_or("Agent not found")?;
+        let mut agent = self.store.get_mut(agent_id).ok_or(PaymentError::AgentNotFound)?;
+        let history = self.payment_history(agent_id);
+
+        // Agents in a budget group pay from the shared wallet at the top of its tree
+        let mut groups = agent.group.is_some().then(|| self.groups.write().unwrap());
+        let path = match (&groups, &agent.group) {
+            (Some(groups), Some(group)) => groups::path(groups, group),
+            _ => Vec::new(),
+        };
+        let wallet = path.last().cloned();
         
-        // CHECK 1: Wallet Balance
-        if agent.balance_usdc < invoice.amount {
-            return Err("Wallet Exhausted: Insufficient funds".to_string());
-        }
+        // CHECK 1: Wallet Balance (in the invoiced asset)
+        let balance = match (&groups, &wallet) {
+            (Some(groups), Some(wallet)) => groups[wallet].balance(&token.symbol),
+            _ => agent.balance(&token.symbol),
+        };
+        let Some(new_balance) = balance.checked_sub(invoice.amount) else {
+            return Err(PaymentError::InsufficientFunds { balance, currency: token.symbol });
+        };
         
-        // CHECK 2: Safety Budget (Total Spend Cap)
-        if (agent.total_spend + invoice.amount) > agent.budget_limit {
-            return Err("Safety Limit: Budget cap exceeded".to_string());
+        // CHECK 2: Safety Budget (Total Spend Cap, in USD)
+        let total_spend = agent.total_spend.checked_add(usd_value)
+            .filter(|spend| *spend <= agent.budget_limit)
+            .ok_or(PaymentError::BudgetExceeded)?;
+
+        // CHECK 2b: Group Budgets (every level from the agent's group up to the wallet)
+        let mut group_spends = Vec::with_capacity(path.len());
+        if let Some(groups) = &groups {
+            for id in &path {
+                let group = &groups[id];
+                let spend = group.total_spend.checked_add(usd_value);
+                if group.budget_limit.is_some_and(|limit| spend.is_none_or(|spend| spend > limit)) {
+                    return Err(PaymentError::GroupBudgetExceeded { group: id.clone() });
+                }
+                group_spends.push(spend.ok_or_else(|| PaymentError::AmountOverflow { currency: "USD".to_string() })?);
+            }
+        }
+
+        // CHECK 3: Spending Policies (windows, rate, payee, asset), net of escrow refunds
+        let mut refunds: HashMap<&str, (Amount, Amount)> = HashMap::new();
+        for tx in history.iter().filter(|tx| tx.kind == TxKind::Refund) {
+            let refunded = refunds.entry(tx.invoice_id.as_str()).or_default();
+            refunded.0 += tx.usd_value;
+            refunded.1 += tx.amount;
+        }
+        let spends: Vec<Spend> = history.iter()
+            .filter(|tx| tx.kind == TxKind::Payment)
+            .map(|tx| {
+                let (usd_refunded, refunded) = refunds.get(tx.invoice_id.as_str()).copied().unwrap_or_default();
+                Spend {
+                    usd_value: tx.usd_value.saturating_sub(usd_refunded),
+                    amount: tx.amount.saturating_sub(refunded),
+                    currency: &tx.currency,
+                    payee: &tx.payee,
+                    timestamp: tx.timestamp,
+                }
+            })
+            .collect();
+        let payment = Spend { usd_value, amount: invoice.amount, currency: &token.symbol, payee: &invoice.payee, timestamp: now };
+        agent.policy.check(&spends, &payment, now).map_err(PaymentError::Policy)?;
+
+        // CHECK 4: Alternatives (only one invoice of a multi-asset offer is ever paid)
+        if let Some(offer) = invoice.offer() {
+            match self.offers.entry(offer.to_string()) {
+                dashmap::mapref::entry::Entry::Occupied(paid) if *paid.get() != invoice.id => {
+                    return Err(PaymentError::InvoiceCancelled);
+                }
+                dashmap::mapref::entry::Entry::Occupied(_) => {}
+                dashmap::mapref::entry::Entry::Vacant(v) => {
+                    v.insert(invoice.id.clone());
+                }
+            }
         }
 
         // 3. Execute
-        agent.balance_usdc -= invoice.amount;
-        agent.total_spend += invoice.amount;
+        match (groups.as_mut(), &wallet) {
+            (Some(groups), Some(wallet)) => {
+                for (id, spend) in path.iter().zip(group_spends) {
+                    let Some(group) = groups.get_mut(id) else { continue };
+                    group.total_spend = spend;
+                    group.payment_count += 1;
+                    if id == wallet {
+                        group.balances.insert(token.symbol.clone(), new_balance);
+                    }
+                    self.persist(StorageRecord::Group(group.clone()));
+                }
+            }
+            _ => {
+                agent.balances.insert(token.symbol.clone(), new_balance);
+            }
+        }
+        drop(groups);
+        agent.total_spend = total_spend;
         agent.payment_count += 1;
         
         invoice.is_paid = true;
+        invoice.paid_at = Some(now_secs());
         let chain_id = if network == "cronos-mainnet" { "25" } else { "338" }; // 338 is Testnet
 
-        Ok(PaymentReceipt {
-            new_balance: agent.balance_usdc,
-            tx_hash: self.generate_cronos_hash(),
+        // The transfer goes to the mempool (and straight into a block with automine)
+        let tx = Transaction {
+            hash: self.generate_cronos_hash(),
+            kind: TxKind::Payment,
+            status: TxStatus::Pending,
+            block_height: None,
+            block_hash: None,
+            from: agent.address.clone(),
+            to: to.clone(),
+            amount: invoice.amount,
+            currency: token.symbol.clone(),
+            usd_value,
+            payee: invoice.payee.clone(),
+            agent_id: agent_id.to_string(),
+            invoice_id: invoice.id.clone(),
+            timestamp: now,
+            group: wallet.as_ref().and(agent.group.clone()),
+            payee_agent: payee_agent.clone(),
+        };
+        let mut receipt = PaymentReceipt {
+            new_balance,
+            currency: token.symbol.clone(),
+            tx_hash: tx.hash.clone(),
             chain_id: chain_id.to_string(),
-            block_height: 10_000_000 + agent.payment_count, // Fake block height increment
-        })
+            block_height: None,
+        };
+
+        let kind = if to == ESCROW_ADDRESS { EntryKind::Hold } else { EntryKind::Payment };
+        let entry = JournalEntry::for_tx(kind, &tx, invoice.amount, Amount::ZERO);
+        let entry = match &wallet {
+            Some(wallet) => entry.in_wallet(wallet, new_balance),
+            None => entry,
+        };
+        self.post(&agent, entry);
+        self.persist(StorageRecord::Agent(agent.value().clone()));
+        let paid_invoice = invoice.value().clone();
+        self.persist(StorageRecord::Invoice(paid_invoice.clone()));
+        // Recorded before the agent is released: its next payment's policy check must see this one
+        self.insert_tx(&tx);
+        drop(agent);
+        drop(invoice);
+        self.cancel_alternatives(&paid_invoice);
+        if let Some(payee) = &payee_agent {
+            self.settle_payee(payee, &tx, true, format!("Received from {}", agent_id));
+        }
+
+        let automine = {
+            let mut chain = self.chain.lock().unwrap();
+            chain.mempool.push(tx.hash);
+            chain.automine
+        };
+        if automine {
+            receipt.block_height = Some(self.mine_block().number);
+        }
+        Ok(receipt)
     }
-}
\ No newline at end of file
+}
+
+/// `asset:payer:nonce`, lowercased: the same nonce on another token is another authorization.
+pub fn authorization_key(asset: &str, payer: &str, nonce: &str) -> String {
+    format!("{}:{}:{}", asset.to_lowercase(), payer.to_lowercase(), nonce.to_lowercase())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usdc(whole: u64) -> Amount {
+        Amount::from_whole(whole)
+    }
+
+    /// Issues and pays an invoice of `amount` USDC for `agent_id`.
+    fn pay(ledger: &Ledger, agent_id: &str, amount: Amount) -> Result<PaymentReceipt, PaymentError> {
+        ledger.register_or_get(agent_id);
+        let invoice = ledger.create_invoice(agent_id, amount, "USDC", "api.openai.com", None, None);
+        ledger.pay_invoice(&invoice.id, agent_id, "cronos-testnet", None)
+    }
+
+    #[test]
+    fn paying_one_alternative_cancels_the_others() {
+        let ledger = Ledger::new();
+        ledger.register_or_get("agent-1");
+        let resource = InvoiceResource::new("POST", "https://api.openai.com/v1/chat/completions");
+        let in_usdc = ledger.create_invoice("agent-1", usdc(1), "USDC", "api.openai.com", None, Some(resource.clone()));
+        let in_usdt = ledger.create_invoice("agent-1", usdc(1), "USDT", "api.openai.com", None, Some(resource.clone()));
+        ledger.link_alternatives(&[in_usdc.id.clone(), in_usdt.id.clone()]);
+
+        ledger.pay_invoice(&in_usdt.id, "agent-1", "cronos-testnet", Some(&resource)).unwrap();
+        let now = now_secs();
+        assert_eq!(ledger.get_invoice(&in_usdc.id).unwrap().status(now), InvoiceStatus::Cancelled);
+        assert_eq!(
+            ledger.pay_invoice(&in_usdc.id, "agent-1", "cronos-testnet", Some(&resource)).unwrap_err(),
+            PaymentError::InvoiceCancelled
+        );
+        assert_eq!(ledger.get_state("agent-1").unwrap().balance("USDC"), usdc(100));
+    }
+
+    #[test]
+    fn concurrent_payments_of_one_agent_respect_its_rate_limit() {
+        let ledger = Ledger::new();
+        ledger.register_or_get("agent-1");
+        let policy = SpendingPolicy { rate_limits: vec![policy::RateLimit { window_secs: 60, max_payments: 1 }], ..Default::default() };
+        ledger.set_policy("agent-1", policy).unwrap();
+        let invoices: Vec<Invoice> = (0..8)
+            .map(|_| ledger.create_invoice("agent-1", usdc(1), "USDC", "api.openai.com", None, None))
+            .collect();
+        let paid = std::thread::scope(|scope| {
+            let handles: Vec<_> = invoices.iter()
+                .map(|invoice| scope.spawn(|| ledger.pay_invoice(&invoice.id, "agent-1", "cronos-testnet", None).is_ok()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).filter(|ok| *ok).count()
+        });
+        assert_eq!(paid, 1);
+        assert_eq!(ledger.get_state("agent-1").unwrap().payment_count, 1);
+    }
+
+    #[test]
+    fn mined_payments_are_orphaned_and_refunded_by_a_reorg() {
+        let ledger = Ledger::new();
+        ledger.set_automine(false);
+        let receipt = pay(&ledger, "agent-1", usdc(2)).unwrap();
+        assert_eq!(ledger.get_transaction(&receipt.tx_hash).unwrap().status, TxStatus::Pending);
+
+        let block = ledger.mine_block();
+        let tx = ledger.get_transaction(&receipt.tx_hash).unwrap();
+        assert_eq!((tx.status, tx.block_height), (TxStatus::Mined, Some(block.number)));
+        assert_eq!(ledger.get_state("agent-1").unwrap().balance("USDC"), usdc(98));
+
+        let reorg = ledger.reorg(1);
+        assert_eq!(reorg.depth, 1);
+        assert_eq!(reorg.orphaned.len(), 1);
+        assert_eq!(ledger.get_transaction(&receipt.tx_hash).unwrap().status, TxStatus::Orphaned);
+        assert_eq!(ledger.get_state("agent-1").unwrap().balance("USDC"), usdc(100));
+        assert_eq!(ledger.block_number(), block.number);
+    }
+
+    #[test]
+    fn invoices_are_bound_to_their_payer_resource_and_lifetime() {
+        let chat = InvoiceResource::new("post", "https://api.openai.com/v1/chat/completions");
+        let invoice = Invoice {
+            id: "inv-1".to_string(),
+            amount: Amount::from_whole(1),
+            currency: "USDC".to_string(),
+            is_paid: false,
+            agent_id: "agent-1".to_string(),
+            payee: "api.openai.com".to_string(),
+            payee_agent: None,
+            issued_at: 1_000,
+            expires_at: Some(1_300),
+            resource: Some(chat.clone()),
+            cancelled_at: None,
+            paid_at: None,
+            alternatives: Vec::new(),
+        };
+        assert_eq!(invoice.check("agent-1", Some(&chat), 1_299), Ok(()));
+        assert_eq!(invoice.check("agent-1", None, 1_299), Ok(()));
+        assert_eq!(invoice.check("agent-2", Some(&chat), 1_299), Err(PaymentError::WrongAgent));
+        let embeddings = InvoiceResource::new("POST", "https://api.openai.com/v1/embeddings");
+        assert_eq!(
+            invoice.check("agent-1", Some(&embeddings), 1_299),
+            Err(PaymentError::ResourceMismatch { issued_for: "POST https://api.openai.com/v1/chat/completions".to_string() })
+        );
+        assert_eq!(invoice.check("agent-1", Some(&chat), 1_300), Err(PaymentError::InvoiceExpired { expired_at: 1_300 }));
+        let cancelled = Invoice { cancelled_at: Some(1_100), ..invoice };
+        assert_eq!(cancelled.status(1_200), InvoiceStatus::Cancelled);
+    }
+
+    #[test]
+    fn cancelled_invoices_cannot_be_paid_and_are_swept() {
+        let ledger = Ledger::new();
+        ledger.register_or_get("agent-1");
+        let invoice = ledger.create_invoice("agent-1", Amount::from_whole(1), "USDC", "api.openai.com", None, None);
+        ledger.cancel_invoice(&invoice.id).unwrap();
+        assert!(ledger.cancel_invoice(&invoice.id).is_err());
+        assert_eq!(ledger.pay_invoice(&invoice.id, "agent-1", "cronos-testnet", None).unwrap_err(), PaymentError::InvoiceCancelled);
+        assert_eq!(ledger.sweep_invoices(0), 1);
+        assert!(ledger.get_invoice(&invoice.id).is_none());
+    }
+
+    #[test]
+    fn cancelling_reports_why_it_cannot() {
+        let ledger = Ledger::new();
+        ledger.register_or_get("agent-1");
+        let open = ledger.create_invoice("agent-1", usdc(1), "USDC", "api.openai.com", None, None);
+        assert!(ledger.cancel_invoice(&open.id).unwrap().cancelled_at.is_some());
+        assert_eq!(ledger.cancel_invoice(&open.id).unwrap_err(), CancelError::AlreadyCancelled(open.id.clone()));
+        assert_eq!(ledger.cancel_invoice("nope").unwrap_err(), CancelError::NotFound("nope".to_string()));
+
+        let paid = ledger.create_invoice("agent-1", usdc(1), "USDC", "api.openai.com", None, None);
+        ledger.pay_invoice(&paid.id, "agent-1", "cronos-testnet", None).unwrap();
+        let err = ledger.cancel_invoice(&paid.id).unwrap_err();
+        assert_eq!(err.to_string(), format!("Invoice {} is already paid", paid.id));
+    }
+
+    #[test]
+    fn sweeping_forgets_paid_invoices_after_the_retention_too() {
+        let ledger = Ledger::new();
+        ledger.register_or_get("agent-1");
+        let open = ledger.create_invoice("agent-1", usdc(1), "USDC", "api.openai.com", None, None);
+        let cancelled = ledger.create_invoice("agent-1", usdc(1), "USDC", "api.openai.com", None, None);
+        ledger.cancel_invoice(&cancelled.id).unwrap();
+        let paid = ledger.create_invoice("agent-1", usdc(1), "USDC", "api.openai.com", None, None);
+        ledger.pay_invoice(&paid.id, "agent-1", "cronos-testnet", None).unwrap();
+        assert!(ledger.get_invoice(&paid.id).unwrap().paid_at.is_some());
+
+        assert_eq!(ledger.sweep_invoices(3600), 0);
+        assert_eq!(ledger.sweep_invoices(0), 2);
+        assert!(ledger.get_invoice(&paid.id).is_none());
+        assert!(ledger.get_invoice(&cancelled.id).is_none());
+        assert_eq!(ledger.get_invoice(&open.id).unwrap().status(now_secs()), InvoiceStatus::Open);
+    }
+
+    #[test]
+    fn group_spend_rolls_up_to_every_level_and_the_tightest_cap_wins() {
+        let ledger = Ledger::new();
+        ledger.upsert_group("org", GroupUpdate::replace(None, Some(usdc(9)))).unwrap();
+        ledger.upsert_group("team", GroupUpdate::replace(Some("org"), Some(usdc(3)))).unwrap();
+        ledger.set_group_balance("org", "USDC", usdc(20)).unwrap();
+        ledger.register_or_get("agent-1");
+        ledger.assign_group("agent-1", Some("team")).unwrap();
+
+        pay(&ledger, "agent-1", usdc(2)).unwrap();
+        let (org, team) = (ledger.get_group("org").unwrap(), ledger.get_group("team").unwrap());
+        assert_eq!((org.total_spend, team.total_spend), (usdc(2), usdc(2)));
+        assert_eq!((org.payment_count, team.payment_count), (1, 1));
+        assert_eq!(org.balance("USDC"), usdc(18));
+        // The agent's own wallet is untouched
+        assert_eq!(ledger.get_state("agent-1").unwrap().balance("USDC"), usdc(100));
+
+        assert_eq!(pay(&ledger, "agent-1", usdc(2)).unwrap_err(), PaymentError::GroupBudgetExceeded { group: "team".to_string() });
+        ledger.upsert_group("team", GroupUpdate::replace(Some("org"), None)).unwrap();
+        pay(&ledger, "agent-1", usdc(7)).unwrap();
+        assert_eq!(pay(&ledger, "agent-1", usdc(1)).unwrap_err(), PaymentError::GroupBudgetExceeded { group: "org".to_string() });
+        assert_eq!(ledger.get_group("org").unwrap().total_spend, usdc(9));
+    }
+
+    #[test]
+    fn moved_groups_take_their_spend_to_the_new_ancestors() {
+        let ledger = Ledger::new();
+        for (id, parent) in [("org", None), ("lab", Some("org")), ("ops", Some("org")), ("team", Some("lab")), ("other", None)] {
+            ledger.upsert_group(id, GroupUpdate::replace(parent, None)).unwrap();
+        }
+        ledger.set_group_balance("org", "USDC", usdc(20)).unwrap();
+        ledger.assign_group("agent-1", Some("team")).unwrap();
+        pay(&ledger, "agent-1", usdc(2)).unwrap();
+
+        // Updating only the cap leaves the group where it is
+        let capped = ledger.upsert_group("team", GroupUpdate { budget_limit: Some(Some(usdc(5))), ..Default::default() }).unwrap();
+        assert_eq!((capped.parent.as_deref(), capped.budget_limit), (Some("lab"), Some(usdc(5))));
+
+        let spend = |id: &str| { let g = ledger.get_group(id).unwrap(); (g.total_spend, g.payment_count) };
+        ledger.upsert_group("team", GroupUpdate { parent: Some(Some("ops".to_string())), ..Default::default() }).unwrap();
+        assert_eq!(ledger.get_group("team").unwrap().budget_limit, Some(usdc(5)));
+        assert_eq!([spend("lab"), spend("ops"), spend("org")], [(Amount::ZERO, 0), (usdc(2), 1), (usdc(2), 1)]);
+
+        ledger.upsert_group("team", GroupUpdate { parent: Some(Some("other".to_string())), ..Default::default() }).unwrap();
+        assert_eq!([spend("ops"), spend("org"), spend("other"), spend("team")], [(Amount::ZERO, 0), (Amount::ZERO, 0), (usdc(2), 1), (usdc(2), 1)]);
+    }
+
+    #[test]
+    fn agents_only_leave_the_group_they_are_in() {
+        let ledger = Ledger::new();
+        ledger.upsert_group("org", GroupUpdate::replace(None, None)).unwrap();
+        ledger.upsert_group("lab", GroupUpdate::replace(None, None)).unwrap();
+        assert_eq!(ledger.assign_group("agent-1", Some("nope")).unwrap_err(), GroupMemberError::UnknownGroup("nope".to_string()));
+        ledger.assign_group("agent-1", Some("lab")).unwrap();
+
+        let not_in_org = GroupMemberError::NotAMember("agent-1".to_string(), "org".to_string());
+        assert_eq!(ledger.leave_group("agent-1", "org").unwrap_err(), not_in_org);
+        assert_eq!(ledger.leave_group("agent-1", "lab").unwrap().group, None);
+        assert!(matches!(ledger.leave_group("agent-1", "lab"), Err(GroupMemberError::NotAMember(..))));
+        assert!(matches!(ledger.leave_group("ghost", "lab"), Err(GroupMemberError::NotAMember(..))));
+    }
+
+    #[test]
+    fn group_wallet_overrides_are_journaled_and_block_removal() {
+        let ledger = Ledger::new();
+        ledger.upsert_group("org", GroupUpdate::replace(None, None)).unwrap();
+        ledger.set_group_balance("org", "USDC", usdc(5)).unwrap();
+        assert_eq!(
+            ledger.remove_group("org").unwrap_err(),
+            RemoveGroupError::WalletNotEmpty("org".to_string(), "USDC".to_string(), usdc(5))
+        );
+        ledger.set_group_balance("org", "USDC", Amount::ZERO).unwrap();
+
+        let entries = ledger.journal(&JournalQuery::default()).entries;
+        let moves: Vec<_> = entries.iter().map(|e| (e.kind, e.wallet.as_deref(), e.debit, e.credit, e.balance_after)).collect();
+        assert_eq!(moves, vec![
+            (EntryKind::AdminSet, Some("org"), Amount::ZERO, usdc(5), usdc(5)),
+            (EntryKind::AdminSet, Some("org"), usdc(5), Amount::ZERO, Amount::ZERO),
+        ]);
+        assert!(entries.iter().all(|e| e.agent_id.is_empty()));
+
+        ledger.remove_group("org").unwrap();
+        assert_eq!(ledger.remove_group("org").unwrap_err(), RemoveGroupError::NotFound("org".to_string()));
+    }
+
+    #[test]
+    fn agent_payments_are_transfers_to_the_payee() {
+        let ledger = Ledger::new();
+        ledger.register_or_get("alice");
+        let bob = ledger.register_or_get("bob").0;
+        let invoice = ledger.create_invoice("alice", Amount::from_whole(5), "USDC", "bob.agent", Some("bob"), None);
+        let receipt = ledger.pay_invoice(&invoice.id, "alice", "cronos-testnet", None).unwrap();
+
+        let tx = ledger.get_transaction(&receipt.tx_hash).unwrap();
+        assert_eq!((tx.to.as_str(), tx.payee_agent.as_deref()), (bob.address.as_str(), Some("bob")));
+        assert_eq!(ledger.get_state("alice").unwrap().balance("USDC"), Amount::from_whole(95));
+        assert_eq!(ledger.get_state("bob").unwrap().balance("USDC"), Amount::from_whole(105));
+        // Income isn't spend
+        assert_eq!(ledger.get_state("bob").unwrap().total_spend, Amount::ZERO);
+    }
+
+    #[test]
+    fn reversed_agent_payments_leave_the_payee_in_debt() {
+        let ledger = Ledger::new();
+        ledger.set_automine(false);
+        ledger.register_or_get("alice");
+        ledger.register_or_get("bob");
+        let invoice = ledger.create_invoice("alice", usdc(5), "USDC", "bob.agent", Some("bob"), None);
+        ledger.pay_invoice(&invoice.id, "alice", "cronos-testnet", None).unwrap();
+        ledger.mine_block();
+        assert_eq!(ledger.get_state("bob").unwrap().balance("USDC"), usdc(105));
+
+        // Bob spends most of it before the payment is orphaned
+        ledger.set_balance("bob", "USDC", usdc(2)).unwrap();
+        ledger.reorg(1);
+        assert_eq!(ledger.get_state("alice").unwrap().balance("USDC"), usdc(100));
+        let bob = ledger.get_state("bob").unwrap();
+        assert_eq!(bob.balance("USDC"), Amount::ZERO);
+        assert_eq!(bob.debts.get("USDC"), Some(&usdc(3)));
+
+        // The next income pays the debt first
+        let invoice = ledger.create_invoice("alice", usdc(4), "USDC", "bob.agent", Some("bob"), None);
+        ledger.pay_invoice(&invoice.id, "alice", "cronos-testnet", None).unwrap();
+        let bob = ledger.get_state("bob").unwrap();
+        assert_eq!(bob.balance("USDC"), usdc(1));
+        assert!(bob.debts.is_empty());
+    }
+
+    #[test]
+    fn payments_that_would_overflow_the_payee_are_refused() {
+        let ledger = Ledger::new();
+        ledger.register_or_get("alice");
+        ledger.register_or_get("bob");
+        ledger.set_balance("bob", "USDC", Amount::from_units(u64::MAX)).unwrap();
+        let invoice = ledger.create_invoice("alice", usdc(1), "USDC", "bob.agent", Some("bob"), None);
+        assert_eq!(
+            ledger.pay_invoice(&invoice.id, "alice", "cronos-testnet", None).unwrap_err(),
+            PaymentError::AmountOverflow { currency: "USDC".to_string() }
+        );
+        assert_eq!(ledger.get_state("alice").unwrap().balance("USDC"), usdc(100));
+        assert_eq!(ledger.get_state("bob").unwrap().balance("USDC"), Amount::from_units(u64::MAX));
+    }
+
+    #[test]
+    fn authorization_nonces_are_scoped_to_the_token() {
+        let ledger = Ledger::new();
+        assert!(ledger.claim_authorization("0xUSDC", "0xPayer", "0x01"));
+        assert!(!ledger.claim_authorization("0xusdc", "0xpayer", "0x01"));
+        // The same nonce signed for another token contract is a different authorization
+        assert!(ledger.claim_authorization("0xUSDT", "0xPayer", "0x01"));
+        ledger.release_authorization("0xUSDC", "0xPayer", "0x01");
+        assert!(ledger.claim_authorization("0xUSDC", "0xPayer", "0x01"));
+    }
+
+    #[test]
+    fn unpaid_invoices_can_be_owed() {
+        let ledger = Ledger::new();
+        ledger.register_or_get("alice");
+        ledger.set_balance("alice", "USDC", usdc(1)).unwrap();
+        let invoice = ledger.create_invoice("alice", usdc(3), "USDC", "api.openai.com", None, None);
+        assert!(ledger.pay_invoice(&invoice.id, "alice", "cronos-testnet", None).is_err());
+
+        assert_eq!(ledger.owe_invoice(&invoice.id), Some(usdc(3)));
+        assert_eq!(ledger.get_state("alice").unwrap().debts.get("USDC"), Some(&usdc(3)));
+        // Owed once: the invoice can no longer be paid or owed again
+        assert_eq!(ledger.owe_invoice(&invoice.id), None);
+        assert_eq!(ledger.pay_invoice(&invoice.id, "alice", "cronos-testnet", None).unwrap_err(), PaymentError::InvoiceCancelled);
+    }
+
+    #[test]
+    fn agents_cannot_pay_their_own_service() {
+        let ledger = Ledger::new();
+        ledger.register_or_get("bob");
+        let invoice = ledger.create_invoice("bob", usdc(1), "USDC", "bob.agent", Some("bob"), None);
+        assert_eq!(ledger.pay_invoice(&invoice.id, "bob", "cronos-testnet", None).unwrap_err(), PaymentError::SelfPayment);
+        assert_eq!(ledger.get_state("bob").unwrap().balance("USDC"), usdc(100));
+    }
+}
@@ -0,0 +1,300 @@
+//! Per-agent spending policies.
+//!
+//! The lifetime `budget_limit` can't stop a runaway loop until the whole
+//! budget is gone. Policies add finer rails, all checked before a payment
+//! settles: rolling-window spend caps (in USD), payment-rate limits, and caps
+//! per payee and per asset (lifetime or windowed). The first rule a payment
+//! would break is reported as a `PolicyViolation`.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::Amount;
+
+/// At most `max_usd` spent in any rolling `window_secs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpendLimit {
+    pub window_secs: u64,
+    pub max_usd: Amount,
+}
+
+/// At most `max_payments` payments in any rolling `window_secs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub window_secs: u64,
+    pub max_payments: u32,
+}
+
+/// At most `max_usd` paid to one payee (an upstream host), ever or per window.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PayeeCap {
+    pub payee: String,
+    pub max_usd: Amount,
+    #[serde(default)]
+    pub window_secs: Option<u64>,
+}
+
+/// At most `max_amount` of one asset spent, ever or per window.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AssetCap {
+    pub currency: String,
+    pub max_amount: Amount,
+    #[serde(default)]
+    pub window_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpendingPolicy {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spend_limits: Vec<SpendLimit>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rate_limits: Vec<RateLimit>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub payee_caps: Vec<PayeeCap>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub asset_caps: Vec<AssetCap>,
+}
+
+/// A settled (non-orphaned) payment, as far as policies care.
+#[derive(Debug, Clone)]
+pub struct Spend<'a> {
+    pub usd_value: Amount,
+    pub amount: Amount,
+    pub currency: &'a str,
+    pub payee: &'a str,
+    /// Unix seconds
+    pub timestamp: u64,
+}
+
+/// Which policy a payment would break, with the numbers behind it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum PolicyViolation {
+    SpendLimit { window_secs: u64, limit: Amount, spent: Amount },
+    RateLimit { window_secs: u64, max_payments: u32, retry_after_secs: u64 },
+    PayeeCap { payee: String, window_secs: Option<u64>, limit: Amount, spent: Amount },
+    AssetCap { currency: String, window_secs: Option<u64>, limit: Amount, spent: Amount },
+}
+
+impl PolicyViolation {
+    /// Stable error code for clients to branch on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::SpendLimit { .. } => "policy_spend_limit",
+            Self::RateLimit { .. } => "policy_rate_limit",
+            Self::PayeeCap { .. } => "policy_payee_cap",
+            Self::AssetCap { .. } => "policy_asset_cap",
+        }
+    }
+
+    /// Seconds until the payment could succeed, when that is known.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Self::RateLimit { retry_after_secs, .. } => Some(*retry_after_secs),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SpendLimit { window_secs, limit, spent } => write!(
+                f, "spend limit of ${} per {} exceeded (${} with this payment)",
+                limit, window_label(*window_secs), spent
+            ),
+            Self::RateLimit { window_secs, max_payments, retry_after_secs } => write!(
+                f, "rate limit of {} payment(s) per {} reached (retry in {}s)",
+                max_payments, window_label(*window_secs), retry_after_secs
+            ),
+            Self::PayeeCap { payee, window_secs, limit, spent } => write!(
+                f, "cap of ${} {} for payee {} exceeded (${} with this payment)",
+                limit, scope_label(*window_secs), payee, spent
+            ),
+            Self::AssetCap { currency, window_secs, limit, spent } => write!(
+                f, "cap of {} {} {} exceeded ({} with this payment)",
+                limit, currency, scope_label(*window_secs), spent
+            ),
+        }
+    }
+}
+
+/// "30s", "5m", "1h", "1d"
+pub fn window_label(secs: u64) -> String {
+    match secs {
+        s if s >= 86_400 && s % 86_400 == 0 => format!("{}d", s / 86_400),
+        s if s >= 3_600 && s % 3_600 == 0 => format!("{}h", s / 3_600),
+        s if s >= 60 && s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+fn scope_label(window_secs: Option<u64>) -> String {
+    match window_secs {
+        Some(secs) => format!("per {}", window_label(secs)),
+        None => "in total".to_string(),
+    }
+}
+
+impl SpendingPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.spend_limits.is_empty() && self.rate_limits.is_empty() && self.payee_caps.is_empty() && self.asset_caps.is_empty()
+    }
+
+    /// Rejects rules that can never be satisfied or make no sense.
+    pub fn validate(&self) -> Result<(), String> {
+        let windows = self.spend_limits.iter().map(|l| Some(l.window_secs))
+            .chain(self.rate_limits.iter().map(|l| Some(l.window_secs)))
+            .chain(self.payee_caps.iter().map(|c| c.window_secs))
+            .chain(self.asset_caps.iter().map(|c| c.window_secs));
+        for window in windows.flatten() {
+            if window == 0 {
+                return Err("window_secs must be greater than zero".to_string());
+            }
+        }
+        if self.payee_caps.iter().any(|c| c.payee.is_empty()) {
+            return Err("payee cap without a payee".to_string());
+        }
+        if self.asset_caps.iter().any(|c| c.currency.is_empty()) {
+            return Err("asset cap without a currency".to_string());
+        }
+        Ok(())
+    }
+
+    /// Checks `payment` against every rule, given the agent's earlier payments.
+    pub fn check(&self, history: &[Spend], payment: &Spend, now: u64) -> Result<(), PolicyViolation> {
+        let within = |window: Option<u64>, s: &Spend| window.is_none_or(|w| s.timestamp + w > now);
+
+        for limit in &self.spend_limits {
+            let spent = history.iter()
+                .filter(|s| within(Some(limit.window_secs), s))
+                .map(|s| s.usd_value);
+            let spent = total(spent, payment.usd_value);
+            if spent > limit.max_usd {
+                return Err(PolicyViolation::SpendLimit { window_secs: limit.window_secs, limit: limit.max_usd, spent });
+            }
+        }
+
+        for limit in &self.rate_limits {
+            let mut recent: Vec<u64> = history.iter()
+                .filter(|s| within(Some(limit.window_secs), s))
+                .map(|s| s.timestamp)
+                .collect();
+            if recent.len() >= limit.max_payments as usize {
+                // A slot frees up once enough of the oldest payments age out
+                recent.sort_unstable();
+                let freeing = recent.get(recent.len() - limit.max_payments as usize).copied().unwrap_or(now);
+                return Err(PolicyViolation::RateLimit {
+                    window_secs: limit.window_secs,
+                    max_payments: limit.max_payments,
+                    retry_after_secs: (freeing + limit.window_secs).saturating_sub(now).max(1),
+                });
+            }
+        }
+
+        for cap in self.payee_caps.iter().filter(|c| c.payee.eq_ignore_ascii_case(payment.payee)) {
+            let spent = history.iter()
+                .filter(|s| s.payee.eq_ignore_ascii_case(&cap.payee) && within(cap.window_secs, s))
+                .map(|s| s.usd_value);
+            let spent = total(spent, payment.usd_value);
+            if spent > cap.max_usd {
+                return Err(PolicyViolation::PayeeCap {
+                    payee: cap.payee.clone(), window_secs: cap.window_secs, limit: cap.max_usd, spent,
+                });
+            }
+        }
+
+        for cap in self.asset_caps.iter().filter(|c| c.currency.eq_ignore_ascii_case(payment.currency)) {
+            let spent = history.iter()
+                .filter(|s| s.currency.eq_ignore_ascii_case(&cap.currency) && within(cap.window_secs, s))
+                .map(|s| s.amount);
+            let spent = total(spent, payment.amount);
+            if spent > cap.max_amount {
+                return Err(PolicyViolation::AssetCap {
+                    currency: cap.currency.clone(), window_secs: cap.window_secs, limit: cap.max_amount, spent,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// One-line summary for the TUI, e.g. "$1.00/1m, 10 tx/1m, 2 caps".
+    pub fn summary(&self) -> String {
+        let mut parts: Vec<String> = self.spend_limits.iter()
+            .map(|l| format!("${}/{}", l.max_usd, window_label(l.window_secs)))
+            .chain(self.rate_limits.iter().map(|l| format!("{} tx/{}", l.max_payments, window_label(l.window_secs))))
+            .collect();
+        let caps = self.payee_caps.len() + self.asset_caps.len();
+        if caps > 0 {
+            parts.push(format!("{} cap(s)", caps));
+        }
+        parts.join(", ")
+    }
+}
+
+/// `payment` plus everything in `spent`. A total past the largest amount
+/// stops there: it is over any limit either way.
+fn total(spent: impl Iterator<Item = Amount>, payment: Amount) -> Amount {
+    spent.fold(payment, Amount::saturating_add)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spend(usd: u64, timestamp: u64) -> Spend<'static> {
+        Spend { usd_value: Amount::from_whole(usd), amount: Amount::from_whole(usd), currency: "USDC", payee: "api.openai.com", timestamp }
+    }
+
+    #[test]
+    fn spend_limit_only_counts_the_rolling_window() {
+        let policy = SpendingPolicy { spend_limits: vec![SpendLimit { window_secs: 60, max_usd: Amount::from_whole(5) }], ..Default::default() };
+        let now = 1_000;
+        // 4 spent 60s ago has aged out; 3 spent 59s ago still counts
+        let history = [spend(4, now - 60), spend(3, now - 59)];
+        assert!(policy.check(&history, &spend(2, now), now).is_ok());
+        assert_eq!(
+            policy.check(&history, &spend(3, now), now),
+            Err(PolicyViolation::SpendLimit { window_secs: 60, limit: Amount::from_whole(5), spent: Amount::from_whole(6) })
+        );
+    }
+
+    #[test]
+    fn rate_limit_reports_when_the_oldest_payment_ages_out() {
+        let policy = SpendingPolicy { rate_limits: vec![RateLimit { window_secs: 60, max_payments: 2 }], ..Default::default() };
+        let now = 1_000;
+        let history = [spend(1, now - 50), spend(1, now - 10), spend(1, now - 70)];
+        let violation = policy.check(&history, &spend(1, now), now).unwrap_err();
+        assert_eq!(violation.retry_after(), Some(10));
+        assert!(policy.check(&history[..1], &spend(1, now), now).is_ok());
+    }
+
+    #[test]
+    fn payee_and_asset_caps_apply_to_their_own_payments() {
+        let policy = SpendingPolicy {
+            payee_caps: vec![PayeeCap { payee: "API.openai.com".into(), max_usd: Amount::from_whole(3), window_secs: None }],
+            asset_caps: vec![AssetCap { currency: "CRO".into(), max_amount: Amount::from_whole(10), window_secs: Some(3_600) }],
+            ..Default::default()
+        };
+        let now = 10_000;
+        let history = [spend(2, 0)];
+        assert_eq!(policy.check(&history, &spend(2, now), now).unwrap_err().code(), "policy_payee_cap");
+        let other_payee = Spend { payee: "api.anthropic.com", ..spend(2, now) };
+        assert!(policy.check(&history, &other_payee, now).is_ok());
+
+        let cro = |amount: u64, timestamp: u64| Spend { currency: "CRO", payee: "rpc", amount: Amount::from_whole(amount), ..spend(0, timestamp) };
+        let history = [cro(8, now - 3_601), cro(8, now - 100)];
+        assert_eq!(policy.check(&history, &cro(3, now), now).unwrap_err().code(), "policy_asset_cap");
+        assert!(policy.check(&history, &cro(2, now), now).is_ok());
+    }
+
+    #[test]
+    fn rejects_zero_windows_and_empty_targets() {
+        let zero = SpendingPolicy { rate_limits: vec![RateLimit { window_secs: 0, max_payments: 1 }], ..Default::default() };
+        assert!(zero.validate().is_err());
+        let no_payee = SpendingPolicy { payee_caps: vec![PayeeCap { payee: String::new(), max_usd: Amount::ZERO, window_secs: None }], ..Default::default() };
+        assert!(no_payee.validate().is_err());
+        assert_eq!(window_label(90), "90s");
+        assert_eq!(window_label(7_200), "2h");
+    }
+}
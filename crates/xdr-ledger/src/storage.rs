@@ -0,0 +1,588 @@
+//! Pluggable persistence for the ledger.
+//!
+//! The `Ledger` keeps its working set in `DashMap`s and writes every mutation
+//! through to a `Storage` backend. On startup the backend hands back the last
+//! known state, so a restarted runtime resumes where it left off.
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::warn;
+
+use crate::chain::Block;
+use crate::escrow::Hold;
+use crate::groups::BudgetGroup;
+use crate::journal::JournalEntry;
+use crate::tokens::{self, Token};
+use crate::{AgentState, Invoice, Transaction};
+
+/// Full ledger state at a point in time.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LedgerSnapshot {
+    pub agents: Vec<AgentState>,
+    pub invoices: Vec<Invoice>,
+    /// Spent authorization nonces ("payer:nonce")
+    #[serde(default)]
+    pub authorizations: Vec<String>,
+    #[serde(default)]
+    pub transactions: Vec<Transaction>,
+    /// `upto` escrow holds (open and closed)
+    #[serde(default)]
+    pub holds: Vec<Hold>,
+    /// Every balance change, oldest first
+    #[serde(default)]
+    pub journal: Vec<JournalEntry>,
+    /// Latest mined block
+    #[serde(default)]
+    pub head: Option<Block>,
+    /// Budget groups (shared wallets and their allocations)
+    #[serde(default)]
+    pub groups: Vec<BudgetGroup>,
+    /// Tokens registered at runtime, on top of the network's list
+    #[serde(default)]
+    pub custom_tokens: Vec<Token>,
+}
+
+/// A single ledger mutation, as written to the append-only log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum StorageRecord {
+    /// Agent created or updated (full state)
+    Agent(AgentState),
+    /// Invoice created or updated (full state)
+    Invoice(Invoice),
+    /// Stale invoices removed by the sweeper
+    InvoicesSwept { ids: Vec<String> },
+    /// Authorization nonce (`asset:payer:nonce`) spent (`used: true`) or released
+    Authorization { key: String, used: bool },
+    /// Payment submitted, mined or orphaned (full state)
+    Transaction(Transaction),
+    /// Escrow hold reserved, captured or released (full state)
+    Hold(Hold),
+    /// Balance change journaled (entries are only ever appended)
+    Journal(JournalEntry),
+    /// Block mined (becomes the new head)
+    Block(Block),
+    /// Budget group created or updated (full state)
+    Group(BudgetGroup),
+    /// Budget group deleted
+    GroupRemoved { id: String },
+    /// Custom token registered or replaced (full state)
+    Token(Token),
+    /// Whole ledger replaced (snapshot load)
+    Reset(LedgerSnapshot),
+}
+
+/// Ledger state being rebuilt from the log. Everything is keyed by id so a
+/// record applies in constant time however long the log is.
+#[derive(Default)]
+struct Replay {
+    agents: HashMap<String, AgentState>,
+    invoices: HashMap<String, Invoice>,
+    authorizations: HashSet<String>,
+    transactions: HashMap<String, Transaction>,
+    holds: HashMap<String, Hold>,
+    journal: Vec<JournalEntry>,
+    head: Option<Block>,
+    groups: HashMap<String, BudgetGroup>,
+    custom_tokens: Vec<Token>,
+}
+
+impl Replay {
+    /// Applies a logged mutation on top of the state so far.
+    fn apply(&mut self, record: StorageRecord) {
+        match record {
+            StorageRecord::Agent(agent) => {
+                self.agents.insert(agent.id.clone(), agent);
+            }
+            StorageRecord::Invoice(invoice) => {
+                self.invoices.insert(invoice.id.clone(), invoice);
+            }
+            StorageRecord::InvoicesSwept { ids } => {
+                for id in &ids {
+                    self.invoices.remove(id);
+                }
+            }
+            StorageRecord::Authorization { key, used: true } => {
+                self.authorizations.insert(key);
+            }
+            StorageRecord::Authorization { key, used: false } => {
+                self.authorizations.remove(&key);
+            }
+            StorageRecord::Transaction(tx) => {
+                self.transactions.insert(tx.hash.clone(), tx);
+            }
+            StorageRecord::Hold(hold) => {
+                self.holds.insert(hold.id.clone(), hold);
+            }
+            StorageRecord::Journal(entry) => self.journal.push(entry),
+            StorageRecord::Block(block) => self.head = Some(block),
+            StorageRecord::Group(group) => {
+                self.groups.insert(group.id.clone(), group);
+            }
+            StorageRecord::GroupRemoved { id } => {
+                self.groups.remove(&id);
+            }
+            StorageRecord::Token(token) => tokens::upsert(&mut self.custom_tokens, token),
+            StorageRecord::Reset(snapshot) => *self = Self::from(snapshot),
+        }
+    }
+
+    /// The rebuilt state, in a stable order (journal by `seq`, the rest by id).
+    fn finish(self) -> LedgerSnapshot {
+        fn sorted<T>(map: HashMap<String, T>) -> Vec<T> {
+            let mut entries: Vec<(String, T)> = map.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries.into_iter().map(|(_, value)| value).collect()
+        }
+        let mut authorizations: Vec<String> = self.authorizations.into_iter().collect();
+        authorizations.sort();
+        let mut journal = self.journal;
+        journal.sort_by_key(|e| e.seq);
+        LedgerSnapshot {
+            agents: sorted(self.agents),
+            invoices: sorted(self.invoices),
+            authorizations,
+            transactions: sorted(self.transactions),
+            holds: sorted(self.holds),
+            journal,
+            head: self.head,
+            groups: sorted(self.groups),
+            custom_tokens: self.custom_tokens,
+        }
+    }
+}
+
+impl From<LedgerSnapshot> for Replay {
+    fn from(snapshot: LedgerSnapshot) -> Self {
+        Self {
+            agents: snapshot.agents.into_iter().map(|a| (a.id.clone(), a)).collect(),
+            invoices: snapshot.invoices.into_iter().map(|i| (i.id.clone(), i)).collect(),
+            authorizations: snapshot.authorizations.into_iter().collect(),
+            transactions: snapshot.transactions.into_iter().map(|t| (t.hash.clone(), t)).collect(),
+            holds: snapshot.holds.into_iter().map(|h| (h.id.clone(), h)).collect(),
+            journal: snapshot.journal,
+            head: snapshot.head,
+            groups: snapshot.groups.into_iter().map(|g| (g.id.clone(), g)).collect(),
+            custom_tokens: snapshot.custom_tokens,
+        }
+    }
+}
+
+pub trait Storage: Send + Sync {
+    /// State to resume from when the ledger starts.
+    fn load(&self) -> io::Result<LedgerSnapshot>;
+    /// Appends one mutation. Called after every write to the ledger, while the
+    /// lock it changed is still held, so it must not wait on the disk.
+    fn record(&self, record: &StorageRecord) -> io::Result<()>;
+    /// Makes every record appended so far durable. Called with no ledger lock
+    /// held; concurrent callers may share one flush.
+    fn sync(&self) -> io::Result<()>;
+    fn save_snapshot(&self, name: &str, snapshot: &LedgerSnapshot) -> io::Result<()>;
+    fn load_snapshot(&self, name: &str) -> io::Result<LedgerSnapshot>;
+    fn list_snapshots(&self) -> io::Result<Vec<String>>;
+}
+
+/// Snapshot names end up as file names, so keep them boring.
+pub fn validate_snapshot_name(name: &str) -> io::Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && !name.starts_with('.');
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid snapshot name '{}' (use letters, digits, '-', '_', '.')", name),
+        ))
+    }
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("Snapshot '{}' not found", name))
+}
+
+/// Nothing survives a restart; snapshots live for the lifetime of the process.
+#[derive(Default)]
+pub struct MemoryStorage {
+    snapshots: DashMap<String, LedgerSnapshot>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&self) -> io::Result<LedgerSnapshot> {
+        Ok(LedgerSnapshot::default())
+    }
+
+    fn record(&self, _record: &StorageRecord) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn save_snapshot(&self, name: &str, snapshot: &LedgerSnapshot) -> io::Result<()> {
+        validate_snapshot_name(name)?;
+        self.snapshots.insert(name.to_string(), snapshot.clone());
+        Ok(())
+    }
+
+    fn load_snapshot(&self, name: &str) -> io::Result<LedgerSnapshot> {
+        self.snapshots.get(name).map(|s| s.clone()).ok_or_else(|| not_found(name))
+    }
+
+    fn list_snapshots(&self) -> io::Result<Vec<String>> {
+        let mut names: Vec<String> = self.snapshots.iter().map(|e| e.key().clone()).collect();
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// Append-only JSON log plus named snapshot files:
+///
+/// ```text
+/// <dir>/ledger.jsonl          one StorageRecord per line
+/// <dir>/snapshots/<name>.json one LedgerSnapshot per file
+/// ```
+///
+/// `record` only appends; `sync` flushes the log to disk (`sync_data`) outside
+/// the ledger's locks, and callers that queue behind a flush in progress are
+/// covered by the next one (group commit). A mutation synced before it is
+/// acknowledged survives a crash or power loss. After a failed write or flush
+/// the log takes no more records: a torn line followed by more would leave it
+/// unreadable. The log is compacted into a single `reset` record every time it
+/// is opened; opening fails rather than compacts if any line but the last is
+/// unreadable.
+pub struct FileStorage {
+    dir: PathBuf,
+    log: Mutex<Log>,
+    /// Records known to be on disk. Held across a flush, so callers waiting
+    /// on it find their records covered once it's their turn.
+    synced: Mutex<u64>,
+    /// Second handle on the log, flushed without holding `log`
+    sync_handle: File,
+}
+
+struct Log {
+    file: File,
+    /// Records appended since the log was opened
+    written: u64,
+    /// First failed write or flush; the log is closed from then on
+    failed: Option<String>,
+}
+
+impl Log {
+    fn check(&self) -> io::Result<()> {
+        match &self.failed {
+            Some(e) => Err(io::Error::other(format!("Ledger log is closed after a failed write: {}", e))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FileStorage {
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join("snapshots"))?;
+
+        // Replay, then compact so the log doesn't grow across soak-test restarts
+        let state = Self::replay(&dir.join("ledger.jsonl"))?;
+        let tmp = dir.join("ledger.jsonl.tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            writeln!(f, "{}", serde_json::to_string(&StorageRecord::Reset(state))?)?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, dir.join("ledger.jsonl"))?;
+
+        let file = OpenOptions::new().append(true).open(dir.join("ledger.jsonl"))?;
+        let sync_handle = file.try_clone()?;
+        Ok(Self {
+            dir,
+            log: Mutex::new(Log { file, written: 0, failed: None }),
+            synced: Mutex::new(0),
+            sync_handle,
+        })
+    }
+
+    fn replay(path: &PathBuf) -> io::Result<LedgerSnapshot> {
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(LedgerSnapshot::default()),
+            Err(e) => return Err(e),
+        };
+        let mut state = Replay::default();
+        // An unreadable line is only forgiven if nothing follows it: that is a
+        // write torn by a crash. Anywhere else the log is corrupt, and compacting
+        // over it would throw away every record after the damage
+        let mut torn: Option<(usize, serde_json::Error)> = None;
+        for (number, line) in BufReader::new(file).split(b'\n').enumerate() {
+            let line = line?;
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            if let Some((number, e)) = torn.take() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Corrupt record on line {} of {}: {}", number + 1, path.display(), e),
+                ));
+            }
+            match serde_json::from_slice::<StorageRecord>(&line) {
+                Ok(record) => state.apply(record),
+                Err(e) => torn = Some((number, e)),
+            }
+        }
+        if let Some((number, e)) = torn {
+            warn!(target: "xdr_ledger", "Dropping torn final line {} of {}: {}", number + 1, path.display(), e);
+        }
+        Ok(state.finish())
+    }
+
+    fn snapshot_path(&self, name: &str) -> PathBuf {
+        self.dir.join("snapshots").join(format!("{}.json", name))
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&self) -> io::Result<LedgerSnapshot> {
+        Self::replay(&self.dir.join("ledger.jsonl"))
+    }
+
+    fn record(&self, record: &StorageRecord) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut log = self.log.lock().unwrap();
+        log.check()?;
+        if let Err(e) = log.file.write_all(line.as_bytes()) {
+            log.failed = Some(e.to_string());
+            return Err(e);
+        }
+        log.written += 1;
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let target = {
+            let log = self.log.lock().unwrap();
+            log.check()?;
+            log.written
+        };
+        let mut synced = self.synced.lock().unwrap();
+        if *synced >= target {
+            return Ok(());
+        }
+        // Everything appended by now is covered, not just this caller's records
+        let upto = self.log.lock().unwrap().written;
+        if let Err(e) = self.sync_handle.sync_data() {
+            self.log.lock().unwrap().failed = Some(e.to_string());
+            return Err(e);
+        }
+        *synced = upto;
+        Ok(())
+    }
+
+    /// Written aside and renamed into place, so a crash never leaves a
+    /// truncated snapshot under the real name.
+    fn save_snapshot(&self, name: &str, snapshot: &LedgerSnapshot) -> io::Result<()> {
+        validate_snapshot_name(name)?;
+        let path = self.snapshot_path(name);
+        let tmp = path.with_extension("json.tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(&serde_json::to_vec_pretty(snapshot)?)?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, path)
+    }
+
+    fn load_snapshot(&self, name: &str) -> io::Result<LedgerSnapshot> {
+        validate_snapshot_name(name)?;
+        match fs::read(self.snapshot_path(name)) {
+            Ok(raw) => Ok(serde_json::from_slice(&raw)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(not_found(name)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list_snapshots(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(self.dir.join("snapshots"))? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("json") {
+                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(stem.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, Ledger};
+    use std::sync::Arc;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("xdr-ledger-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn append_line(dir: &std::path::Path, line: &str) {
+        let mut log = OpenOptions::new().append(true).open(dir.join("ledger.jsonl")).unwrap();
+        writeln!(log, "{}", line).unwrap();
+    }
+
+    #[test]
+    fn replay_resumes_the_last_state_of_every_key() {
+        let dir = temp_dir();
+        let ledger = Ledger::with_storage(Arc::new(FileStorage::open(&dir).unwrap())).unwrap();
+        ledger.register_or_get("agent-1");
+        ledger.set_balance("agent-1", "USDC", Amount::from_whole(5)).unwrap();
+        ledger.set_balance("agent-1", "USDC", Amount::from_whole(7)).unwrap();
+        drop(ledger);
+
+        let state = FileStorage::open(&dir).unwrap().load().unwrap();
+        assert_eq!(state.agents.len(), 1);
+        assert_eq!(state.agents[0].balance("USDC"), Amount::from_whole(7));
+        let seqs: Vec<u64> = state.journal.iter().map(|e| e.seq).collect();
+        assert!(seqs.windows(2).all(|w| w[0] < w[1]), "journal out of order: {:?}", seqs);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_drops_a_torn_final_line() {
+        let dir = temp_dir();
+        let ledger = Ledger::with_storage(Arc::new(FileStorage::open(&dir).unwrap())).unwrap();
+        ledger.set_balance("agent-1", "USDC", Amount::from_whole(5)).unwrap();
+        drop(ledger);
+        append_line(&dir, "{\"op\":\"agent\",\"id\":");
+
+        let state = FileStorage::open(&dir).unwrap().load().unwrap();
+        assert_eq!(state.agents[0].balance("USDC"), Amount::from_whole(5));
+        // Compaction rewrote the log without the torn line
+        assert!(FileStorage::open(&dir).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corruption_before_the_last_line_refuses_to_open_and_keeps_the_log() {
+        let dir = temp_dir();
+        let ledger = Ledger::with_storage(Arc::new(FileStorage::open(&dir).unwrap())).unwrap();
+        ledger.set_balance("agent-1", "USDC", Amount::from_whole(5)).unwrap();
+        append_line(&dir, "\u{fffd} not json");
+        ledger.set_balance("agent-1", "USDC", Amount::from_whole(9)).unwrap();
+        drop(ledger);
+        let before = fs::read(dir.join("ledger.jsonl")).unwrap();
+
+        let err = FileStorage::open(&dir).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("Corrupt record on line"), "{}", err);
+        assert_eq!(fs::read(dir.join("ledger.jsonl")).unwrap(), before);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn runtime_tokens_survive_a_restart_and_a_new_network_list() {
+        let dir = temp_dir();
+        let ledger = Ledger::with_storage(Arc::new(FileStorage::open(&dir).unwrap())).unwrap();
+        ledger
+            .add_token(Token {
+                symbol: "WETH".into(),
+                name: "Wrapped Ether".into(),
+                decimals: 18,
+                address: Some("0x1111111111111111111111111111111111111111".into()),
+                eip712_version: None,
+                usd_rate: Amount::from_whole(3000),
+                faucet: Amount::ZERO,
+            })
+            .unwrap();
+        drop(ledger);
+
+        let ledger = Ledger::with_storage(Arc::new(FileStorage::open(&dir).unwrap())).unwrap();
+        ledger.set_tokens(tokens::TokenRegistry::default().tokens("cronos-testnet"));
+        let symbols: Vec<String> = ledger.tokens().into_iter().map(|t| t.symbol).collect();
+        assert!(symbols.contains(&"WETH".to_string()), "{:?}", symbols);
+        assert!(symbols.contains(&"USDC".to_string()), "{:?}", symbols);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn one_sync_covers_every_record_appended_before_it() {
+        let dir = temp_dir();
+        let storage = FileStorage::open(&dir).unwrap();
+        for nonce in 0..3 {
+            storage.record(&StorageRecord::Authorization { key: format!("0xabc:{}", nonce), used: true }).unwrap();
+        }
+        storage.sync().unwrap();
+        assert_eq!(*storage.synced.lock().unwrap(), 3);
+        // Nothing new to flush
+        storage.sync().unwrap();
+        assert_eq!(storage.load().unwrap().authorizations.len(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn a_failed_write_closes_the_log_and_fails_every_commit() {
+        let dir = temp_dir();
+        let mut storage = FileStorage::open(&dir).unwrap();
+        // Every write to /dev/full fails with ENOSPC
+        let full = OpenOptions::new().append(true).open("/dev/full").unwrap();
+        storage.sync_handle = full.try_clone().unwrap();
+        storage.log.get_mut().unwrap().file = full;
+        let ledger = Ledger::with_storage(Arc::new(storage)).unwrap();
+        assert!(ledger.commit().is_ok());
+
+        ledger.set_balance("agent-1", "USDC", Amount::from_whole(5)).unwrap();
+        assert!(ledger.storage_error().is_err());
+        assert!(ledger.commit().is_err());
+        ledger.set_balance("agent-1", "USDC", Amount::from_whole(6)).unwrap();
+        assert!(ledger.commit().unwrap_err().to_string().contains("No space left"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn snapshots_are_written_aside_and_renamed_into_place() {
+        let dir = temp_dir();
+        let storage = FileStorage::open(&dir).unwrap();
+        storage.save_snapshot("golden", &LedgerSnapshot::default()).unwrap();
+        let files: Vec<_> = fs::read_dir(dir.join("snapshots")).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(files, ["golden.json"]);
+        assert_eq!(storage.list_snapshots().unwrap(), ["golden"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn removals_and_resets_apply_in_log_order() {
+        let dir = temp_dir();
+        let storage = FileStorage::open(&dir).unwrap();
+        storage.record(&StorageRecord::Authorization { key: "0xabc:1".into(), used: true }).unwrap();
+        storage.record(&StorageRecord::Authorization { key: "0xabc:2".into(), used: true }).unwrap();
+        storage.record(&StorageRecord::Authorization { key: "0xabc:1".into(), used: false }).unwrap();
+        storage.record(&StorageRecord::Group(BudgetGroup::new("team", None, None))).unwrap();
+        storage.record(&StorageRecord::GroupRemoved { id: "team".into() }).unwrap();
+        let state = storage.load().unwrap();
+        assert_eq!(state.authorizations, vec!["0xabc:2".to_string()]);
+        assert!(state.groups.is_empty());
+
+        storage.record(&StorageRecord::Reset(LedgerSnapshot::default())).unwrap();
+        storage.record(&StorageRecord::Group(BudgetGroup::new("ops", None, None))).unwrap();
+        let state = storage.load().unwrap();
+        assert!(state.authorizations.is_empty());
+        assert_eq!(state.groups.iter().map(|g| g.id.as_str()).collect::<Vec<_>>(), ["ops"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
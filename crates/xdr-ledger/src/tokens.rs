@@ -0,0 +1,215 @@
+//! Token registry.
+//!
+//! Every simulated network has a list of assets an agent can hold and pay
+//! with: the native coin (CRO) plus ERC-20 contracts (USDC, USDT and any
+//! custom token loaded at startup or through the control plane). Wallet
+//! balances are kept as `Amount`s (6 decimals); a token's own `decimals` only
+//! matter where amounts meet the chain, i.e. atomic units in x402 payments
+//! and RPC responses.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::Amount;
+
+fn default_usd_rate() -> Amount {
+    Amount::from_whole(1)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Token {
+    /// Ticker, e.g. `USDC` (matched case-insensitively)
+    pub symbol: String,
+    /// Contract name; doubles as the EIP-712 domain name
+    pub name: String,
+    pub decimals: u32,
+    /// ERC-20 contract, or None for the network's native coin
+    #[serde(default)]
+    pub address: Option<String>,
+    /// EIP-712 domain version for tokens with EIP-3009
+    /// `transferWithAuthorization` (required to be payable over x402)
+    #[serde(default)]
+    pub eip712_version: Option<String>,
+    /// Mock USD price of one whole token, used to apply the budget cap
+    #[serde(default = "default_usd_rate")]
+    pub usd_rate: Amount,
+    /// Balance every new agent starts with
+    #[serde(default)]
+    pub faucet: Amount,
+}
+
+impl Token {
+    pub fn is_native(&self) -> bool {
+        self.address.is_none()
+    }
+
+    /// Whether an x402 `exact` payment can be signed for this token.
+    pub fn supports_eip3009(&self) -> bool {
+        self.address.is_some() && self.eip712_version.is_some()
+    }
+
+    /// Atomic units (what a transfer carries) for an amount of this token.
+    pub fn to_atomic(&self, amount: Amount) -> u128 {
+        let units = amount.units() as u128;
+        if self.decimals >= Amount::DECIMALS {
+            units * 10u128.pow(self.decimals - Amount::DECIMALS)
+        } else {
+            units / 10u128.pow(Amount::DECIMALS - self.decimals)
+        }
+    }
+
+    /// Amount for a number of atomic units. Dust below 6 decimals is dropped.
+    pub fn from_atomic(&self, atomic: u128) -> Option<Amount> {
+        let units = if self.decimals >= Amount::DECIMALS {
+            atomic / 10u128.pow(self.decimals - Amount::DECIMALS)
+        } else {
+            atomic.checked_mul(10u128.pow(Amount::DECIMALS - self.decimals))?
+        };
+        u64::try_from(units).ok().map(Amount::from_units)
+    }
+
+    /// USD value of an amount at the token's mock rate, rounded to the nearest micro-dollar.
+    pub fn usd_value(&self, amount: Amount) -> Amount {
+        let micro = (amount.units() as u128 * self.usd_rate.units() as u128 + 500_000) / 1_000_000;
+        Amount::from_units(u64::try_from(micro).unwrap_or(u64::MAX))
+    }
+
+    /// Amount of this token worth `usd` at its mock rate, rounded up to the
+    /// base unit. None for a token priced at zero.
+    pub fn from_usd(&self, usd: Amount) -> Option<Amount> {
+        let rate = self.usd_rate.units() as u128;
+        if rate == 0 {
+            return None;
+        }
+        u64::try_from((usd.units() as u128 * 1_000_000).div_ceil(rate)).ok().map(Amount::from_units)
+    }
+
+    /// Rejects tokens the chain simulation can't represent.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.symbol.is_empty() || !self.symbol.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(format!("Invalid token symbol '{}'", self.symbol));
+        }
+        if self.decimals > 30 {
+            return Err(format!("{}: decimals must be at most 30", self.symbol));
+        }
+        if let Some(address) = &self.address {
+            let hex = address.strip_prefix("0x").unwrap_or_default();
+            if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("{}: invalid contract address '{}'", self.symbol, address));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Token lists per network name (`cronos-testnet`, `cronos-mainnet`, ...).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TokenRegistry {
+    networks: BTreeMap<String, Vec<Token>>,
+}
+
+impl Default for TokenRegistry {
+    /// USDC, USDT and native CRO on both Cronos networks.
+    fn default() -> Self {
+        let testnet = vec![
+            erc20("USDC", "USD Coin", 6, "0xc01efAaF7C5C61bEbFAeb358E1161b537b8bC0e0", Some("2"), 1_000_000),
+            // Tether has no canonical testnet deployment: this is the simulator's own mock contract
+            erc20("USDT", "Tether USD", 6, "0xdbe2c63cde23bff6f92bde4e9f1e002886a88017", Some("1"), 1_000_000),
+            native(),
+        ];
+        let mainnet = vec![
+            erc20("USDC", "USD Coin", 6, "0xf951eC28187D9E5Ca673Da8FE6757E6f0Be5F77C", Some("2"), 1_000_000),
+            erc20("USDT", "Tether USD", 6, "0x66e428c3f67a68878562e79A0234c1F83c208770", Some("1"), 1_000_000),
+            native(),
+        ];
+        Self {
+            networks: BTreeMap::from([
+                ("cronos-testnet".to_string(), testnet),
+                ("cronos-mainnet".to_string(), mainnet),
+            ]),
+        }
+    }
+}
+
+fn erc20(symbol: &str, name: &str, decimals: u32, address: &str, version: Option<&str>, usd_micros: u64) -> Token {
+    Token {
+        symbol: symbol.to_string(),
+        name: name.to_string(),
+        decimals,
+        address: Some(address.to_string()),
+        eip712_version: version.map(str::to_string),
+        usd_rate: Amount::from_units(usd_micros),
+        faucet: Amount::from_whole(100),
+    }
+}
+
+fn native() -> Token {
+    Token {
+        symbol: "CRO".to_string(),
+        name: "Cronos".to_string(),
+        decimals: 18,
+        address: None,
+        eip712_version: None,
+        usd_rate: Amount::from_units(90_000), // $0.09
+        faucet: Amount::from_whole(100),
+    }
+}
+
+impl TokenRegistry {
+    /// Tokens of `network`; unknown networks get the testnet list.
+    pub fn tokens(&self, network: &str) -> Vec<Token> {
+        self.networks
+            .get(network)
+            .or_else(|| self.networks.get("cronos-testnet"))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Adds (or replaces, by symbol) the tokens listed in `other`.
+    pub fn merge(&mut self, other: TokenRegistry) -> Result<(), String> {
+        for (network, tokens) in other.networks {
+            let list = self.networks.entry(network).or_default();
+            for token in tokens {
+                token.validate()?;
+                upsert(list, token);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Inserts `token` into `list`, replacing any token with the same symbol.
+pub fn upsert(list: &mut Vec<Token>, token: Token) {
+    match list.iter_mut().find(|t| t.symbol.eq_ignore_ascii_case(&token.symbol)) {
+        Some(existing) => *existing = token,
+        None => list.push(token),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_networks_use_distinct_contracts() {
+        let registry = TokenRegistry::default();
+        let testnet = registry.tokens("cronos-testnet");
+        let mainnet = registry.tokens("cronos-mainnet");
+        for token in testnet.iter().filter(|t| !t.is_native()) {
+            token.validate().unwrap();
+            let twin = mainnet.iter().find(|t| t.symbol == token.symbol).unwrap();
+            assert!(!twin.address.as_ref().unwrap().eq_ignore_ascii_case(token.address.as_ref().unwrap()), "{} reuses its mainnet address", token.symbol);
+        }
+    }
+
+    #[test]
+    fn converts_between_atomic_units_and_amounts() {
+        let registry = TokenRegistry::default();
+        let cro = registry.tokens("cronos-testnet").into_iter().find(Token::is_native).unwrap();
+        assert_eq!(cro.to_atomic(Amount::from_units(1)), 1_000_000_000_000);
+        assert_eq!(cro.from_atomic(1_500_000_000_000_000_000), Some(Amount::from_units(1_500_000)));
+        assert_eq!(cro.usd_value(Amount::from_whole(10)), Amount::from_units(900_000));
+        assert_eq!(cro.from_usd(Amount::from_units(900_000)), Some(Amount::from_whole(10)));
+    }
+}
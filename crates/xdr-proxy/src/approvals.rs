@@ -0,0 +1,527 @@
+//! Human-in-the-loop payment approvals.
+//!
+//! Payments over a USD threshold, or matching an approval rule, don't settle
+//! right away: they wait in a queue until an operator approves or denies them
+//! (from the TUI or `/_xdr/approvals`). In `hold` mode the agent's request is
+//! held open until the decision; in `defer` mode it gets a `202` with a retry
+//! hint and retries with the same payment credential. Items nobody decides
+//! on resolve to `on_timeout` once `timeout_secs` have passed.
+//!
+//! Decided items stay around for `DECIDED_RETENTION_SECS` (and at most
+//! `MAX_DECIDED` of them) so a deferred agent's retry still finds its
+//! decision; older ones are dropped from the queue.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use xdr_chaos::rules::{RequestType, Target, TargetMatcher};
+use xdr_ledger::Amount;
+
+/// How long a decided item is kept for retries and the approval list
+pub const DECIDED_RETENTION_SECS: u64 = 3600;
+/// Most decided items kept at once; the oldest go first
+pub const MAX_DECIDED: usize = 1000;
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+fn default_retry_after_secs() -> u64 {
+    5
+}
+
+/// What the agent sees while its payment waits for a decision.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalMode {
+    /// Keep the request open until the payment is decided
+    #[default]
+    Hold,
+    /// Answer `202 Accepted` with `Retry-After`; the agent retries with the same credential
+    Defer,
+}
+
+impl std::str::FromStr for ApprovalMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hold" => Ok(Self::Hold),
+            "defer" => Ok(Self::Defer),
+            other => Err(format!("Unknown approval mode '{}' (expected hold or defer)", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Approve,
+    #[default]
+    Deny,
+}
+
+impl std::str::FromStr for Decision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "approve" => Ok(Self::Approve),
+            "deny" => Ok(Self::Deny),
+            other => Err(format!("Unknown decision '{}' (expected approve or deny)", other)),
+        }
+    }
+}
+
+/// Payments matching every set field need approval. The target takes the
+/// same matchers as chaos rules; its host is the payee.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApprovalRule {
+    #[serde(flatten)]
+    pub target: Target,
+    /// Only payments worth at least this much (USD)
+    #[serde(default)]
+    pub min_usd: Option<Amount>,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApprovalConfig {
+    /// Payments worth more than this (USD) need approval
+    #[serde(default)]
+    pub threshold_usd: Option<Amount>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<ApprovalRule>,
+    #[serde(default)]
+    pub mode: ApprovalMode,
+    /// How long an item waits before `on_timeout` applies
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub on_timeout: Decision,
+    /// Retry hint sent with `202` responses in defer mode
+    #[serde(default = "default_retry_after_secs")]
+    pub retry_after_secs: u64,
+}
+
+impl Default for ApprovalConfig {
+    /// No threshold and no rules: every payment settles at once.
+    fn default() -> Self {
+        Self {
+            threshold_usd: None,
+            rules: Vec::new(),
+            mode: ApprovalMode::default(),
+            timeout_secs: default_timeout_secs(),
+            on_timeout: Decision::default(),
+            retry_after_secs: default_retry_after_secs(),
+        }
+    }
+}
+
+impl ApprovalConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.threshold_usd.is_some() || !self.rules.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+impl ApprovalStatus {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Denied => "denied",
+        }
+    }
+}
+
+/// A payment waiting for (or given) an operator's decision.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Approval {
+    pub id: String,
+    pub agent_id: String,
+    pub method: String,
+    pub url: String,
+    pub payee: String,
+    pub amount: Amount,
+    pub currency: String,
+    pub usd_value: Amount,
+    /// Why the payment needs approval
+    pub reason: String,
+    pub status: ApprovalStatus,
+    /// "operator" or "timeout" once decided
+    #[serde(default)]
+    pub decided_by: Option<String>,
+    #[serde(default)]
+    pub note: Option<String>,
+    /// Unix seconds
+    pub created_at: u64,
+    pub expires_at: u64,
+    #[serde(default)]
+    pub decided_at: Option<u64>,
+    /// Payment credential the approval is bound to (L402 invoice or x402 payer:nonce)
+    #[serde(skip)]
+    subject: String,
+}
+
+/// A payment about to settle, as the approval gate sees it.
+#[derive(Debug, Clone)]
+pub struct PaymentRequest<'a> {
+    pub agent_id: &'a str,
+    pub method: &'a str,
+    pub url: &'a str,
+    pub host: &'a str,
+    pub path: &'a str,
+    pub amount: Amount,
+    pub currency: &'a str,
+    pub usd_value: Amount,
+    pub request_type: RequestType,
+}
+
+struct CompiledRule {
+    rule: ApprovalRule,
+    target: TargetMatcher,
+}
+
+impl CompiledRule {
+    fn compile(rule: ApprovalRule) -> Result<Self, String> {
+        Ok(Self { target: rule.target.compile()?, rule })
+    }
+
+    fn matches(&self, payment: &PaymentRequest) -> bool {
+        self.target.matches_request(payment.agent_id, payment.host, payment.path, payment.method, payment.request_type)
+            && self.rule.min_usd.is_none_or(|min| payment.usd_value >= min)
+    }
+}
+
+struct ConfigState {
+    config: ApprovalConfig,
+    rules: Vec<CompiledRule>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    next_id: u64,
+    items: BTreeMap<u64, Approval>,
+    /// Credential -> key of the newest item opened for it
+    subjects: HashMap<String, u64>,
+    /// Replayed decisions for items not opened yet, by approval id
+    recorded: HashMap<String, (Decision, Option<String>)>,
+}
+
+impl QueueState {
+    fn get_mut(&mut self, id: &str) -> Option<&mut Approval> {
+        let key = id.strip_prefix("apr-")?.parse().ok()?;
+        self.items.get_mut(&key)
+    }
+
+    /// Drops decided items past their retention, then the oldest decided
+    /// ones over `MAX_DECIDED`.
+    fn prune(&mut self, now: u64) {
+        let cutoff = now.saturating_sub(DECIDED_RETENTION_SECS);
+        self.items.retain(|_, a| a.decided_at.is_none_or(|at| at > cutoff));
+        let decided: Vec<u64> = self.items.iter()
+            .filter(|(_, a)| a.status != ApprovalStatus::Pending)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in decided.iter().take(decided.len().saturating_sub(MAX_DECIDED)) {
+            self.items.remove(key);
+        }
+        let items = &self.items;
+        self.subjects.retain(|_, key| items.contains_key(key));
+    }
+}
+
+/// Shared approval queue (cloned into the proxy, control plane and TUI).
+#[derive(Clone)]
+pub struct ApprovalQueue {
+    config: Arc<RwLock<ConfigState>>,
+    queue: Arc<Mutex<QueueState>>,
+    /// Wakes held requests when an item is decided
+    decided: Arc<Notify>,
+}
+
+impl Default for ApprovalQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ApprovalQueue {
+    pub fn new() -> Self {
+        Self {
+            config: Arc::new(RwLock::new(ConfigState { config: ApprovalConfig::default(), rules: Vec::new() })),
+            queue: Arc::new(Mutex::new(QueueState::default())),
+            decided: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Validates and swaps in a new config. Items already queued keep their deadlines.
+    pub fn set_config(&self, config: ApprovalConfig) -> Result<(), String> {
+        if config.timeout_secs == 0 {
+            return Err("timeout_secs must be greater than zero".to_string());
+        }
+        let rules = config.rules.iter().cloned().map(CompiledRule::compile).collect::<Result<_, _>>()?;
+        *self.config.write().unwrap() = ConfigState { config, rules };
+        Ok(())
+    }
+
+    pub fn get_config(&self) -> ApprovalConfig {
+        self.config.read().unwrap().config.clone()
+    }
+
+    /// Why `payment` needs approval, or None if it can settle right away.
+    pub fn requires_approval(&self, payment: &PaymentRequest) -> Option<String> {
+        let state = self.config.read().unwrap();
+        if let Some(threshold) = state.config.threshold_usd {
+            if payment.usd_value > threshold {
+                return Some(format!("${} is over the ${} approval threshold", payment.usd_value, threshold));
+            }
+        }
+        state.rules.iter().position(|r| r.matches(payment)).map(|idx| {
+            match &state.rules[idx].rule.description {
+                Some(description) => format!("matches approval rule #{} ({})", idx, description),
+                None => format!("matches approval rule #{}", idx),
+            }
+        })
+    }
+
+    /// The item bound to a payment credential, if the agent already asked.
+    pub fn find(&self, subject: &str) -> Option<Approval> {
+        let mut queue = self.queue.lock().unwrap();
+        self.expire(&mut queue);
+        let key = *queue.subjects.get(subject)?;
+        queue.items.get(&key).cloned()
+    }
+
+    /// Queues a payment for approval.
+    pub fn open(&self, subject: &str, payment: &PaymentRequest, reason: String) -> Approval {
+        let timeout = self.get_config().timeout_secs;
+        let mut queue = self.queue.lock().unwrap();
+        queue.next_id += 1;
+        let now = now_secs();
+        let mut approval = Approval {
+            id: format!("apr-{}", queue.next_id),
+            agent_id: payment.agent_id.to_string(),
+            method: payment.method.to_string(),
+            url: payment.url.to_string(),
+            payee: payment.host.to_string(),
+            amount: payment.amount,
+            currency: payment.currency.to_string(),
+            usd_value: payment.usd_value,
+            reason,
+            status: ApprovalStatus::Pending,
+            decided_by: None,
+            note: None,
+            created_at: now,
+            expires_at: now + timeout,
+            decided_at: None,
+            subject: subject.to_string(),
+        };
+        if let Some((decision, note)) = queue.recorded.remove(&approval.id) {
+            resolve(&mut approval, decision, "operator", note);
+        }
+        let key = queue.next_id;
+        queue.subjects.insert(approval.subject.clone(), key);
+        queue.items.insert(key, approval.clone());
+        approval
+    }
+
+    pub fn get(&self, id: &str) -> Option<Approval> {
+        let mut queue = self.queue.lock().unwrap();
+        self.expire(&mut queue);
+        queue.get_mut(id).map(|a| a.clone())
+    }
+
+    /// Approves or denies a pending item.
+    pub fn decide(&self, id: &str, decision: Decision, note: Option<String>) -> Result<Approval, String> {
+        let mut queue = self.queue.lock().unwrap();
+        self.expire(&mut queue);
+        let approval = queue.get_mut(id).ok_or_else(|| format!("Approval {} not found", id))?;
+        if approval.status != ApprovalStatus::Pending {
+            return Err(format!(
+                "Approval {} was already {} by {}",
+                id, approval.status.label(), approval.decided_by.as_deref().unwrap_or("operator")
+            ));
+        }
+        resolve(approval, decision, "operator", note);
+        let approval = approval.clone();
+        drop(queue);
+        self.decided.notify_waiters();
+        Ok(approval)
+    }
+
+    /// Applies a decision read from a cassette. Replay reaches the decision
+    /// before the request that opens a held item, so an id not opened yet
+    /// is decided as soon as it is.
+    pub fn replay_decision(&self, id: &str, decision: Decision, note: Option<String>) -> Result<(), String> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.get_mut(id).is_none() {
+            queue.recorded.insert(id.to_string(), (decision, note));
+            return Ok(());
+        }
+        drop(queue);
+        self.decide(id, decision, note).map(|_| ())
+    }
+
+    /// Every item, newest first.
+    pub fn list(&self) -> Vec<Approval> {
+        let mut queue = self.queue.lock().unwrap();
+        self.expire(&mut queue);
+        queue.items.values().rev().cloned().collect()
+    }
+
+    /// Items still waiting for a decision, oldest first.
+    pub fn pending(&self) -> Vec<Approval> {
+        let mut queue = self.queue.lock().unwrap();
+        self.expire(&mut queue);
+        queue.items.values().filter(|a| a.status == ApprovalStatus::Pending).cloned().collect()
+    }
+
+    /// Seconds an agent in defer mode should wait before asking again.
+    pub fn retry_after(&self, approval: &Approval) -> u64 {
+        let remaining = approval.expires_at.saturating_sub(now_secs());
+        self.get_config().retry_after_secs.min(remaining).max(1)
+    }
+
+    /// Waits until the item is decided by an operator or times out
+    /// (None if the id is unknown).
+    pub async fn wait(&self, id: &str) -> Option<Approval> {
+        loop {
+            let notified = self.decided.notified();
+            let approval = self.get(id)?;
+            if approval.status != ApprovalStatus::Pending {
+                return Some(approval);
+            }
+            // Deadlines are whole seconds: sleep at least one so the item can expire
+            let remaining = approval.expires_at.saturating_sub(now_secs()).max(1);
+            let _ = tokio::time::timeout(Duration::from_secs(remaining), notified).await;
+        }
+    }
+
+    /// Applies the timeout decision to every overdue item and prunes old
+    /// decided ones.
+    fn expire(&self, queue: &mut QueueState) {
+        let now = now_secs();
+        let on_timeout = self.get_config().on_timeout;
+        for approval in queue.items.values_mut() {
+            if approval.status == ApprovalStatus::Pending && approval.expires_at <= now {
+                resolve(approval, on_timeout, "timeout", None);
+            }
+        }
+        queue.prune(now);
+    }
+}
+
+fn resolve(approval: &mut Approval, decision: Decision, by: &str, note: Option<String>) {
+    approval.status = match decision {
+        Decision::Approve => ApprovalStatus::Approved,
+        Decision::Deny => ApprovalStatus::Denied,
+    };
+    approval.decided_by = Some(by.to_string());
+    approval.decided_at = Some(now_secs());
+    approval.note = note;
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payment(agent_id: &str) -> PaymentRequest<'_> {
+        PaymentRequest {
+            agent_id,
+            method: "GET",
+            url: "https://api.example.com/data",
+            host: "api.example.com",
+            path: "/data",
+            amount: Amount::from_whole(10),
+            currency: "USDC",
+            usd_value: Amount::from_whole(10),
+            request_type: RequestType::AiInference,
+        }
+    }
+
+    #[test]
+    fn rules_match_like_chaos_targets() {
+        let queue = ApprovalQueue::new();
+        let rule: ApprovalRule = serde_json::from_str(
+            r#"{ "host": "example.com", "method": "get", "request_type": "ai_inference", "min_usd": 5, "description": "big reads" }"#,
+        ).unwrap();
+        queue.set_config(ApprovalConfig { rules: vec![rule], ..Default::default() }).unwrap();
+
+        // A plain domain covers its subdomains, and methods ignore case
+        let reason = queue.requires_approval(&payment("agent-a")).unwrap();
+        assert_eq!(reason, "matches approval rule #0 (big reads)");
+        let cheap = PaymentRequest { usd_value: Amount::from_whole(1), ..payment("agent-a") };
+        assert!(queue.requires_approval(&cheap).is_none());
+        let rpc = PaymentRequest { request_type: RequestType::Rpc, ..payment("agent-a") };
+        assert!(queue.requires_approval(&rpc).is_none());
+
+        let bad = ApprovalRule { target: Target { path: Some("[".to_string()), ..Default::default() }, ..Default::default() };
+        let err = queue.set_config(ApprovalConfig { rules: vec![bad], ..Default::default() }).unwrap_err();
+        assert!(err.starts_with("Invalid path glob '['"), "{}", err);
+    }
+
+    #[test]
+    fn find_returns_the_newest_item_for_a_credential() {
+        let queue = ApprovalQueue::new();
+        let first = queue.open("inv-1", &payment("agent-a"), "big".to_string());
+        queue.decide(&first.id, Decision::Deny, None).unwrap();
+        let second = queue.open("inv-1", &payment("agent-a"), "big".to_string());
+
+        assert_eq!(queue.find("inv-1").unwrap().id, second.id);
+        assert!(queue.find("inv-2").is_none());
+        assert_eq!(queue.get(&first.id).unwrap().status, ApprovalStatus::Denied);
+        assert!(queue.get("apr-x").is_none());
+    }
+
+    #[test]
+    fn replayed_decisions_apply_to_items_opened_later() {
+        let queue = ApprovalQueue::new();
+        queue.replay_decision("apr-1", Decision::Approve, Some("ok".to_string())).unwrap();
+        let held = queue.open("inv-1", &payment("agent-a"), "big".to_string());
+        assert_eq!(held.status, ApprovalStatus::Approved);
+        assert_eq!(held.note.as_deref(), Some("ok"));
+
+        let pending = queue.open("inv-2", &payment("agent-a"), "big".to_string());
+        assert_eq!(pending.status, ApprovalStatus::Pending);
+        queue.replay_decision(&pending.id, Decision::Deny, None).unwrap();
+        assert_eq!(queue.get(&pending.id).unwrap().status, ApprovalStatus::Denied);
+        assert!(queue.replay_decision(&pending.id, Decision::Approve, None).is_err());
+    }
+
+    #[test]
+    fn prune_drops_old_and_excess_decided_items() {
+        let queue = ApprovalQueue::new();
+        for i in 0..MAX_DECIDED + 5 {
+            let approval = queue.open(&format!("inv-{}", i), &payment("agent-a"), "big".to_string());
+            queue.decide(&approval.id, Decision::Approve, None).unwrap();
+        }
+        let pending = queue.open("inv-pending", &payment("agent-a"), "big".to_string());
+
+        let mut state = queue.queue.lock().unwrap();
+        let now = now_secs();
+        state.prune(now);
+        assert_eq!(state.items.len(), MAX_DECIDED + 1);
+        assert!(!state.subjects.contains_key("inv-0"));
+        assert!(state.subjects.contains_key(&format!("inv-{}", MAX_DECIDED + 4)));
+
+        state.prune(now + DECIDED_RETENTION_SECS);
+        assert_eq!(state.items.values().map(|a| a.id.clone()).collect::<Vec<_>>(), vec![pending.id]);
+        assert_eq!(state.subjects.len(), 1);
+    }
+}
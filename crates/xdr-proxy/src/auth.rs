@@ -0,0 +1,460 @@
+//! Agent authentication: proves a request's `X-Agent-ID` belongs to the
+//! caller before it can spend that agent's wallet.
+//!
+//! Each registered agent has one credential:
+//! - `bearer`: a static key sent as `X-Agent-Key`.
+//! - `hmac`: a shared secret. Requests carry `X-Agent-Timestamp`, a fresh
+//!   `X-Agent-Nonce` and an `X-Agent-Signature` over the agent, timestamp,
+//!   nonce, method, upstream host, path and body hash.
+//! - `ethereum`: a wallet address. Requests carry `X-Agent-Timestamp`, a fresh
+//!   `X-Agent-Nonce` and an EIP-191 `personal_sign` signature over both.
+//!
+//! A signed request can't be replayed: its nonce is single use while its
+//! timestamp is within the clock skew, and older ones are refused as stale,
+//! so the proxy forgets them.
+//!
+//! A registered agent must always authenticate. Agents without a credential
+//! are let through unless the registry is in strict mode.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sha3::Keccak256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use axum::http::HeaderMap;
+
+use crate::eip3009;
+
+pub const HEADER_AGENT_KEY: &str = "x-agent-key";
+pub const HEADER_AGENT_TIMESTAMP: &str = "x-agent-timestamp";
+pub const HEADER_AGENT_NONCE: &str = "x-agent-nonce";
+pub const HEADER_AGENT_SIGNATURE: &str = "x-agent-signature";
+
+/// HMAC and Ethereum timestamps further than this from the proxy's clock are refused
+pub const MAX_CLOCK_SKEW_SECS: u64 = 300;
+/// Largest body an HMAC agent can sign (the proxy buffers it to check the hash)
+pub const MAX_SIGNED_BODY_BYTES: usize = 16 * 1024 * 1024;
+/// Longest nonce a signing agent may send
+const MAX_NONCE_LEN: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Credential {
+    /// Static API key, sent as `X-Agent-Key`
+    Bearer { key: String },
+    /// Shared secret for HMAC-SHA256 request signatures
+    Hmac { secret: String },
+    /// Wallet that signs a fresh nonce per request
+    Ethereum { address: String },
+}
+
+impl Credential {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Bearer { .. } => "bearer",
+            Self::Hmac { .. } => "hmac",
+            Self::Ethereum { .. } => "ethereum",
+        }
+    }
+
+    /// The credential with its secret blanked, for listing over the control plane.
+    pub fn redacted(&self) -> Self {
+        match self {
+            Self::Bearer { .. } => Self::Bearer { key: "[redacted]".to_string() },
+            Self::Hmac { .. } => Self::Hmac { secret: "[redacted]".to_string() },
+            Self::Ethereum { address } => Self::Ethereum { address: address.clone() },
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::Bearer { key } if key.is_empty() => Err("bearer key must not be empty".to_string()),
+            Self::Hmac { secret } if secret.is_empty() => Err("hmac secret must not be empty".to_string()),
+            Self::Ethereum { address } => {
+                let hex = address.strip_prefix("0x").unwrap_or(address);
+                match hex.len() == 40 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    true => Ok(()),
+                    false => Err(format!("'{}' is not a 20-byte hex address", address)),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentCredential {
+    pub agent_id: String,
+    #[serde(flatten)]
+    pub credential: Credential,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuthConfig {
+    /// Reject agents without a registered credential (401)
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub agents: Vec<AgentCredential>,
+}
+
+impl AuthConfig {
+    /// The config with every secret blanked.
+    pub fn redacted(&self) -> Self {
+        Self {
+            strict: self.strict,
+            agents: self.agents.iter()
+                .map(|a| AgentCredential { agent_id: a.agent_id.clone(), credential: a.credential.redacted() })
+                .collect(),
+        }
+    }
+}
+
+/// Why a request was refused: a stable code plus a human-readable reason.
+#[derive(Debug, Clone)]
+pub struct AuthError {
+    pub code: &'static str,
+    pub detail: String,
+}
+
+impl AuthError {
+    fn new(code: &'static str, detail: impl Into<String>) -> Self {
+        Self { code, detail: detail.into() }
+    }
+}
+
+/// What the proxy saw of the request, for checking its credential.
+pub struct AuthRequest<'a> {
+    pub headers: &'a HeaderMap,
+    pub method: &'a str,
+    /// Upstream host the proxy resolved (`X-Upstream-Host` or the absolute-form
+    /// URL's host); empty when there is none
+    pub host: &'a str,
+    /// Path and query as sent to the proxy
+    pub path: &'a str,
+    /// Only needed (and only buffered) for HMAC agents
+    pub body: Option<&'a [u8]>,
+    /// Unix seconds
+    pub now: u64,
+}
+
+/// How a request was let through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Authenticated {
+    /// Proved with the agent's credential (its kind)
+    Verified(&'static str),
+    /// No credential registered for the agent, and the registry isn't strict
+    Anonymous,
+}
+
+struct RegistryState {
+    config: AuthConfig,
+    credentials: HashMap<String, Credential>,
+    /// Nonces signing agents used, with their timestamps, until they go stale
+    nonces: HashMap<String, HashMap<String, u64>>,
+}
+
+/// Shared agent registry (cloned into the proxy and control plane).
+#[derive(Clone)]
+pub struct AgentAuth {
+    state: Arc<Mutex<RegistryState>>,
+}
+
+impl Default for AgentAuth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AgentAuth {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(RegistryState { config: AuthConfig::default(), credentials: HashMap::new(), nonces: HashMap::new() })),
+        }
+    }
+
+    /// Validates and swaps in a new registry. Used nonces are kept.
+    pub fn set_config(&self, config: AuthConfig) -> Result<(), String> {
+        let mut credentials = HashMap::new();
+        for agent in &config.agents {
+            agent.credential.validate().map_err(|e| format!("Agent {}: {}", agent.agent_id, e))?;
+            if credentials.insert(agent.agent_id.clone(), agent.credential.clone()).is_some() {
+                return Err(format!("Agent {} is registered twice", agent.agent_id));
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        state.config = config;
+        state.credentials = credentials;
+        Ok(())
+    }
+
+    pub fn get_config(&self) -> AuthConfig {
+        self.state.lock().unwrap().config.clone()
+    }
+
+    /// Adds or replaces one agent's credential.
+    pub fn register(&self, agent: AgentCredential) -> Result<(), String> {
+        let mut config = self.get_config();
+        config.agents.retain(|a| a.agent_id != agent.agent_id);
+        config.agents.push(agent);
+        self.set_config(config)
+    }
+
+    /// Drops an agent's credential. False if it had none.
+    pub fn remove(&self, agent_id: &str) -> bool {
+        let mut config = self.get_config();
+        let before = config.agents.len();
+        config.agents.retain(|a| a.agent_id != agent_id);
+        let removed = config.agents.len() != before;
+        if removed {
+            self.set_config(config).expect("removing a credential keeps the registry valid");
+        }
+        removed
+    }
+
+    pub fn set_strict(&self, strict: bool) {
+        self.state.lock().unwrap().config.strict = strict;
+    }
+
+    /// Whether checking this agent needs the request body (HMAC signatures cover it).
+    pub fn needs_body(&self, agent_id: &str) -> bool {
+        matches!(self.state.lock().unwrap().credentials.get(agent_id), Some(Credential::Hmac { .. }))
+    }
+
+    /// Checks the request's credential for `agent_id`.
+    pub fn authenticate(&self, agent_id: &str, req: &AuthRequest) -> Result<Authenticated, AuthError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(credential) = state.credentials.get(agent_id).cloned() else {
+            return match state.config.strict {
+                true => Err(AuthError::new("unknown_agent", format!("Agent {} has no registered credential", agent_id))),
+                false => Ok(Authenticated::Anonymous),
+            };
+        };
+        let header = |name: &str| req.headers.get(name).and_then(|v| v.to_str().ok());
+        let missing = |name: &str| AuthError::new("missing_credentials", format!("{} header is required for {} agents", name, credential.kind()));
+
+        match &credential {
+            Credential::Bearer { key } => {
+                let sent = header(HEADER_AGENT_KEY).ok_or_else(|| missing("X-Agent-Key"))?;
+                if !constant_time_eq(sent.as_bytes(), key.as_bytes()) {
+                    return Err(AuthError::new("invalid_key", "X-Agent-Key does not match the agent's key"));
+                }
+            }
+            Credential::Hmac { secret } => {
+                let timestamp = header(HEADER_AGENT_TIMESTAMP).ok_or_else(|| missing("X-Agent-Timestamp"))?;
+                let nonce = header(HEADER_AGENT_NONCE).ok_or_else(|| missing("X-Agent-Nonce"))?;
+                let signature = header(HEADER_AGENT_SIGNATURE).ok_or_else(|| missing("X-Agent-Signature"))?;
+                let signed_at = check_timestamp(timestamp, req.now)?;
+                check_nonce(nonce)?;
+                let body = req.body.unwrap_or_default();
+                let expected = hmac_signature(secret, agent_id, timestamp, nonce, req.method, req.host, req.path, body);
+                let sent = signature.trim().to_ascii_lowercase();
+                if !constant_time_eq(sent.as_bytes(), expected.as_bytes()) {
+                    // The expected input helps debug a client, but only in the proxy's own log
+                    warn!(target: "xdr_core", "HMAC mismatch for {}; expected a signature of {:?}",
+                        agent_id, string_to_sign(agent_id, timestamp, nonce, req.method, req.host, req.path, body));
+                    return Err(AuthError::new("invalid_signature", "HMAC does not match the request"));
+                }
+                spend_nonce(&mut state, agent_id, nonce, signed_at, req.now)?;
+            }
+            Credential::Ethereum { address } => {
+                let timestamp = header(HEADER_AGENT_TIMESTAMP).ok_or_else(|| missing("X-Agent-Timestamp"))?;
+                let nonce = header(HEADER_AGENT_NONCE).ok_or_else(|| missing("X-Agent-Nonce"))?;
+                let signature = header(HEADER_AGENT_SIGNATURE).ok_or_else(|| missing("X-Agent-Signature"))?;
+                let signed_at = check_timestamp(timestamp, req.now)?;
+                check_nonce(nonce)?;
+                let signer = eip3009::recover_signer(signature, &personal_message_hash(&nonce_message(agent_id, timestamp, nonce)))
+                    .map_err(|e| AuthError::new("invalid_signature", e.detail))?;
+                let signer = eip3009::format_address(&signer);
+                if !signer.eq_ignore_ascii_case(address) {
+                    return Err(AuthError::new("invalid_signature", format!("Signed by {}, expected {}", signer, address)));
+                }
+                spend_nonce(&mut state, agent_id, nonce, signed_at, req.now)?;
+            }
+        }
+        Ok(Authenticated::Verified(credential.kind()))
+    }
+}
+
+/// Credential headers are for the proxy only and never reach the upstream.
+pub fn strip_headers(headers: &mut HeaderMap) {
+    for name in [HEADER_AGENT_KEY, HEADER_AGENT_TIMESTAMP, HEADER_AGENT_NONCE, HEADER_AGENT_SIGNATURE] {
+        headers.remove(name);
+    }
+}
+
+/// Parses a signed timestamp and checks it against the proxy clock.
+fn check_timestamp(timestamp: &str, now: u64) -> Result<u64, AuthError> {
+    let signed_at: u64 = timestamp.parse()
+        .map_err(|_| AuthError::new("invalid_timestamp", "X-Agent-Timestamp must be unix seconds"))?;
+    if signed_at.abs_diff(now) > MAX_CLOCK_SKEW_SECS {
+        return Err(AuthError::new("stale_timestamp", format!(
+            "X-Agent-Timestamp {} is more than {}s from the proxy clock ({})", signed_at, MAX_CLOCK_SKEW_SECS, now
+        )));
+    }
+    Ok(signed_at)
+}
+
+fn check_nonce(nonce: &str) -> Result<(), AuthError> {
+    match nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+        true => Err(AuthError::new("invalid_nonce", format!("X-Agent-Nonce must be 1-{} characters", MAX_NONCE_LEN))),
+        false => Ok(()),
+    }
+}
+
+/// Marks a verified request's nonce as used. Only call once the signature checks
+/// out, or anyone could burn an agent's nonces.
+fn spend_nonce(state: &mut RegistryState, agent_id: &str, nonce: &str, signed_at: u64, now: u64) -> Result<(), AuthError> {
+    // Nonces past the skew window can't come back (their timestamp is refused)
+    let used = state.nonces.entry(agent_id.to_string()).or_default();
+    used.retain(|_, at| at.abs_diff(now) <= MAX_CLOCK_SKEW_SECS);
+    match used.insert(nonce.to_string(), signed_at) {
+        Some(_) => Err(AuthError::new("nonce_reused", format!("Nonce {} was already used by {}", nonce, agent_id))),
+        None => Ok(()),
+    }
+}
+
+/// What an HMAC agent signs: one field per line. The host is lowercased.
+pub fn string_to_sign(agent_id: &str, timestamp: &str, nonce: &str, method: &str, host: &str, path: &str, body: &[u8]) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}\n{}",
+        agent_id, timestamp, nonce, method.to_ascii_uppercase(), host.to_ascii_lowercase(), path, hex::encode(Sha256::digest(body))
+    )
+}
+
+/// Lowercase hex HMAC-SHA256 of `string_to_sign`.
+#[allow(clippy::too_many_arguments)]
+pub fn hmac_signature(secret: &str, agent_id: &str, timestamp: &str, nonce: &str, method: &str, host: &str, path: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(string_to_sign(agent_id, timestamp, nonce, method, host, path, body).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// What an Ethereum agent signs with `personal_sign`.
+pub fn nonce_message(agent_id: &str, timestamp: &str, nonce: &str) -> String {
+    format!("XDR agent {} nonce {} at {}", agent_id, nonce, timestamp)
+}
+
+/// EIP-191 hash of a `personal_sign` message.
+fn personal_message_hash(message: &str) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(format!("\x19Ethereum Signed Message:\n{}", message.len()).as_bytes());
+    hasher.update(message.as_bytes());
+    hasher.finalize().into()
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use k256::ecdsa::SigningKey;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn registry(agent_id: &str, credential: Credential) -> AgentAuth {
+        let auth = AgentAuth::new();
+        auth.register(AgentCredential { agent_id: agent_id.to_string(), credential }).unwrap();
+        auth
+    }
+
+    fn headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn check(auth: &AgentAuth, headers: &HeaderMap, host: &str, body: &[u8], now: u64) -> Result<Authenticated, AuthError> {
+        let req = AuthRequest { headers, method: "POST", host, path: "/v1/chat/completions", body: Some(body), now };
+        auth.authenticate("agent-1", &req)
+    }
+
+    #[test]
+    fn hmac_signatures_cover_the_upstream_host() {
+        let auth = registry("agent-1", Credential::Hmac { secret: "s3cret".to_string() });
+        let signed = |nonce: &str| {
+            let timestamp = NOW.to_string();
+            let signature = hmac_signature("s3cret", "agent-1", &timestamp, nonce, "post", "API.openai.com", "/v1/chat/completions", b"{}");
+            headers(&[(HEADER_AGENT_TIMESTAMP, timestamp), (HEADER_AGENT_NONCE, nonce.to_string()), (HEADER_AGENT_SIGNATURE, signature)])
+        };
+
+        assert_eq!(check(&auth, &signed("n-1"), "api.openai.com", b"{}", NOW).unwrap(), Authenticated::Verified("hmac"));
+        // Replaying the signature against another upstream fails, and the 401 doesn't leak what to sign
+        let err = check(&auth, &signed("n-2"), "api.anthropic.com", b"{}", NOW).unwrap_err();
+        assert_eq!(err.code, "invalid_signature");
+        assert!(!err.detail.contains("agent-1"), "{}", err.detail);
+        assert_eq!(check(&auth, &signed("n-2"), "api.openai.com", b"{}", NOW + MAX_CLOCK_SKEW_SECS + 1).unwrap_err().code, "stale_timestamp");
+    }
+
+    #[test]
+    fn hmac_requests_cannot_be_replayed() {
+        let auth = registry("agent-1", Credential::Hmac { secret: "s3cret".to_string() });
+        let timestamp = NOW.to_string();
+        let signature = hmac_signature("s3cret", "agent-1", &timestamp, "n-1", "POST", "api.openai.com", "/v1/chat/completions", b"{}");
+        let signed = headers(&[(HEADER_AGENT_TIMESTAMP, timestamp.clone()), (HEADER_AGENT_NONCE, "n-1".to_string()), (HEADER_AGENT_SIGNATURE, signature.clone())]);
+
+        assert!(check(&auth, &signed, "api.openai.com", b"{}", NOW).is_ok());
+        assert_eq!(check(&auth, &signed, "api.openai.com", b"{}", NOW).unwrap_err().code, "nonce_reused");
+        // The nonce is signed, so swapping it for a fresh one breaks the signature
+        let swapped = headers(&[(HEADER_AGENT_TIMESTAMP, timestamp.clone()), (HEADER_AGENT_NONCE, "n-2".to_string()), (HEADER_AGENT_SIGNATURE, signature)]);
+        assert_eq!(check(&auth, &swapped, "api.openai.com", b"{}", NOW).unwrap_err().code, "invalid_signature");
+        // A forged request can't burn a nonce the agent hasn't used yet
+        let unsigned = headers(&[(HEADER_AGENT_TIMESTAMP, timestamp), (HEADER_AGENT_NONCE, "n-3".to_string())]);
+        assert_eq!(check(&auth, &unsigned, "api.openai.com", b"{}", NOW).unwrap_err().code, "missing_credentials");
+        assert!(!auth.state.lock().unwrap().nonces["agent-1"].contains_key("n-2"));
+    }
+
+    #[test]
+    fn ethereum_nonces_are_single_use_until_they_go_stale() {
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let address = eip3009::format_address(&eip3009::address_of(key.verifying_key()));
+        let auth = registry("agent-1", Credential::Ethereum { address });
+        let signed = |timestamp: u64, nonce: &str| {
+            let timestamp = timestamp.to_string();
+            let digest = personal_message_hash(&nonce_message("agent-1", &timestamp, nonce));
+            let (signature, recid) = key.sign_prehash_recoverable(&digest).unwrap();
+            let mut bytes = signature.to_bytes().to_vec();
+            bytes.push(recid.to_byte() + 27);
+            headers(&[
+                (HEADER_AGENT_TIMESTAMP, timestamp),
+                (HEADER_AGENT_NONCE, nonce.to_string()),
+                (HEADER_AGENT_SIGNATURE, format!("0x{}", hex::encode(bytes))),
+            ])
+        };
+
+        assert!(check(&auth, &signed(NOW, "n-1"), "", b"", NOW).is_ok());
+        assert_eq!(check(&auth, &signed(NOW, "n-1"), "", b"", NOW).unwrap_err().code, "nonce_reused");
+        assert!(check(&auth, &signed(NOW, "n-2"), "", b"", NOW).is_ok());
+        // A timestamp the signature doesn't cover is refused
+        let mut tampered = signed(NOW, "n-3");
+        tampered.insert(HEADER_AGENT_TIMESTAMP, HeaderValue::from(NOW + 1));
+        assert_eq!(check(&auth, &tampered, "", b"", NOW).unwrap_err().code, "invalid_signature");
+
+        // Once stale, old nonces are refused by their timestamp and forgotten
+        let later = NOW + MAX_CLOCK_SKEW_SECS + 1;
+        assert_eq!(check(&auth, &signed(NOW, "n-1"), "", b"", later).unwrap_err().code, "stale_timestamp");
+        assert!(check(&auth, &signed(later, "n-4"), "", b"", later).is_ok());
+        assert_eq!(auth.state.lock().unwrap().nonces["agent-1"].len(), 1);
+    }
+
+    #[test]
+    fn bearer_keys_and_strict_mode() {
+        let auth = registry("agent-1", Credential::Bearer { key: "sk-1".to_string() });
+        assert!(check(&auth, &headers(&[(HEADER_AGENT_KEY, "sk-1".to_string())]), "", b"", NOW).is_ok());
+        assert_eq!(check(&auth, &headers(&[(HEADER_AGENT_KEY, "sk-2".to_string())]), "", b"", NOW).unwrap_err().code, "invalid_key");
+        assert_eq!(check(&auth, &HeaderMap::new(), "", b"", NOW).unwrap_err().code, "missing_credentials");
+
+        let req = AuthRequest { headers: &HeaderMap::new(), method: "GET", host: "", path: "/", body: None, now: NOW };
+        assert_eq!(auth.authenticate("agent-2", &req).unwrap(), Authenticated::Anonymous);
+        auth.set_strict(true);
+        assert_eq!(auth.authenticate("agent-2", &req).unwrap_err().code, "unknown_agent");
+    }
+}
@@ -0,0 +1,318 @@
+//! Record & replay of proxy sessions.
+//!
+//! A cassette is a JSON-lines file. The first line is a `Header` holding
+//! everything needed to restart the runtime in the same state (ledger
+//! snapshot, id seed, chaos and pricing config). Each proxied request then
+//! appends an `Interaction`, and control-plane changes made mid-session
+//! append a `Control` entry at the point they happened.
+//!
+//! In replay mode the proxy runs its normal pipeline, but upstream responses
+//! come from the cassette instead of the network. Every interaction is
+//! compared with its recording and differences are reported as divergences.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::Mutex;
+use xdr_chaos::scenario::Scenario;
+use xdr_chaos::ChaosConfig;
+use xdr_ledger::policy::SpendingPolicy;
+use xdr_ledger::storage::LedgerSnapshot;
+use xdr_ledger::tokens::Token;
+use xdr_ledger::Amount;
+use xdr_trace::{EventCategory, Trace};
+
+use crate::approvals::{ApprovalConfig, Decision};
+use crate::pricing::PricingConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::services::ServiceConfig;
+
+pub const CASSETTE_VERSION: u32 = 1;
+/// Set on replayed responses whose interaction diverged from the recording
+pub const HEADER_REPLAY_DIVERGENCE: &str = "x-xdr-replay-divergence";
+/// Response bodies larger than this are recorded empty
+pub const MAX_RECORDED_BODY_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CassetteHeader {
+    pub version: u32,
+    pub network: String,
+    pub protocol: String,
+    /// Seed for invoice ids and tx hashes
+    pub ledger_seed: u64,
+    pub ledger: LedgerSnapshot,
+    pub chaos: ChaosConfig,
+    /// Chaos scenario playing when recording started (restarted from its first phase on replay)
+    pub scenario: Option<Scenario>,
+    pub pricing: PricingConfig,
+    /// Assets of the recorded network
+    pub tokens: Vec<Token>,
+    pub approvals: ApprovalConfig,
+    /// Upstream quotas (buckets start full on replay)
+    pub rate_limits: RateLimitConfig,
+    /// Invoice lifetime in seconds (None: never expires)
+    pub invoice_ttl: Option<u64>,
+    /// Agent services, so payments to them are transfers on replay too
+    pub services: ServiceConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedBody {
+    /// UTF-8 text, or base64 when `base64` is set
+    pub data: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub base64: bool,
+}
+
+impl RecordedBody {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Self { data: text.to_string(), base64: false },
+            Err(_) => Self { data: BASE64.encode(bytes), base64: true },
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.base64 {
+            BASE64.decode(&self.data).unwrap_or_default()
+        } else {
+            self.data.clone().into_bytes()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: RecordedBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: RecordedBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    pub seq: u64,
+    pub agent_id: String,
+    pub request: RecordedRequest,
+    /// Chaos decisions taken while serving the request
+    pub chaos: Vec<String>,
+    /// Ledger mutations (invoices, payments, funding)
+    pub ledger: Vec<String>,
+    /// True when the response came from the upstream (and is replayable as such)
+    pub upstream: bool,
+    pub response: RecordedResponse,
+}
+
+/// Control-plane changes made while recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ControlChange {
+    Chaos(ChaosConfig),
+    Scenario(Scenario),
+    Pricing(PricingConfig),
+    Balance {
+        agent_id: String,
+        amount: Amount,
+        currency: String,
+    },
+    Ledger(LedgerSnapshot),
+    Mine { blocks: u64 },
+    Token(Token),
+    Policy { agent_id: String, policy: SpendingPolicy },
+    Approvals(ApprovalConfig),
+    /// An operator approved or denied a queued payment
+    Decision {
+        approval_id: String,
+        decision: Decision,
+        #[serde(default)]
+        note: Option<String>,
+    },
+    RateLimits(RateLimitConfig),
+    CancelInvoice { invoice_id: String },
+    Group { id: String, parent: Option<String>, budget_limit: Option<Amount> },
+    GroupRemoved { id: String },
+    GroupBalance {
+        group_id: String,
+        amount: Amount,
+        currency: String,
+    },
+    GroupMember { agent_id: String, group: Option<String> },
+    Services(ServiceConfig),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CassetteEntry {
+    Header(Box<CassetteHeader>),
+    Interaction(Interaction),
+    Control(ControlChange),
+}
+
+/// Splits a finished trace into the chaos decisions and ledger mutations it logged.
+pub fn decisions(trace: &Trace) -> (Vec<String>, Vec<String>) {
+    let mut chaos = Vec::new();
+    let mut ledger = Vec::new();
+    for event in &trace.events {
+        match event.category {
+            EventCategory::Chaos => chaos.push(event.message.clone()),
+            EventCategory::Payment | EventCategory::Policy => ledger.push(event.message.clone()),
+            _ => {}
+        }
+    }
+    (chaos, ledger)
+}
+
+/// Differences between what the agent sent / got now and what was recorded.
+pub fn diverges(recorded: &Interaction, actual: &Interaction) -> Vec<String> {
+    let mut issues = Vec::new();
+    if recorded.request.method != actual.request.method || recorded.request.url != actual.request.url {
+        issues.push(format!(
+            "request: expected {} {}, got {} {}",
+            recorded.request.method, recorded.request.url, actual.request.method, actual.request.url
+        ));
+    }
+    if recorded.request.body.data != actual.request.body.data {
+        issues.push("request: body differs from recording".to_string());
+    }
+    if recorded.chaos != actual.chaos {
+        issues.push(format!("chaos: expected {:?}, got {:?}", recorded.chaos, actual.chaos));
+    }
+    if recorded.ledger != actual.ledger {
+        issues.push(format!("ledger: expected {:?}, got {:?}", recorded.ledger, actual.ledger));
+    }
+    if recorded.response.status != actual.response.status {
+        issues.push(format!(
+            "response: expected status {}, got {}",
+            recorded.response.status, actual.response.status
+        ));
+    }
+    issues
+}
+
+/// Appends entries to a cassette file.
+pub struct Recorder {
+    file: Mutex<File>,
+    seq: Mutex<u64>,
+}
+
+impl Recorder {
+    pub fn create(path: &str, header: CassetteHeader) -> io::Result<Self> {
+        let recorder = Self { file: Mutex::new(File::create(path)?), seq: Mutex::new(0) };
+        recorder.write(&CassetteEntry::Header(Box::new(header)))?;
+        Ok(recorder)
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        let mut seq = self.seq.lock().unwrap();
+        *seq += 1;
+        *seq
+    }
+
+    pub fn write(&self, entry: &CassetteEntry) -> io::Result<()> {
+        let line = serde_json::to_string(entry)?;
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)?;
+        file.flush()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplayReport {
+    pub served: u64,
+    pub remaining: usize,
+    pub divergences: Vec<String>,
+}
+
+struct PlayerState {
+    /// Per-agent queues of (position in file, interaction)
+    queues: HashMap<String, VecDeque<(usize, Interaction)>>,
+    /// Control changes not yet applied, in file order
+    controls: VecDeque<(usize, ControlChange)>,
+    report: ReplayReport,
+}
+
+/// Serves interactions from a cassette, per agent and in recorded order.
+pub struct Player {
+    pub header: CassetteHeader,
+    state: Mutex<PlayerState>,
+}
+
+impl Player {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut header = None;
+        let mut queues: HashMap<String, VecDeque<(usize, Interaction)>> = HashMap::new();
+        let mut controls = VecDeque::new();
+        let mut total = 0;
+
+        for (pos, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: CassetteEntry = serde_json::from_str(&line)
+                .map_err(|e| invalid(format!("{}:{}: {}", path, pos + 1, e)))?;
+            match entry {
+                CassetteEntry::Header(h) => header = Some(*h),
+                CassetteEntry::Interaction(i) => {
+                    total += 1;
+                    queues.entry(i.agent_id.clone()).or_default().push_back((pos, i));
+                }
+                CassetteEntry::Control(c) => controls.push_back((pos, c)),
+            }
+        }
+
+        let header = header.ok_or_else(|| invalid(format!("{}: missing cassette header", path)))?;
+        if header.version != CASSETTE_VERSION {
+            return Err(invalid(format!("{}: unsupported cassette version {}", path, header.version)));
+        }
+        Ok(Self {
+            header,
+            state: Mutex::new(PlayerState {
+                queues,
+                controls,
+                report: ReplayReport { remaining: total, ..Default::default() },
+            }),
+        })
+    }
+
+    /// Takes the next recorded interaction for `agent_id`, along with every
+    /// control change that was recorded before it.
+    pub fn next(&self, agent_id: &str) -> (Option<Interaction>, Vec<ControlChange>) {
+        let mut state = self.state.lock().unwrap();
+        let Some((pos, interaction)) = state.queues.get_mut(agent_id).and_then(|q| q.pop_front()) else {
+            return (None, Vec::new());
+        };
+        let mut due = Vec::new();
+        while state.controls.front().is_some_and(|(p, _)| *p < pos) {
+            due.push(state.controls.pop_front().unwrap().1);
+        }
+        state.report.served += 1;
+        state.report.remaining -= 1;
+        (Some(interaction), due)
+    }
+
+    pub fn flag(&self, seq: Option<u64>, agent_id: &str, issue: &str) {
+        let mut state = self.state.lock().unwrap();
+        let at = seq.map(|s| format!("#{}", s)).unwrap_or_else(|| "unrecorded".to_string());
+        state.report.divergences.push(format!("[{} {}] {}", agent_id, at, issue));
+    }
+
+    pub fn report(&self) -> ReplayReport {
+        self.state.lock().unwrap().report.clone()
+    }
+}
+
+pub enum Session {
+    Record(Recorder),
+    Replay(Box<Player>),
+}
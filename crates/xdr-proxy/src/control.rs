@@ -0,0 +1,402 @@
+//! Access control for the `/_xdr` control plane.
+//!
+//! Operators authenticate with `Authorization: Bearer <token>`. Each token has
+//! a role: observers can only read, fund managers move money (budgets,
+//! budget groups, policies, approvals, invoices, snapshots), chaos operators
+//! drive failure injection (chaos, scenarios, rate limits, mining) and admins
+//! can do all of it plus reconfigure the runtime. Every mutation, allowed or refused, goes to
+//! the audit log; refusals without a valid token are logged at most once a
+//! minute, with a count of the ones in between.
+//!
+//! The control plane has its own listener, and it needs at least one token.
+//! Serving it without any, with every caller an admin, takes an explicit
+//! `insecure_open`.
+
+use axum::http::{HeaderMap, Method};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::constant_time_eq;
+
+/// Audit entries kept in memory for `GET /_xdr/audit`
+const AUDIT_CAPACITY: usize = 1000;
+/// Request bodies larger than this are not copied into the audit log
+const MAX_AUDITED_BODY: usize = 16 * 1024;
+/// Largest request body the control plane accepts (413 beyond)
+pub const MAX_CONTROL_BODY_BYTES: usize = 1024 * 1024;
+/// At most one unauthenticated refusal is audited per interval
+const UNAUTHENTICATED_AUDIT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Read-only access to every control route
+    Observer,
+    /// Budgets, budget groups, policies, approvals, invoices and snapshots
+    FundManager,
+    /// Chaos settings, scenarios, rate limits and mining
+    ChaosOperator,
+    /// Everything, including pricing, tokens, agent services and both credential registries
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Observer => "observer",
+            Self::FundManager => "fund_manager",
+            Self::ChaosOperator => "chaos_operator",
+            Self::Admin => "admin",
+        }
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        match self {
+            Self::Admin => true,
+            Self::FundManager => matches!(permission, Permission::Read | Permission::Funds),
+            Self::ChaosOperator => matches!(permission, Permission::Read | Permission::Chaos),
+            Self::Observer => permission == Permission::Read,
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "_").as_str() {
+            "observer" => Ok(Self::Observer),
+            "fund_manager" => Ok(Self::FundManager),
+            "chaos_operator" => Ok(Self::ChaosOperator),
+            "admin" => Ok(Self::Admin),
+            other => Err(format!("Unknown role '{}' (expected observer, fund_manager, chaos_operator or admin)", other)),
+        }
+    }
+}
+
+/// What a control route needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Funds,
+    Chaos,
+    Admin,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Funds => "funds",
+            Self::Chaos => "chaos",
+            Self::Admin => "admin",
+        }
+    }
+
+    /// The permission a control-plane request needs.
+    pub fn required(method: &Method, path: &str) -> Self {
+        if matches!(*method, Method::GET | Method::HEAD) {
+            return Self::Read;
+        }
+        let segments: Vec<&str> = path.trim_start_matches("/_xdr/").split('/').collect();
+        match segments.as_slice() {
+            ["budget", _] | ["policy", _] | ["snapshot", "save" | "load", _] | ["invoices", _, "cancel"] => Self::Funds,
+            ["approvals", _, "approve" | "deny"] => Self::Funds,
+            ["groups", ..] => Self::Funds,
+            ["chaos"] | ["chaos", "scenario"] | ["ratelimits"] | ["mine"] => Self::Chaos,
+            _ => Self::Admin,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlToken {
+    /// Who holds the token, as written in the audit log
+    pub name: String,
+    pub token: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone)]
+pub struct ControlConfig {
+    /// Address of the control plane's listener (the proxy port only carries agent traffic)
+    pub bind: SocketAddr,
+    /// Required unless `insecure_open`
+    pub tokens: Vec<ControlToken>,
+    /// Serve the control plane without tokens, every caller an admin
+    pub insecure_open: bool,
+    /// Append every audit entry to this JSON-lines file
+    pub audit_log: Option<PathBuf>,
+}
+
+impl ControlConfig {
+    /// Refuses a control plane without tokens unless it was opened on purpose.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.tokens.is_empty(), self.insecure_open) {
+            (true, false) => Err(format!(
+                "The control plane on {} needs an operator token (--admin-token or --control-tokens); \
+                 --insecure-open-control-plane serves it without one",
+                self.bind
+            )),
+            (false, true) => Err("--insecure-open-control-plane can't be combined with operator tokens".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Who made a control-plane request.
+#[derive(Debug, Clone)]
+pub struct Operator {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Why a control-plane request was refused.
+#[derive(Debug, Clone)]
+pub enum Denied {
+    /// No token, or one that matches nobody (401)
+    Unauthenticated,
+    /// Valid token whose role lacks the permission (403)
+    Forbidden { operator: Operator, needed: Permission },
+}
+
+impl Denied {
+    /// The operator behind the refused request, when the token was valid.
+    pub fn operator(&self) -> Option<&Operator> {
+        match self {
+            Self::Unauthenticated => None,
+            Self::Forbidden { operator, .. } => Some(operator),
+        }
+    }
+}
+
+/// Token lookup for the control plane (shared by both listeners).
+#[derive(Clone)]
+pub struct ControlAuth {
+    tokens: Arc<Vec<ControlToken>>,
+}
+
+impl ControlAuth {
+    pub fn new(tokens: Vec<ControlToken>) -> Result<Self, String> {
+        for (i, token) in tokens.iter().enumerate() {
+            if token.token.is_empty() {
+                return Err(format!("Control token '{}' is empty", token.name));
+            }
+            if tokens[..i].iter().any(|t| t.token == token.token) {
+                return Err(format!("Control tokens '{}' and another entry share the same secret", token.name));
+            }
+        }
+        Ok(Self { tokens: Arc::new(tokens) })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Resolves the bearer token in `headers` and checks it may use `permission`.
+    pub fn authorize(&self, headers: &HeaderMap, permission: Permission) -> Result<Operator, Denied> {
+        if self.is_open() {
+            return Ok(Operator { name: "anonymous".to_string(), role: Role::Admin });
+        }
+        let sent = headers.get("authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or(Denied::Unauthenticated)?;
+        let token = self.tokens.iter()
+            .find(|t| constant_time_eq(t.token.as_bytes(), sent.as_bytes()))
+            .ok_or(Denied::Unauthenticated)?;
+        let operator = Operator { name: token.name.clone(), role: token.role };
+        match token.role.allows(permission) {
+            true => Ok(operator),
+            false => Err(Denied::Forbidden { operator, needed: permission }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// RFC 3339
+    pub timestamp: String,
+    /// Token name, or "anonymous" when the token was missing or unknown
+    pub operator: String,
+    #[serde(default)]
+    pub role: Option<Role>,
+    pub method: String,
+    pub path: String,
+    /// Response status (401/403 when refused)
+    pub status: u16,
+    /// JSON request body; secrets in credential registries are not copied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+    /// Unauthenticated refusals left out of the log since the previous one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suppressed: Option<u64>,
+}
+
+impl AuditEntry {
+    pub fn new(operator: Option<&Operator>, method: &Method, path: &str, status: u16, body: &[u8]) -> Self {
+        let body = match path.starts_with("/_xdr/auth") {
+            true => (!body.is_empty()).then(|| Value::String("[redacted]".to_string())),
+            false => audited_body(body),
+        };
+        Self {
+            timestamp: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            operator: operator.map_or("anonymous", |o| o.name.as_str()).to_string(),
+            role: operator.map(|o| o.role),
+            method: method.to_string(),
+            path: path.to_string(),
+            status,
+            body,
+            suppressed: None,
+        }
+    }
+}
+
+fn audited_body(body: &[u8]) -> Option<Value> {
+    if body.is_empty() {
+        return None;
+    }
+    if body.len() > MAX_AUDITED_BODY {
+        return Some(Value::String(format!("[{} bytes]", body.len())));
+    }
+    Some(serde_json::from_slice(body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned())))
+}
+
+struct AuditState {
+    entries: VecDeque<AuditEntry>,
+    file: Option<File>,
+    /// When the last unauthenticated refusal was logged
+    last_unauthenticated: Option<Instant>,
+    suppressed: u64,
+}
+
+/// Recent control-plane mutations, optionally mirrored to a JSON-lines file.
+#[derive(Clone)]
+pub struct AuditLog {
+    state: Arc<Mutex<AuditState>>,
+}
+
+impl AuditLog {
+    pub fn open(path: Option<&PathBuf>) -> std::io::Result<Self> {
+        let file = path.map(|p| OpenOptions::new().create(true).append(true).open(p)).transpose()?;
+        let state = AuditState { entries: VecDeque::new(), file, last_unauthenticated: None, suppressed: 0 };
+        Ok(Self { state: Arc::new(Mutex::new(state)) })
+    }
+
+    pub fn push(&self, entry: AuditEntry) {
+        self.push_at(entry, Instant::now())
+    }
+
+    fn push_at(&self, mut entry: AuditEntry, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if entry.status == 401 {
+            let recent = state.last_unauthenticated.is_some_and(|last| now.duration_since(last) < UNAUTHENTICATED_AUDIT_INTERVAL);
+            if recent {
+                state.suppressed += 1;
+                return;
+            }
+            entry.suppressed = (state.suppressed > 0).then_some(state.suppressed);
+            state.suppressed = 0;
+            state.last_unauthenticated = Some(now);
+        }
+        if let Some(file) = state.file.as_mut() {
+            let line = serde_json::to_string(&entry).unwrap_or_default();
+            if let Err(e) = writeln!(file, "{}", line) {
+                tracing::warn!(target: "xdr_core", "Failed to write audit log: {}", e);
+            }
+        }
+        if state.entries.len() >= AUDIT_CAPACITY {
+            state.entries.pop_front();
+        }
+        state.entries.push_back(entry);
+    }
+
+    /// Oldest first.
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.state.lock().unwrap().entries.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(name: &str, role: Role) -> ControlToken {
+        ControlToken { name: name.to_string(), token: format!("{}-token", name), role }
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn roles_gate_routes() {
+        let auth = ControlAuth::new(vec![token("finance", Role::FundManager), token("qa", Role::ChaosOperator)]).unwrap();
+        let budget = Permission::required(&Method::POST, "/_xdr/budget/bot");
+        assert_eq!(auth.authorize(&bearer("finance-token"), budget).unwrap().name, "finance");
+        assert!(matches!(auth.authorize(&bearer("qa-token"), budget), Err(Denied::Forbidden { .. })));
+        assert!(matches!(auth.authorize(&bearer("nobody"), budget), Err(Denied::Unauthenticated)));
+        assert!(matches!(auth.authorize(&HeaderMap::new(), Permission::Read), Err(Denied::Unauthenticated)));
+    }
+
+    #[test]
+    fn the_control_plane_is_only_open_on_purpose() {
+        let config = |tokens: Vec<ControlToken>, insecure_open| ControlConfig {
+            bind: SocketAddr::from(([127, 0, 0, 1], 4003)),
+            tokens,
+            insecure_open,
+            audit_log: None,
+        };
+        assert!(config(Vec::new(), false).validate().unwrap_err().contains("needs an operator token"));
+        assert!(config(vec![token("ops", Role::Admin)], true).validate().is_err());
+        assert!(config(vec![token("ops", Role::Admin)], false).validate().is_ok());
+        assert!(config(Vec::new(), true).validate().is_ok());
+    }
+
+    #[test]
+    fn routes_map_to_permissions() {
+        assert_eq!(Permission::required(&Method::GET, "/_xdr/auth").as_str(), "read");
+        assert_eq!(Permission::required(&Method::POST, "/_xdr/invoices/inv-1/cancel").as_str(), "funds");
+        assert_eq!(Permission::required(&Method::PUT, "/_xdr/chaos/scenario").as_str(), "chaos");
+        assert_eq!(Permission::required(&Method::POST, "/_xdr/auth/agents").as_str(), "admin");
+    }
+
+    #[test]
+    fn audit_entries_redact_credentials() {
+        let entry = AuditEntry::new(None, &Method::POST, "/_xdr/auth/agents", 200, br#"{"key":"sk-1"}"#);
+        assert_eq!(entry.body, Some(Value::String("[redacted]".to_string())));
+        assert_eq!(entry.operator, "anonymous");
+        let entry = AuditEntry::new(None, &Method::POST, "/_xdr/budget/bot", 200, br#"{"limit":5}"#);
+        assert_eq!(entry.body, Some(serde_json::json!({ "limit": 5 })));
+    }
+
+    #[test]
+    fn unauthenticated_refusals_are_collapsed() {
+        let log = AuditLog::open(None).unwrap();
+        let refused = || AuditEntry::new(None, &Method::POST, "/_xdr/budget/bot", 401, &[]);
+        let start = Instant::now();
+        for i in 0..5 {
+            log.push_at(refused(), start + Duration::from_secs(i));
+        }
+        let operator = Operator { name: "finance".to_string(), role: Role::FundManager };
+        log.push_at(AuditEntry::new(Some(&operator), &Method::POST, "/_xdr/budget/bot", 403, &[]), start);
+        log.push_at(refused(), start + UNAUTHENTICATED_AUDIT_INTERVAL);
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].suppressed, None);
+        assert_eq!(entries[1].status, 403);
+        assert_eq!(entries[2].suppressed, Some(4));
+    }
+}
@@ -0,0 +1,408 @@
+//! Verifier for the x402 `exact` (and `upto`) scheme on EVM networks.
+//!
+//! The payload is an EIP-3009 `transferWithAuthorization` signed as EIP-712
+//! typed data. We rebuild the digest, recover the signer with secp256k1 and
+//! check every authorization field against the payment requirements.
+
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use sha3::{Digest, Keccak256};
+
+use crate::x402::{self, PaymentPayload, PaymentRequirements};
+
+const DOMAIN_TYPE: &str =
+    "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+const TRANSFER_TYPE: &str = "TransferWithAuthorization(address from,address to,uint256 value,uint256 validAfter,uint256 validBefore,bytes32 nonce)";
+
+/// A rejected payment: the x402 error code plus a human-readable reason.
+#[derive(Debug, Clone)]
+pub struct VerifyError {
+    pub code: &'static str,
+    pub detail: String,
+}
+
+impl VerifyError {
+    fn new(code: &'static str, detail: impl Into<String>) -> Self {
+        Self { code, detail: detail.into() }
+    }
+}
+
+/// EIP-712 domain of the token contract that receives the authorization.
+#[derive(Debug, Clone)]
+pub struct Domain {
+    pub name: String,
+    pub version: String,
+    pub chain_id: u64,
+    pub verifying_contract: [u8; 20],
+}
+
+impl Domain {
+    /// Domain advertised by the requirements (`asset` plus `extra.name`/`extra.version`).
+    pub fn from_requirements(requirements: &PaymentRequirements) -> Result<Self, VerifyError> {
+        let extra = requirements.extra.as_ref();
+        let field = |key: &str| {
+            extra
+                .and_then(|e| e.get(key))
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+                .ok_or_else(|| VerifyError::new("invalid_payment_requirements", format!("extra.{} is missing", key)))
+        };
+        Ok(Self {
+            name: field("name")?,
+            version: field("version")?,
+            chain_id: x402::chain_id(&requirements.network),
+            verifying_contract: parse_address(&requirements.asset)
+                .map_err(|e| VerifyError::new("invalid_payment_requirements", e))?,
+        })
+    }
+
+    fn separator(&self) -> [u8; 32] {
+        let mut enc = Vec::with_capacity(5 * 32);
+        enc.extend_from_slice(&keccak(DOMAIN_TYPE.as_bytes()));
+        enc.extend_from_slice(&keccak(self.name.as_bytes()));
+        enc.extend_from_slice(&keccak(self.version.as_bytes()));
+        enc.extend_from_slice(&u128_word(self.chain_id as u128));
+        enc.extend_from_slice(&address_word(&self.verifying_contract));
+        keccak(&enc)
+    }
+}
+
+/// The parsed `transferWithAuthorization` message.
+#[derive(Debug, Clone)]
+pub struct TransferAuthorization {
+    pub from: [u8; 20],
+    pub to: [u8; 20],
+    pub value: u128,
+    pub valid_after: u64,
+    pub valid_before: u64,
+    pub nonce: [u8; 32],
+}
+
+impl TransferAuthorization {
+    pub fn parse(auth: &x402::Authorization) -> Result<Self, VerifyError> {
+        let bad = |field: &str, e: String| VerifyError::new("invalid_payload", format!("authorization.{}: {}", field, e));
+        Ok(Self {
+            from: parse_address(&auth.from).map_err(|e| bad("from", e))?,
+            to: parse_address(&auth.to).map_err(|e| bad("to", e))?,
+            value: auth.value.parse().map_err(|_| bad("value", "not a decimal integer".into()))?,
+            valid_after: auth.valid_after.parse().map_err(|_| bad("validAfter", "not a decimal integer".into()))?,
+            valid_before: auth.valid_before.parse().map_err(|_| bad("validBefore", "not a decimal integer".into()))?,
+            nonce: parse_bytes32(&auth.nonce).map_err(|e| bad("nonce", e))?,
+        })
+    }
+
+    fn struct_hash(&self) -> [u8; 32] {
+        let mut enc = Vec::with_capacity(7 * 32);
+        enc.extend_from_slice(&keccak(TRANSFER_TYPE.as_bytes()));
+        enc.extend_from_slice(&address_word(&self.from));
+        enc.extend_from_slice(&address_word(&self.to));
+        enc.extend_from_slice(&u128_word(self.value));
+        enc.extend_from_slice(&u128_word(self.valid_after as u128));
+        enc.extend_from_slice(&u128_word(self.valid_before as u128));
+        enc.extend_from_slice(&self.nonce);
+        keccak(&enc)
+    }
+
+    /// The EIP-712 digest the client is expected to have signed.
+    pub fn signing_hash(&self, domain: &Domain) -> [u8; 32] {
+        let mut enc = Vec::with_capacity(2 + 64);
+        enc.extend_from_slice(&[0x19, 0x01]);
+        enc.extend_from_slice(&domain.separator());
+        enc.extend_from_slice(&self.struct_hash());
+        keccak(&enc)
+    }
+}
+
+/// Verifies signature, recipient, value and validity window (which may not
+/// run past `maxTimeoutSeconds` from now).
+/// Returns the payer address (lowercase hex) on success.
+/// Nonce reuse is checked by the ledger at settlement time.
+pub fn verify(
+    payment: &PaymentPayload,
+    requirements: &PaymentRequirements,
+    now: u64,
+) -> Result<String, VerifyError> {
+    let domain = Domain::from_requirements(requirements)?;
+    let auth = TransferAuthorization::parse(&payment.payload.authorization)?;
+
+    let pay_to = parse_address(&requirements.pay_to)
+        .map_err(|e| VerifyError::new("invalid_payment_requirements", e))?;
+    if auth.to != pay_to {
+        return Err(VerifyError::new(
+            "invalid_exact_evm_payload_recipient_mismatch",
+            format!("authorization.to is {}, expected {}", format_address(&auth.to), requirements.pay_to),
+        ));
+    }
+
+    let required: u128 = requirements.max_amount_required.parse().unwrap_or(u128::MAX);
+    if auth.value < required {
+        return Err(VerifyError::new(
+            "invalid_exact_evm_payload_authorization_value",
+            format!("authorization.value is {}, {} required", auth.value, required),
+        ));
+    }
+
+    if now < auth.valid_after {
+        return Err(VerifyError::new(
+            "invalid_exact_evm_payload_authorization_valid_after",
+            format!("authorization is not valid until {} (now {})", auth.valid_after, now),
+        ));
+    }
+    if now >= auth.valid_before {
+        return Err(VerifyError::new(
+            "invalid_exact_evm_payload_authorization_valid_before",
+            format!("authorization expired at {} (now {})", auth.valid_before, now),
+        ));
+    }
+    if auth.valid_before > now.saturating_add(requirements.max_timeout_seconds) {
+        return Err(VerifyError::new(
+            "invalid_exact_evm_payload_authorization_timeout",
+            format!(
+                "authorization is valid until {}, more than {}s from now ({})",
+                auth.valid_before, requirements.max_timeout_seconds, now
+            ),
+        ));
+    }
+
+    let digest = auth.signing_hash(&domain);
+    let signer = recover_signer(&payment.payload.signature, &digest)?;
+    if signer != auth.from {
+        return Err(VerifyError::new(
+            "invalid_exact_evm_payload_signature",
+            format!(
+                "signature recovers to {}, but authorization.from is {}",
+                format_address(&signer),
+                format_address(&auth.from)
+            ),
+        ));
+    }
+
+    Ok(format_address(&auth.from))
+}
+
+/// Whether the payment was signed for the token `requirements` describe, i.e.
+/// the signature recovers to `authorization.from` under its EIP-712 domain.
+pub fn signed_for(payment: &PaymentPayload, requirements: &PaymentRequirements) -> bool {
+    let (Ok(domain), Ok(auth)) = (
+        Domain::from_requirements(requirements),
+        TransferAuthorization::parse(&payment.payload.authorization),
+    ) else {
+        return false;
+    };
+    recover_signer(&payment.payload.signature, &auth.signing_hash(&domain)).is_ok_and(|signer| signer == auth.from)
+}
+
+/// Recovers the signing address from a 65-byte `r || s || v` signature.
+pub fn recover_signer(signature: &str, digest: &[u8; 32]) -> Result<[u8; 20], VerifyError> {
+    let invalid = |detail: &str| VerifyError::new("invalid_exact_evm_payload_signature", detail);
+    let bytes = hex::decode(signature.trim_start_matches("0x")).map_err(|_| invalid("signature is not hex"))?;
+    if bytes.len() != 65 {
+        return Err(invalid("signature must be 65 bytes (r || s || v)"));
+    }
+
+    let v = match bytes[64] {
+        27 | 28 => bytes[64] - 27,
+        0 | 1 => bytes[64],
+        _ => return Err(invalid("signature has an invalid recovery byte")),
+    };
+    let sig = Signature::from_slice(&bytes[..64]).map_err(|_| invalid("signature is malformed"))?;
+    let recid = RecoveryId::from_byte(v).ok_or_else(|| invalid("signature has an invalid recovery byte"))?;
+    let key = VerifyingKey::recover_from_prehash(digest, &sig, recid)
+        .map_err(|_| invalid("signature does not recover to a public key"))?;
+
+    Ok(address_of(&key))
+}
+
+/// Ethereum address of a secp256k1 public key.
+pub fn address_of(key: &VerifyingKey) -> [u8; 20] {
+    let point = key.to_encoded_point(false);
+    let hash = keccak(&point.as_bytes()[1..]);
+    let mut addr = [0u8; 20];
+    addr.copy_from_slice(&hash[12..]);
+    addr
+}
+
+pub fn format_address(addr: &[u8; 20]) -> String {
+    format!("0x{}", hex::encode(addr))
+}
+
+fn parse_address(s: &str) -> Result<[u8; 20], String> {
+    let bytes = hex::decode(s.trim_start_matches("0x")).map_err(|_| format!("'{}' is not hex", s))?;
+    bytes.try_into().map_err(|_| format!("'{}' is not a 20-byte address", s))
+}
+
+fn parse_bytes32(s: &str) -> Result<[u8; 32], String> {
+    let bytes = hex::decode(s.trim_start_matches("0x")).map_err(|_| format!("'{}' is not hex", s))?;
+    bytes.try_into().map_err(|_| format!("'{}' is not 32 bytes", s))
+}
+
+fn keccak(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+fn u128_word(v: u128) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[16..].copy_from_slice(&v.to_be_bytes());
+    word
+}
+
+fn address_word(addr: &[u8; 20]) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(addr);
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::SigningKey;
+    use url::Url;
+    use xdr_ledger::tokens::TokenRegistry;
+    use xdr_ledger::Amount;
+
+    use crate::pricing::{AssetPrice, PaymentScheme};
+    use crate::x402::{Authorization, ExactEvmPayload};
+
+    const NOW: u64 = 1_700_000_000;
+
+    /// Requirements for paying $0.01 in `symbol` on the testnet.
+    fn requirements(symbol: &str) -> PaymentRequirements {
+        let token = TokenRegistry::default().tokens("cronos-testnet").into_iter().find(|t| t.symbol == symbol).unwrap();
+        let option = AssetPrice { currency: symbol.to_string(), price: Amount::from_units(10_000) };
+        let resource = Url::parse("https://api.openai.com/v1/chat/completions").unwrap();
+        x402::requirements(&option, PaymentScheme::Exact, &token, &resource, "cronos-testnet")
+    }
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    /// A payment of `value` atomic units from `key`, valid from `valid_after`
+    /// until `valid_before`.
+    fn authorization(key: &SigningKey, requirements: &PaymentRequirements, value: u128, valid_after: u64, valid_before: u64) -> Authorization {
+        Authorization {
+            from: format_address(&address_of(key.verifying_key())),
+            to: requirements.pay_to.clone(),
+            value: value.to_string(),
+            valid_after: valid_after.to_string(),
+            valid_before: valid_before.to_string(),
+            nonce: format!("0x{}", "ab".repeat(32)),
+        }
+    }
+
+    /// `authorization` signed by `key` under `domain`.
+    fn sign(key: &SigningKey, domain: &Domain, requirements: &PaymentRequirements, authorization: Authorization) -> PaymentPayload {
+        let digest = TransferAuthorization::parse(&authorization).unwrap().signing_hash(domain);
+        let (signature, recid) = key.sign_prehash_recoverable(&digest).unwrap();
+        let mut bytes = signature.to_bytes().to_vec();
+        bytes.push(27 + recid.to_byte());
+        PaymentPayload {
+            x402_version: 1,
+            scheme: requirements.scheme.clone(),
+            network: requirements.network.clone(),
+            payload: ExactEvmPayload { signature: format!("0x{}", hex::encode(bytes)), authorization },
+        }
+    }
+
+    /// A payment of `value` atomic units signed by `key` under the domain of `requirements`.
+    fn signed(key: &SigningKey, requirements: &PaymentRequirements, value: u128) -> PaymentPayload {
+        let authorization = authorization(key, requirements, value, 0, NOW + 60);
+        sign(key, &Domain::from_requirements(requirements).unwrap(), requirements, authorization)
+    }
+
+    #[test]
+    fn signatures_recover_to_the_payer() {
+        let (key, usdc) = (key(7), requirements("USDC"));
+        let payment = signed(&key, &usdc, 10_000);
+        let payer = verify(&payment, &usdc, NOW).unwrap();
+        assert_eq!(payer, format_address(&address_of(key.verifying_key())));
+
+        // `v` may also be sent as 0/1
+        let mut raw = payment.clone();
+        let v = raw.payload.signature.split_off(raw.payload.signature.len() - 2);
+        raw.payload.signature.push_str(if v == "1b" { "00" } else { "01" });
+        assert_eq!(verify(&raw, &usdc, NOW).unwrap(), payer);
+    }
+
+    #[test]
+    fn tampered_authorizations_do_not_recover_to_the_payer() {
+        let usdc = requirements("USDC");
+        let mut payment = signed(&key(7), &usdc, 10_000);
+        payment.payload.authorization.value = "20000".to_string();
+        assert_eq!(verify(&payment, &usdc, NOW).unwrap_err().code, "invalid_exact_evm_payload_signature");
+    }
+
+    #[test]
+    fn signers_other_than_from_are_rejected() {
+        let usdc = requirements("USDC");
+        // Signed by one key, claimed for another
+        let authorization = authorization(&key(8), &usdc, 10_000, 0, NOW + 60);
+        let payment = sign(&key(7), &Domain::from_requirements(&usdc).unwrap(), &usdc, authorization);
+        let err = verify(&payment, &usdc, NOW).unwrap_err();
+        assert_eq!(err.code, "invalid_exact_evm_payload_signature");
+        assert!(err.detail.contains(&format_address(&address_of(key(7).verifying_key()))), "{}", err.detail);
+    }
+
+    #[test]
+    fn signatures_are_bound_to_the_chain_and_the_token_contract() {
+        let usdc = requirements("USDC");
+        let domain = Domain::from_requirements(&usdc).unwrap();
+
+        // Signed for mainnet (chain 25), presented on the testnet (chain 338)
+        let mainnet = Domain { chain_id: x402::chain_id("cronos-mainnet"), ..domain.clone() };
+        let payment = sign(&key(7), &mainnet, &usdc, authorization(&key(7), &usdc, 10_000, 0, NOW + 60));
+        assert_eq!(verify(&payment, &usdc, NOW).unwrap_err().code, "invalid_exact_evm_payload_signature");
+
+        // Signed for another token contract
+        let other = Domain { verifying_contract: [0x11; 20], ..domain };
+        let payment = sign(&key(7), &other, &usdc, authorization(&key(7), &usdc, 10_000, 0, NOW + 60));
+        assert_eq!(verify(&payment, &usdc, NOW).unwrap_err().code, "invalid_exact_evm_payload_signature");
+    }
+
+    #[test]
+    fn authorization_fields_are_checked_against_the_requirements() {
+        let usdc = requirements("USDC");
+        let short = signed(&key(7), &usdc, 9_999);
+        assert_eq!(verify(&short, &usdc, NOW).unwrap_err().code, "invalid_exact_evm_payload_authorization_value");
+
+        let mut elsewhere = authorization(&key(7), &usdc, 10_000, 0, NOW + 60);
+        elsewhere.to = format_address(&[0x22; 20]);
+        let payment = sign(&key(7), &Domain::from_requirements(&usdc).unwrap(), &usdc, elsewhere);
+        assert_eq!(verify(&payment, &usdc, NOW).unwrap_err().code, "invalid_exact_evm_payload_recipient_mismatch");
+    }
+
+    #[test]
+    fn authorizations_are_only_good_inside_their_window() {
+        let usdc = requirements("USDC");
+        let domain = Domain::from_requirements(&usdc).unwrap();
+        let window = |valid_after, valid_before| sign(&key(7), &domain, &usdc, authorization(&key(7), &usdc, 10_000, valid_after, valid_before));
+
+        let payment = window(NOW - 10, NOW + 50);
+        assert!(verify(&payment, &usdc, NOW - 10).is_ok());
+        assert_eq!(verify(&payment, &usdc, NOW - 11).unwrap_err().code, "invalid_exact_evm_payload_authorization_valid_after");
+        assert!(verify(&payment, &usdc, NOW + 49).is_ok());
+        // `validBefore` is exclusive
+        assert_eq!(verify(&payment, &usdc, NOW + 50).unwrap_err().code, "invalid_exact_evm_payload_authorization_valid_before");
+        assert_eq!(verify(&window(0, NOW - 1), &usdc, NOW).unwrap_err().code, "invalid_exact_evm_payload_authorization_valid_before");
+    }
+
+    #[test]
+    fn authorizations_may_not_outlive_the_max_timeout() {
+        let usdc = requirements("USDC");
+        let domain = Domain::from_requirements(&usdc).unwrap();
+        let until = |valid_before| sign(&key(7), &domain, &usdc, authorization(&key(7), &usdc, 10_000, 0, valid_before));
+
+        assert!(verify(&until(NOW + usdc.max_timeout_seconds), &usdc, NOW).is_ok());
+        let err = verify(&until(NOW + usdc.max_timeout_seconds + 1), &usdc, NOW).unwrap_err();
+        assert_eq!(err.code, "invalid_exact_evm_payload_authorization_timeout");
+        // Good for years: a leaked authorization would stay spendable
+        assert_eq!(verify(&until(u64::MAX), &usdc, NOW).unwrap_err().code, "invalid_exact_evm_payload_authorization_timeout");
+    }
+
+    #[test]
+    fn the_signing_domain_picks_the_asset() {
+        let accepts = [requirements("USDC"), requirements("USDT")];
+        let payment = signed(&key(7), &accepts[1], 10_000);
+        assert!(!signed_for(&payment, &accepts[0]));
+        assert!(signed_for(&payment, &accepts[1]));
+    }
+}
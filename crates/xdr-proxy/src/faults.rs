@@ -0,0 +1,235 @@
+//! Applies the response-side chaos faults the engine rolled to an upstream
+//! response: status and headers are rewritten up front, and the body is
+//! wrapped so it can be throttled, cut short or reset mid-stream.
+
+use axum::body::Body;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::Response;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use std::io;
+use std::time::Duration;
+use tracing::warn;
+use xdr_chaos::response::{ResponseFault, ResponseFaultKind, DEFAULT_ERROR_BODY, DEFAULT_WRONG_CONTENT_TYPE};
+
+/// A throttled body goes out in this many slices per second.
+const SLICES_PER_SEC: u64 = 10;
+
+#[derive(Clone, Copy)]
+struct Cut {
+    at: usize,
+    /// Fail the stream (the client sees a dropped connection) instead of ending it
+    reset: bool,
+}
+
+pub(crate) async fn apply(faults: &[ResponseFault], response: Response) -> Response {
+    if faults.is_empty() {
+        return response;
+    }
+    let find = |kind: ResponseFaultKind| faults.iter().find(|f| f.kind == kind);
+    let (mut parts, mut body) = response.into_parts();
+
+    if let Some(fault) = find(ResponseFaultKind::ErrorBody) {
+        parts.status = StatusCode::OK;
+        parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        body = Body::from(fault.body.clone().unwrap_or_else(|| DEFAULT_ERROR_BODY.to_string()));
+    }
+    if let Some(fault) = find(ResponseFaultKind::WrongContentType) {
+        let content_type = fault.content_type.as_deref().unwrap_or(DEFAULT_WRONG_CONTENT_TYPE);
+        if let Ok(value) = HeaderValue::from_str(content_type) {
+            parts.headers.insert(header::CONTENT_TYPE, value);
+        }
+    }
+    if find(ResponseFaultKind::MalformedJson).is_some() {
+        body = match axum::body::to_bytes(body, crate::MAX_BUFFERED_BODY_BYTES).await {
+            Ok(bytes) => Body::from(corrupt_json(&bytes)),
+            // Too large to buffer, or the upstream failed mid-body: the agent sees
+            // the connection drop rather than an empty 200
+            Err(e) => {
+                warn!(target: "xdr_core", "malformed_json couldn't buffer the response: {}", e);
+                parts.headers.remove(header::CONTENT_LENGTH);
+                Body::from_stream(futures_util::stream::once(async move { Err::<Bytes, _>(io::Error::other(e)) }))
+            }
+        };
+    }
+
+    // The earliest cut wins when truncate and connection_reset both hit
+    let cut = faults.iter()
+        .filter(|f| matches!(f.kind, ResponseFaultKind::Truncate | ResponseFaultKind::ConnectionReset))
+        .min_by_key(|f| f.cut_after())
+        .map(|f| Cut { at: f.cut_after(), reset: f.kind == ResponseFaultKind::ConnectionReset });
+    let bytes_per_sec = find(ResponseFaultKind::SlowBody).and_then(|f| f.bytes_per_sec).filter(|b| *b > 0);
+    if cut.is_some() || bytes_per_sec.is_some() {
+        parts.headers.remove(header::CONTENT_LENGTH);
+        body = Body::from_stream(wrap(body, cut, bytes_per_sec));
+    }
+    Response::from_parts(parts, body)
+}
+
+/// Slips a trailing comma in before the closing bracket (`{"id":1,}`), a
+/// glitch strict parsers reject; bodies without one get a stray `}`.
+fn corrupt_json(body: &[u8]) -> Bytes {
+    let mut out = body.to_vec();
+    match body.iter().rposition(|b| *b == b'}' || *b == b']') {
+        Some(close) => out.insert(close, b','),
+        None => out.push(b'}'),
+    }
+    out.into()
+}
+
+struct BodyState {
+    inner: axum::body::BodyDataStream,
+    pending: Bytes,
+    sent: usize,
+    done: bool,
+}
+
+fn wrap(body: Body, cut: Option<Cut>, bytes_per_sec: Option<u64>) -> impl Stream<Item = io::Result<Bytes>> {
+    let state = BodyState { inner: body.into_data_stream(), pending: Bytes::new(), sent: 0, done: false };
+    let reset = || Err(io::Error::from(io::ErrorKind::ConnectionReset));
+
+    futures_util::stream::unfold(state, move |mut st| async move {
+        loop {
+            if st.done {
+                return None;
+            }
+            if let Some(cut) = cut.filter(|c| st.sent >= c.at) {
+                st.done = true;
+                if cut.reset {
+                    // Let hyper flush what was sent so far, or the agent sees an empty reply
+                    tokio::task::yield_now().await;
+                }
+                return cut.reset.then(|| (reset(), st));
+            }
+            if st.pending.is_empty() {
+                match st.inner.next().await {
+                    Some(Ok(chunk)) => st.pending = chunk,
+                    Some(Err(e)) => {
+                        st.done = true;
+                        return Some((Err(io::Error::other(e)), st));
+                    }
+                    // A reset still drops the connection when the body is shorter than the cut
+                    None => {
+                        st.done = true;
+                        if !cut.is_some_and(|c| c.reset) {
+                            return None;
+                        }
+                        tokio::task::yield_now().await;
+                        return Some((reset(), st));
+                    }
+                }
+                continue;
+            }
+
+            let mut take = st.pending.len();
+            if let Some(cut) = cut {
+                take = take.min(cut.at - st.sent);
+            }
+            if let Some(bytes_per_sec) = bytes_per_sec {
+                take = take.min((bytes_per_sec / SLICES_PER_SEC).max(1) as usize);
+                tokio::time::sleep(Duration::from_secs_f64(take as f64 / bytes_per_sec as f64)).await;
+            }
+            st.sent += take;
+            return Some((Ok(st.pending.split_to(take)), st));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// "0123456789", streamed as two chunks.
+    fn body() -> Body {
+        Body::from_stream(futures_util::stream::iter([Ok::<_, io::Error>(Bytes::from("01234")), Ok(Bytes::from("56789"))]))
+    }
+
+    /// Drains the stream into its chunks and the error it ended with, if any.
+    async fn drain(stream: impl Stream<Item = io::Result<Bytes>>) -> (Vec<Bytes>, Option<io::ErrorKind>) {
+        let mut stream = std::pin::pin!(stream);
+        let mut chunks = Vec::new();
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(chunk) => chunks.push(chunk),
+                Err(e) => return (chunks, Some(e.kind())),
+            }
+        }
+        (chunks, None)
+    }
+
+    fn joined(chunks: &[Bytes]) -> Vec<u8> {
+        chunks.concat()
+    }
+
+    #[tokio::test]
+    async fn truncate_ends_the_body_cleanly_at_the_cut() {
+        let (chunks, error) = drain(wrap(body(), Some(Cut { at: 7, reset: false }), None)).await;
+        assert_eq!(joined(&chunks), b"0123456");
+        assert_eq!(error, None);
+
+        let (chunks, error) = drain(wrap(body(), Some(Cut { at: 64, reset: false }), None)).await;
+        assert_eq!(joined(&chunks), b"0123456789");
+        assert_eq!(error, None);
+    }
+
+    #[tokio::test]
+    async fn connection_reset_fails_the_stream_after_the_cut() {
+        let (chunks, error) = drain(wrap(body(), Some(Cut { at: 7, reset: true }), None)).await;
+        assert_eq!(joined(&chunks), b"0123456");
+        assert_eq!(error, Some(io::ErrorKind::ConnectionReset));
+
+        let (chunks, error) = drain(wrap(body(), Some(Cut { at: 0, reset: true }), None)).await;
+        assert!(chunks.is_empty());
+        assert_eq!(error, Some(io::ErrorKind::ConnectionReset));
+    }
+
+    #[tokio::test]
+    async fn connection_reset_past_the_end_still_drops_the_connection() {
+        let (chunks, error) = drain(wrap(body(), Some(Cut { at: 64, reset: true }), None)).await;
+        assert_eq!(joined(&chunks), b"0123456789");
+        assert_eq!(error, Some(io::ErrorKind::ConnectionReset));
+    }
+
+    #[tokio::test]
+    async fn slow_body_sends_throttled_slices() {
+        let started = Instant::now();
+        // 100 B/s goes out as 10-byte slices every 100ms
+        let (chunks, error) = drain(wrap(Body::from("x".repeat(25)), None, Some(100))).await;
+        assert_eq!(chunks.iter().map(Bytes::len).collect::<Vec<_>>(), [10, 10, 5]);
+        assert_eq!(error, None);
+        assert!(started.elapsed() >= Duration::from_millis(240));
+    }
+
+    #[tokio::test]
+    async fn malformed_json_no_longer_parses() {
+        let response = Response::new(Body::from(r#"{"id":1}"#));
+        let response = apply(&[malformed()], response).await;
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&bytes[..], br#"{"id":1,}"#);
+        assert!(serde_json::from_slice::<serde_json::Value>(&bytes).is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_json_fails_bodies_it_cannot_buffer() {
+        let huge = Response::new(Body::from(vec![b' '; crate::MAX_BUFFERED_BODY_BYTES + 1]));
+        let response = apply(&[malformed()], huge).await;
+        assert!(axum::body::to_bytes(response.into_body(), usize::MAX).await.is_err());
+
+        let broken = Body::from_stream(futures_util::stream::iter([Ok(Bytes::from("{")), Err(io::Error::from(io::ErrorKind::ConnectionReset))]));
+        let response = apply(&[malformed()], Response::new(broken)).await;
+        assert!(axum::body::to_bytes(response.into_body(), usize::MAX).await.is_err());
+    }
+
+    fn malformed() -> ResponseFault {
+        ResponseFault {
+            kind: ResponseFaultKind::MalformedJson,
+            rate: 1.0,
+            target: Default::default(),
+            bytes_per_sec: None,
+            after_bytes: None,
+            body: None,
+            content_type: None,
+        }
+    }
+}
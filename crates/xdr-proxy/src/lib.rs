@@ -1,29 +1,74 @@
 use axum::{
     body::Body,
-    extract::{Path, Request, State},
-    http::{HeaderMap, HeaderValue, StatusCode},
-    response::{IntoResponse, Response, Json},
-    routing::{any, get, post},
+    extract::{DefaultBodyLimit, Path, Query, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{any, delete, get, post},
     Router,
     
 };
 use reqwest::Client;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::collections::VecDeque;
-use std::time::Instant;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
 use tower_http::trace::{self, TraceLayer};
 use tracing::{error, info, warn, Level};
 use url::Url;
-use xdr_ledger::Ledger;
-use xdr_chaos::{ChaosEngine, ChaosConfig};
-use xdr_trace::{Trace, EventCategory};
+use xdr_ledger::policy::SpendingPolicy;
+use xdr_ledger::tokens::{Token, TokenRegistry};
+use xdr_ledger::escrow::Hold;
+use xdr_ledger::groups::{BudgetGroup, GroupUpdate};
+use xdr_ledger::journal::JournalQuery;
+use xdr_ledger::{AgentState, Amount, CancelError, Invoice, InvoiceResource, InvoiceStatus, Ledger, PaymentError, PaymentReceipt, RemoveGroupError};
+use xdr_chaos::scenario::Scenario;
+use xdr_chaos::rules::RequestType;
+use xdr_chaos::{ChaosConfig, ChaosContext, ChaosEngine, DecisionLog};
+use xdr_trace::{Trace, EventCategory, UsageCharge};
 use serde_json::json; 
 
+pub mod approvals;
+pub mod auth;
+pub mod cassette;
+pub mod control;
+pub mod eip3009;
+mod faults;
+pub mod miner;
+pub mod mock_ai;
+pub mod pricing;
+pub mod ratelimit;
+pub mod rpc;
+pub mod services;
+pub mod usage;
+pub mod wire;
+pub mod x402;
+
+use auth::{AgentAuth, AgentCredential, AuthConfig, AuthRequest, Authenticated};
+use control::{AuditEntry, AuditLog, ControlAuth, ControlConfig, Denied, Permission};
+use approvals::{Approval, ApprovalConfig, ApprovalMode, ApprovalQueue, ApprovalStatus, Decision, PaymentRequest};
+use cassette::{
+    CassetteEntry, CassetteHeader, ControlChange, Interaction, Player, RecordedBody,
+    RecordedRequest, RecordedResponse, Recorder, Session,
+};
+use mock_ai::{MockAi, MockAiConfig};
+use pricing::{AssetPrice, PaymentScheme, PricingConfig, PricingTable};
+use ratelimit::{RateLimitConfig, RateLimiter};
+use services::{AgentService, ServiceConfig, ServiceRegistry};
+use wire::Json;
+use x402::PaymentProtocol;
+
 // --- Constants ---
 const HEADER_UPSTREAM_HOST: &str = "x-upstream-host";
 const HEADER_AGENT_ID: &str = "x-agent-id";
 const HEADER_SIMULATE_PAYMENT: &str = "x-simulate-payment"; 
+/// How often stale invoices are swept
+const INVOICE_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+/// Expired or cancelled invoices are kept this long so late payers still get
+/// `invoice_expired` / `invoice_cancelled` rather than `invoice_invalid`
+const INVOICE_RETENTION_SECS: u64 = 600;
+/// Largest body the proxy buffers whole (it streams everything else)
+const MAX_BUFFERED_BODY_BYTES: usize = 16 * 1024 * 1024;
 
 // --- State ---
 #[derive(Clone)]
@@ -31,22 +76,107 @@ struct AppState {
     client: Client,
     ledger: Ledger,
     chaos: ChaosEngine,
+    pricing: PricingTable,
+    approvals: ApprovalQueue,
+    rate_limits: RateLimiter,
+    services: ServiceRegistry,
+    auth: AgentAuth,
+    control: ControlAuth,
+    audit: AuditLog,
     traces: Arc<Mutex<VecDeque<Trace>>>,
+    /// Updates for traces whose response is still streaming (e.g. usage
+    /// charges), keyed by trace id until the trace is committed
+    amendments: Arc<Mutex<HashMap<String, Vec<TraceAmendment>>>>,
     network: String,
+    protocol: PaymentProtocol,
+    session: Option<Arc<Session>>,
+    mock_rpc: bool,
+    mock_ai: Option<Arc<MockAi>>,
+}
+
+type TraceAmendment = Box<dyn FnOnce(&mut Trace) + Send>;
+
+impl AppState {
+    /// Adds a finished request's trace to the ring buffer, with any updates
+    /// that arrived before it was committed.
+    fn commit_trace(&self, mut trace: Trace) {
+        let mut store = self.traces.lock().unwrap();
+        for amend in self.amendments.lock().unwrap().remove(&trace.id).unwrap_or_default() {
+            amend(&mut trace);
+        }
+        if store.len() >= 1000 { store.pop_front(); } // Ring buffer logic
+        store.push_back(trace);
+    }
+
+    /// Expects `amend_trace` calls for this trace after the pipeline returns.
+    fn expect_amendments(&self, trace_id: &str) {
+        self.amendments.lock().unwrap().entry(trace_id.to_string()).or_default();
+    }
+
+    /// Updates a trace after its response left the pipeline. Dropped if the
+    /// trace has already been pushed out of the ring buffer.
+    fn amend_trace(&self, trace_id: &str, amend: impl FnOnce(&mut Trace) + Send + 'static) {
+        let mut store = self.traces.lock().unwrap();
+        if let Some(trace) = store.iter_mut().rev().find(|t| t.id == trace_id) {
+            amend(trace);
+        } else if let Some(pending) = self.amendments.lock().unwrap().get_mut(trace_id) {
+            pending.push(Box::new(amend));
+        }
+    }
+
+    /// Logs a control-plane change into the cassette when recording.
+    fn record_control(&self, change: ControlChange) {
+        if let Some(Session::Record(recorder)) = self.session.as_deref() {
+            if let Err(e) = recorder.write(&CassetteEntry::Control(change)) {
+                warn!(target: "xdr_core", "Failed to write cassette entry: {}", e);
+            }
+        }
+    }
+}
+
+/// Startup options for the proxy. Shared state (ledger, chaos, pricing,
+/// approvals, traces) is passed to `run_server` separately.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub port: u16,
+    pub network: String,
+    pub protocol: PaymentProtocol,
+    pub session: Option<SessionMode>,
+    /// Answer RPC-classified requests from the built-in mock node
+    pub mock_rpc: bool,
+    /// Answer AI-inference requests from the built-in OpenAI/Anthropic mock
+    pub mock_ai: Option<MockAiConfig>,
+    /// Interval mining. None mines every payment instantly (automine);
+    /// zero leaves mining to `/_xdr/mine`.
+    pub block_time: Option<Duration>,
+    /// Assets per network; the active network's list is loaded into the ledger
+    pub tokens: TokenRegistry,
+    /// Simulated upstream quotas; empty forwards everything
+    pub rate_limits: RateLimitConfig,
+    /// Services agents sell to each other, reached at `<agent_id>.agent`
+    pub services: ServiceConfig,
+    /// Seconds an invoice stays payable; None never expires
+    pub invoice_ttl: Option<u64>,
+    /// Agent credentials; empty (and not strict) trusts `X-Agent-ID`
+    pub auth: AuthConfig,
+    /// Where the `/_xdr` control plane listens and who may use it
+    pub control: ControlConfig,
 }
 
-// --- Classification Enum ---
-#[derive(Debug, Clone, PartialEq)]
-enum RequestType {
-    AiInference,
-    Payment,
-    Rpc,
-    Unknown,
+/// Whether (and how) the proxy session is captured to a cassette file.
+#[derive(Debug, Clone)]
+pub enum SessionMode {
+    /// Write every interaction to this cassette
+    Record(String),
+    /// Serve upstream responses from this cassette and flag divergences
+    Replay(String),
 }
 
 #[derive(serde::Deserialize)]
 struct BudgetRequest {
-    amount: f64,
+    amount: Amount,
+    #[serde(default = "pricing::default_currency")]
+    currency: String,
 }
 
 async fn set_agent_budget(
@@ -54,43 +184,213 @@ async fn set_agent_budget(
     Path(agent_id): Path<String>,
     Json(payload): Json<BudgetRequest>,
 ) -> impl IntoResponse {
-    state.ledger.set_balance(&agent_id, payload.amount);
-    info!(target: "xdr_core", "💰 Admin set balance for {} to ${}", agent_id, payload.amount);
-    StatusCode::OK
+    if let Err(e) = state.ledger.set_balance(&agent_id, &payload.currency, payload.amount) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    state.record_control(ControlChange::Balance {
+        agent_id: agent_id.clone(),
+        amount: payload.amount,
+        currency: payload.currency.clone(),
+    });
+    info!(target: "xdr_core", "💰 Admin set {} balance for {} to {}", payload.currency, agent_id, payload.amount);
+    StatusCode::OK.into_response()
+}
+
+async fn get_agent_policy(
+    State(state): State<AppState>,
+    Path(agent_id): Path<String>,
+) -> impl IntoResponse {
+    match state.ledger.get_state(&agent_id) {
+        Some(agent) => Json(agent.policy).into_response(),
+        None => (StatusCode::NOT_FOUND, "Agent not found").into_response(),
+    }
+}
+
+async fn set_agent_policy(
+    State(state): State<AppState>,
+    Path(agent_id): Path<String>,
+    Json(policy): Json<SpendingPolicy>,
+) -> impl IntoResponse {
+    if let Err(e) = state.ledger.set_policy(&agent_id, policy.clone()) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    info!(target: "xdr_core", "🛡️  Spending policy for {} set to [{}]", agent_id, policy.summary());
+    state.record_control(ControlChange::Policy { agent_id, policy });
+    StatusCode::OK.into_response()
 }
 
 /// Runs the XDR proxy server with externally provided state.
-/// This allows the TUI to share the same Ledger, ChaosEngine, and trace buffer.
+/// This allows the TUI to share the same Ledger, ChaosEngine, approval queue and trace buffer.
 pub async fn run_server(
-    port: u16, 
-    network: String,
+    config: ServerConfig,
     ledger: Ledger,
     chaos: ChaosEngine,
+    pricing: PricingTable,
+    approvals: ApprovalQueue,
     traces: Arc<Mutex<VecDeque<Trace>>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let ServerConfig { port, network, protocol, session, mock_rpc, mock_ai, block_time, tokens, rate_limits: rate_limit_config, services: service_config, invoice_ttl, auth: auth_config, control: control_config } = config;
     let client = Client::builder()
         .redirect(reqwest::redirect::Policy::none())
         .build()?;
 
-    let state = AppState { client, ledger, chaos, traces, network: network.clone() };
+    ledger.set_tokens(tokens.tokens(&network));
+    ledger.set_invoice_ttl(invoice_ttl);
+    let rate_limits = RateLimiter::new();
+    rate_limits.set_config(rate_limit_config)?;
+    let services = ServiceRegistry::new();
+    services.set_config(service_config)?;
+    let auth = AgentAuth::new();
+    auth.set_config(auth_config)?;
+    control_config.validate()?;
+    let control = ControlAuth::new(control_config.tokens)?;
+    let audit = AuditLog::open(control_config.audit_log.as_ref())?;
+    let mock_ai_enabled = mock_ai.is_some();
+    let mock_ai = mock_ai.map(MockAi::new).transpose()?.map(Arc::new);
+
+    let (network, protocol, session) = match session {
+        None => (network, protocol, None),
+        Some(SessionMode::Record(path)) => {
+            // Fresh id seed per recording; stored in the header for replay
+            let seed = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0);
+            ledger.reseed(seed);
+            let header = CassetteHeader {
+                version: cassette::CASSETTE_VERSION,
+                network: network.clone(),
+                protocol: format!("{:?}", protocol),
+                ledger_seed: seed,
+                ledger: ledger.snapshot(),
+                chaos: chaos.get_config(),
+                scenario: chaos.get_scenario(),
+                pricing: pricing.get_config(),
+                tokens: ledger.tokens(),
+                approvals: approvals.get_config(),
+                rate_limits: rate_limits.get_config(),
+                invoice_ttl: ledger.invoice_ttl(),
+                services: services.get_config(),
+            };
+            info!(target: "xdr_core", "⏺️  Recording session to {}", path);
+            (network, protocol, Some(Arc::new(Session::Record(Recorder::create(&path, header)?))))
+        }
+        Some(SessionMode::Replay(path)) => {
+            let player = Player::load(&path)?;
+            let header = player.header.clone();
+            ledger.set_tokens(header.tokens);
+            ledger.restore(header.ledger);
+            ledger.reseed(header.ledger_seed);
+            chaos.set_config(header.chaos)?;
+            if let Some(scenario) = header.scenario {
+                chaos.load_scenario(scenario)?;
+            }
+            pricing.set_config(header.pricing)?;
+            approvals.set_config(header.approvals)?;
+            rate_limits.set_config(header.rate_limits)?;
+            services.set_config(header.services)?;
+            ledger.set_invoice_ttl(header.invoice_ttl);
+            info!(target: "xdr_core", "▶️  Replaying session from {} (network and protocol taken from cassette)", path);
+            let protocol = header.protocol.parse().unwrap_or(protocol);
+            (header.network, protocol, Some(Arc::new(Session::Replay(Box::new(player)))))
+        }
+    };
+
+    if let Some(block_time) = block_time {
+        ledger.set_automine(false);
+        if block_time.is_zero() {
+            info!(target: "xdr_core", "⛏️  Manual mining: payments stay pending until POST /_xdr/mine");
+        } else {
+            info!(target: "xdr_core", "⛏️  Mining a block every {:?}", block_time);
+            miner::spawn_clock(ledger.clone(), chaos.clone(), block_time);
+        }
+    }
+
+    spawn_invoice_sweeper(ledger.clone());
+
+    // Payees get a wallet up front, so their balance shows before the first sale
+    for service in services.get_config().services {
+        ledger.register_or_get(&service.agent_id);
+        info!(target: "xdr_core", "🏪 Agent {} sells at {} (served from {})", service.agent_id, service.host(), service.url);
+    }
+
+    let state = AppState { client, ledger, chaos, pricing, approvals, rate_limits, services, auth, control, audit, traces, amendments: Default::default(), network: network.clone(), protocol, session, mock_rpc, mock_ai };
 
-    let app = Router::new()
-        // 1. Management Routes (Internal)
+    // 1. Management Routes (Internal), behind operator tokens
+    let control_routes = Router::new()
         .route("/_xdr/status/:agent_id", get(get_agent_status))
+        .route("/_xdr/agents/:agent_id/transactions", get(get_agent_transactions))
         .route("/_xdr/budget/:agent_id", post(set_agent_budget))
-        .route("/_xdr/chaos", post(update_chaos_config))
+        .route("/_xdr/policy/:agent_id", get(get_agent_policy).post(set_agent_policy))
+        .route("/_xdr/chaos", get(get_chaos_config).post(update_chaos_config))
+        .route("/_xdr/chaos/scenario", get(get_chaos_scenario).post(load_chaos_scenario))
+        .route("/_xdr/pricing", get(get_pricing).post(update_pricing))
+        .route("/_xdr/ratelimits", get(get_rate_limits).post(update_rate_limits))
+        .route("/_xdr/auth", get(get_auth).post(update_auth))
+        .route("/_xdr/auth/strict", post(set_auth_strict))
+        .route("/_xdr/auth/agents", post(register_agent_credential))
+        .route("/_xdr/auth/agents/:agent_id", delete(remove_agent_credential))
+        .route("/_xdr/tokens", get(get_tokens).post(add_token))
+        .route("/_xdr/approvals", get(list_approvals))
+        .route("/_xdr/approvals/config", get(get_approval_config).post(update_approval_config))
+        .route("/_xdr/approvals/:id", get(get_approval))
+        .route("/_xdr/approvals/:id/approve", post(approve_payment))
+        .route("/_xdr/approvals/:id/deny", post(deny_payment))
+        .route("/_xdr/snapshot", get(list_snapshots))
+        .route("/_xdr/snapshot/save/:name", post(save_snapshot))
+        .route("/_xdr/snapshot/load/:name", post(load_snapshot))
         .route("/_xdr/traces", get(get_traces))
-        // 2. Proxy Routes (Catch-all)
-        .route("/*path", any(proxy_handler)) 
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-                .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
-        )
-        .with_state(state);
+        .route("/_xdr/replay", get(get_replay_report))
+        .route("/_xdr/mine", post(mine_blocks))
+        .route("/_xdr/chain", get(get_chain_info))
+        .route("/_xdr/tx/:hash", get(get_transaction))
+        .route("/_xdr/invoices", get(list_invoices))
+        .route("/_xdr/invoices/:id", get(get_invoice))
+        .route("/_xdr/invoices/:id/cancel", post(cancel_invoice))
+        .route("/_xdr/holds", get(list_holds))
+        .route("/_xdr/holds/:agent_id", get(list_agent_holds))
+        .route("/_xdr/groups", get(list_groups).post(upsert_group))
+        .route("/_xdr/groups/:id", get(get_group).delete(delete_group))
+        .route("/_xdr/groups/:id/balance", post(set_group_balance))
+        .route("/_xdr/groups/:id/members", post(add_group_member))
+        .route("/_xdr/groups/:id/members/:agent_id", delete(remove_group_member))
+        .route("/_xdr/services", get(list_services).post(register_service))
+        .route("/_xdr/services/:agent_id", delete(remove_service))
+        .route("/_xdr/audit", get(get_audit_log))
+        .route_layer(middleware::from_fn_with_state(state.clone(), control_guard))
+        .layer(DefaultBodyLimit::max(control::MAX_CONTROL_BODY_BYTES));
+
+    // 2. Proxy Routes (Catch-all; RPC endpoints usually live at the root).
+    // The mock chain's JSON-RPC endpoint is for agents, so it stays here.
+    let proxy_routes = Router::new()
+        .route("/_xdr/rpc", post(rpc_endpoint))
+        .route("/", any(proxy_handler))
+        .route("/*path", any(proxy_handler));
+
+    let control_addr = control_config.bind;
+    if state.control.is_open() {
+        warn!(target: "xdr_core", "🔓 INSECURE: the control plane on {} is open (--insecure-open-control-plane): anyone who can reach it has full admin access", control_addr);
+    }
+    let control_app = with_http_trace(durable(control_routes, &state)).with_state(state.clone());
+    let control_listener = tokio::net::TcpListener::bind(control_addr).await?;
+    info!(target: "xdr_core", "🎛️  Control plane listening on {}", control_addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(control_listener, control_app).await {
+            warn!(target: "xdr_core", "Control plane stopped: {}", e);
+        }
+    });
+    let elsewhere = format!("The control plane is served on {}", control_addr);
+    let app = proxy_routes.route("/_xdr/*rest", any(move || async move { (StatusCode::NOT_FOUND, elsewhere) }));
+    let app = with_http_trace(durable(app, &state)).with_state(state);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    info!(target: "xdr_core", "🌍 Network Mode: {} (Chain ID: 338)", network);
+    info!(target: "xdr_core", "🌍 Network Mode: {} (Chain ID: {}) | Protocol: {:?}", network, x402::chain_id(&network), protocol);
+    if mock_rpc {
+        info!(target: "xdr_core", "⛓️  RPC requests served by the mock node");
+    }
+    if mock_ai_enabled {
+        info!(target: "xdr_core", "🤖 AI requests served by the mock OpenAI/Anthropic provider");
+    }
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
     axum::serve(listener, app).await?;
@@ -98,6 +398,90 @@ pub async fn run_server(
     Ok(())
 }
 
+fn with_http_trace(router: Router<AppState>) -> Router<AppState> {
+    router.layer(
+        TraceLayer::new_for_http()
+            .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+            .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
+    )
+}
+
+/// Answers only once the ledger changes a request made are on disk.
+fn durable(router: Router<AppState>, state: &AppState) -> Router<AppState> {
+    router.layer(middleware::from_fn_with_state(state.clone(), commit_ledger))
+}
+
+/// Syncs the ledger before the response goes out, so an acknowledged payment
+/// survives a crash. Once the ledger can't be written nothing is acknowledged
+/// any more: every request gets a 503 without running.
+async fn commit_ledger(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if let Err(e) = state.ledger.storage_error() {
+        return (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response();
+    }
+    let response = next.run(req).await;
+    let ledger = state.ledger.clone();
+    match tokio::task::spawn_blocking(move || ledger.commit()).await {
+        Ok(Ok(())) => response,
+        Ok(Err(e)) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("Ledger sync failed: {}", e)).into_response(),
+    }
+}
+
+/// Checks the operator's token against the route and audits every mutation,
+/// including refused ones. Refused requests are answered before their body
+/// is read.
+async fn control_guard(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let authorized = state.control.authorize(req.headers(), Permission::required(&method, &path));
+    let operator = match authorized {
+        Ok(operator) => operator,
+        Err(denied) => {
+            let response = control_denied(&denied, &method, &path);
+            if !matches!(method, Method::GET | Method::HEAD) {
+                state.audit.push(AuditEntry::new(denied.operator(), &method, &path, response.status().as_u16(), &[]));
+            }
+            return response;
+        }
+    };
+    if matches!(method, Method::GET | Method::HEAD) {
+        return next.run(req).await;
+    }
+
+    let (parts, body) = req.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, control::MAX_CONTROL_BODY_BYTES).await else {
+        let error = format!("Control request bodies are limited to {} bytes", control::MAX_CONTROL_BODY_BYTES);
+        let response = (StatusCode::PAYLOAD_TOO_LARGE, Json(json!({ "status": 413, "error": error, "code": "payload_too_large" }))).into_response();
+        state.audit.push(AuditEntry::new(Some(&operator), &method, &path, 413, &[]));
+        return response;
+    };
+    let response = next.run(Request::from_parts(parts, Body::from(body.clone()))).await;
+    state.audit.push(AuditEntry::new(Some(&operator), &method, &path, response.status().as_u16(), &body));
+    response
+}
+
+fn control_denied(denied: &Denied, method: &Method, path: &str) -> Response {
+    match denied {
+        Denied::Unauthenticated => {
+            warn!(target: "xdr_core", "🔐 Control request without a valid token: {} {}", method, path);
+            let body = json!({ "status": 401, "error": "A valid operator token is required (Authorization: Bearer <token>)", "code": "unauthorized" });
+            let mut resp = (StatusCode::UNAUTHORIZED, Json(body)).into_response();
+            resp.headers_mut().insert("www-authenticate", HeaderValue::from_static("Bearer realm=\"xdr-control\""));
+            resp
+        }
+        Denied::Forbidden { operator, needed } => {
+            warn!(target: "xdr_core", "🔐 {} ({}) may not {} {}", operator.name, operator.role.as_str(), method, path);
+            let error = format!("Role {} lacks the {} permission for {} {}", operator.role.as_str(), needed.as_str(), method, path);
+            let body = json!({ "status": 403, "error": error, "code": "forbidden", "role": operator.role, "required": needed.as_str() });
+            (StatusCode::FORBIDDEN, Json(body)).into_response()
+        }
+    }
+}
+
+async fn get_audit_log(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.audit.entries())
+}
+
 async fn get_agent_status(
     State(state): State<AppState>,
     Path(agent_id): Path<String>,
@@ -108,261 +492,1905 @@ async fn get_agent_status(
     }
 }
 
+#[derive(serde::Deserialize)]
+struct TransactionsParams {
+    /// Unix seconds, RFC 3339 or YYYY-MM-DD (inclusive)
+    from: Option<String>,
+    /// Unix seconds, RFC 3339 or YYYY-MM-DD (exclusive)
+    to: Option<String>,
+    /// Cursor from the previous page's `next`
+    after: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// The agent's journal: every balance change, oldest first, one page at a time.
+async fn get_agent_transactions(
+    State(state): State<AppState>,
+    Path(agent_id): Path<String>,
+    Query(params): Query<TransactionsParams>,
+) -> impl IntoResponse {
+    if state.ledger.get_state(&agent_id).is_none() {
+        return (StatusCode::NOT_FOUND, "Agent not found").into_response();
+    }
+    let bound = |value: Option<String>| value.map(|v| parse_time(&v)).transpose();
+    let (from, to) = match (bound(params.from), bound(params.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let query = JournalQuery { agent_id: Some(agent_id), from, to, after: params.after, limit: params.limit };
+    Json(state.ledger.journal(&query)).into_response()
+}
+
+/// Unix seconds from a timestamp, an RFC 3339 date-time or a UTC date.
+fn parse_time(value: &str) -> Result<u64, String> {
+    if let Ok(secs) = value.parse::<u64>() {
+        return Ok(secs);
+    }
+    let secs = chrono::DateTime::parse_from_rfc3339(value)
+        .map(|t| t.timestamp())
+        .or_else(|_| chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|d| d.and_time(chrono::NaiveTime::MIN).and_utc().timestamp()))
+        .map_err(|_| format!("Invalid time '{}' (use unix seconds, RFC 3339 or YYYY-MM-DD)", value))?;
+    u64::try_from(secs).map_err(|_| format!("Time '{}' is before 1970", value))
+}
+
+async fn get_chaos_config(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.chaos.get_config())
+}
+
 async fn update_chaos_config(
     State(state): State<AppState>,
     Json(payload): Json<ChaosConfig>,
 ) -> impl IntoResponse {
-    state.chaos.set_config(payload);
-    StatusCode::OK
+    match state.chaos.set_config(payload.clone()) {
+        Ok(()) => {
+            state.record_control(ControlChange::Chaos(payload));
+            StatusCode::OK.into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
 }
 
-async fn get_traces(State(state): State<AppState>) -> impl IntoResponse {
-    let traces = state.traces.lock().unwrap();
-    // Return the list (JSON)
-    Json(traces.clone()).into_response()
+async fn get_chaos_scenario(State(state): State<AppState>) -> impl IntoResponse {
+    match (state.chaos.get_scenario(), state.chaos.scenario_status()) {
+        (Some(scenario), Some(status)) => Json(json!({ "scenario": scenario, "status": status })).into_response(),
+        _ => (StatusCode::NOT_FOUND, "No chaos scenario loaded").into_response(),
+    }
 }
 
-async fn proxy_handler(
+async fn load_chaos_scenario(
     State(state): State<AppState>,
-    mut req: Request,
+    Json(payload): Json<Scenario>,
 ) -> impl IntoResponse {
-    let start_time = Instant::now();
-    
-    
-
-    // 1. ENFORCE AGENT IDENTITY
-    let agent_id = match req.headers().get(HEADER_AGENT_ID).and_then(|h| h.to_str().ok()) {
-        Some(id) => id.to_string(),
-        None => {
-            return (StatusCode::BAD_REQUEST, format!("Missing mandatory header: {}", HEADER_AGENT_ID)).into_response();
+    match state.chaos.load_scenario(payload.clone()) {
+        Ok(()) => {
+            info!(target: "xdr_core", "🎬 Chaos scenario '{}' loaded ({} phases)", payload.name.as_deref().unwrap_or("unnamed"), payload.phases.len());
+            state.record_control(ControlChange::Scenario(payload));
+            StatusCode::OK.into_response()
         }
-    };
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
 
-    // 2. REGISTER AGENT
-    state.ledger.register_or_get(&agent_id);
+async fn get_pricing(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.pricing.get_config())
+}
 
-    // 3. Latency Injection (The Lag)
-    // if let Some(delay) = state.chaos.inject_latency().await {
-    //     info!(target: "xdr_chaos", "⏳ Injecting Latency: {}ms", delay.as_millis());
-    //     tokio::time::sleep(delay).await;
-    // }
-    state.chaos.inject_latency().await;
-
-    // 4. Failure Injection (The Drop)
-    // if let Some(status_code) = state.chaos.inject_failure() {
-    //     warn!(target: "xdr_chaos", "💥 Injecting Failure: {}", status_code);
-    //     return (
-    //         StatusCode::from_u16(status_code).unwrap(), 
-    //         format!("Chaos Simulation: {}", status_code)
-    //     ).into_response();
-    // }
-    if let Some(status_code) = state.chaos.roll_network_failure() {
-        warn!(target: "xdr_chaos", "💥 Network Failure Injected: {}", status_code);
-        return (StatusCode::from_u16(status_code).unwrap(), "Chaos: Network Error").into_response();
+async fn update_pricing(
+    State(state): State<AppState>,
+    Json(payload): Json<PricingConfig>,
+) -> impl IntoResponse {
+    match state.pricing.set_config(payload.clone()) {
+        Ok(()) => {
+            state.record_control(ControlChange::Pricing(payload));
+            info!(target: "xdr_core", "🏷️  Pricing table updated");
+            StatusCode::OK.into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
     }
+}
 
-    let mut trace = Trace::new("unknown", req.method().as_str(), &req.uri().to_string());
-    
-    // Helper macro to save typing
-    macro_rules! record {
-        ($cat:expr, $msg:expr) => { trace.log($cat, &$msg) };
-    }
+async fn get_rate_limits(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.rate_limits.get_config())
+}
 
-    // 1. CHAOS (Latency)
-    state.chaos.inject_latency().await;
-    
-    // 2. CHAOS (Network Failure)
-    if let Some(status_code) = state.chaos.roll_network_failure() {
-        record!(EventCategory::Chaos, format!("Injected Network Failure: {}", status_code));
-        trace.finish(status_code);
-        state.traces.lock().unwrap().push_back(trace); // Commit trace
-        return (StatusCode::from_u16(status_code).unwrap(), "Chaos Error").into_response();
+async fn update_rate_limits(
+    State(state): State<AppState>,
+    Json(payload): Json<RateLimitConfig>,
+) -> impl IntoResponse {
+    match state.rate_limits.set_config(payload.clone()) {
+        Ok(()) => {
+            info!(target: "xdr_core", "🚦 Rate limits updated ({} rules)", payload.rules.len());
+            state.record_control(ControlChange::RateLimits(payload));
+            StatusCode::OK.into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
     }
+}
 
-    // 3. IDENTITY
-    let agent_id = match req.headers().get(HEADER_AGENT_ID).and_then(|h| h.to_str().ok()) {
-        Some(id) => id.to_string(),
-        None => {
-            record!(EventCategory::Error, "Missing X-Agent-ID header".to_string());
-            trace.finish(400);
-            state.traces.lock().unwrap().push_back(trace);
-            return (StatusCode::BAD_REQUEST, "Missing X-Agent-ID").into_response();
-        }
-    };
-    trace.agent_id = agent_id.clone(); // Update correct ID
-    record!(EventCategory::Info, format!("Agent identified: {}", agent_id));
+/// Secrets are never sent back; the registry only shows who has which kind of credential.
+async fn get_auth(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.auth.get_config().redacted())
+}
 
-    // 4. REGISTER (with explicit funding event for new agents)
-    let (agent_state, is_new_agent) = state.ledger.register_or_get(&agent_id);
-    if is_new_agent {
-        record!(EventCategory::Payment, format!(
-            "FUNDED: Agent {} received $100.00 USDC (Welcome bonus)", 
-            agent_id
-        ));
+async fn update_auth(
+    State(state): State<AppState>,
+    Json(payload): Json<AuthConfig>,
+) -> impl IntoResponse {
+    let (agents, strict) = (payload.agents.len(), payload.strict);
+    match state.auth.set_config(payload) {
+        Ok(()) => {
+            info!(target: "xdr_core", "🔑 Agent registry replaced ({} agents, strict: {})", agents, strict);
+            StatusCode::OK.into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
     }
-    record!(EventCategory::Info, format!("Balance: ${:.2} USDC", agent_state.balance_usdc));
+}
 
-    // 5. PAYMENT LOGIC
-    let should_gate = req.uri().path().contains("paid") 
-                   || req.headers().contains_key(HEADER_SIMULATE_PAYMENT);
+#[derive(serde::Deserialize)]
+struct StrictPayload {
+    strict: bool,
+}
 
-    if should_gate {
-        let auth_header = req.headers().get("Authorization").and_then(|h| h.to_str().ok());
-        match auth_header {
-            Some(token) if token.starts_with("L402") => {
-                // Payment Chaos
-                if state.chaos.roll_payment_failure() {
-                    record!(EventCategory::Chaos, "Payment transaction failed on-chain".to_string());
-                    trace.finish(402);
-                    state.traces.lock().unwrap().push_back(trace);
-                    return (StatusCode::PAYMENT_REQUIRED, "Chaos: Payment Failed").into_response();
-                }
+async fn set_auth_strict(
+    State(state): State<AppState>,
+    Json(payload): Json<StrictPayload>,
+) -> impl IntoResponse {
+    state.auth.set_strict(payload.strict);
+    info!(target: "xdr_core", "🔑 Strict agent auth {}", if payload.strict { "enabled" } else { "disabled" });
+    StatusCode::OK
+}
 
-                let invoice_id = token.replace("L402 ", "");
-               match state.ledger.pay_invoice(&invoice_id, &agent_id, &state.network) {
-                    Ok(receipt) => {
-                        // LOG THE CRONOS DATA
-                        record!(EventCategory::Payment, format!(
-                            "Payment Confirmed on Cronos (Testnet). Tx: {} | Block: {}", 
-                            receipt.tx_hash, receipt.block_height
-                        ));
-                        
-                        // Trace the economics
-                        record!(EventCategory::Info, format!(
-                            "Wallet: {:.2} USDC | Chain: {}", 
-                            receipt.new_balance, receipt.chain_id
-                        ));
-                        record!(EventCategory::Payment, format!("Payment accepted. Bal: ${:.2}", receipt.new_balance));
-                        
-                        // Rug Chaos
-                        if state.chaos.roll_rug_pull() {
-                             record!(EventCategory::Chaos, "RUG PULL: Payment taken, request dropped".to_string());
-                             trace.finish(500);
-                             state.traces.lock().unwrap().push_back(trace);
-                             return (StatusCode::INTERNAL_SERVER_ERROR, "Rug Pull").into_response();
-                        }
-                        
-                        req.headers_mut().remove("Authorization");
-                    },
-                    Err(e) => {
-                        record!(EventCategory::Payment, format!("Payment rejected: {}", e));
-                        trace.finish(402);
-                        state.traces.lock().unwrap().push_back(trace);
-                        
-                        // Copy the specific budget error logic from Stage 5 here
-                        let body = json!({ "status": 402, "error": e, "agent": agent_id });
-                        return (StatusCode::PAYMENT_REQUIRED, Json(body)).into_response();
-                    }
-                }
-            },
-            _ => {
-                // Generate Invoice
-                let invoice = state.ledger.create_invoice(&agent_id, 0.01);
-                record!(EventCategory::Payment, format!("Generated Invoice: {}", invoice.id));
-                trace.finish(402);
-                state.traces.lock().unwrap().push_back(trace);
-                
-                // Copy the L402 response logic here
-                let body = json!({
-                    "status": 402,
-                    "x402_invoice": invoice.id,
-                    "amount": "0.01",
-                    "currency": "USDC",
-                    "chain": "cronos",
-                    "network": state.network,
-                    "chain_id": 338, // Cronos Testnet ID
-                    "payment_address": "0x000000000000000000000000000000000000dead" // Burn addr for mock
-                });
-                
-                let mut resp = Json(body).into_response();
-                *resp.status_mut() = StatusCode::PAYMENT_REQUIRED;
-                resp.headers_mut().insert("WWW-Authenticate", HeaderValue::from_str(&format!("L402 token={}", invoice.id)).unwrap());
-                return resp;
-            }
+async fn register_agent_credential(
+    State(state): State<AppState>,
+    Json(payload): Json<AgentCredential>,
+) -> impl IntoResponse {
+    let (agent_id, kind) = (payload.agent_id.clone(), payload.credential.kind());
+    match state.auth.register(payload) {
+        Ok(()) => {
+            info!(target: "xdr_core", "🔑 Agent {} registered ({} credential)", agent_id, kind);
+            StatusCode::OK.into_response()
         }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
     }
+}
 
-    // 6. UPSTREAM
-    let upstream_url = match resolve_upstream_url(&req) {
-        Ok(u) => u,
-        Err(e) => {
-            record!(EventCategory::Error, format!("Resolution failed: {}", e));
-            trace.finish(400);
-            state.traces.lock().unwrap().push_back(trace);
-            return (StatusCode::BAD_REQUEST, e).into_response();
+async fn remove_agent_credential(
+    State(state): State<AppState>,
+    Path(agent_id): Path<String>,
+) -> impl IntoResponse {
+    match state.auth.remove(&agent_id) {
+        true => {
+            info!(target: "xdr_core", "🔑 Agent {} credential removed", agent_id);
+            StatusCode::OK.into_response()
         }
-    };
-    
-    record!(EventCategory::Upstream, format!("Forwarding to {}", upstream_url));
+        false => (StatusCode::NOT_FOUND, format!("Agent {} has no credential", agent_id)).into_response(),
+    }
+}
 
-    // 7. CLASSIFY & LOG
-    let req_type = classify_request(&upstream_url, req.method());
-    info!(target: "xdr_proxy", "➡️  [{:?}] {} {}", req_type, req.method(), upstream_url);
+async fn get_tokens(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.ledger.tokens())
+}
 
-    // 8. FORWARD UPSTREAM
-    // Safety: Strip hop-by-hop headers
-    remove_hop_by_hop_headers(req.headers_mut());
-    if let Some(host) = upstream_url.host_str() {
-        req.headers_mut().insert("host", HeaderValue::from_str(host).unwrap());
+async fn add_token(
+    State(state): State<AppState>,
+    Json(payload): Json<Token>,
+) -> impl IntoResponse {
+    match state.ledger.add_token(payload.clone()) {
+        Ok(()) => {
+            info!(target: "xdr_core", "🪙 Token {} registered ({} decimals)", payload.symbol, payload.decimals);
+            state.record_control(ControlChange::Token(payload));
+            StatusCode::OK.into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
     }
-    
-    let method = req.method().clone();
-    let headers = req.headers().clone();
-    let body = req.into_body();
+}
 
-    let response = match state.client.request(method, upstream_url).headers(headers).body(reqwest::Body::wrap_stream(http_body_util::BodyExt::into_data_stream(body))).send().await {
-        Ok(res) => res,
-        Err(e) => {
-            record!(EventCategory::Upstream, format!("Upstream Failed: {}", e));
-            trace.finish(502);
-            state.traces.lock().unwrap().push_back(trace);
-            return (StatusCode::BAD_GATEWAY, e.to_string()).into_response();
-        }
-    };
+async fn list_approvals(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.approvals.list())
+}
 
-    // 9. RETURN RESPONSE
-    let status = response.status();
-    record!(EventCategory::Upstream, format!("Upstream responded: {}", status));
-    
-    // Log final balance after request completes
-    if let Some(final_state) = state.ledger.get_state(&agent_id) {
-        record!(EventCategory::Info, format!("Balance after request: ${:.2} USDC", final_state.balance_usdc));
+async fn get_approval(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.approvals.get(&id) {
+        Some(approval) => Json(approval).into_response(),
+        None => (StatusCode::NOT_FOUND, "Approval not found").into_response(),
     }
-    
-    trace.finish(status.as_u16());
+}
 
-    {
-        let mut store = state.traces.lock().unwrap();
-        if store.len() >= 1000 { store.pop_front(); } // Ring buffer logic
-        store.push_back(trace);
+async fn get_approval_config(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.approvals.get_config())
+}
+
+async fn update_approval_config(
+    State(state): State<AppState>,
+    Json(payload): Json<ApprovalConfig>,
+) -> impl IntoResponse {
+    match state.approvals.set_config(payload.clone()) {
+        Ok(()) => {
+            info!(target: "xdr_core", "✋ Approval policy updated ({:?} mode, {}s timeout)", payload.mode, payload.timeout_secs);
+            state.record_control(ControlChange::Approvals(payload));
+            StatusCode::OK.into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
     }
+}
 
-    let mut resp_headers = response.headers().clone();
-    remove_hop_by_hop_headers(&mut resp_headers);
-    let resp_body = Body::from_stream(response.bytes_stream());
-    let mut response_builder = Response::builder().status(status);
-    *response_builder.headers_mut().unwrap() = resp_headers;
-    response_builder.body(resp_body).unwrap()
+#[derive(serde::Deserialize, Default)]
+struct DecisionRequest {
+    #[serde(default)]
+    note: Option<String>,
 }
 
-// --- Helper Logic ---
+async fn approve_payment(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    payload: Option<Json<DecisionRequest>>,
+) -> impl IntoResponse {
+    decide_payment(&state, &id, Decision::Approve, payload.map(|Json(p)| p).unwrap_or_default())
+}
 
-fn resolve_upstream_url(req: &Request) -> Result<Url, String> {
-    let uri = req.uri();
+async fn deny_payment(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    payload: Option<Json<DecisionRequest>>,
+) -> impl IntoResponse {
+    decide_payment(&state, &id, Decision::Deny, payload.map(|Json(p)| p).unwrap_or_default())
+}
 
-    // Case A: Absolute URL
-    if let (Some(_scheme), Some(_host)) = (uri.scheme(), uri.host()) {
-        let url_str = uri.to_string();
-        return Url::parse(&url_str).map_err(|_| "Invalid Absolute URL".to_string());
+fn decide_payment(state: &AppState, id: &str, decision: Decision, request: DecisionRequest) -> Response {
+    if state.approvals.get(id).is_none() {
+        return (StatusCode::NOT_FOUND, "Approval not found").into_response();
+    }
+    match state.approvals.decide(id, decision, request.note.clone()) {
+        Ok(approval) => {
+            info!(target: "xdr_core", "✋ Payment {} {} ({} {} for {})", approval.id, approval.status.label(), approval.amount, approval.currency, approval.agent_id);
+            state.record_control(ControlChange::Decision {
+                approval_id: approval.id.clone(),
+                decision,
+                note: request.note,
+            });
+            Json(approval).into_response()
+        }
+        Err(e) => (StatusCode::CONFLICT, e).into_response(),
     }
+}
 
-    // Case B: Relative URL -> Need Header
-    let upstream_host = req.headers()
-        .get(HEADER_UPSTREAM_HOST)
-        .and_then(|v| v.to_str().ok())
-        .ok_or("Missing X-Upstream-Host header or Absolute URL")?;
+fn storage_error_response(e: std::io::Error) -> Response {
+    let status = match e.kind() {
+        std::io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+        std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string()).into_response()
+}
 
-    let path = uri.path();
+async fn list_snapshots(State(state): State<AppState>) -> impl IntoResponse {
+    match state.ledger.list_snapshots() {
+        Ok(names) => Json(names).into_response(),
+        Err(e) => storage_error_response(e),
+    }
+}
+
+async fn save_snapshot(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match state.ledger.save_snapshot(&name) {
+        Ok(snapshot) => {
+            info!(target: "xdr_core", "💾 Snapshot '{}' saved ({} agents)", name, snapshot.agents.len());
+            Json(json!({ "name": name, "agents": snapshot.agents.len(), "invoices": snapshot.invoices.len() })).into_response()
+        }
+        Err(e) => storage_error_response(e),
+    }
+}
+
+async fn load_snapshot(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match state.ledger.load_snapshot(&name) {
+        Ok(snapshot) => {
+            state.record_control(ControlChange::Ledger(snapshot.clone()));
+            info!(target: "xdr_core", "📂 Snapshot '{}' loaded ({} agents)", name, snapshot.agents.len());
+            Json(json!({ "name": name, "agents": snapshot.agents.len(), "invoices": snapshot.invoices.len() })).into_response()
+        }
+        Err(e) => storage_error_response(e),
+    }
+}
+
+async fn get_replay_report(State(state): State<AppState>) -> impl IntoResponse {
+    match state.session.as_deref() {
+        Some(Session::Replay(player)) => Json(player.report()).into_response(),
+        _ => (StatusCode::NOT_FOUND, "Not replaying a cassette").into_response(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct MineRequest {
+    #[serde(default = "default_mine_blocks")]
+    blocks: u64,
+}
+
+fn default_mine_blocks() -> u64 {
+    1
+}
+
+async fn mine_blocks(
+    State(state): State<AppState>,
+    payload: Option<Json<MineRequest>>,
+) -> impl IntoResponse {
+    let blocks = payload.map(|Json(p)| p.blocks).unwrap_or(1);
+    state.record_control(ControlChange::Mine { blocks });
+    Json(miner::mine(&state.ledger, &state.chaos, blocks))
+}
+
+async fn get_chain_info(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.ledger.chain_info())
+}
+
+async fn get_transaction(
+    State(state): State<AppState>,
+    Path(hash): Path<String>,
+) -> impl IntoResponse {
+    match state.ledger.get_transaction(&hash) {
+        Some(tx) => {
+            let confirmations = state.ledger.confirmations(&tx);
+            Json(json!({ "transaction": wire::value(&tx), "confirmations": confirmations })).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "Transaction not found").into_response(),
+    }
+}
+
+/// An invoice with its lifecycle state as of now.
+#[derive(serde::Serialize)]
+struct InvoiceView {
+    #[serde(flatten)]
+    invoice: Invoice,
+    status: InvoiceStatus,
+}
+
+impl From<Invoice> for InvoiceView {
+    fn from(invoice: Invoice) -> Self {
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        Self { status: invoice.status(now), invoice }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct InvoicesParams {
+    agent: Option<String>,
+}
+
+async fn list_invoices(State(state): State<AppState>, Query(params): Query<InvoicesParams>) -> impl IntoResponse {
+    let invoices: Vec<InvoiceView> = state.ledger.list_invoices(params.agent.as_deref()).into_iter().map(Into::into).collect();
+    Json(invoices)
+}
+
+async fn get_invoice(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.ledger.get_invoice(&id) {
+        Some(invoice) => Json(InvoiceView::from(invoice)).into_response(),
+        None => (StatusCode::NOT_FOUND, "Invoice not found").into_response(),
+    }
+}
+
+async fn cancel_invoice(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.ledger.cancel_invoice(&id) {
+        Ok(invoice) => {
+            state.record_control(ControlChange::CancelInvoice { invoice_id: invoice.id.clone() });
+            info!(target: "xdr_core", "🚫 Invoice {} cancelled ({} {} for {})", invoice.id, invoice.amount, invoice.currency, invoice.agent_id);
+            Json(InvoiceView::from(invoice)).into_response()
+        }
+        Err(e @ CancelError::NotFound(_)) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        Err(e) => (StatusCode::CONFLICT, e.to_string()).into_response(),
+    }
+}
+
+/// Drops stale invoices every `INVOICE_SWEEP_INTERVAL` until the process exits.
+fn spawn_invoice_sweeper(ledger: Ledger) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(INVOICE_SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let swept = ledger.sweep_invoices(INVOICE_RETENTION_SECS);
+            if swept > 0 {
+                info!(target: "xdr_core", "🧹 Swept {} stale invoice(s)", swept);
+            }
+        }
+    });
+}
+
+/// Escrow holds of `upto` payments, open and settled.
+async fn list_holds(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.ledger.list_holds(None))
+}
+
+async fn list_agent_holds(
+    State(state): State<AppState>,
+    Path(agent_id): Path<String>,
+) -> impl IntoResponse {
+    Json(state.ledger.list_holds(Some(&agent_id)))
+}
+
+/// A budget group with the agents that pay through it directly.
+#[derive(serde::Serialize)]
+struct GroupView {
+    #[serde(flatten)]
+    group: BudgetGroup,
+    members: Vec<String>,
+}
+
+fn group_view(ledger: &Ledger, group: BudgetGroup) -> GroupView {
+    let mut members: Vec<String> = ledger.list_agents().into_iter()
+        .filter(|a| a.group.as_deref() == Some(group.id.as_str()))
+        .map(|a| a.id)
+        .collect();
+    members.sort();
+    GroupView { group, members }
+}
+
+async fn list_groups(State(state): State<AppState>) -> impl IntoResponse {
+    let groups: Vec<GroupView> = state.ledger.list_groups().into_iter().map(|g| group_view(&state.ledger, g)).collect();
+    Json(groups)
+}
+
+async fn get_group(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.ledger.get_group(&id) {
+        Some(group) => Json(group_view(&state.ledger, group)).into_response(),
+        None => (StatusCode::NOT_FOUND, "Group not found").into_response(),
+    }
+}
+
+/// A field left out keeps the group's current value; `null` clears it.
+#[derive(serde::Deserialize)]
+struct GroupRequest {
+    id: String,
+    #[serde(default, deserialize_with = "present")]
+    parent: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    budget_limit: Option<Option<Amount>>,
+}
+
+/// Tells a field sent as `null` (Some(None)) from one left out (None).
+fn present<'de, T: serde::Deserialize<'de>, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Option<T>>, D::Error> {
+    <Option<T> as serde::Deserialize>::deserialize(deserializer).map(Some)
+}
+
+async fn upsert_group(
+    State(state): State<AppState>,
+    Json(payload): Json<GroupRequest>,
+) -> impl IntoResponse {
+    match state.ledger.upsert_group(&payload.id, GroupUpdate { parent: payload.parent, budget_limit: payload.budget_limit }) {
+        Ok(group) => {
+            state.record_control(ControlChange::Group {
+                id: group.id.clone(),
+                parent: group.parent.clone(),
+                budget_limit: group.budget_limit,
+            });
+            let cap = group.budget_limit.map_or("no cap".to_string(), |limit| format!("cap ${}", limit));
+            match &group.parent {
+                Some(parent) => info!(target: "xdr_core", "👥 Budget group {} under {} ({})", group.id, parent, cap),
+                None => info!(target: "xdr_core", "👥 Budget group {} with its own wallet ({})", group.id, cap),
+            }
+            Json(group_view(&state.ledger, group)).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+async fn delete_group(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match state.ledger.remove_group(&id) {
+        Ok(_) => {
+            state.record_control(ControlChange::GroupRemoved { id: id.clone() });
+            info!(target: "xdr_core", "👥 Budget group {} deleted", id);
+            StatusCode::OK.into_response()
+        }
+        Err(e @ RemoveGroupError::NotFound(_)) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        Err(e) => (StatusCode::CONFLICT, e.to_string()).into_response(),
+    }
+}
+
+async fn set_group_balance(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(payload): Json<BudgetRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = state.ledger.set_group_balance(&id, &payload.currency, payload.amount) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    state.record_control(ControlChange::GroupBalance {
+        group_id: id.clone(),
+        amount: payload.amount,
+        currency: payload.currency.clone(),
+    });
+    info!(target: "xdr_core", "💰 Admin set {} wallet of group {} to {}", payload.currency, id, payload.amount);
+    StatusCode::OK.into_response()
+}
+
+#[derive(serde::Deserialize)]
+struct MemberRequest {
+    agent_id: String,
+}
+
+async fn add_group_member(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(payload): Json<MemberRequest>,
+) -> impl IntoResponse {
+    match state.ledger.assign_group(&payload.agent_id, Some(&id)) {
+        Ok(agent) => {
+            state.record_control(ControlChange::GroupMember { agent_id: agent.id.clone(), group: agent.group.clone() });
+            info!(target: "xdr_core", "👥 {} now pays through group {}", agent.id, id);
+            StatusCode::OK.into_response()
+        }
+        Err(e) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+    }
+}
+
+async fn remove_group_member(
+    State(state): State<AppState>,
+    Path((id, agent_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    match state.ledger.leave_group(&agent_id, &id) {
+        Ok(_) => {
+            state.record_control(ControlChange::GroupMember { agent_id: agent_id.clone(), group: None });
+            info!(target: "xdr_core", "👥 {} left group {} (back on its own wallet)", agent_id, id);
+            StatusCode::OK.into_response()
+        }
+        Err(e) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+    }
+}
+
+/// A registered service with where other agents call it and what the payee holds.
+#[derive(serde::Serialize)]
+struct ServiceView {
+    #[serde(flatten)]
+    service: AgentService,
+    host: String,
+    wallet: String,
+}
+
+async fn list_services(State(state): State<AppState>) -> impl IntoResponse {
+    let views: Vec<ServiceView> = state.services.get_config().services.into_iter()
+        .map(|service| {
+            let wallet = state.ledger.get_state(&service.agent_id)
+                .map(|agent| wallet_summary(&state.ledger, &agent))
+                .unwrap_or_else(|| "no wallet yet".to_string());
+            ServiceView { host: service.host(), wallet, service }
+        })
+        .collect();
+    Json(views)
+}
+
+async fn register_service(
+    State(state): State<AppState>,
+    Json(payload): Json<AgentService>,
+) -> impl IntoResponse {
+    let (host, agent_id, url) = (payload.host(), payload.agent_id.clone(), payload.url.clone());
+    if let Err(e) = state.services.register(payload) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    state.ledger.register_or_get(&agent_id);
+    state.record_control(ControlChange::Services(state.services.get_config()));
+    info!(target: "xdr_core", "🏪 Agent {} sells at {} (served from {})", agent_id, host, url);
+    (StatusCode::OK, Json(json!({ "agent_id": agent_id, "host": host }))).into_response()
+}
+
+async fn remove_service(
+    State(state): State<AppState>,
+    Path(agent_id): Path<String>,
+) -> impl IntoResponse {
+    match state.services.remove(&agent_id) {
+        true => {
+            state.record_control(ControlChange::Services(state.services.get_config()));
+            info!(target: "xdr_core", "🏪 Agent {} no longer sells a service", agent_id);
+            StatusCode::OK.into_response()
+        }
+        false => (StatusCode::NOT_FOUND, format!("Agent {} has no service", agent_id)).into_response(),
+    }
+}
+
+/// JSON-RPC endpoint of the mock chain node.
+async fn rpc_endpoint(State(state): State<AppState>, body: axum::body::Bytes) -> impl IntoResponse {
+    Json(rpc::handle(&state.ledger, &state.network, &body))
+}
+
+async fn get_traces(State(state): State<AppState>) -> impl IntoResponse {
+    let traces = state.traces.lock().unwrap();
+    // Return the list (JSON)
+    Json(traces.clone()).into_response()
+}
+
+async fn proxy_handler(
+    State(state): State<AppState>,
+    req: Request,
+) -> Response {
+    let (response, trace) = match state.session.clone() {
+        None => handle_request(&state, req, None).await,
+        Some(session) => handle_in_session(&state, session, req).await,
+    };
+
+    state.commit_trace(trace);
+    response
+}
+
+/// Marks a response that was produced by the upstream (or its recording).
+#[derive(Clone, Copy)]
+struct FromUpstream;
+
+/// Runs the full proxy pipeline and returns the response plus its finished
+/// (not yet committed) trace. `replayed` stands in for the upstream response
+/// when replaying a cassette.
+async fn handle_request(
+    state: &AppState,
+    req: Request,
+    replayed: Option<RecordedResponse>,
+) -> (Response, Trace) {
+    let decisions = DecisionLog::default();
+    let (response, mut trace) = run_pipeline(state, req, replayed, &decisions).await;
+    trace.chaos_decisions = decisions.take();
+    (response, trace)
+}
+
+async fn run_pipeline(
+    state: &AppState,
+    mut req: Request,
+    replayed: Option<RecordedResponse>,
+    decisions: &DecisionLog,
+) -> (Response, Trace) {
+    let mut trace = Trace::new("unknown", req.method().as_str(), &req.uri().to_string());
+    
+    // Helper macro to save typing
+    macro_rules! record {
+        ($cat:expr, $msg:expr) => { trace.log($cat, &$msg) };
+    }
+
+    // 3. IDENTITY
+    let agent_id = match req.headers().get(HEADER_AGENT_ID).and_then(|h| h.to_str().ok()) {
+        Some(id) => id.to_string(),
+        None => {
+            record!(EventCategory::Error, "Missing X-Agent-ID header");
+            trace.finish(400);
+            return ((StatusCode::BAD_REQUEST, "Missing X-Agent-ID").into_response(), trace);
+        }
+    };
+    trace.agent_id = agent_id.clone(); // Update correct ID
+    record!(EventCategory::Info, format!("Agent identified: {}", agent_id));
+
+    // 3b. AUTHENTICATION (the claimed id must be backed by the agent's credential)
+    let body = match state.auth.needs_body(&agent_id) {
+        true => {
+            let (parts, body) = req.into_parts();
+            let Ok(body) = axum::body::to_bytes(body, auth::MAX_SIGNED_BODY_BYTES).await else {
+                record!(EventCategory::Error, format!("Signed body is over {} bytes", auth::MAX_SIGNED_BODY_BYTES));
+                trace.finish(413);
+                let message = format!("Bodies of HMAC-authenticated requests are limited to {} bytes", auth::MAX_SIGNED_BODY_BYTES);
+                return ((StatusCode::PAYLOAD_TOO_LARGE, message).into_response(), trace);
+            };
+            req = Request::from_parts(parts, Body::from(body.clone()));
+            Some(body)
+        }
+        false => None,
+    };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str()).to_string();
+    let upstream = resolve_upstream_url(&req).ok();
+    let auth_request = AuthRequest {
+        headers: req.headers(),
+        method: req.method().as_str(),
+        host: upstream.as_ref().and_then(|u| u.host_str()).unwrap_or_default(),
+        path: &path,
+        body: body.as_deref(),
+        now: chrono::Utc::now().timestamp().max(0) as u64,
+    };
+    match state.auth.authenticate(&agent_id, &auth_request) {
+        Ok(Authenticated::Verified(kind)) => record!(EventCategory::Info, format!("Authenticated with {} credential", kind)),
+        Ok(Authenticated::Anonymous) => record!(EventCategory::Info, "Unauthenticated: no credential registered for this agent"),
+        Err(e) => {
+            record!(EventCategory::Error, format!("Authentication failed [{}]: {}", e.code, e.detail));
+            trace.finish(401);
+            let body = json!({ "status": 401, "error": e.detail, "code": e.code, "agent": agent_id });
+            let mut resp = (StatusCode::UNAUTHORIZED, Json(body)).into_response();
+            resp.headers_mut().insert("www-authenticate", HeaderValue::from_static("XDR-Agent realm=\"xdr\""));
+            return (resp, trace);
+        }
+    }
+    auth::strip_headers(req.headers_mut());
+
+    // Chaos is keyed on the authenticated agent, so requests that fail to
+    // authenticate can't advance (or shift) another agent's decisions
+    let chaos_url = resolve_upstream_url(&req).ok();
+    let chaos_method = req.method().to_string();
+    let chaos_ctx = ChaosContext {
+        agent_id: &agent_id,
+        host: chaos_url.as_ref().and_then(|u| u.host_str()).unwrap_or_default(),
+        path: chaos_url.as_ref().map_or("", |u| u.path()),
+        method: &chaos_method,
+        request_type: chaos_url.as_ref().map_or(RequestType::Unknown, |u| classify_request(u, req.method())),
+        seq: state.chaos.next_seq(&agent_id),
+        decisions,
+    };
+    if let Some(phase) = state.chaos.begin_request() {
+        record!(EventCategory::Info, format!("Chaos scenario entered phase {}", phase.label()));
+    }
+
+    // 3c. CHAOS (Latency)
+    state.chaos.inject_latency(&chaos_ctx).await;
+    
+    // 3d. CHAOS (Network Failure)
+    if let Some(status_code) = state.chaos.roll_network_failure(&chaos_ctx) {
+        record!(EventCategory::Chaos, format!("Injected Network Failure: {}", status_code));
+        trace.finish(status_code);
+        return ((StatusCode::from_u16(status_code).unwrap(), "Chaos Error").into_response(), trace);
+    }
+
+    // 4. REGISTER (with explicit funding event for new agents)
+    let (agent_state, is_new_agent) = state.ledger.register_or_get(&agent_id);
+    if is_new_agent {
+        record!(EventCategory::Payment, format!(
+            "FUNDED: Agent {} received {} (Welcome bonus)", 
+            agent_id, wallet_summary(&state.ledger, &agent_state)
+        ));
+    }
+    record!(EventCategory::Info, format!("Balance: {}", wallet_summary(&state.ledger, &agent_state)));
+
+    // 5. UPSTREAM RESOLUTION (needed up front so pricing can match on host)
+    let upstream_url = match resolve_upstream_url(&req) {
+        Ok(u) => u,
+        Err(e) => {
+            record!(EventCategory::Error, format!("Resolution failed: {}", e));
+            trace.finish(400);
+            return ((StatusCode::BAD_REQUEST, e).into_response(), trace);
+        }
+    };
+
+    // 5b. AGENT SERVICES (`<agent_id>.agent` goes to the URL that agent serves)
+    let service = match state.services.route(&upstream_url) {
+        Some(Ok(route)) => {
+            record!(EventCategory::Info, format!("Agent service: {} sells at {}", route.agent_id, upstream_url.host_str().unwrap_or_default()));
+            Some(route)
+        }
+        Some(Err(e)) => {
+            record!(EventCategory::Error, format!("Resolution failed: {}", e));
+            trace.finish(502);
+            return ((StatusCode::BAD_GATEWAY, e).into_response(), trace);
+        }
+        None => None,
+    };
+    // Paying an agent's service is a transfer to its wallet
+    let payee_agent = service.as_ref().map(|route| route.agent_id.as_str());
+
+    // 6. PRICING & PAYMENT LOGIC
+    let quote = state.pricing
+        .quote(upstream_url.host_str().unwrap_or(""), req.method().as_str(), upstream_url.path())
+        .filter(|q| q.requires_payment)
+        .or_else(|| req.headers().contains_key(HEADER_SIMULATE_PAYMENT).then(|| state.pricing.default_quote()));
+
+    // Set when an x402 payment settles; echoed back as X-PAYMENT-RESPONSE
+    let mut payment_response: Option<String> = None;
+    // Escrow for an `upto` payment, settled once the response is known
+    let mut hold: Option<Hold> = None;
+    // Payment goes to the service behind the route
+    let payee = upstream_url.host_str().unwrap_or_default();
+
+    // Upstream quota, checked before any money moves. Fetching a 402 challenge
+    // doesn't reach the upstream, so it doesn't take a token.
+    let carries_payment = match state.protocol {
+        PaymentProtocol::L402 => req.headers().get("Authorization")
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|token| token.starts_with("L402")),
+        PaymentProtocol::X402 => req.headers().contains_key(x402::HEADER_PAYMENT),
+    };
+    let rate_limit = match quote.is_none() || carries_payment {
+        true => state.rate_limits.check(&agent_id, payee),
+        false => None,
+    };
+    if let Some(verdict) = rate_limit.as_ref().filter(|v| !v.allowed) {
+        record!(EventCategory::Upstream, format!(
+            "Rate limited by {}: burst of {} used up, retry in {:.2}s",
+            payee, verdict.limit, verdict.retry_after.unwrap_or_default().as_secs_f64()
+        ));
+        trace.finish(429);
+        return (verdict.rejection(), trace);
+    }
+
+    if let Some(quote) = quote {
+        // Paying an agent's service is a transfer to its wallet, never to the payer's own
+        if payee_agent == Some(agent_id.as_str()) {
+            let error = PaymentError::SelfPayment;
+            record!(EventCategory::Payment, format!("Payment rejected [{}]: {}", error.code(), error));
+            trace.finish(402);
+            return (payment_rejection(&agent_id, &error), trace);
+        }
+        let upto = quote.scheme == PaymentScheme::Upto;
+        match state.protocol {
+            PaymentProtocol::L402 => {
+                let auth_header = req.headers().get("Authorization").and_then(|h| h.to_str().ok());
+                match auth_header {
+                    Some(token) if token.starts_with("L402") => {
+                        let invoice_id = token.replace("L402 ", "");
+                        let resource = InvoiceResource::new(req.method().as_str(), upstream_url.as_str());
+
+                        // Stale, cancelled, already-paid or rebound invoices are refused up front
+                        let invoice = match state.ledger.validate_invoice(&invoice_id, &agent_id, Some(&resource)) {
+                            Ok(invoice) => invoice,
+                            Err(e) => {
+                                record!(EventCategory::Payment, format!("Invoice rejected [{}]: {}", e.code(), e));
+                                trace.finish(402);
+                                return (payment_rejection(&agent_id, &e), trace);
+                            }
+                        };
+
+                        // Approval Gate (payments over the threshold wait for an operator)
+                        if let Some(asset) = state.ledger.token(&invoice.currency) {
+                            let url = upstream_url.to_string();
+                            let payment = PaymentRequest {
+                                agent_id: &agent_id,
+                                method: req.method().as_str(),
+                                url: &url,
+                                host: payee,
+                                path: upstream_url.path(),
+                                amount: invoice.amount,
+                                currency: &invoice.currency,
+                                usd_value: asset.usd_value(invoice.amount),
+                                request_type: classify_request(&upstream_url, req.method()),
+                            };
+                            if let Err(held) = approval_gate(state, &mut trace, &invoice_id, &payment).await {
+                                let resp = match held {
+                                    Held::Pending(approval) => approval_pending(&state.approvals, &approval),
+                                    Held::Denied(approval) => {
+                                        let body = json!({ "status": 402, "error": denial_message(&approval), "code": "approval_denied", "agent": agent_id, "approval": wire::value(&approval) });
+                                        (StatusCode::PAYMENT_REQUIRED, Json(body)).into_response()
+                                    }
+                                };
+                                trace.finish(resp.status().as_u16());
+                                return (resp, trace);
+                            }
+                        }
+
+                        // Payment Chaos
+                        if state.chaos.roll_payment_failure(&chaos_ctx) {
+                            record!(EventCategory::Chaos, "Payment transaction failed on-chain");
+                            trace.finish(402);
+                            return ((StatusCode::PAYMENT_REQUIRED, "Chaos: Payment Failed").into_response(), trace);
+                        }
+
+                        let settled = charge(state, &invoice_id, &agent_id, &resource, upto);
+                        let reject = |e: &PaymentError| payment_rejection(&agent_id, e);
+                        match settle_payment(state, &mut trace, &chaos_ctx, None, settled, reject) {
+                            Ok((_, reserved)) => hold = reserved,
+                            Err(resp) => return (*resp, trace),
+                        }
+                        req.headers_mut().remove("Authorization");
+                    },
+                    _ => {
+                        let options = payable_options(&state.ledger, &quote.options, |_| true);
+                        if options.is_empty() {
+                            return no_payable_asset(trace, &quote.options);
+                        }
+
+                        // One invoice per accepted asset; the agent pays whichever it picks
+                        // (the others are then cancelled). Each is only good for this method and URL.
+                        let resource = InvoiceResource::new(req.method().as_str(), upstream_url.as_str());
+                        let invoices: Vec<_> = options.iter()
+                            .map(|(option, token)| {
+                                let invoice = state.ledger.create_invoice(&agent_id, option.price, &token.symbol, payee, payee_agent, Some(resource.clone()));
+                                (invoice, token)
+                            })
+                            .collect();
+                        let ids: Vec<String> = invoices.iter().map(|(invoice, _)| invoice.id.clone()).collect();
+                        state.ledger.link_alternatives(&ids);
+                        let listed: Vec<String> = invoices.iter()
+                            .map(|(invoice, _)| format!("{} ({} {})", invoice.id, invoice.amount, invoice.currency))
+                            .collect();
+                        record!(EventCategory::Payment, format!(
+                            "Generated Invoice: {} (rule: {})",
+                            listed.join(", "),
+                            quote.rule.map(|i| format!("#{}", i)).unwrap_or_else(|| "default".to_string())
+                        ));
+                        trace.finish(402);
+                
+                        // The first option stays at the top level for single-asset clients
+                        let (invoice, _) = &invoices[0];
+                        let accepts: Vec<_> = invoices.iter()
+                            .map(|(invoice, token)| json!({
+                                "x402_invoice": invoice.id,
+                                "amount": invoice.amount.to_string(),
+                                "currency": invoice.currency,
+                                "asset": token.address,
+                                "decimals": token.decimals,
+                                "expires_at": invoice.expires_at,
+                            }))
+                            .collect();
+                        let body = json!({
+                            "status": 402,
+                            "x402_invoice": invoice.id,
+                            "amount": invoice.amount.to_string(),
+                            "currency": invoice.currency,
+                            "accepts": accepts,
+                            "expires_at": invoice.expires_at,
+                            "resource": resource.to_string(),
+                            "chain": "cronos",
+                            "network": state.network,
+                            "chain_id": x402::chain_id(&state.network),
+                            "scheme": quote.scheme.as_str(),
+                            // Burn addr for mock (escrow when the amount is only a ceiling)
+                            "payment_address": if upto { xdr_ledger::escrow::ESCROW_ADDRESS } else { xdr_ledger::BURN_ADDRESS }
+                        });
+                
+                        let mut resp = Json(body).into_response();
+                        *resp.status_mut() = StatusCode::PAYMENT_REQUIRED;
+                        for (invoice, _) in &invoices {
+                            resp.headers_mut().append("WWW-Authenticate", HeaderValue::from_str(&format!("L402 token={}", invoice.id)).unwrap());
+                        }
+                        return (resp, trace);
+                    }
+                }
+            }
+            PaymentProtocol::X402 => {
+                // Only EIP-3009 tokens can be paid with a signed authorization
+                let options = payable_options(&state.ledger, &quote.options, Token::supports_eip3009);
+                if options.is_empty() {
+                    return no_payable_asset(trace, &quote.options);
+                }
+                let requirements: Vec<_> = options.iter()
+                    .map(|(option, token)| x402::requirements(option, quote.scheme, token, &upstream_url, &state.network))
+                    .collect();
+                let header = req.headers().get(x402::HEADER_PAYMENT).and_then(|h| h.to_str().ok());
+                let Some(header) = header else {
+                    let listed: Vec<String> = options.iter()
+                        .zip(&requirements)
+                        .map(|((_, token), r)| format!("{} atomic units of {} ({})", r.max_amount_required, token.symbol, r.asset))
+                        .collect();
+                    record!(EventCategory::Payment, format!(
+                        "Payment required: {} (rule: {})",
+                        listed.join(" or "),
+                        quote.rule.map(|i| format!("#{}", i)).unwrap_or_else(|| "default".to_string())
+                    ));
+                    trace.finish(402);
+                    return (x402::payment_required("X-PAYMENT header is required", &requirements), trace);
+                };
+
+                let payment = match x402::decode_payment(header) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        record!(EventCategory::Payment, format!("Payment rejected [invalid_payload]: {}", e));
+                        trace.finish(402);
+                        return (x402::rejected("invalid_payload", Some(e), &requirements), trace);
+                    }
+                };
+                let (chosen, payer) = match x402::select(&payment, &requirements) {
+                    Ok(selected) => selected,
+                    Err(e) => {
+                        record!(EventCategory::Payment, format!("Payment rejected [{}]: {}", e.code, e.detail));
+                        trace.finish(402);
+                        return (x402::rejected(e.code, Some(e.detail), &requirements), trace);
+                    }
+                };
+                let (option, token) = &options[chosen];
+                record!(EventCategory::Payment, format!("Signature verified: EIP-3009 authorization from {} ({})", payer, token.symbol));
+                // The signing key is the agent's wallet on the mock chain
+                state.ledger.bind_address(&agent_id, &payer);
+
+                // Settle the authorized value (validated to cover the price above)
+                let auth = &payment.payload.authorization;
+                let value = auth.value.parse().ok().and_then(|v| token.from_atomic(v)).unwrap_or(option.price);
+
+                // Approval Gate (bound to the signed authorization, so a retry reuses it)
+                let nonce = &auth.nonce;
+                let asset = token.address.as_deref().unwrap_or_default();
+                let url = upstream_url.to_string();
+                let pending_payment = PaymentRequest {
+                    agent_id: &agent_id,
+                    method: req.method().as_str(),
+                    url: &url,
+                    host: payee,
+                    path: upstream_url.path(),
+                    amount: value,
+                    currency: &token.symbol,
+                    usd_value: token.usd_value(value),
+                    request_type: classify_request(&upstream_url, req.method()),
+                };
+                let subject = xdr_ledger::authorization_key(asset, &payer, nonce);
+                if let Err(held) = approval_gate(state, &mut trace, &subject, &pending_payment).await {
+                    let resp = match held {
+                        Held::Pending(approval) => approval_pending(&state.approvals, &approval),
+                        Held::Denied(approval) => x402::rejected("approval_denied", Some(denial_message(&approval)), &requirements),
+                    };
+                    trace.finish(resp.status().as_u16());
+                    return (resp, trace);
+                }
+
+                if !state.ledger.claim_authorization(asset, &payer, nonce) {
+                    let detail = format!("authorization nonce {} was already used by {} on {}", nonce, payer, token.symbol);
+                    record!(EventCategory::Payment, format!("Payment rejected [invalid_transaction_state]: {}", detail));
+                    trace.finish(402);
+                    return (x402::rejected("invalid_transaction_state", Some(detail), &requirements), trace);
+                }
+
+                // Payment Chaos
+                if state.chaos.roll_payment_failure(&chaos_ctx) {
+                    state.ledger.release_authorization(asset, &payer, nonce);
+                    record!(EventCategory::Chaos, "Payment transaction failed on-chain");
+                    trace.finish(402);
+                    return (x402::rejected("unexpected_settle_error", Some("Chaos: Payment Failed".to_string()), &requirements), trace);
+                }
+
+                let resource = InvoiceResource::new(req.method().as_str(), upstream_url.as_str());
+                let invoice = state.ledger.create_invoice(&agent_id, value, &token.symbol, payee, payee_agent, Some(resource.clone()));
+                let settled = charge(state, &invoice.id, &agent_id, &resource, upto);
+                let reject = |e: &PaymentError| {
+                    state.ledger.release_authorization(asset, &payer, nonce);
+                    let mut resp = x402::rejected(x402::settle_error_code(e), Some(e.to_string()), &requirements);
+                    set_retry_after(&mut resp, e);
+                    resp
+                };
+                let receipt = match settle_payment(state, &mut trace, &chaos_ctx, Some(&payer), settled, reject) {
+                    Ok((receipt, reserved)) => {
+                        hold = reserved;
+                        receipt
+                    }
+                    Err(resp) => return (*resp, trace),
+                };
+                payment_response = Some(x402::encode_settlement(&x402::SettlementResponse {
+                    success: true,
+                    transaction: receipt.tx_hash,
+                    network: state.network.clone(),
+                    payer: payer.clone(),
+                }));
+                req.headers_mut().remove(x402::HEADER_PAYMENT);
+            }
+        }
+    }
+
+    // 7. UPSTREAM
+    let forward_url = match service {
+        Some(route) => {
+            record!(EventCategory::Upstream, format!("Forwarding to {} (service of agent {})", route.url, route.agent_id));
+            route.url
+        }
+        None => {
+            record!(EventCategory::Upstream, format!("Forwarding to {}", upstream_url));
+            upstream_url.clone()
+        }
+    };
+
+    // 8. CLASSIFY & LOG
+    let req_type = classify_request(&upstream_url, req.method());
+    info!(target: "xdr_proxy", "➡️  [{:?}] {} {}", req_type, req.method(), upstream_url);
+
+    // 9. CHAOS (Response faults, applied to whatever the upstream sends back)
+    let response_faults = state.chaos.roll_response_faults(&chaos_ctx);
+    for fault in &response_faults {
+        record!(EventCategory::Chaos, format!("Injected response fault: {}", fault.describe()));
+    }
+
+    // 10. FORWARD UPSTREAM
+    // Safety: Strip hop-by-hop headers
+    remove_hop_by_hop_headers(req.headers_mut());
+    if let Some(host) = forward_url.host_str() {
+        let host = match forward_url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        req.headers_mut().insert("host", HeaderValue::from_str(&host).unwrap());
+    }
+    
+    // Replay: the cassette stands in for the network
+    if let Some(Session::Replay(_)) = state.session.as_deref() {
+        let Some(recorded) = replayed else {
+            record!(EventCategory::Replay, "No recorded upstream response for this request");
+            if let Some(hold) = &hold {
+                for (category, message) in settle_hold(state, hold, None) {
+                    record!(category, message);
+                }
+            }
+            trace.finish(502);
+            return ((StatusCode::BAD_GATEWAY, "Replay: no recorded upstream response").into_response(), trace);
+        };
+        // Recorded after any response faults were applied, so they aren't applied
+        // again and usage is metered on the body the agent got
+        record!(EventCategory::Upstream, format!("Replayed upstream response: {}", recorded.status));
+        let mut resp_headers = HeaderMap::new();
+        for (name, value) in &recorded.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                resp_headers.append(name, value);
+            }
+        }
+        remove_hop_by_hop_headers(&mut resp_headers);
+        resp_headers.remove(x402::HEADER_PAYMENT_RESPONSE);
+        if let Some(settlement) = payment_response {
+            resp_headers.insert(x402::HEADER_PAYMENT_RESPONSE, HeaderValue::from_str(&settlement).unwrap());
+        }
+        if let Some(verdict) = &rate_limit {
+            verdict.apply_headers(&mut resp_headers);
+        }
+        trace.finish(recorded.status);
+        let mut response_builder = Response::builder().status(recorded.status);
+        *response_builder.headers_mut().unwrap() = resp_headers;
+        let mut response = response_builder.body(Body::from(recorded.body.to_bytes())).unwrap();
+        if req_type == RequestType::AiInference || hold.is_some() {
+            response = meter_usage(state, &mut trace, &agent_id, payee, hold, response);
+        }
+        response.extensions_mut().insert(FromUpstream);
+        return (response, trace);
+    }
+
+    // Mock chain: RPC calls are answered from the ledger
+    if state.mock_rpc && req_type == RequestType::Rpc {
+        let Ok(body) = axum::body::to_bytes(req.into_body(), MAX_BUFFERED_BODY_BYTES).await else {
+            record!(EventCategory::Error, format!("Mock RPC request body is over {} bytes", MAX_BUFFERED_BODY_BYTES));
+            trace.finish(413);
+            let message = format!("Mock RPC requests are limited to {} bytes", MAX_BUFFERED_BODY_BYTES);
+            return ((StatusCode::PAYLOAD_TOO_LARGE, message).into_response(), trace);
+        };
+        let result = rpc::handle(&state.ledger, &state.network, &body);
+        record!(EventCategory::Upstream, "Served by mock RPC node");
+        // Faults first: the agent pays for the response it actually gets
+        let mut response = faults::apply(&response_faults, Json(result).into_response()).await;
+        if hold.is_some() {
+            response = meter_usage(state, &mut trace, &agent_id, payee, hold, response);
+        }
+        trace.finish(response.status().as_u16());
+        if let Some(settlement) = payment_response {
+            response.headers_mut().insert(x402::HEADER_PAYMENT_RESPONSE, HeaderValue::from_str(&settlement).unwrap());
+        }
+        if let Some(verdict) = &rate_limit {
+            verdict.apply_headers(response.headers_mut());
+        }
+        response.extensions_mut().insert(FromUpstream);
+        return (response, trace);
+    }
+
+    // Mock providers: chat, messages and embeddings answered offline
+    if let Some(mock_ai) = state.mock_ai.as_deref().filter(|_| req_type == RequestType::AiInference) {
+        let method = req.method().to_string();
+        let Ok(body) = axum::body::to_bytes(req.into_body(), MAX_BUFFERED_BODY_BYTES).await else {
+            record!(EventCategory::Error, format!("Mock AI request body is over {} bytes", MAX_BUFFERED_BODY_BYTES));
+            trace.finish(413);
+            let body = json!({ "error": { "message": format!("Request bodies are limited to {} bytes", MAX_BUFFERED_BODY_BYTES), "type": "invalid_request_error", "param": null, "code": "request_too_large" } });
+            return ((StatusCode::PAYLOAD_TOO_LARGE, Json(body)).into_response(), trace);
+        };
+        let (response, usage) = mock_ai.handle(&method, upstream_url.path(), &body).unwrap_or_else(|| {
+            let body = json!({ "error": { "message": format!("Unknown endpoint {} (XDR mocks chat/completions, messages and embeddings)", upstream_url.path()), "type": "invalid_request_error", "param": null, "code": "unknown_url" } });
+            ((StatusCode::NOT_FOUND, Json(body)).into_response(), Default::default())
+        });
+        record!(EventCategory::Upstream, format!(
+            "Served by mock AI provider: {} ({} input + {} output tokens)",
+            response.status(), usage.input_tokens, usage.output_tokens
+        ));
+        let response = faults::apply(&response_faults, response).await;
+        let mut response = meter_usage(state, &mut trace, &agent_id, payee, hold, response);
+        trace.finish(response.status().as_u16());
+        if let Some(settlement) = payment_response {
+            response.headers_mut().insert(x402::HEADER_PAYMENT_RESPONSE, HeaderValue::from_str(&settlement).unwrap());
+        }
+        if let Some(verdict) = &rate_limit {
+            verdict.apply_headers(response.headers_mut());
+        }
+        response.extensions_mut().insert(FromUpstream);
+        return (response, trace);
+    }
+
+    let method = req.method().clone();
+    let headers = req.headers().clone();
+    let body = req.into_body();
+
+    let response = match state.client.request(method, forward_url).headers(headers).body(reqwest::Body::wrap_stream(http_body_util::BodyExt::into_data_stream(body))).send().await {
+        Ok(res) => res,
+        Err(e) => {
+            record!(EventCategory::Upstream, format!("Upstream Failed: {}", e));
+            if let Some(hold) = &hold {
+                for (category, message) in settle_hold(state, hold, None) {
+                    record!(category, message);
+                }
+            }
+            trace.finish(502);
+            return ((StatusCode::BAD_GATEWAY, e.to_string()).into_response(), trace);
+        }
+    };
+
+    // 11. RETURN RESPONSE
+    let status = response.status();
+    record!(EventCategory::Upstream, format!("Upstream responded: {}", status));
+    
+    // Log final balance after request completes
+    if let Some(final_state) = state.ledger.get_state(&agent_id) {
+        record!(EventCategory::Info, format!("Balance after request: {}", wallet_summary(&state.ledger, &final_state)));
+    }
+    
+    let mut resp_headers = response.headers().clone();
+    remove_hop_by_hop_headers(&mut resp_headers);
+    let resp_body = Body::from_stream(response.bytes_stream());
+    if let Some(settlement) = payment_response {
+        resp_headers.insert(x402::HEADER_PAYMENT_RESPONSE, HeaderValue::from_str(&settlement).unwrap());
+    }
+    if let Some(verdict) = &rate_limit {
+        verdict.apply_headers(&mut resp_headers);
+    }
+    let mut response_builder = Response::builder().status(status);
+    *response_builder.headers_mut().unwrap() = resp_headers;
+    let mut response = faults::apply(&response_faults, response_builder.body(resp_body).unwrap()).await;
+    if req_type == RequestType::AiInference || hold.is_some() {
+        response = meter_usage(state, &mut trace, &agent_id, payee, hold, response);
+    }
+    trace.finish(response.status().as_u16());
+    response.extensions_mut().insert(FromUpstream);
+    (response, trace)
+}
+
+/// Buffers the request so the exchange can be written to, or checked
+/// against, the session cassette. Responses are never held back: a recorded
+/// body is teed into the cassette as it streams to the agent.
+async fn handle_in_session(state: &AppState, session: Arc<Session>, req: Request) -> (Response, Trace) {
+    let agent_id = req.headers().get(HEADER_AGENT_ID)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("unknown")
+        .to_string();
+    let (parts, body) = req.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_BUFFERED_BODY_BYTES).await else {
+        let mut trace = Trace::new(&agent_id, parts.method.as_str(), &parts.uri.to_string());
+        trace.log(EventCategory::Error, &format!("Request body is over {} bytes (or failed to arrive)", MAX_BUFFERED_BODY_BYTES));
+        trace.finish(413);
+        let message = format!("Request bodies are limited to {} bytes while recording or replaying a session", MAX_BUFFERED_BODY_BYTES);
+        return ((StatusCode::PAYLOAD_TOO_LARGE, message).into_response(), trace);
+    };
+    let request = RecordedRequest {
+        method: parts.method.to_string(),
+        url: parts.uri.to_string(),
+        headers: cassette_headers(&parts.headers),
+        body: RecordedBody::from_bytes(&body),
+    };
+    let req = Request::from_parts(parts, Body::from(body));
+
+    match &*session {
+        Session::Record(recorder) => {
+            let seq = recorder.next_seq();
+            let (response, trace) = handle_request(state, req, None).await;
+            let mut interaction = interaction(seq, &agent_id, request, &response, &trace);
+            let (parts, body) = response.into_parts();
+            let body = usage::tap(body, cassette::MAX_RECORDED_BODY_BYTES, move |body| {
+                match body {
+                    Some(body) => interaction.response.body = RecordedBody::from_bytes(body),
+                    None => warn!(target: "xdr_core", "Response #{} is too large to record; its body is left empty", seq),
+                }
+                let Session::Record(recorder) = &*session else { return };
+                if let Err(e) = recorder.write(&CassetteEntry::Interaction(interaction)) {
+                    warn!(target: "xdr_core", "Failed to write cassette entry: {}", e);
+                }
+            });
+            (Response::from_parts(parts, body), trace)
+        }
+        Session::Replay(player) => {
+            let (recorded, controls) = player.next(&agent_id);
+            for change in controls {
+                apply_control(state, change);
+            }
+            let replayed = recorded.as_ref().filter(|i| i.upstream).map(|i| i.response.clone());
+            let seq = recorded.as_ref().map(|i| i.seq);
+
+            let (mut response, mut trace) = handle_request(state, req, replayed).await;
+            let actual = interaction(seq.unwrap_or(0), &agent_id, request, &response, &trace);
+
+            let issues = match &recorded {
+                Some(recorded) => cassette::diverges(recorded, &actual),
+                None => vec!["no recorded interaction left for this agent".to_string()],
+            };
+            for issue in &issues {
+                warn!(target: "xdr_core", "⚠️  Replay divergence ({}): {}", agent_id, issue);
+                trace.log(EventCategory::Replay, &format!("DIVERGENCE: {}", issue));
+                player.flag(seq, &agent_id, issue);
+            }
+            if !issues.is_empty() {
+                response.headers_mut().insert(
+                    cassette::HEADER_REPLAY_DIVERGENCE,
+                    HeaderValue::from_str(&issues.len().to_string()).unwrap(),
+                );
+            }
+            (response, trace)
+        }
+    }
+}
+
+/// Turns the exchange into a cassette interaction. The response body is left
+/// empty: it's filled in as it streams when recording, and isn't compared on replay.
+fn interaction(seq: u64, agent_id: &str, request: RecordedRequest, response: &Response, trace: &Trace) -> Interaction {
+    let (chaos, ledger) = cassette::decisions(trace);
+    Interaction {
+        seq,
+        agent_id: agent_id.to_string(),
+        request,
+        chaos,
+        ledger,
+        upstream: response.extensions().get::<FromUpstream>().is_some(),
+        response: RecordedResponse {
+            status: response.status().as_u16(),
+            headers: cassette_headers(response.headers()),
+            body: RecordedBody::from_bytes(&[]),
+        },
+    }
+}
+
+/// Header list for a cassette, with credentials redacted so cassettes can be committed.
+fn cassette_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers.iter()
+        .map(|(name, value)| {
+            let value = value.to_str().unwrap_or_default();
+            // L402 tokens and x402 authorizations are bearer credentials too
+            let secret = matches!(
+                name.as_str(),
+                "authorization" | "x-api-key" | "cookie" | "proxy-authorization" | x402::HEADER_PAYMENT | auth::HEADER_AGENT_KEY
+            );
+            let value = if secret { "[redacted]" } else { value };
+            (name.to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// Re-applies a control-plane change recorded in a cassette.
+fn apply_control(state: &AppState, change: ControlChange) {
+    match change {
+        ControlChange::Chaos(config) => {
+            if let Err(e) = state.chaos.set_config(config) {
+                warn!(target: "xdr_core", "Recorded chaos config rejected: {}", e);
+            }
+        }
+        ControlChange::Scenario(scenario) => {
+            if let Err(e) = state.chaos.load_scenario(scenario) {
+                warn!(target: "xdr_core", "Recorded chaos scenario rejected: {}", e);
+            }
+        }
+        ControlChange::Pricing(config) => {
+            if let Err(e) = state.pricing.set_config(config) {
+                warn!(target: "xdr_core", "Recorded pricing table rejected: {}", e);
+            }
+        }
+        ControlChange::Balance { agent_id, amount, currency } => {
+            if let Err(e) = state.ledger.set_balance(&agent_id, &currency, amount) {
+                warn!(target: "xdr_core", "Recorded balance change rejected: {}", e);
+            }
+        }
+        ControlChange::Token(token) => {
+            if let Err(e) = state.ledger.add_token(token) {
+                warn!(target: "xdr_core", "Recorded token rejected: {}", e);
+            }
+        }
+        ControlChange::Ledger(snapshot) => state.ledger.restore(snapshot),
+        ControlChange::Policy { agent_id, policy } => {
+            if let Err(e) = state.ledger.set_policy(&agent_id, policy) {
+                warn!(target: "xdr_core", "Recorded policy rejected: {}", e);
+            }
+        }
+        ControlChange::Approvals(config) => {
+            if let Err(e) = state.approvals.set_config(config) {
+                warn!(target: "xdr_core", "Recorded approval policy rejected: {}", e);
+            }
+        }
+        ControlChange::Decision { approval_id, decision, note } => {
+            if let Err(e) = state.approvals.replay_decision(&approval_id, decision, note) {
+                warn!(target: "xdr_core", "Recorded approval decision rejected: {}", e);
+            }
+        }
+        ControlChange::RateLimits(config) => {
+            if let Err(e) = state.rate_limits.set_config(config) {
+                warn!(target: "xdr_core", "Recorded rate limits rejected: {}", e);
+            }
+        }
+        ControlChange::Mine { blocks } => {
+            miner::mine(&state.ledger, &state.chaos, blocks);
+        }
+        ControlChange::CancelInvoice { invoice_id } => {
+            if let Err(e) = state.ledger.cancel_invoice(&invoice_id) {
+                warn!(target: "xdr_core", "Recorded invoice cancellation rejected: {}", e);
+            }
+        }
+        ControlChange::Group { id, parent, budget_limit } => {
+            if let Err(e) = state.ledger.upsert_group(&id, GroupUpdate::replace(parent.as_deref(), budget_limit)) {
+                warn!(target: "xdr_core", "Recorded budget group rejected: {}", e);
+            }
+        }
+        ControlChange::GroupRemoved { id } => {
+            if let Err(e) = state.ledger.remove_group(&id) {
+                warn!(target: "xdr_core", "Recorded budget group removal rejected: {}", e);
+            }
+        }
+        ControlChange::GroupBalance { group_id, amount, currency } => {
+            if let Err(e) = state.ledger.set_group_balance(&group_id, &currency, amount) {
+                warn!(target: "xdr_core", "Recorded group balance change rejected: {}", e);
+            }
+        }
+        ControlChange::GroupMember { agent_id, group } => {
+            if let Err(e) = state.ledger.assign_group(&agent_id, group.as_deref()) {
+                warn!(target: "xdr_core", "Recorded group membership change rejected: {}", e);
+            }
+        }
+        ControlChange::Services(config) => {
+            match state.services.set_config(config.clone()) {
+                Ok(()) => config.services.iter().for_each(|service| {
+                    state.ledger.register_or_get(&service.agent_id);
+                }),
+                Err(e) => warn!(target: "xdr_core", "Recorded agent services rejected: {}", e),
+            }
+        }
+    }
+}
+
+// --- Helper Logic ---
+
+/// "99.99 USDC, 100.00 USDT, 100.00 CRO", or for an agent in a budget group
+/// "250.00 USDC (group wallet: acme > research)"
+fn wallet_summary(ledger: &Ledger, agent: &AgentState) -> String {
+    let path = agent.group.as_deref().map(|group| ledger.group_path(group)).unwrap_or_default();
+    let (balances, suffix) = match path.last() {
+        Some(wallet) => {
+            let chain: Vec<&str> = path.iter().rev().map(|g| g.id.as_str()).collect();
+            (&wallet.balances, format!(" (group wallet: {})", chain.join(" > ")))
+        }
+        None => (&agent.balances, String::new()),
+    };
+    if balances.is_empty() {
+        return format!("empty wallet{}", suffix);
+    }
+    let summary = balances.iter()
+        .map(|(symbol, amount)| format!("{} {}", amount, symbol))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("{}{}", summary, suffix)
+}
+
+/// The quote's options whose asset is registered on the network (and passes `usable`).
+fn payable_options(ledger: &Ledger, options: &[AssetPrice], usable: impl Fn(&Token) -> bool) -> Vec<(AssetPrice, Token)> {
+    options.iter()
+        .filter_map(|option| ledger.token(&option.currency).map(|token| (option.clone(), token)))
+        .filter(|(_, token)| usable(token))
+        .collect()
+}
+
+/// Why the approval gate stopped a payment.
+enum Held {
+    /// Still waiting for an operator (defer mode)
+    Pending(Approval),
+    Denied(Approval),
+}
+
+/// Runs a payment past the approval queue. `subject` is the credential the
+/// agent pays with, so a retry finds the item it already opened. Ok means the
+/// payment may settle now.
+async fn approval_gate(state: &AppState, trace: &mut Trace, subject: &str, payment: &PaymentRequest<'_>) -> Result<(), Held> {
+    let approval = match state.approvals.find(subject) {
+        Some(existing) => existing,
+        None => {
+            let Some(reason) = state.approvals.requires_approval(payment) else {
+                return Ok(());
+            };
+            let approval = state.approvals.open(subject, payment, reason);
+            trace.log(EventCategory::Approval, &format!(
+                "Payment of {} {} (${}) to {} held for approval as {}: {}",
+                approval.amount, approval.currency, approval.usd_value, approval.payee, approval.id, approval.reason
+            ));
+            info!(target: "xdr_core", "✋ Payment {} by {} awaits approval: {}", approval.id, approval.agent_id, approval.reason);
+            approval
+        }
+    };
+
+    let approval = match (approval.status, state.approvals.get_config().mode) {
+        (ApprovalStatus::Pending, ApprovalMode::Hold) => state.approvals.wait(&approval.id).await.unwrap_or(approval),
+        _ => approval,
+    };
+    let decided_by = approval.decided_by.as_deref().unwrap_or("operator");
+    match approval.status {
+        ApprovalStatus::Approved => {
+            trace.log(EventCategory::Approval, &format!("{} approved by {}", approval.id, decided_by));
+            Ok(())
+        }
+        ApprovalStatus::Denied => {
+            trace.log(EventCategory::Approval, &format!("{} denied by {}", approval.id, decided_by));
+            Err(Held::Denied(approval))
+        }
+        ApprovalStatus::Pending => {
+            trace.log(EventCategory::Approval, &format!(
+                "{} still pending, retry in {}s", approval.id, state.approvals.retry_after(&approval)
+            ));
+            Err(Held::Pending(approval))
+        }
+    }
+}
+
+/// 202 for a payment that waits for approval; the agent retries with the same credential.
+fn approval_pending(approvals: &ApprovalQueue, approval: &Approval) -> Response {
+    let retry_after = approvals.retry_after(approval);
+    let body = json!({
+        "status": 202,
+        "code": "approval_pending",
+        "message": format!("Payment awaits operator approval ({})", approval.reason),
+        "approval": wire::value(approval),
+        "retry_after": retry_after,
+    });
+    let mut resp = (StatusCode::ACCEPTED, Json(body)).into_response();
+    resp.headers_mut().insert("retry-after", HeaderValue::from(retry_after));
+    resp
+}
+
+fn denial_message(approval: &Approval) -> String {
+    let by = approval.decided_by.as_deref().unwrap_or("operator");
+    match &approval.note {
+        Some(note) => format!("Payment {} denied by {}: {}", approval.id, by, note),
+        None => format!("Payment {} denied by {}", approval.id, by),
+    }
+}
+
+/// The L402 402 body for a payment the ledger refused.
+fn payment_rejection(agent_id: &str, error: &PaymentError) -> Response {
+    let mut body = json!({ "status": 402, "error": error.to_string(), "code": error.code(), "agent": agent_id });
+    if let PaymentError::Policy(violation) = error {
+        body["policy"] = wire::value(violation);
+    }
+    let mut resp = (StatusCode::PAYMENT_REQUIRED, Json(body)).into_response();
+    set_retry_after(&mut resp, error);
+    resp
+}
+
+/// Tells the agent when a rate-limited payment can be retried.
+fn set_retry_after(resp: &mut Response, error: &PaymentError) {
+    if let Some(secs) = match error {
+        PaymentError::Policy(violation) => violation.retry_after(),
+        _ => None,
+    } {
+        resp.headers_mut().insert("retry-after", HeaderValue::from(secs));
+    }
+}
+
+/// Misconfigured pricing: the route can't be paid in any asset the network has.
+fn no_payable_asset(mut trace: Trace, options: &[AssetPrice]) -> (Response, Trace) {
+    let currencies: Vec<&str> = options.iter().map(|o| o.currency.as_str()).collect();
+    let message = format!("No payable asset for this route (priced in {})", currencies.join(", "));
+    trace.log(EventCategory::Error, &message);
+    trace.finish(500);
+    ((StatusCode::INTERNAL_SERVER_ERROR, message).into_response(), trace)
+}
+
+/// Events to add to a trace once they're known.
+type TraceEvents = Vec<(EventCategory, String)>;
+
+/// Takes payment for an invoice: straight to the payee, or into escrow when
+/// the price is only a ceiling (`upto`).
+fn charge(state: &AppState, invoice_id: &str, agent_id: &str, resource: &InvoiceResource, upto: bool) -> Result<(PaymentReceipt, Option<Hold>), PaymentError> {
+    match upto {
+        true => state.ledger.reserve(invoice_id, agent_id, &state.network, Some(resource)).map(|(hold, receipt)| (receipt, Some(hold))),
+        false => state.ledger.pay_invoice(invoice_id, agent_id, &state.network, Some(resource)).map(|receipt| (receipt, None)),
+    }
+}
+
+/// Traces a payment the way every protocol settles it: the receipt, its
+/// escrow and transfers, then the reorg and rug-pull chaos that can follow.
+/// A rejection is traced (with the policy that fired) and answered by
+/// `reject`, in the protocol's own format. `payer` is the signer of an x402
+/// authorization.
+fn settle_payment(
+    state: &AppState,
+    trace: &mut Trace,
+    chaos_ctx: &ChaosContext<'_>,
+    payer: Option<&str>,
+    settled: Result<(PaymentReceipt, Option<Hold>), PaymentError>,
+    reject: impl FnOnce(&PaymentError) -> Response,
+) -> Result<(PaymentReceipt, Option<Hold>), Box<Response>> {
+    let (receipt, hold) = match settled {
+        Ok(settled) => settled,
+        Err(e) => {
+            trace.log(EventCategory::Payment, &format!("Payment rejected [{}]: {}", e.code(), e));
+            if let PaymentError::Policy(violation) = &e {
+                trace.log(EventCategory::Policy, &format!("{} fired: {}", violation.code(), violation));
+            }
+            let resp = reject(&e);
+            trace.finish(resp.status().as_u16());
+            return Err(Box::new(resp));
+        }
+    };
+    let submitted = match payer {
+        Some(payer) => format!("x402 payment settled. From: {} | Tx: {} | Block: {}", payer, receipt.tx_hash, block_label(receipt.block_height)),
+        None => format!("Payment submitted to Cronos. Tx: {} | Block: {}", receipt.tx_hash, block_label(receipt.block_height)),
+    };
+    trace.log(EventCategory::Payment, &submitted);
+    if let Some(hold) = &hold {
+        trace.log(EventCategory::Payment, &escrow_message(hold));
+    }
+    trace.log(EventCategory::Info, &format!("Wallet: {} {} | Chain: {}", receipt.new_balance, receipt.currency, receipt.chain_id));
+    trace.log(EventCategory::Payment, &format!("Payment accepted in {}. Bal: {}", receipt.currency, receipt.new_balance));
+    for (category, message) in transfer_events(state, &receipt.tx_hash) {
+        trace.log(category, &message);
+    }
+
+    // Reorg Chaos (automine: the payment's own block can be orphaned)
+    if receipt.block_height.is_some() {
+        if let Some(reorg) = miner::maybe_reorg(&state.ledger, &state.chaos) {
+            trace.log(EventCategory::Chaos, &reorg_message(&reorg));
+        }
+    }
+
+    // Rug Chaos
+    if state.chaos.roll_rug_pull(chaos_ctx) {
+        trace.log(EventCategory::Chaos, "RUG PULL: Payment taken, request dropped");
+        if let Some(hold) = &hold {
+            for (category, message) in settle_hold(state, hold, Some(hold.amount)) {
+                trace.log(category, &message);
+            }
+        }
+        trace.finish(500);
+        return Err(Box::new((StatusCode::INTERNAL_SERVER_ERROR, "Rug Pull").into_response()));
+    }
+    Ok((receipt, hold))
+}
+
+/// Meters an AI response's token usage and settles it as a post-paid charge
+/// once the body has been read through (streams included). With an `upto`
+/// hold the usage is captured from escrow instead, and a failed response
+/// releases the hold straight away. The charge and its breakdown are added to
+/// the trace afterwards.
+fn meter_usage(state: &AppState, trace: &mut Trace, agent_id: &str, payee: &str, hold: Option<Hold>, response: Response) -> Response {
+    if let Some(held) = hold.as_ref().filter(|_| !response.status().is_success()) {
+        trace.log(EventCategory::Payment, &format!("Upstream answered {}: releasing the escrow hold", response.status()));
+        for (category, message) in settle_hold(state, held, None) {
+            trace.log(category, &message);
+        }
+        return response;
+    }
+    if hold.is_none() && (!state.pricing.meters_usage() || !response.status().is_success()) {
+        return response;
+    }
+    match &hold {
+        Some(_) => trace.log(EventCategory::Payment, "Metering token usage (captured from escrow when the response completes)"),
+        None => trace.log(EventCategory::Payment, "Metering token usage (charged when the response completes)"),
+    }
+    state.expect_amendments(&trace.id);
+    let (parts, body) = response.into_parts();
+    let (state, trace_id, agent_id, payee) = (state.clone(), trace.id.clone(), agent_id.to_string(), payee.to_string());
+    let body = usage::meter(body, move |reported| {
+        // Runs as the body is dropped: charge off the async workers, which
+        // may be in the middle of serving other requests
+        let settle = move || {
+            let (mut events, charge) = match &hold {
+                Some(hold) => capture_usage(&state, hold, reported),
+                None => settle_usage(&state, &agent_id, &payee, reported),
+            };
+            if let Err(e) = state.ledger.commit() {
+                error!(target: "xdr_core", "Usage charge for {} is not durable: {}", agent_id, e);
+                events.push((EventCategory::Error, format!("Usage charge not persisted: {}", e)));
+            }
+            state.amend_trace(&trace_id, move |trace| {
+                for (category, message) in events {
+                    trace.log(category, &message);
+                }
+                trace.usage = charge;
+            });
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(settle)),
+            Err(_) => settle(),
+        }
+    });
+    Response::from_parts(parts, body)
+}
+
+/// Prices the usage a response reports against the model price sheet. None
+/// (with the reason in the events) when there's no usage or no price.
+fn price_usage(state: &AppState, reported: Option<usage::ReportedUsage>) -> (TraceEvents, Option<(UsageCharge, Amount)>) {
+    let Some(reported) = reported else {
+        return (vec![(EventCategory::Info, "No token usage reported".to_string())], None);
+    };
+    let model = reported.model.unwrap_or_else(|| "unknown".to_string());
+    let usage::TokenUsage { input_tokens, output_tokens } = reported.tokens;
+    let Some(price) = state.pricing.model_price(&model) else {
+        return (vec![(EventCategory::Info, format!(
+            "No price for model {} ({} input + {} output tokens)",
+            model, input_tokens, output_tokens
+        ))], None);
+    };
+
+    let cost = price.cost(input_tokens, output_tokens);
+    let charge = UsageCharge {
+        model,
+        input_tokens,
+        output_tokens,
+        input_cost: cost.input.to_string(),
+        output_cost: cost.output.to_string(),
+        total_cost: cost.total().to_string(),
+        currency: price.currency.clone(),
+        tx_hash: None,
+    };
+    let events = vec![(EventCategory::Payment, format!(
+        "Usage for {}: {} input × {}/M + {} output × {}/M = {} {}",
+        charge.model, input_tokens, price.input_per_mtok, output_tokens, price.output_per_mtok, charge.total_cost, charge.currency
+    ))];
+    (events, Some((charge, cost.total())))
+}
+
+fn settle_usage(state: &AppState, agent_id: &str, payee: &str, reported: Option<usage::ReportedUsage>) -> (TraceEvents, Option<UsageCharge>) {
+    let (mut events, priced) = price_usage(state, reported);
+    let Some((mut charge, total)) = priced else {
+        if let Some((_, reason)) = events.last_mut() {
+            reason.push_str(": nothing charged");
+        }
+        return (events, None);
+    };
+    if total.is_zero() {
+        return (events, Some(charge));
+    }
+
+    let payee_agent = state.services.payee_agent(payee);
+    let invoice = state.ledger.create_invoice(agent_id, total, &charge.currency, payee, payee_agent.as_deref(), None);
+    match state.ledger.pay_invoice(&invoice.id, agent_id, &state.network, None) {
+        Ok(receipt) => {
+            info!(target: "xdr_core", "🧮 {} charged {} {} for {} tokens of {}", agent_id, charge.total_cost, charge.currency, charge.input_tokens + charge.output_tokens, charge.model);
+            events.push((EventCategory::Payment, format!(
+                "USAGE CHARGED: {} {} post-paid. Tx: {} | Block: {} | Bal: {}",
+                charge.total_cost, charge.currency, receipt.tx_hash, block_label(receipt.block_height), receipt.new_balance
+            )));
+            events.extend(transfer_events(state, &receipt.tx_hash));
+            charge.tx_hash = Some(receipt.tx_hash);
+        }
+        // The response has already been served: what it cost is owed instead
+        Err(e) => {
+            let owed = state.ledger.owe_invoice(&invoice.id);
+            warn!(target: "xdr_core", "Usage charge for {} rejected: {}; {} {} recorded as debt", agent_id, e, charge.total_cost, charge.currency);
+            events.push((EventCategory::Error, format!(
+                "Usage charge rejected [{}]: {}. {} {} recorded as debt (invoice {}, now owing {} {})",
+                e.code(), e, charge.total_cost, charge.currency, invoice.id,
+                owed.unwrap_or_default(), charge.currency
+            )));
+        }
+    }
+    (events, Some(charge))
+}
+
+/// Captures what a response's usage cost (in the hold's asset, at most the
+/// authorized amount) and refunds the rest. Without a priced usage there is
+/// nothing to go by, so the whole authorization is captured.
+fn capture_usage(state: &AppState, hold: &Hold, reported: Option<usage::ReportedUsage>) -> (TraceEvents, Option<UsageCharge>) {
+    let (mut events, priced) = price_usage(state, reported);
+    let Some((mut charge, total)) = priced else {
+        if let Some((_, reason)) = events.last_mut() {
+            reason.push_str(": capturing the full authorization");
+        }
+        events.extend(settle_hold(state, hold, Some(hold.amount)));
+        return (events, None);
+    };
+
+    let cost = match charge.currency.eq_ignore_ascii_case(&hold.currency) {
+        true => Some(total),
+        false => state.ledger.token(&charge.currency)
+            .zip(state.ledger.token(&hold.currency))
+            .and_then(|(priced_in, held_in)| held_in.from_usd(priced_in.usd_value(total))),
+    };
+    let captured = match cost {
+        Some(cost) if cost > hold.amount => {
+            events.push((EventCategory::Payment, format!(
+                "Usage ({} {}) exceeds the {} {} authorized: capturing the cap",
+                cost, hold.currency, hold.amount, hold.currency
+            )));
+            hold.amount
+        }
+        Some(cost) => cost,
+        None => {
+            events.push((EventCategory::Error, format!(
+                "Cannot convert {} {} into {}: capturing the full authorization",
+                charge.total_cost, charge.currency, hold.currency
+            )));
+            hold.amount
+        }
+    };
+    events.extend(settle_hold(state, hold, Some(captured)));
+    charge.tx_hash = Some(hold.tx_hash.clone());
+    (events, Some(charge))
+}
+
+/// Captures `amount` from a hold (or releases it whole when None) and
+/// describes the capture and the refund for the trace.
+fn settle_hold(state: &AppState, hold: &Hold, amount: Option<Amount>) -> TraceEvents {
+    let settled = match amount {
+        Some(amount) => state.ledger.capture(&hold.id, amount),
+        None => state.ledger.release(&hold.id),
+    };
+    let (closed, refund) = match settled {
+        Ok(settled) => settled,
+        Err(e) => {
+            warn!(target: "xdr_core", "Escrow hold {} not settled: {}", hold.id, e);
+            return vec![(EventCategory::Error, format!("Escrow hold {} not settled: {}", hold.id, e))];
+        }
+    };
+    let mut events = vec![(EventCategory::Payment, match amount {
+        Some(_) => format!("CAPTURED: {} of {} {} (hold {})", closed.captured, closed.amount, closed.currency, closed.id),
+        None => format!("RELEASED: hold {} ({} {}), nothing captured", closed.id, closed.amount, closed.currency),
+    })];
+    if let Some(refund) = refund {
+        info!(target: "xdr_core", "↩️  Refunded {} {} of unused escrow to {}", refund.amount, refund.currency, closed.agent_id);
+        events.push((EventCategory::Payment, format!(
+            "REFUND: {} {} returned to {}. Tx: {} | Block: {}",
+            refund.amount, refund.currency, closed.agent_id, refund.hash, block_label(refund.block_height)
+        )));
+    }
+    if let Some(payout) = &closed.payout_tx {
+        events.extend(transfer_events(state, payout));
+    }
+    events
+}
+
+/// Both sides of an agent-to-agent transfer: the payer's trace gets the
+/// event returned here, and the payee gets a trace of its own for the
+/// income. Empty for payments to an upstream.
+fn transfer_events(state: &AppState, tx_hash: &str) -> TraceEvents {
+    let Some(tx) = state.ledger.get_transaction(tx_hash) else { return Vec::new() };
+    let Some(payee) = tx.payee_agent.as_deref() else { return Vec::new() };
+    let wallet = state.ledger.get_state(payee)
+        .map(|agent| wallet_summary(&state.ledger, &agent))
+        .unwrap_or_default();
+    let via = if tx.from == xdr_ledger::escrow::ESCROW_ADDRESS { " via escrow" } else { "" };
+    info!(target: "xdr_core", "🤝 {} paid {} {} to agent {}{}", tx.agent_id, tx.amount, tx.currency, payee, via);
+
+    let (method, url) = state.ledger.get_invoice(&tx.invoice_id)
+        .and_then(|invoice| invoice.resource)
+        .map_or_else(|| ("TRANSFER".to_string(), format!("https://{}/", tx.payee)), |r| (r.method, r.url));
+    let mut income = Trace::new(payee, &method, &url);
+    income.log(EventCategory::Payment, &format!(
+        "INCOME: {} {} from agent {}{} ({}). Tx: {} | Block: {}",
+        tx.amount, tx.currency, tx.agent_id, via, tx.from, tx.hash, block_label(tx.block_height)
+    ));
+    income.log(EventCategory::Info, &format!("Balance: {}", wallet));
+    income.finish(200);
+    state.commit_trace(income);
+
+    vec![(EventCategory::Payment, format!(
+        "TRANSFER: {} {} to agent {}{} ({}), not burned. Payee balance: {}",
+        tx.amount, tx.currency, payee, via, tx.to, wallet
+    ))]
+}
+
+fn escrow_message(hold: &Hold) -> String {
+    format!(
+        "ESCROW: reserved up to {} {} (hold {}); the unused part is refunded after the response",
+        hold.amount, hold.currency, hold.id
+    )
+}
+
+fn block_label(height: Option<u64>) -> String {
+    height.map(|h| h.to_string()).unwrap_or_else(|| "pending".to_string())
+}
+
+fn reorg_message(reorg: &xdr_ledger::chain::Reorg) -> String {
+    let orphaned: Vec<&str> = reorg.orphaned.iter().map(|tx| tx.hash.as_str()).collect();
+    format!(
+        "REORG: {} block(s) replaced from #{}, orphaned: {:?}",
+        reorg.depth, reorg.from_block, orphaned
+    )
+}
+
+fn resolve_upstream_url(req: &Request) -> Result<Url, String> {
+    let uri = req.uri();
+
+    // Case A: Absolute URL
+    if let (Some(_scheme), Some(_host)) = (uri.scheme(), uri.host()) {
+        let url_str = uri.to_string();
+        return Url::parse(&url_str).map_err(|_| "Invalid Absolute URL".to_string());
+    }
+
+    // Case B: Relative URL -> Need Header
+    let upstream_host = req.headers()
+        .get(HEADER_UPSTREAM_HOST)
+        .and_then(|v| v.to_str().ok())
+        .ok_or("Missing X-Upstream-Host header or Absolute URL")?;
+
+    let path = uri.path();
     let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
     let url_string = format!("https://{}{}{}", upstream_host, path, query);
     
@@ -371,6 +2399,9 @@ fn resolve_upstream_url(req: &Request) -> Result<Url, String> {
 
 fn classify_request(url: &Url, _method: &axum::http::Method) -> RequestType {
     let host = url.host_str().unwrap_or("");
+    if host.ends_with(services::AGENT_HOST_SUFFIX) {
+        return RequestType::Unknown;
+    }
     
     if host.contains("openai.com") || host.contains("anthropic") {
         return RequestType::AiInference;
@@ -390,4 +2421,141 @@ fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
     for header in to_remove {
         headers.remove(header);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state(chaos: ChaosEngine, auth: AgentAuth) -> AppState {
+        AppState {
+            client: Client::new(),
+            ledger: Ledger::new(),
+            chaos,
+            pricing: PricingTable::new(),
+            approvals: ApprovalQueue::new(),
+            rate_limits: RateLimiter::new(),
+            services: ServiceRegistry::new(),
+            auth,
+            control: ControlAuth::new(Vec::new()).unwrap(),
+            audit: AuditLog::open(None).unwrap(),
+            traces: Default::default(),
+            amendments: Default::default(),
+            network: "cronos-testnet".to_string(),
+            protocol: PaymentProtocol::X402,
+            session: None,
+            mock_rpc: false,
+            mock_ai: None,
+        }
+    }
+
+    fn agent_request(agent_id: &str, key: &str) -> Request {
+        Request::builder()
+            .uri("/v1/chat/completions")
+            .header(HEADER_UPSTREAM_HOST, "api.openai.com")
+            .header(HEADER_AGENT_ID, agent_id)
+            .header(auth::HEADER_AGENT_KEY, key)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sessions_refuse_request_bodies_too_large_to_buffer() {
+        let state = test_state(ChaosEngine::new(), AgentAuth::new());
+        let path = std::env::temp_dir().join(format!("xdr-session-{}-{}.jsonl", std::process::id(), chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()));
+        let header = CassetteHeader {
+            version: cassette::CASSETTE_VERSION,
+            network: state.network.clone(),
+            protocol: "X402".to_string(),
+            ledger_seed: 0,
+            ledger: state.ledger.snapshot(),
+            chaos: state.chaos.get_config(),
+            scenario: None,
+            pricing: state.pricing.get_config(),
+            tokens: state.ledger.tokens(),
+            approvals: state.approvals.get_config(),
+            rate_limits: state.rate_limits.get_config(),
+            invoice_ttl: None,
+            services: state.services.get_config(),
+        };
+        let session = Arc::new(Session::Record(Recorder::create(path.to_str().unwrap(), header).unwrap()));
+
+        let mut req = agent_request("agent-1", "sk-1");
+        *req.body_mut() = Body::from(vec![b'x'; MAX_BUFFERED_BODY_BYTES + 1]);
+        let (response, trace) = handle_in_session(&state, session, req).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!((trace.agent_id.as_str(), trace.status_code), ("agent-1", Some(413)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mock_rpc_refuses_bodies_too_large_to_buffer() {
+        let mut state = test_state(ChaosEngine::new(), AgentAuth::new());
+        state.mock_rpc = true;
+        let rpc = |body: Body| Request::builder()
+            .method("POST")
+            .uri("/")
+            .header(HEADER_UPSTREAM_HOST, "evm-t3.cronos.org")
+            .header(HEADER_AGENT_ID, "agent-1")
+            .body(body)
+            .unwrap();
+
+        let call = json!({ "jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": [] }).to_string();
+        let (response, _) = handle_request(&state, rpc(Body::from(call)), None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let (response, trace) = handle_request(&state, rpc(Body::from(vec![b' '; MAX_BUFFERED_BODY_BYTES + 1])), None).await;
+        assert_eq!((response.status(), trace.status_code), (StatusCode::PAYLOAD_TOO_LARGE, Some(413)));
+    }
+
+    #[tokio::test]
+    async fn mock_ai_refuses_bodies_too_large_to_buffer() {
+        let mut state = test_state(ChaosEngine::new(), AgentAuth::new());
+        state.mock_ai = Some(Arc::new(MockAi::new(MockAiConfig::default()).unwrap()));
+        let mut req = agent_request("agent-1", "");
+        *req.method_mut() = axum::http::Method::POST;
+        *req.body_mut() = Body::from(vec![b' '; MAX_BUFFERED_BODY_BYTES + 1]);
+
+        let (response, trace) = handle_request(&state, req, None).await;
+        assert_eq!((response.status(), trace.status_code), (StatusCode::PAYLOAD_TOO_LARGE, Some(413)));
+    }
+
+    #[test]
+    fn group_requests_tell_null_from_missing_fields() {
+        let request: GroupRequest = serde_json::from_str(r#"{ "id": "team", "parent": null }"#).unwrap();
+        assert_eq!((request.parent, request.budget_limit), (Some(None), None));
+        let request: GroupRequest = serde_json::from_str(r#"{ "id": "team", "parent": "org", "budget_limit": "5" }"#).unwrap();
+        assert_eq!((request.parent, request.budget_limit), (Some(Some("org".to_string())), Some(Some(Amount::from_whole(5)))));
+    }
+
+    #[tokio::test]
+    async fn chaos_only_counts_authenticated_requests() {
+        let chaos = ChaosEngine::new();
+        chaos.set_config(ChaosConfig { enabled: true, seed: 42, global_failure_rate: 1.0, ..Default::default() }).unwrap();
+        let auth = AgentAuth::new();
+        auth.register(AgentCredential { agent_id: "agent-1".to_string(), credential: auth::Credential::Bearer { key: "sk-1".to_string() } }).unwrap();
+        let state = test_state(chaos, auth);
+
+        let mut seqs = Vec::new();
+        for key in ["sk-1", "forged", "sk-1", "forged", "sk-1"] {
+            let (response, trace) = handle_request(&state, agent_request("agent-1", key), None).await;
+            assert_eq!(response.status() == StatusCode::UNAUTHORIZED, key == "forged");
+            seqs.extend(trace.chaos_decisions.iter().map(|d| d.seq));
+        }
+        // Forged requests never reached chaos, so the real agent's numbering is unbroken
+        seqs.dedup();
+        assert_eq!(seqs, [1, 2, 3]);
+    }
+
+    #[test]
+    fn cassette_headers_redact_every_credential() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("L402 invoice-token"));
+        headers.insert(x402::HEADER_PAYMENT, HeaderValue::from_static("eyJ4NDAyVmVyc2lvbiI6MX0="));
+        headers.insert(auth::HEADER_AGENT_KEY, HeaderValue::from_static("sk-agent"));
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        let recorded: HashMap<String, String> = cassette_headers(&headers).into_iter().collect();
+        assert_eq!(recorded["authorization"], "[redacted]");
+        assert_eq!(recorded[x402::HEADER_PAYMENT], "[redacted]");
+        assert_eq!(recorded[auth::HEADER_AGENT_KEY], "[redacted]");
+        assert_eq!(recorded["content-type"], "application/json");
+    }
+}
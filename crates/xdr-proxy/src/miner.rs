@@ -0,0 +1,57 @@
+//! Chain clock: mines blocks on an interval or on demand, and gives the chaos
+//! engine a chance to reorg after every block.
+
+use serde::Serialize;
+use std::time::Duration;
+use tracing::{info, warn};
+use xdr_chaos::ChaosEngine;
+use xdr_ledger::chain::{Block, Reorg};
+use xdr_ledger::Ledger;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MineReport {
+    pub height: u64,
+    pub blocks: Vec<Block>,
+    pub reorgs: Vec<Reorg>,
+}
+
+/// Mines `count` blocks, rolling for a chaos reorg after each one.
+pub fn mine(ledger: &Ledger, chaos: &ChaosEngine, count: u64) -> MineReport {
+    let mut report = MineReport::default();
+    for _ in 0..count {
+        let block = ledger.mine_block();
+        if !block.transactions.is_empty() {
+            info!(target: "xdr_core", "⛏️  Block {} mined with {} payment(s)", block.number, block.transactions.len());
+        }
+        report.blocks.push(block);
+        if let Some(reorg) = maybe_reorg(ledger, chaos) {
+            report.reorgs.push(reorg);
+        }
+    }
+    report.height = ledger.block_number();
+    report
+}
+
+/// Rolls the chaos dice for a reorg and performs it.
+pub fn maybe_reorg(ledger: &Ledger, chaos: &ChaosEngine) -> Option<Reorg> {
+    let depth = chaos.roll_reorg(ledger.block_number())?;
+    let reorg = ledger.reorg(depth);
+    warn!(
+        target: "xdr_core",
+        "🔀 Chaos reorg: {} block(s) replaced from #{}, {} payment(s) orphaned",
+        reorg.depth, reorg.from_block, reorg.orphaned.len()
+    );
+    Some(reorg)
+}
+
+/// Mines a block every `block_time` until the process exits.
+pub fn spawn_clock(ledger: Ledger, chaos: ChaosEngine, block_time: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(block_time);
+        ticker.tick().await; // first tick fires immediately
+        loop {
+            ticker.tick().await;
+            mine(&ledger, &chaos, 1);
+        }
+    });
+}
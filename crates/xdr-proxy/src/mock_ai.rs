@@ -0,0 +1,449 @@
+//! Mock OpenAI and Anthropic upstreams.
+//!
+//! Answers `/v1/chat/completions`, `/v1/messages` and `/v1/embeddings` in the
+//! provider's own wire format, so agents (and their SDKs) can run with no
+//! network and no API keys. Replies come from templates, can stream as SSE,
+//! and report a `usage` block counted with the usual ~4 characters per token.
+
+use axum::body::Body;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use bytes::Bytes;
+use globset::GlobMatcher;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha3::{Digest, Keccak256};
+use std::convert::Infallible;
+use std::time::Duration;
+use xdr_chaos::rules::glob;
+
+use crate::usage::TokenUsage;
+
+/// Used when no reply template matches.
+pub const DEFAULT_REPLY: &str = "This is a mock response from XDR to: {{prompt}}";
+
+const DEFAULT_EMBEDDING_DIMENSIONS: usize = 1536;
+/// Longest embedding the mock builds (OpenAI's largest model has 3072)
+pub const MAX_EMBEDDING_DIMENSIONS: usize = 8192;
+const DEFAULT_CHUNK_DELAY_MS: u64 = 20;
+
+fn default_chunk_delay_ms() -> u64 {
+    DEFAULT_CHUNK_DELAY_MS
+}
+
+fn default_embedding_dimensions() -> usize {
+    DEFAULT_EMBEDDING_DIMENSIONS
+}
+
+/// A canned reply. `{{prompt}}` (the last user message) and `{{model}}` are
+/// filled in from the request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MockReply {
+    /// Glob on the requested model, e.g. `gpt-4o*`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Only when the last user message contains this (case-insensitive)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contains: Option<String>,
+    pub reply: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MockAiConfig {
+    /// First match wins; unmatched requests get `DEFAULT_REPLY`
+    #[serde(default)]
+    pub replies: Vec<MockReply>,
+    /// Pause between SSE chunks
+    #[serde(default = "default_chunk_delay_ms")]
+    pub chunk_delay_ms: u64,
+    /// Vector length when the request doesn't ask for `dimensions` (at most
+    /// `MAX_EMBEDDING_DIMENSIONS`)
+    #[serde(default = "default_embedding_dimensions")]
+    pub embedding_dimensions: usize,
+}
+
+impl Default for MockAiConfig {
+    fn default() -> Self {
+        Self {
+            replies: Vec::new(),
+            chunk_delay_ms: DEFAULT_CHUNK_DELAY_MS,
+            embedding_dimensions: DEFAULT_EMBEDDING_DIMENSIONS,
+        }
+    }
+}
+
+/// The mock provider, with its reply globs compiled.
+pub struct MockAi {
+    config: MockAiConfig,
+    models: Vec<Option<GlobMatcher>>,
+}
+
+impl MockAi {
+    pub fn new(config: MockAiConfig) -> Result<Self, String> {
+        if !(1..=MAX_EMBEDDING_DIMENSIONS).contains(&config.embedding_dimensions) {
+            return Err(format!("embedding_dimensions must be between 1 and {}", MAX_EMBEDDING_DIMENSIONS));
+        }
+        let models = config.replies.iter()
+            .map(|r| r.model.as_deref().map(|p| glob(p, "model", true)).transpose())
+            .collect::<Result<_, String>>()?;
+        Ok(Self { config, models })
+    }
+
+    /// Answers one request. None when the path isn't a mocked endpoint.
+    pub fn handle(&self, method: &str, path: &str, body: &[u8]) -> Option<(Response, TokenUsage)> {
+        let path = path.trim_end_matches('/');
+        let style = if path.ends_with("/chat/completions") {
+            Endpoint::ChatCompletions
+        } else if path.ends_with("/messages") {
+            Endpoint::Messages
+        } else if path.ends_with("/embeddings") {
+            Endpoint::Embeddings
+        } else if path.ends_with("/models") && method == "GET" {
+            return Some((Json(models_list()).into_response(), TokenUsage::default()));
+        } else {
+            return None;
+        };
+        if method != "POST" {
+            return Some((style.error(StatusCode::METHOD_NOT_ALLOWED, &format!("{} is not allowed on {}", method, path)), TokenUsage::default()));
+        }
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(v @ Value::Object(_)) => v,
+            Ok(_) => return Some((style.error(StatusCode::BAD_REQUEST, "Request body must be a JSON object"), TokenUsage::default())),
+            Err(e) => return Some((style.error(StatusCode::BAD_REQUEST, &format!("Invalid JSON body: {}", e)), TokenUsage::default())),
+        };
+        let id = hex::encode(&Keccak256::digest(body)[..12]);
+        Some(match style {
+            Endpoint::Embeddings => self.embeddings(&request),
+            _ => self.completion(style, &request, &id),
+        })
+    }
+
+    fn completion(&self, style: Endpoint, request: &Value, id: &str) -> (Response, TokenUsage) {
+        let Some(messages) = request.get("messages").and_then(Value::as_array) else {
+            return (style.error(StatusCode::BAD_REQUEST, "'messages' is a required property"), TokenUsage::default());
+        };
+        let model = request.get("model").and_then(Value::as_str).unwrap_or("mock-model");
+        let prompt = messages.iter().rev()
+            .find(|m| m.get("role").and_then(Value::as_str) == Some("user"))
+            .map(|m| message_text(m.get("content")))
+            .unwrap_or_default();
+        let system = message_text(request.get("system"));
+        let input_tokens = count_tokens(&system) + messages.iter().map(|m| count_tokens(&message_text(m.get("content"))) + 4).sum::<u64>();
+
+        // Reply cut to max_tokens, word by word, like a real model running out of budget
+        let reply = self.reply(model, &prompt);
+        let limit = request.get("max_completion_tokens").or_else(|| request.get("max_tokens")).and_then(Value::as_u64);
+        let mut pieces = Vec::new();
+        let mut output_tokens = 0;
+        let mut truncated = false;
+        for piece in reply.split_inclusive(' ') {
+            let tokens = count_tokens(piece);
+            if limit.is_some_and(|max| output_tokens + tokens > max) {
+                truncated = true;
+                break;
+            }
+            output_tokens += tokens;
+            pieces.push(piece.to_string());
+        }
+        let usage = TokenUsage { input_tokens, output_tokens };
+        let stream = request.get("stream").and_then(Value::as_bool).unwrap_or(false);
+
+        let response = match (style, stream) {
+            (Endpoint::Messages, false) => Json(json!({
+                "id": format!("msg_{}", id),
+                "type": "message",
+                "role": "assistant",
+                "model": model,
+                "content": [{ "type": "text", "text": pieces.concat() }],
+                "stop_reason": if truncated { "max_tokens" } else { "end_turn" },
+                "stop_sequence": null,
+                "usage": { "input_tokens": usage.input_tokens, "output_tokens": usage.output_tokens },
+            })).into_response(),
+            (Endpoint::Messages, true) => self.sse(anthropic_events(id, model, &pieces, truncated, usage)),
+            (_, false) => Json(json!({
+                "id": format!("chatcmpl-{}", id),
+                "object": "chat.completion",
+                "created": chrono::Utc::now().timestamp(),
+                "model": model,
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": pieces.concat(), "refusal": null },
+                    "logprobs": null,
+                    "finish_reason": if truncated { "length" } else { "stop" },
+                }],
+                "usage": openai_usage(usage),
+            })).into_response(),
+            (_, true) => {
+                let include_usage = request.pointer("/stream_options/include_usage").and_then(Value::as_bool).unwrap_or(false);
+                self.sse(openai_events(id, model, &pieces, truncated, include_usage.then_some(usage)))
+            }
+        };
+        (response, usage)
+    }
+
+    fn embeddings(&self, request: &Value) -> (Response, TokenUsage) {
+        let inputs: Vec<String> = match request.get("input") {
+            Some(Value::String(s)) => vec![s.clone()],
+            Some(Value::Array(items)) if !items.is_empty() => items.iter().map(|i| match i {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            }).collect(),
+            _ => return (Endpoint::Embeddings.error(StatusCode::BAD_REQUEST, "'input' must be a string or a non-empty array"), TokenUsage::default()),
+        };
+        let model = request.get("model").and_then(Value::as_str).unwrap_or("text-embedding-3-small");
+        let dimensions = match request.get("dimensions").and_then(Value::as_u64) {
+            None => self.config.embedding_dimensions,
+            Some(d) if (1..=MAX_EMBEDDING_DIMENSIONS as u64).contains(&d) => d as usize,
+            Some(d) => {
+                let message = format!("'dimensions' must be between 1 and {}, got {}", MAX_EMBEDDING_DIMENSIONS, d);
+                return (Endpoint::Embeddings.error(StatusCode::BAD_REQUEST, &message), TokenUsage::default());
+            }
+        };
+        let usage = TokenUsage { input_tokens: inputs.iter().map(|i| count_tokens(i)).sum(), output_tokens: 0 };
+        let data: Vec<Value> = inputs.iter().enumerate()
+            .map(|(index, text)| json!({ "object": "embedding", "index": index, "embedding": embedding(text, dimensions) }))
+            .collect();
+        let body = json!({
+            "object": "list",
+            "data": data,
+            "model": model,
+            "usage": { "prompt_tokens": usage.input_tokens, "total_tokens": usage.input_tokens },
+        });
+        (Json(body).into_response(), usage)
+    }
+
+    fn reply(&self, model: &str, prompt: &str) -> String {
+        let prompt_lower = prompt.to_lowercase();
+        let template = self.config.replies.iter().zip(&self.models)
+            .find(|(reply, glob)| {
+                glob.as_ref().is_none_or(|g| g.is_match(model))
+                    && reply.contains.as_ref().is_none_or(|c| prompt_lower.contains(&c.to_lowercase()))
+            })
+            .map_or(DEFAULT_REPLY, |(reply, _)| reply.reply.as_str());
+        template.replace("{{prompt}}", prompt).replace("{{model}}", model)
+    }
+
+    /// Sends pre-rendered SSE frames, one every `chunk_delay_ms`.
+    fn sse(&self, frames: Vec<String>) -> Response {
+        let delay = Duration::from_millis(self.config.chunk_delay_ms);
+        let stream = futures_util::stream::unfold(frames.into_iter(), move |mut frames| async move {
+            let frame = frames.next()?;
+            tokio::time::sleep(delay).await;
+            Some((Ok::<_, Infallible>(Bytes::from(frame)), frames))
+        });
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/event-stream; charset=utf-8")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Body::from_stream(stream))
+            .unwrap()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Endpoint {
+    ChatCompletions,
+    Messages,
+    Embeddings,
+}
+
+impl Endpoint {
+    /// The provider's error envelope.
+    fn error(self, status: StatusCode, message: &str) -> Response {
+        let body = match self {
+            Endpoint::Messages => json!({ "type": "error", "error": { "type": "invalid_request_error", "message": message } }),
+            _ => json!({ "error": { "message": message, "type": "invalid_request_error", "param": null, "code": null } }),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+/// Roughly 4 characters per token, like OpenAI's rule of thumb.
+pub fn count_tokens(text: &str) -> u64 {
+    match text.chars().count() {
+        0 => 0,
+        n => n.div_ceil(4) as u64,
+    }
+}
+
+/// Text of a message `content` (or Anthropic `system`): a string, or the
+/// `text` of each part.
+fn message_text(content: Option<&Value>) -> String {
+    match content {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(parts)) => parts.iter()
+            .filter_map(|p| p.get("text").and_then(Value::as_str))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+fn openai_usage(usage: TokenUsage) -> Value {
+    json!({
+        "prompt_tokens": usage.input_tokens,
+        "completion_tokens": usage.output_tokens,
+        "total_tokens": usage.input_tokens + usage.output_tokens,
+    })
+}
+
+fn openai_events(id: &str, model: &str, pieces: &[String], truncated: bool, usage: Option<TokenUsage>) -> Vec<String> {
+    let created = chrono::Utc::now().timestamp();
+    let chunk = |delta: Value, finish_reason: Value| {
+        let mut chunk = json!({
+            "id": format!("chatcmpl-{}", id),
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [{ "index": 0, "delta": delta, "logprobs": null, "finish_reason": finish_reason }],
+        });
+        if usage.is_some() {
+            chunk["usage"] = Value::Null;
+        }
+        format!("data: {}\n\n", chunk)
+    };
+    let mut frames = vec![chunk(json!({ "role": "assistant", "content": "" }), Value::Null)];
+    frames.extend(pieces.iter().map(|p| chunk(json!({ "content": p }), Value::Null)));
+    frames.push(chunk(json!({}), json!(if truncated { "length" } else { "stop" })));
+    if let Some(usage) = usage {
+        let last = json!({
+            "id": format!("chatcmpl-{}", id),
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [],
+            "usage": openai_usage(usage),
+        });
+        frames.push(format!("data: {}\n\n", last));
+    }
+    frames.push("data: [DONE]\n\n".to_string());
+    frames
+}
+
+fn anthropic_events(id: &str, model: &str, pieces: &[String], truncated: bool, usage: TokenUsage) -> Vec<String> {
+    let event = |name: &str, data: Value| format!("event: {}\ndata: {}\n\n", name, data);
+    let mut frames = vec![
+        event("message_start", json!({
+            "type": "message_start",
+            "message": {
+                "id": format!("msg_{}", id),
+                "type": "message",
+                "role": "assistant",
+                "model": model,
+                "content": [],
+                "stop_reason": null,
+                "stop_sequence": null,
+                "usage": { "input_tokens": usage.input_tokens, "output_tokens": 1 },
+            },
+        })),
+        event("content_block_start", json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "" } })),
+        event("ping", json!({ "type": "ping" })),
+    ];
+    frames.extend(pieces.iter().map(|p| {
+        event("content_block_delta", json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": p } }))
+    }));
+    frames.push(event("content_block_stop", json!({ "type": "content_block_stop", "index": 0 })));
+    frames.push(event("message_delta", json!({
+        "type": "message_delta",
+        "delta": { "stop_reason": if truncated { "max_tokens" } else { "end_turn" }, "stop_sequence": null },
+        "usage": { "output_tokens": usage.output_tokens },
+    })));
+    frames.push(event("message_stop", json!({ "type": "message_stop" })));
+    frames
+}
+
+/// A unit vector derived from the text, so equal inputs embed identically.
+fn embedding(text: &str, dimensions: usize) -> Vec<f32> {
+    let mut seed = Keccak256::digest(text.as_bytes()).to_vec();
+    let mut values = Vec::with_capacity(dimensions);
+    while values.len() < dimensions {
+        values.extend(seed.chunks(2).map(|b| (u16::from_le_bytes([b[0], b[1]]) as f32 / u16::MAX as f32) * 2.0 - 1.0));
+        seed = Keccak256::digest(&seed).to_vec();
+    }
+    values.truncate(dimensions);
+    let norm = values.iter().map(|v| v * v).sum::<f32>().sqrt().max(f32::EPSILON);
+    values.iter().map(|v| v / norm).collect()
+}
+
+fn models_list() -> Value {
+    let models = ["gpt-4o", "gpt-4o-mini", "text-embedding-3-small", "claude-sonnet-4-5", "claude-haiku-4-5"];
+    json!({
+        "object": "list",
+        "data": models.iter().map(|id| json!({ "id": id, "object": "model", "created": 0, "owned_by": "xdr-mock" })).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn chat(mock: &MockAi, body: Value) -> (Value, TokenUsage) {
+        let (response, usage) = mock.handle("POST", "/v1/chat/completions", body.to_string().as_bytes()).unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (serde_json::from_slice(&bytes).unwrap(), usage)
+    }
+
+    fn embed(mock: &MockAi, body: Value) -> (StatusCode, TokenUsage) {
+        let (response, usage) = mock.handle("POST", "/v1/embeddings", body.to_string().as_bytes()).unwrap();
+        (response.status(), usage)
+    }
+
+    #[test]
+    fn embeddings_are_deterministic_unit_vectors() {
+        let a = embedding("hello", 64);
+        assert_eq!(a.len(), 64);
+        assert_eq!(a, embedding("hello", 64));
+        assert_ne!(a, embedding("world", 64));
+        let norm = a.iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-4);
+    }
+
+    #[tokio::test]
+    async fn replies_match_on_model_and_prompt_and_stop_at_max_tokens() {
+        let replies = serde_json::from_value(json!([
+            { "model": "gpt-4o*", "contains": "WEATHER", "reply": "Sunny in {{model}}" },
+            { "reply": "one two three four five" },
+        ]))
+        .unwrap();
+        let mock = MockAi::new(MockAiConfig { replies, chunk_delay_ms: 0, ..Default::default() }).unwrap();
+        let ask = |model: &str, prompt: &str| json!({ "model": model, "messages": [{ "role": "user", "content": prompt }] });
+
+        let (body, _) = chat(&mock, ask("gpt-4o-mini", "what's the weather?")).await;
+        assert_eq!(body.pointer("/choices/0/message/content"), Some(&json!("Sunny in gpt-4o-mini")));
+
+        let mut capped = ask("claude-3", "what's the weather?");
+        capped["max_tokens"] = json!(4);
+        let (body, usage) = chat(&mock, capped).await;
+        assert_eq!(body.pointer("/choices/0/message/content"), Some(&json!("one two three ")));
+        assert_eq!(body.pointer("/choices/0/finish_reason"), Some(&json!("length")));
+        assert_eq!(usage.output_tokens, 4);
+        assert_eq!(body.pointer("/usage/completion_tokens"), Some(&json!(4)));
+    }
+
+    #[test]
+    fn unknown_paths_are_not_mocked() {
+        let mock = MockAi::new(MockAiConfig::default()).unwrap();
+        assert!(mock.handle("POST", "/v1/files", b"{}").is_none());
+        let (response, _) = mock.handle("GET", "/v1/chat/completions", b"").unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert!(MockAi::new(MockAiConfig { replies: vec![MockReply { model: Some("[".into()), contains: None, reply: String::new() }], ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn embedding_dimensions_are_bounded() {
+        let mock = MockAi::new(MockAiConfig::default()).unwrap();
+        let (status, usage) = embed(&mock, json!({ "input": "hello world", "dimensions": 256 }));
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(usage.input_tokens, 3);
+
+        for dimensions in [0, MAX_EMBEDDING_DIMENSIONS as u64 + 1, u64::MAX] {
+            let (status, usage) = embed(&mock, json!({ "input": "hello", "dimensions": dimensions }));
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", dimensions);
+            assert_eq!(usage.input_tokens, 0);
+        }
+
+        let config = MockAiConfig { embedding_dimensions: MAX_EMBEDDING_DIMENSIONS + 1, ..Default::default() };
+        assert!(MockAi::new(config).is_err());
+    }
+}
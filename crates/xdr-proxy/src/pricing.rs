@@ -0,0 +1,477 @@
+//! Per-route pricing for the payment gate.
+//!
+//! Rules are evaluated top to bottom and the first match wins. A rule matches
+//! on upstream host (glob), HTTP method and path (glob or regex); any matcher
+//! left empty matches everything.
+//!
+//! A rule can accept several assets (`accepts`), each at its own price; the
+//! agent picks one when it pays.
+//!
+//! AI traffic can also be charged by usage: `models` is a per-model price
+//! sheet (per million input/output tokens) settled after the response.
+//!
+//! A rule with `scheme: "upto"` treats its price as a ceiling: the agent pays
+//! it into escrow up front, and whatever the response didn't use (by the
+//! model price sheet) is refunded once it completes.
+
+use globset::GlobMatcher;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use xdr_chaos::rules::glob;
+use xdr_ledger::Amount;
+
+/// Prices and requests that name no asset are in USDC.
+pub(crate) fn default_currency() -> String {
+    "USDC".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_price() -> Amount {
+    Amount::from_units(10_000) // $0.01
+}
+
+/// How a route's price is charged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentScheme {
+    /// The price, paid in full
+    #[default]
+    Exact,
+    /// Up to the price: reserved in escrow, the unused part refunded
+    Upto,
+}
+
+impl PaymentScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Exact => "exact",
+            Self::Upto => "upto",
+        }
+    }
+
+    fn is_exact(&self) -> bool {
+        *self == Self::Exact
+    }
+}
+
+/// A price in one specific asset.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AssetPrice {
+    pub currency: String,
+    pub price: Amount,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PricingRule {
+    /// Glob on the upstream host, e.g. `*.openai.com`
+    #[serde(default)]
+    pub host: Option<String>,
+    /// HTTP method, e.g. `POST` (case-insensitive)
+    #[serde(default)]
+    pub method: Option<String>,
+    /// Glob on the request path, e.g. `/v1/chat/*`
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Regex on the request path (checked in addition to `path`)
+    #[serde(default)]
+    pub path_regex: Option<String>,
+    pub price: Amount,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    /// Other assets the route can be paid in (offered after `price`/`currency`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accepts: Vec<AssetPrice>,
+    #[serde(default = "default_true")]
+    pub requires_payment: bool,
+    /// `exact` (default) or `upto` (the price is a ceiling, see above)
+    #[serde(default, skip_serializing_if = "PaymentScheme::is_exact")]
+    pub scheme: PaymentScheme,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+/// Token prices for one model (or family), per million tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelPrice {
+    /// Glob on the model the upstream reports, e.g. `gpt-4o-mini*` (put
+    /// specific models before broader globs like `gpt-4o*`)
+    pub model: String,
+    pub input_per_mtok: Amount,
+    pub output_per_mtok: Amount,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+}
+
+/// What a response's tokens cost under a `ModelPrice`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UsageCost {
+    pub input: Amount,
+    pub output: Amount,
+}
+
+impl UsageCost {
+    /// Both parts; a bill too large for an `Amount` is capped at its maximum.
+    pub fn total(&self) -> Amount {
+        self.input.saturating_add(self.output)
+    }
+}
+
+impl ModelPrice {
+    /// Cost of the given token counts, each part rounded up to the base unit.
+    pub fn cost(&self, input_tokens: u64, output_tokens: u64) -> UsageCost {
+        let part = |tokens: u64, per_mtok: Amount| {
+            let units = (tokens as u128 * per_mtok.units() as u128).div_ceil(1_000_000);
+            Amount::from_units(u64::try_from(units).unwrap_or(u64::MAX))
+        };
+        UsageCost { input: part(input_tokens, self.input_per_mtok), output: part(output_tokens, self.output_per_mtok) }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PricingConfig {
+    #[serde(default)]
+    pub rules: Vec<PricingRule>,
+    /// Usage-based prices for AI responses, first matching model wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<ModelPrice>,
+    /// Price used when `x-simulate-payment` forces a gate on an unpriced route
+    #[serde(default = "default_price")]
+    pub default_price: Amount,
+    #[serde(default = "default_currency")]
+    pub default_currency: String,
+}
+
+impl Default for PricingConfig {
+    /// Mirrors the original behaviour: any path containing "paid" costs $0.01.
+    fn default() -> Self {
+        Self {
+            rules: vec![PricingRule {
+                host: None,
+                method: None,
+                path: Some("*paid*".to_string()),
+                path_regex: None,
+                price: default_price(),
+                currency: default_currency(),
+                accepts: Vec::new(),
+                requires_payment: true,
+                scheme: PaymentScheme::Exact,
+                description: Some("Default paid route".to_string()),
+            }],
+            models: Vec::new(),
+            default_price: default_price(),
+            default_currency: default_currency(),
+        }
+    }
+}
+
+impl PricingRule {
+    /// Every accepted (asset, price) pair, primary first.
+    pub fn options(&self) -> Vec<AssetPrice> {
+        let primary = AssetPrice { currency: self.currency.clone(), price: self.price };
+        std::iter::once(primary).chain(self.accepts.iter().cloned()).collect()
+    }
+}
+
+/// The price the gate should charge for a single request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quote {
+    /// Accepted assets with their prices; the agent pays with one of them
+    pub options: Vec<AssetPrice>,
+    pub requires_payment: bool,
+    pub scheme: PaymentScheme,
+    /// Index of the matching rule (None when falling back to the default price)
+    pub rule: Option<usize>,
+}
+
+struct CompiledRule {
+    rule: PricingRule,
+    host: Option<GlobMatcher>,
+    path: Option<GlobMatcher>,
+    path_regex: Option<Regex>,
+}
+
+impl CompiledRule {
+    fn compile(rule: PricingRule) -> Result<Self, String> {
+        if let Some(empty) = rule.options().iter().find(|o| o.currency.trim().is_empty()) {
+            return Err(format!("Empty currency for price {}", empty.price));
+        }
+        let host = rule.host.as_deref().map(|p| glob(p, "host", true)).transpose()?;
+        let path = rule.path.as_deref().map(|p| glob(p, "path", false)).transpose()?;
+        let path_regex = match &rule.path_regex {
+            Some(pattern) => Some(
+                Regex::new(pattern).map_err(|e| format!("Invalid path regex '{}': {}", pattern, e))?,
+            ),
+            None => None,
+        };
+        Ok(Self { rule, host, path, path_regex })
+    }
+
+    fn matches(&self, host: &str, method: &str, path: &str) -> bool {
+        if let Some(m) = &self.rule.method {
+            if !m.eq_ignore_ascii_case(method) {
+                return false;
+            }
+        }
+        if let Some(glob) = &self.host {
+            if !glob.is_match(host) {
+                return false;
+            }
+        }
+        if let Some(glob) = &self.path {
+            if !glob.is_match(path) {
+                return false;
+            }
+        }
+        if let Some(re) = &self.path_regex {
+            if !re.is_match(path) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+struct PricingState {
+    config: PricingConfig,
+    rules: Vec<CompiledRule>,
+    models: Vec<GlobMatcher>,
+}
+
+/// Shared, hot-swappable pricing table (cloned into the proxy and control plane).
+#[derive(Clone)]
+pub struct PricingTable {
+    state: Arc<RwLock<PricingState>>,
+}
+
+impl Default for PricingTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PricingTable {
+    pub fn new() -> Self {
+        Self::from_config(PricingConfig::default()).expect("default pricing config is valid")
+    }
+
+    pub fn from_config(config: PricingConfig) -> Result<Self, String> {
+        let (rules, models) = Self::compile(&config)?;
+        Ok(Self {
+            state: Arc::new(RwLock::new(PricingState { config, rules, models })),
+        })
+    }
+
+    fn compile(config: &PricingConfig) -> Result<(Vec<CompiledRule>, Vec<GlobMatcher>), String> {
+        let rules = config.rules.iter().cloned().map(CompiledRule::compile).collect::<Result<_, _>>()?;
+        let models = config.models.iter()
+            .map(|m| {
+                if m.currency.trim().is_empty() {
+                    return Err(format!("Empty currency for model '{}'", m.model));
+                }
+                glob(&m.model, "model", true)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok((rules, models))
+    }
+
+    /// Validates and swaps in a new config. The old table stays active on error.
+    pub fn set_config(&self, config: PricingConfig) -> Result<(), String> {
+        let (rules, models) = Self::compile(&config)?;
+        let mut state = self.state.write().unwrap();
+        state.config = config;
+        state.rules = rules;
+        state.models = models;
+        Ok(())
+    }
+
+    pub fn get_config(&self) -> PricingConfig {
+        self.state.read().unwrap().config.clone()
+    }
+
+    /// Finds the first rule matching the request, if any.
+    pub fn quote(&self, host: &str, method: &str, path: &str) -> Option<Quote> {
+        let state = self.state.read().unwrap();
+        state
+            .rules
+            .iter()
+            .position(|r| r.matches(host, method, path))
+            .map(|idx| {
+                let rule = &state.rules[idx].rule;
+                Quote {
+                    options: rule.options(),
+                    requires_payment: rule.requires_payment,
+                    scheme: rule.scheme,
+                    rule: Some(idx),
+                }
+            })
+    }
+
+    /// True when AI responses should be metered for usage-based charges.
+    pub fn meters_usage(&self) -> bool {
+        !self.state.read().unwrap().config.models.is_empty()
+    }
+
+    /// Price sheet entry for a model, if any.
+    pub fn model_price(&self, model: &str) -> Option<ModelPrice> {
+        let state = self.state.read().unwrap();
+        state.models.iter().position(|g| g.is_match(model)).map(|idx| state.config.models[idx].clone())
+    }
+
+    /// Quote used when a gate is forced on a route without a paying rule.
+    pub fn default_quote(&self) -> Quote {
+        let state = self.state.read().unwrap();
+        Quote {
+            options: vec![AssetPrice {
+                currency: state.config.default_currency.clone(),
+                price: state.config.default_price,
+            }],
+            requires_payment: true,
+            scheme: PaymentScheme::Exact,
+            rule: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usd(price: &str) -> Amount {
+        price.parse().unwrap()
+    }
+
+    fn rule(host: Option<&str>, method: Option<&str>, path: Option<&str>, price: &str) -> PricingRule {
+        PricingRule {
+            host: host.map(str::to_string),
+            method: method.map(str::to_string),
+            path: path.map(str::to_string),
+            path_regex: None,
+            price: usd(price),
+            currency: default_currency(),
+            accepts: Vec::new(),
+            scheme: PaymentScheme::Exact,
+            requires_payment: true,
+            description: None,
+        }
+    }
+
+    fn table(rules: Vec<PricingRule>) -> PricingTable {
+        PricingTable::from_config(PricingConfig { rules, ..PricingConfig::default() }).unwrap()
+    }
+
+    fn price(input_per_mtok: Amount, output_per_mtok: Amount) -> ModelPrice {
+        ModelPrice { model: "gpt-4o*".to_string(), input_per_mtok, output_per_mtok, currency: default_currency() }
+    }
+
+    #[test]
+    fn the_first_matching_rule_wins() {
+        let pricing = table(vec![
+            rule(Some("api.openai.com"), Some("POST"), Some("/v1/chat/*"), "0.05"),
+            rule(Some("api.openai.com"), None, None, "0.02"),
+            rule(None, None, None, "0.01"),
+        ]);
+        let quote = pricing.quote("api.openai.com", "POST", "/v1/chat/completions").unwrap();
+        assert_eq!((quote.options[0].price, quote.rule), (usd("0.05"), Some(0)));
+        assert_eq!(pricing.quote("api.openai.com", "GET", "/v1/chat/completions").unwrap().rule, Some(1));
+        assert_eq!(pricing.quote("api.openai.com", "POST", "/v1/embeddings").unwrap().rule, Some(1));
+        assert_eq!(pricing.quote("example.com", "POST", "/v1/chat/completions").unwrap().rule, Some(2));
+    }
+
+    #[test]
+    fn hosts_and_methods_match_case_insensitively() {
+        let pricing = table(vec![rule(Some("*.weather.dev"), Some("get"), None, "0.002")]);
+        assert!(pricing.quote("api.weather.dev", "GET", "/").is_some());
+        assert!(pricing.quote("API.Weather.DEV", "get", "/").is_some());
+        assert!(pricing.quote("weather.dev", "GET", "/").is_none());
+        assert!(pricing.quote("api.weather.dev.evil.com", "GET", "/").is_none());
+        assert!(pricing.quote("api.weather.dev", "POST", "/").is_none());
+    }
+
+    #[test]
+    fn path_globs_and_regexes_must_both_match() {
+        let mut forecast = rule(None, None, Some("/v*/forecast*"), "0.002");
+        forecast.path_regex = Some("^/v[0-9]+/".to_string());
+        let pricing = table(vec![forecast]);
+        assert!(pricing.quote("weather.dev", "GET", "/v2/forecast").is_some());
+        assert!(pricing.quote("weather.dev", "GET", "/v2/forecast/daily").is_some());
+        assert!(pricing.quote("weather.dev", "GET", "/vx/forecast").is_none());
+        // Path globs are case-sensitive
+        assert!(pricing.quote("weather.dev", "GET", "/V2/Forecast").is_none());
+    }
+
+    #[test]
+    fn free_rules_still_match_and_shadow_later_ones() {
+        let mut health = rule(None, None, Some("/health"), "0.0");
+        health.requires_payment = false;
+        let pricing = table(vec![health, rule(None, None, None, "0.01")]);
+        let quote = pricing.quote("api.openai.com", "GET", "/health").unwrap();
+        assert!(!quote.requires_payment);
+        assert_eq!(quote.rule, Some(0));
+    }
+
+    #[test]
+    fn rules_offer_every_accepted_asset() {
+        let mut multi = rule(None, None, None, "0.01");
+        multi.accepts = vec![AssetPrice { currency: "CRO".to_string(), price: usd("0.12") }];
+        let quote = table(vec![multi.clone()]).quote("example.com", "GET", "/").unwrap();
+        let currencies: Vec<&str> = quote.options.iter().map(|o| o.currency.as_str()).collect();
+        assert_eq!(currencies, ["USDC", "CRO"]);
+        assert_eq!(quote.options[1].price, usd("0.12"));
+
+        multi.accepts[0].currency = " ".to_string();
+        assert!(PricingTable::from_config(PricingConfig { rules: vec![multi], ..PricingConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn default_table_prices_paid_paths() {
+        let pricing = PricingTable::new();
+        assert_eq!(pricing.quote("example.com", "GET", "/api/paid/data").unwrap().options[0].price, usd("0.01"));
+        assert!(pricing.quote("example.com", "GET", "/api/free").is_none());
+        let fallback = pricing.default_quote();
+        assert_eq!((fallback.options[0].price, fallback.options[0].currency.as_str(), fallback.rule), (usd("0.01"), "USDC", None));
+    }
+
+    #[test]
+    fn invalid_patterns_keep_the_old_table() {
+        let pricing = table(vec![rule(None, None, Some("/paid"), "0.01")]);
+        let mut bad_regex = rule(None, None, None, "1.0");
+        bad_regex.path_regex = Some("(".to_string());
+        assert!(pricing.set_config(PricingConfig { rules: vec![bad_regex], ..PricingConfig::default() }).is_err());
+        let bad_glob = rule(Some("[a-"), None, None, "1.0");
+        assert!(pricing.set_config(PricingConfig { rules: vec![bad_glob], ..PricingConfig::default() }).is_err());
+        assert_eq!(pricing.quote("example.com", "GET", "/paid").unwrap().options[0].price, usd("0.01"));
+    }
+
+    #[test]
+    fn usage_costs_round_each_part_up() {
+        let cost = price("2.5".parse().unwrap(), "10".parse().unwrap()).cost(1, 1_000_000);
+        assert_eq!(cost.input, Amount::from_units(3));
+        assert_eq!(cost.output, Amount::from_whole(10));
+        assert_eq!(cost.total(), Amount::from_units(10_000_003));
+        assert_eq!(price(Amount::ZERO, Amount::ZERO).cost(5, 5).total(), Amount::ZERO);
+    }
+
+    #[test]
+    fn model_prices_match_the_first_glob() {
+        let models = vec![ModelPrice { model: "gpt-4o-mini*".to_string(), ..price(usd("0.15"), usd("0.6")) }, price(usd("2.5"), usd("10"))];
+        let table = PricingTable::from_config(PricingConfig { models, ..PricingConfig::default() }).unwrap();
+        assert!(table.meters_usage());
+        assert_eq!(table.model_price("GPT-4o-mini-2024-07-18").unwrap().input_per_mtok, usd("0.15"));
+        assert_eq!(table.model_price("gpt-4o-2024-08-06").unwrap().input_per_mtok, usd("2.5"));
+        assert!(table.model_price("claude-3-5-sonnet").is_none());
+        assert!(!PricingTable::new().meters_usage());
+    }
+
+    #[test]
+    fn huge_usage_saturates_instead_of_overflowing() {
+        let max = Amount::from_units(u64::MAX);
+        let cost = price(max, max).cost(u64::MAX, u64::MAX);
+        assert_eq!(cost.input, max);
+        assert_eq!(cost.total(), max);
+        assert_eq!(UsageCost { input: max, output: Amount::from_units(1) }.total(), max);
+    }
+}
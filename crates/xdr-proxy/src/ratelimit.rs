@@ -0,0 +1,318 @@
+//! Upstream quota simulator: a token bucket per (agent, upstream host). Every
+//! forwarded request takes a token; an empty bucket answers 429 with the
+//! headers the provider would send (OpenAI's `x-ratelimit-*` or Anthropic's
+//! `anthropic-ratelimit-*`) and a `Retry-After` that is actually long enough.
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::Utc;
+use globset::GlobMatcher;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use xdr_chaos::rules::glob;
+
+/// Slowest refill accepted: one token every ~17 minutes
+pub const MIN_REFILL_PER_SEC: f64 = 0.001;
+/// Longest reset or retry hint reported
+const MAX_WAIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Which provider's headers and error body to imitate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderStyle {
+    OpenAi,
+    Anthropic,
+}
+
+impl std::str::FromStr for HeaderStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "openai" => Ok(Self::OpenAi),
+            "anthropic" => Ok(Self::Anthropic),
+            other => Err(format!("Unknown header style '{}' (expected openai or anthropic)", other)),
+        }
+    }
+}
+
+/// A quota for the matching (agent, host) pairs. Each pair gets its own bucket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitRule {
+    /// Glob on the upstream host, e.g. `api.openai.com` (any host when unset)
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Glob on the agent id (every agent when unset)
+    #[serde(default)]
+    pub agent: Option<String>,
+    /// Bucket size: how many requests can burst
+    pub capacity: u32,
+    /// Tokens added back per second (at least `MIN_REFILL_PER_SEC`)
+    pub refill_per_sec: f64,
+    /// Headers to send; picked from the host when unset (Anthropic for
+    /// `*anthropic*`, OpenAI otherwise)
+    #[serde(default)]
+    pub style: Option<HeaderStyle>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// First matching rule wins
+    #[serde(default)]
+    pub rules: Vec<RateLimitRule>,
+}
+
+impl RateLimitConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+}
+
+/// What the bucket said about one request.
+#[derive(Debug, Clone)]
+pub struct Verdict {
+    pub allowed: bool,
+    pub limit: u32,
+    /// Whole tokens left after this request
+    pub remaining: u32,
+    /// Until the bucket is full again
+    pub reset: Duration,
+    /// Until the next token (only when refused)
+    pub retry_after: Option<Duration>,
+    pub style: HeaderStyle,
+}
+
+impl Verdict {
+    /// The provider's rate-limit headers, for allowed and refused responses alike.
+    pub fn apply_headers(&self, headers: &mut HeaderMap) {
+        let mut set = |name: &'static str, value: String| {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        };
+        match self.style {
+            HeaderStyle::OpenAi => {
+                set("x-ratelimit-limit-requests", self.limit.to_string());
+                set("x-ratelimit-remaining-requests", self.remaining.to_string());
+                set("x-ratelimit-reset-requests", go_duration(self.reset));
+            }
+            HeaderStyle::Anthropic => {
+                set("anthropic-ratelimit-requests-limit", self.limit.to_string());
+                set("anthropic-ratelimit-requests-remaining", self.remaining.to_string());
+                let reset_at = Utc::now() + chrono::Duration::milliseconds(self.reset.as_millis() as i64);
+                set("anthropic-ratelimit-requests-reset", reset_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+            }
+        }
+        if let Some(retry_after) = self.retry_after {
+            // Whole seconds, rounded up, so honouring it never retries too early
+            set("retry-after", retry_after.as_secs_f64().ceil().max(1.0).to_string());
+            if self.style == HeaderStyle::OpenAi {
+                set("retry-after-ms", retry_after.as_millis().max(1).to_string());
+            }
+        }
+    }
+
+    /// The provider's 429 response.
+    pub fn rejection(&self) -> Response {
+        let message = format!(
+            "Rate limit reached for requests: limit {}, remaining 0. Please try again in {}.",
+            self.limit,
+            go_duration(self.retry_after.unwrap_or_default())
+        );
+        let body = match self.style {
+            HeaderStyle::OpenAi => json!({ "error": { "message": message, "type": "requests", "param": null, "code": "rate_limit_exceeded" } }),
+            HeaderStyle::Anthropic => json!({ "type": "error", "error": { "type": "rate_limit_error", "message": message } }),
+        };
+        let mut response = (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response();
+        self.apply_headers(response.headers_mut());
+        response
+    }
+}
+
+/// Durations the way OpenAI writes them: "20ms", "1.5s", "6m0s".
+fn go_duration(d: Duration) -> String {
+    let millis = d.as_millis();
+    if millis < 1000 {
+        return format!("{}ms", millis);
+    }
+    let secs = d.as_secs_f64();
+    if secs < 60.0 {
+        let text = format!("{:.3}", secs);
+        return format!("{}s", text.trim_end_matches('0').trim_end_matches('.'));
+    }
+    format!("{}m{}s", d.as_secs() / 60, d.as_secs() % 60)
+}
+
+struct CompiledRule {
+    rule: RateLimitRule,
+    host: Option<GlobMatcher>,
+    agent: Option<GlobMatcher>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct LimiterState {
+    config: RateLimitConfig,
+    rules: Vec<CompiledRule>,
+    /// Keyed by (rule index, agent, host)
+    buckets: HashMap<(usize, String, String), Bucket>,
+}
+
+/// Shared rate-limit simulator (cloned into the proxy and control plane).
+#[derive(Clone)]
+pub struct RateLimiter {
+    state: Arc<Mutex<LimiterState>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(LimiterState { config: RateLimitConfig::default(), rules: Vec::new(), buckets: HashMap::new() })),
+        }
+    }
+
+    /// Validates and swaps in a new config. Every bucket starts full again.
+    pub fn set_config(&self, config: RateLimitConfig) -> Result<(), String> {
+        let rules = config.rules.iter()
+            .map(|rule| {
+                if rule.capacity == 0 {
+                    return Err("capacity must be at least 1".to_string());
+                }
+                if !(rule.refill_per_sec >= MIN_REFILL_PER_SEC && rule.refill_per_sec.is_finite()) {
+                    return Err(format!("refill_per_sec must be at least {}", MIN_REFILL_PER_SEC));
+                }
+                let host = rule.host.as_deref().map(|p| glob(p, "host", true)).transpose()?;
+                let agent = rule.agent.as_deref().map(|p| glob(p, "agent", true)).transpose()?;
+                Ok(CompiledRule { host, agent, rule: rule.clone() })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let mut state = self.state.lock().unwrap();
+        state.config = config;
+        state.rules = rules;
+        state.buckets.clear();
+        Ok(())
+    }
+
+    pub fn get_config(&self) -> RateLimitConfig {
+        self.state.lock().unwrap().config.clone()
+    }
+
+    /// Takes a token from the (agent, host) bucket. None when no rule covers the pair.
+    pub fn check(&self, agent_id: &str, host: &str) -> Option<Verdict> {
+        let mut state = self.state.lock().unwrap();
+        let (index, compiled) = state.rules.iter().enumerate().find(|(_, c)| {
+            c.host.as_ref().is_none_or(|g| g.is_match(host)) && c.agent.as_ref().is_none_or(|g| g.is_match(agent_id))
+        })?;
+        let rule = compiled.rule.clone();
+        let capacity = rule.capacity as f64;
+        let style = rule.style.unwrap_or(match host.to_ascii_lowercase().contains("anthropic") {
+            true => HeaderStyle::Anthropic,
+            false => HeaderStyle::OpenAi,
+        });
+
+        let now = Instant::now();
+        let bucket = state.buckets
+            .entry((index, agent_id.to_string(), host.to_ascii_lowercase()))
+            .or_insert(Bucket { tokens: capacity, updated: now });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rule.refill_per_sec).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let secs_until = |tokens: f64| {
+            Duration::try_from_secs_f64(tokens.max(0.0) / rule.refill_per_sec).map_or(MAX_WAIT, |d| d.min(MAX_WAIT))
+        };
+        Some(Verdict {
+            allowed,
+            limit: rule.capacity,
+            remaining: bucket.tokens.floor() as u32,
+            reset: secs_until(capacity - bucket.tokens),
+            retry_after: (!allowed).then(|| secs_until(1.0 - bucket.tokens)),
+            style,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rules: &str) -> RateLimiter {
+        let limiter = RateLimiter::new();
+        limiter.set_config(serde_json::from_str(rules).unwrap()).unwrap();
+        limiter
+    }
+
+    #[test]
+    fn buckets_burst_to_capacity_then_refuse() {
+        let limiter = limiter(r#"{ "rules": [{ "host": "*.openai.com", "capacity": 2, "refill_per_sec": 0.5 }] }"#);
+        let first = limiter.check("agent-a", "api.openai.com").unwrap();
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert!(limiter.check("agent-a", "api.openai.com").unwrap().allowed);
+
+        let refused = limiter.check("agent-a", "api.openai.com").unwrap();
+        assert!(!refused.allowed);
+        assert_eq!(refused.remaining, 0);
+        let retry_after = refused.retry_after.unwrap();
+        assert!(retry_after > Duration::from_millis(1900) && retry_after <= Duration::from_secs(2), "{:?}", retry_after);
+        assert_eq!(refused.style, HeaderStyle::OpenAi);
+
+        // Other agents have their own bucket; unmatched hosts aren't limited
+        assert!(limiter.check("agent-b", "api.openai.com").unwrap().allowed);
+        assert!(limiter.check("agent-a", "api.anthropic.com").is_none());
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = limiter(r#"{ "rules": [{ "capacity": 1, "refill_per_sec": 1.0 }] }"#);
+        assert!(limiter.check("agent-a", "api.anthropic.com").unwrap().allowed);
+        assert!(!limiter.check("agent-a", "api.anthropic.com").unwrap().allowed);
+
+        for bucket in limiter.state.lock().unwrap().buckets.values_mut() {
+            bucket.updated -= Duration::from_secs(5);
+        }
+        let verdict = limiter.check("agent-a", "api.anthropic.com").unwrap();
+        assert!(verdict.allowed);
+        assert_eq!(verdict.style, HeaderStyle::Anthropic);
+        // Refill never overflows the bucket
+        assert_eq!(verdict.remaining, 0);
+    }
+
+    #[test]
+    fn slow_refill_is_bounded() {
+        let rejecting = RateLimiter::new();
+        for refill in [0.0, 1e-300, f64::NAN, f64::INFINITY] {
+            let config = RateLimitConfig {
+                rules: vec![RateLimitRule { host: None, agent: None, capacity: 1, refill_per_sec: refill, style: None }],
+            };
+            assert!(rejecting.set_config(config).is_err(), "{}", refill);
+        }
+
+        let limiter = limiter(r#"{ "rules": [{ "capacity": 4294967295, "refill_per_sec": 0.001 }] }"#);
+        assert!(limiter.check("agent-a", "api.openai.com").unwrap().allowed);
+        for bucket in limiter.state.lock().unwrap().buckets.values_mut() {
+            bucket.tokens = 0.0;
+        }
+        let refused = limiter.check("agent-a", "api.openai.com").unwrap();
+        assert!(!refused.allowed);
+        assert_eq!(refused.reset, MAX_WAIT);
+        assert!(refused.retry_after.unwrap() <= Duration::from_secs(1000));
+    }
+}
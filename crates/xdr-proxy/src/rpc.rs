@@ -0,0 +1,493 @@
+//! Mock Cronos JSON-RPC node.
+//!
+//! Answers the read-side `eth_*` calls an agent makes to confirm a payment,
+//! straight from the ledger: every settled payment is an ERC-20 `Transfer`
+//! of its token (or a plain value transfer when paid in native CRO).
+//! Pending payments have no receipt yet and orphaned ones disappear, just
+//! like on a real node.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha3::{Digest, Keccak256};
+use xdr_ledger::chain::{Block, TxStatus};
+use xdr_ledger::tokens::Token;
+use xdr_ledger::{Ledger, Transaction};
+
+use crate::x402;
+
+/// keccak256("Transfer(address,address,uint256)")
+pub const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+const SELECTOR_BALANCE_OF: &str = "70a08231";
+const SELECTOR_DECIMALS: &str = "313ce567";
+const SELECTOR_TRANSFER: &str = "a9059cbb";
+/// Typical gas for an ERC-20 transfer
+const TRANSFER_GAS: u64 = 52_000;
+/// Gas for a plain native-coin transfer
+const NATIVE_TRANSFER_GAS: u64 = 21_000;
+/// 5000 gwei, the Cronos base fee floor
+const GAS_PRICE: u128 = 5_000_000_000_000;
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(-32602, message)
+    }
+}
+
+/// Handles a JSON-RPC 2.0 request body (single call or batch).
+pub fn handle(ledger: &Ledger, network: &str, body: &[u8]) -> Value {
+    let parsed: Value = match serde_json::from_slice(body) {
+        Ok(v) => v,
+        Err(e) => return error_response(Value::Null, RpcError::new(-32700, format!("Parse error: {}", e))),
+    };
+    match parsed {
+        Value::Array(calls) if calls.is_empty() => {
+            error_response(Value::Null, RpcError::new(-32600, "Invalid Request: empty batch"))
+        }
+        Value::Array(calls) => Value::Array(calls.into_iter().map(|c| handle_call(ledger, network, c)).collect()),
+        call => handle_call(ledger, network, call),
+    }
+}
+
+fn handle_call(ledger: &Ledger, network: &str, call: Value) -> Value {
+    let request: RpcRequest = match serde_json::from_value(call) {
+        Ok(r) => r,
+        Err(e) => return error_response(Value::Null, RpcError::new(-32600, format!("Invalid Request: {}", e))),
+    };
+    match dispatch(ledger, network, &request.method, &request.params) {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": request.id, "result": result }),
+        Err(e) => error_response(request.id, e),
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": error.code, "message": error.message } })
+}
+
+fn dispatch(ledger: &Ledger, network: &str, method: &str, params: &Value) -> Result<Value, RpcError> {
+    match method {
+        "eth_chainId" => Ok(json!(quantity(x402::chain_id(network) as u128))),
+        "net_version" => Ok(json!(x402::chain_id(network).to_string())),
+        "eth_blockNumber" => Ok(json!(quantity(ledger.block_number() as u128))),
+        "eth_gasPrice" => Ok(json!(quantity(GAS_PRICE))),
+        // Native coin balance of an agent wallet (zero for unknown addresses)
+        "eth_getBalance" => {
+            let address = param_str(params, 0, "address")?;
+            let balance = ledger
+                .tokens()
+                .into_iter()
+                .find(Token::is_native)
+                .zip(ledger.find_agent_by_address(address))
+                .map(|(native, agent)| native.to_atomic(agent.balance(&native.symbol)))
+                .unwrap_or(0);
+            Ok(json!(quantity(balance)))
+        }
+        "eth_getBlockByNumber" => {
+            let number = block_param(params.get(0), ledger.block_number())?;
+            let full = params.get(1).and_then(Value::as_bool).unwrap_or(false);
+            Ok(ledger.get_block(number).map(|b| block_json(ledger, &b, full, network)).unwrap_or(Value::Null))
+        }
+        "eth_getTransactionByHash" => {
+            let hash = param_str(params, 0, "transaction hash")?;
+            Ok(ledger
+                .get_transaction(hash)
+                .filter(|tx| tx.status != TxStatus::Orphaned)
+                .map(|tx| transaction_json(ledger, &tx, network))
+                .unwrap_or(Value::Null))
+        }
+        "eth_getTransactionReceipt" => {
+            let hash = param_str(params, 0, "transaction hash")?;
+            Ok(ledger
+                .get_transaction(hash)
+                .filter(|tx| tx.status == TxStatus::Mined)
+                .map(|tx| receipt_json(ledger, &tx))
+                .unwrap_or(Value::Null))
+        }
+        "eth_call" => {
+            let call = params.get(0).ok_or_else(|| RpcError::invalid_params("missing call object"))?;
+            eth_call(ledger, call)
+        }
+        "eth_getLogs" => {
+            let filter = params.get(0).cloned().unwrap_or_else(|| json!({}));
+            get_logs(ledger, &filter)
+        }
+        other => Err(RpcError::new(-32601, format!("Method '{}' is not supported by the XDR mock node", other))),
+    }
+}
+
+fn param_str<'a>(params: &'a Value, idx: usize, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(idx)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::invalid_params(format!("missing {} (param {})", name, idx)))
+}
+
+/// Read-only calls against a registered ERC-20: `balanceOf` and `decimals`.
+fn eth_call(ledger: &Ledger, call: &Value) -> Result<Value, RpcError> {
+    let to = call.get("to").and_then(Value::as_str).unwrap_or_default();
+    let data = call
+        .get("data")
+        .or_else(|| call.get("input"))
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .trim_start_matches("0x")
+        .to_ascii_lowercase();
+    let Some(token) = ledger.token_by_address(to) else {
+        return Err(RpcError::new(3, format!("execution reverted: no contract at {} on the mock node", to)));
+    };
+
+    match data.get(..8) {
+        Some(SELECTOR_BALANCE_OF) => {
+            // balanceOf(address): the address is the last 20 bytes of the first word
+            let word = data.get(8..72).ok_or_else(|| RpcError::invalid_params("balanceOf: missing address argument"))?;
+            let owner = format!("0x{}", &word[24..]);
+            let balance = ledger
+                .find_agent_by_address(&owner)
+                .map(|agent| token.to_atomic(agent.balance(&token.symbol)))
+                .unwrap_or(0);
+            Ok(json!(format!("0x{}", word_hex(balance))))
+        }
+        Some(SELECTOR_DECIMALS) => Ok(json!(format!("0x{}", word_hex(token.decimals as u128)))),
+        _ => Err(RpcError::new(3, "execution reverted: unsupported selector")),
+    }
+}
+
+fn get_logs(ledger: &Ledger, filter: &Value) -> Result<Value, RpcError> {
+    let latest = ledger.block_number();
+    let from = block_param(filter.get("fromBlock"), latest)?;
+    let to = block_param(filter.get("toBlock"), latest)?;
+
+    // `address` may be a single address or a list
+    let addresses: Vec<String> = match filter.get("address") {
+        Some(Value::String(a)) => vec![a.to_ascii_lowercase()],
+        Some(Value::Array(list)) => list.iter().filter_map(Value::as_str).map(str::to_ascii_lowercase).collect(),
+        _ => Vec::new(),
+    };
+
+    let topics: Vec<Value> = filter.get("topics").and_then(Value::as_array).cloned().unwrap_or_default();
+    let logs: Vec<Value> = ledger
+        .list_transactions()
+        .iter()
+        .filter(|tx| tx.status == TxStatus::Mined)
+        .filter(|tx| tx.block_height.is_some_and(|h| h >= from && h <= to))
+        .filter_map(|tx| log_json(ledger, tx))
+        .filter(|log| addresses.is_empty() || log["address"].as_str().is_some_and(|a| addresses.iter().any(|w| w == a)))
+        .filter(|log| topics_match(&topics, log))
+        .collect();
+    Ok(Value::Array(logs))
+}
+
+/// Each filter position is null (any), a topic, or a list of alternatives.
+fn topics_match(filter: &[Value], log: &Value) -> bool {
+    let actual = log["topics"].as_array().cloned().unwrap_or_default();
+    filter.iter().enumerate().all(|(i, wanted)| {
+        let Some(topic) = actual.get(i).and_then(Value::as_str) else {
+            return wanted.is_null();
+        };
+        match wanted {
+            Value::Null => true,
+            Value::String(t) => t.eq_ignore_ascii_case(topic),
+            Value::Array(options) => options.iter().filter_map(Value::as_str).any(|t| t.eq_ignore_ascii_case(topic)),
+            _ => false,
+        }
+    })
+}
+
+fn block_param(value: Option<&Value>, latest: u64) -> Result<u64, RpcError> {
+    match value.and_then(Value::as_str) {
+        None | Some("latest") | Some("pending") | Some("safe") | Some("finalized") => Ok(latest),
+        Some("earliest") => Ok(0),
+        Some(hex) => u64::from_str_radix(hex.trim_start_matches("0x"), 16)
+            .map_err(|_| RpcError::invalid_params(format!("invalid block number '{}'", hex))),
+    }
+}
+
+fn block_json(ledger: &Ledger, block: &Block, full: bool, network: &str) -> Value {
+    let transactions: Vec<Value> = block
+        .transactions
+        .iter()
+        .map(|hash| match ledger.get_transaction(hash).filter(|_| full) {
+            Some(tx) => transaction_json(ledger, &tx, network),
+            None => json!(hash),
+        })
+        .collect();
+    json!({
+        "number": quantity(block.number as u128),
+        "hash": block.hash,
+        "parentHash": block.parent_hash,
+        "timestamp": quantity(block.timestamp as u128),
+        "miner": "0x0000000000000000000000000000000000000000",
+        "gasLimit": quantity(30_000_000),
+        "gasUsed": quantity(block.transactions.iter().filter_map(|h| ledger.get_transaction(h)).map(|tx| gas(ledger, &tx) as u128).sum()),
+        "baseFeePerGas": quantity(GAS_PRICE),
+        "transactions": transactions,
+        "uncles": [],
+    })
+}
+
+fn transaction_json(ledger: &Ledger, tx: &Transaction, network: &str) -> Value {
+    // Account nonce: how many earlier payments came from the same address
+    let nonce = ledger
+        .list_transactions()
+        .iter()
+        .filter(|t| t.from == tx.from && t.status != TxStatus::Orphaned)
+        .position(|t| t.hash == tx.hash)
+        .unwrap_or(0);
+    let index = tx.block_height.map(|_| json!(tx_index(ledger, tx)));
+    // Native payments move value directly; ERC-20 payments call `transfer` on the token
+    let (to, value, input) = match token_of(ledger, tx) {
+        Some(token) if !token.is_native() => (
+            token.address.clone().unwrap_or_default().to_ascii_lowercase(),
+            0,
+            format!("0x{}{}{}", SELECTOR_TRANSFER, address_word(&tx.to), word_hex(token.to_atomic(tx.amount))),
+        ),
+        token => (tx.to.clone(), token.map_or(0, |t| t.to_atomic(tx.amount)), "0x".to_string()),
+    };
+    json!({
+        "hash": tx.hash,
+        "nonce": quantity(nonce as u128),
+        "blockHash": tx.block_hash,
+        "blockNumber": tx.block_height.map(|h| quantity(h as u128)),
+        "transactionIndex": index,
+        "from": tx.from,
+        "to": to,
+        "value": quantity(value),
+        "gas": quantity(gas(ledger, tx) as u128 * 2),
+        "gasPrice": quantity(GAS_PRICE),
+        "input": input,
+        "chainId": quantity(x402::chain_id(network) as u128),
+        "type": "0x0",
+        "v": "0x0",
+        "r": keccak_hex(format!("{}:r", tx.hash).as_bytes()),
+        "s": keccak_hex(format!("{}:s", tx.hash).as_bytes()),
+    })
+}
+
+fn receipt_json(ledger: &Ledger, tx: &Transaction) -> Value {
+    let log = log_json(ledger, tx);
+    let to = log.as_ref().and_then(|l| l["address"].as_str()).map_or_else(|| tx.to.clone(), str::to_string);
+    let gas = gas(ledger, tx) as u128;
+    json!({
+        "transactionHash": tx.hash,
+        "transactionIndex": tx_index(ledger, tx),
+        "blockHash": tx.block_hash,
+        "blockNumber": tx.block_height.map(|h| quantity(h as u128)),
+        "from": tx.from,
+        "to": to,
+        "cumulativeGasUsed": quantity(gas),
+        "gasUsed": quantity(gas),
+        "effectiveGasPrice": quantity(GAS_PRICE),
+        "contractAddress": null,
+        "logsBloom": logs_bloom(log.as_ref()),
+        "logs": log.into_iter().collect::<Vec<_>>(),
+        "status": "0x1",
+        "type": "0x0",
+    })
+}
+
+/// The `Transfer(from, to, value)` event emitted by a mined ERC-20 payment
+/// (native transfers emit no log).
+fn log_json(ledger: &Ledger, tx: &Transaction) -> Option<Value> {
+    let token = token_of(ledger, tx)?;
+    let address = token.address.as_ref()?;
+    let index = tx_index(ledger, tx);
+    Some(json!({
+        "address": address.to_ascii_lowercase(),
+        "topics": [
+            TRANSFER_TOPIC,
+            format!("0x{}", address_word(&tx.from)),
+            format!("0x{}", address_word(&tx.to)),
+        ],
+        "data": format!("0x{}", word_hex(token.to_atomic(tx.amount))),
+        "blockNumber": tx.block_height.map(|h| quantity(h as u128)),
+        "blockHash": tx.block_hash,
+        "transactionHash": tx.hash,
+        "transactionIndex": index,
+        // One Transfer log per transaction, so log and tx indices line up
+        "logIndex": index,
+        "removed": false,
+    }))
+}
+
+/// 2048-bit bloom over the log's address and topics (yellow paper, section 4.3.1).
+fn logs_bloom(log: Option<&Value>) -> String {
+    let mut bloom = [0u8; 256];
+    let Some(log) = log else {
+        return format!("0x{}", hex::encode(bloom));
+    };
+    let topics = log["topics"].as_array().cloned().unwrap_or_default();
+    let items = std::iter::once(&log["address"]).chain(topics.iter());
+    for item in items.filter_map(Value::as_str) {
+        let bytes = hex::decode(item.trim_start_matches("0x")).unwrap_or_default();
+        let hash = Keccak256::digest(&bytes);
+        for i in [0, 2, 4] {
+            let bit = (((hash[i] as usize) << 8) | hash[i + 1] as usize) & 0x7ff;
+            bloom[255 - bit / 8] |= 1 << (bit % 8);
+        }
+    }
+    format!("0x{}", hex::encode(bloom))
+}
+
+/// The registered token a payment was made in.
+fn token_of(ledger: &Ledger, tx: &Transaction) -> Option<Token> {
+    ledger.token(&tx.currency)
+}
+
+fn gas(ledger: &Ledger, tx: &Transaction) -> u64 {
+    match token_of(ledger, tx) {
+        Some(token) if token.is_native() => NATIVE_TRANSFER_GAS,
+        _ => TRANSFER_GAS,
+    }
+}
+
+/// Position of a mined transaction within its block.
+fn tx_index(ledger: &Ledger, tx: &Transaction) -> String {
+    let index = tx
+        .block_height
+        .and_then(|h| ledger.get_block(h))
+        .and_then(|b| b.transactions.iter().position(|t| *t == tx.hash))
+        .unwrap_or(0);
+    quantity(index as u128)
+}
+
+fn keccak_hex(data: &[u8]) -> String {
+    format!("0x{}", hex::encode(Keccak256::digest(data)))
+}
+
+/// Hex quantity without leading zeros, as JSON-RPC expects.
+fn quantity(v: u128) -> String {
+    format!("0x{:x}", v)
+}
+
+/// A value left-padded to a 32-byte ABI word (no 0x prefix).
+fn word_hex(v: u128) -> String {
+    format!("{:064x}", v)
+}
+
+/// An address left-padded to a 32-byte ABI word (no 0x prefix).
+fn address_word(addr: &str) -> String {
+    format!("{:0>64}", addr.trim_start_matches("0x").to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xdr_ledger::Amount;
+
+    const NETWORK: &str = "cronos-testnet";
+
+    fn call(ledger: &Ledger, method: &str, params: Value) -> Value {
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        handle(ledger, NETWORK, body.to_string().as_bytes())
+    }
+
+    /// A settled $0.01 payment by `agent-1`; returns its transaction.
+    fn paid(ledger: &Ledger) -> Transaction {
+        ledger.register_or_get("agent-1");
+        let invoice = ledger.create_invoice("agent-1", Amount::from_units(10_000), "USDC", "api.openai.com", None, None);
+        let receipt = ledger.pay_invoice(&invoice.id, "agent-1", NETWORK, None).unwrap();
+        ledger.get_transaction(&receipt.tx_hash).unwrap()
+    }
+
+    #[test]
+    fn reports_the_simulated_chain() {
+        let ledger = Ledger::new();
+        assert_eq!(call(&ledger, "eth_chainId", json!([]))["result"], "0x152");
+        assert_eq!(call(&ledger, "net_version", json!([]))["result"], "338");
+        let mainnet = handle(&ledger, "cronos-mainnet", br#"{"jsonrpc":"2.0","id":1,"method":"eth_chainId"}"#);
+        assert_eq!(mainnet["result"], "0x19");
+        assert_eq!(call(&ledger, "eth_blockNumber", json!([]))["result"], quantity(ledger.block_number() as u128));
+    }
+
+    #[test]
+    fn malformed_and_unknown_calls_get_json_rpc_errors() {
+        let ledger = Ledger::new();
+        assert_eq!(handle(&ledger, NETWORK, b"{not json")["error"]["code"], -32700);
+        assert_eq!(handle(&ledger, NETWORK, b"[]")["error"]["code"], -32600);
+        assert_eq!(handle(&ledger, NETWORK, br#"{"id":4}"#)["error"]["code"], -32600);
+        let unknown = call(&ledger, "eth_sendRawTransaction", json!(["0x00"]));
+        assert_eq!((unknown["id"].clone(), unknown["error"]["code"].clone()), (json!(1), json!(-32601)));
+        assert_eq!(call(&ledger, "eth_getTransactionReceipt", json!([]))["error"]["code"], -32602);
+
+        // A batch answers each call in order
+        let batch = handle(&ledger, NETWORK, br#"[{"id":1,"method":"eth_chainId"},{"id":2,"method":"nope"}]"#);
+        assert_eq!(batch[0]["result"], "0x152");
+        assert_eq!((batch[1]["id"].clone(), batch[1]["error"]["code"].clone()), (json!(2), json!(-32601)));
+    }
+
+    #[test]
+    fn settled_payments_are_erc20_transfers() {
+        let ledger = Ledger::new();
+        let tx = paid(&ledger);
+        let receipt = call(&ledger, "eth_getTransactionReceipt", json!([tx.hash]))["result"].clone();
+        assert_eq!(receipt["status"], "0x1");
+        assert_eq!(receipt["blockNumber"], quantity(tx.block_height.unwrap() as u128));
+        let log = &receipt["logs"][0];
+        assert_eq!(log["topics"][0], TRANSFER_TOPIC);
+        assert_eq!(log["topics"][1], format!("0x{}", address_word(&tx.from)));
+        assert_eq!(log["data"], format!("0x{}", word_hex(10_000)));
+
+        let transaction = call(&ledger, "eth_getTransactionByHash", json!([tx.hash]))["result"].clone();
+        assert!(transaction["input"].as_str().unwrap().starts_with(&format!("0x{}", SELECTOR_TRANSFER)));
+        assert_eq!(call(&ledger, "eth_getTransactionReceipt", json!(["0x1234"]))["result"], Value::Null);
+    }
+
+    #[test]
+    fn pending_payments_have_no_receipt_until_mined() {
+        let ledger = Ledger::new();
+        ledger.set_automine(false);
+        let tx = paid(&ledger);
+        assert_eq!(call(&ledger, "eth_getTransactionReceipt", json!([tx.hash]))["result"], Value::Null);
+        assert_eq!(call(&ledger, "eth_getTransactionByHash", json!([tx.hash]))["result"]["blockNumber"], Value::Null);
+        ledger.mine_block();
+        assert_eq!(call(&ledger, "eth_getTransactionReceipt", json!([tx.hash]))["result"]["status"], "0x1");
+    }
+
+    #[test]
+    fn balance_of_reads_the_payers_wallet() {
+        let ledger = Ledger::new();
+        let tx = paid(&ledger);
+        let usdc = ledger.token("USDC").unwrap();
+        let data = format!("0x{}{}", SELECTOR_BALANCE_OF, address_word(&tx.from));
+        let balance = call(&ledger, "eth_call", json!([{ "to": usdc.address, "data": data }, "latest"]))["result"].clone();
+        let wallet = ledger.get_state("agent-1").unwrap().balance("USDC");
+        assert_eq!(balance, format!("0x{}", word_hex(usdc.to_atomic(wallet))));
+
+        let decimals = call(&ledger, "eth_call", json!([{ "to": usdc.address, "data": format!("0x{}", SELECTOR_DECIMALS) }]));
+        assert_eq!(decimals["result"], format!("0x{}", word_hex(6)));
+        let elsewhere = call(&ledger, "eth_call", json!([{ "to": "0x0000000000000000000000000000000000000001", "data": data }]));
+        assert_eq!(elsewhere["error"]["code"], 3);
+    }
+
+    #[test]
+    fn logs_filter_by_block_range_and_topics() {
+        let ledger = Ledger::new();
+        let tx = paid(&ledger);
+        let from_topic = format!("0x{}", address_word(&tx.from));
+        let logs = |filter: Value| call(&ledger, "eth_getLogs", json!([filter]))["result"].as_array().unwrap().len();
+        assert_eq!(logs(json!({ "fromBlock": "earliest" })), 1);
+        assert_eq!(logs(json!({ "fromBlock": "earliest", "topics": [TRANSFER_TOPIC, [from_topic, "0x01"]] })), 1);
+        assert_eq!(logs(json!({ "fromBlock": "earliest", "topics": [null, "0x01"] })), 0);
+        assert_eq!(logs(json!({ "fromBlock": quantity(tx.block_height.unwrap() as u128 + 1) })), 0);
+        assert_eq!(logs(json!({ "fromBlock": "earliest", "address": "0x0000000000000000000000000000000000000001" })), 0);
+    }
+}
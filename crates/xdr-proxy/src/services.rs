@@ -0,0 +1,165 @@
+//! Agent service marketplace: agents selling services to each other.
+//!
+//! An agent registers as a payee with the URL of a service it serves
+//! locally. Other agents reach it through the proxy at the host
+//! `<agent_id>.agent` (as an absolute URL or in `X-Upstream-Host`), priced
+//! like any upstream, and XDR forwards the paid request to the service URL.
+//! Their payments are transfers into the payee agent's wallet rather than
+//! burns.
+
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+/// Hosts under this suffix address agent services
+pub const AGENT_HOST_SUFFIX: &str = ".agent";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentService {
+    /// Payee agent; its wallet receives the payments
+    pub agent_id: String,
+    /// Where the agent serves requests, e.g. `http://127.0.0.1:9001`
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl AgentService {
+    /// The host other agents call it at.
+    pub fn host(&self) -> String {
+        format!("{}{}", self.agent_id.to_ascii_lowercase(), AGENT_HOST_SUFFIX)
+    }
+
+    fn validate(&self) -> Result<Url, String> {
+        let valid_id = !self.agent_id.is_empty()
+            && self.agent_id.len() <= 63
+            && self.agent_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+        if !valid_id {
+            return Err(format!("Invalid agent id '{}' for a service (use up to 63 letters, digits, '-' or '_')", self.agent_id));
+        }
+        let url = Url::parse(&self.url).map_err(|e| format!("Invalid service URL '{}': {}", self.url, e))?;
+        if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+            return Err(format!("Service URL '{}' must be an http(s) URL with a host", self.url));
+        }
+        Ok(url)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceConfig {
+    #[serde(default)]
+    pub services: Vec<AgentService>,
+}
+
+/// Where an agent-service request goes.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub agent_id: String,
+    /// The service URL plus the request's path and query
+    pub url: Url,
+}
+
+/// Shared service registry (cloned into the proxy and control plane).
+#[derive(Clone, Default)]
+pub struct ServiceRegistry {
+    config: Arc<Mutex<ServiceConfig>>,
+}
+
+impl ServiceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Validates and swaps in a new registry.
+    pub fn set_config(&self, config: ServiceConfig) -> Result<(), String> {
+        for (i, service) in config.services.iter().enumerate() {
+            service.validate()?;
+            if config.services[..i].iter().any(|s| s.agent_id.eq_ignore_ascii_case(&service.agent_id)) {
+                return Err(format!("Agent {} registers more than one service", service.agent_id));
+            }
+        }
+        *self.config.lock().unwrap() = config;
+        Ok(())
+    }
+
+    pub fn get_config(&self) -> ServiceConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Adds or replaces one agent's service.
+    pub fn register(&self, service: AgentService) -> Result<(), String> {
+        let mut config = self.get_config();
+        config.services.retain(|s| !s.agent_id.eq_ignore_ascii_case(&service.agent_id));
+        config.services.push(service);
+        self.set_config(config)
+    }
+
+    /// Drops an agent's service. False if it had none.
+    pub fn remove(&self, agent_id: &str) -> bool {
+        let mut config = self.config.lock().unwrap();
+        let before = config.services.len();
+        config.services.retain(|s| !s.agent_id.eq_ignore_ascii_case(agent_id));
+        config.services.len() != before
+    }
+
+    /// The agent selling the service at `host`, if any.
+    pub fn payee_agent(&self, host: &str) -> Option<String> {
+        let id = host.to_ascii_lowercase();
+        let id = id.strip_suffix(AGENT_HOST_SUFFIX)?;
+        let config = self.config.lock().unwrap();
+        config.services.iter().find(|s| s.agent_id.eq_ignore_ascii_case(id)).map(|s| s.agent_id.clone())
+    }
+
+    /// Where a request for `url` is forwarded. None when the host isn't an
+    /// agent host; an error when no agent serves it.
+    pub fn route(&self, url: &Url) -> Option<Result<Route, String>> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let id = host.strip_suffix(AGENT_HOST_SUFFIX)?;
+        let config = self.config.lock().unwrap();
+        let Some(service) = config.services.iter().find(|s| s.agent_id.eq_ignore_ascii_case(id)) else {
+            return Some(Err(format!("No agent service registered for {}", host)));
+        };
+        let base = service.url.trim_end_matches('/');
+        let query = url.query().map(|q| format!("?{}", q)).unwrap_or_default();
+        Some(Url::parse(&format!("{}{}{}", base, url.path(), query))
+            .map(|url| Route { agent_id: service.agent_id.clone(), url })
+            .map_err(|e| format!("Invalid service URL for {}: {}", service.agent_id, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(agent_id: &str, url: &str) -> AgentService {
+        AgentService { agent_id: agent_id.to_string(), url: url.to_string(), description: None }
+    }
+
+    #[test]
+    fn agent_hosts_route_to_the_service_url() {
+        let registry = ServiceRegistry::new();
+        registry.register(service("Bob", "http://127.0.0.1:9001/api/")).unwrap();
+        assert_eq!(registry.payee_agent("BOB.agent").as_deref(), Some("Bob"));
+        assert_eq!(registry.payee_agent("api.openai.com"), None);
+
+        let route = registry.route(&Url::parse("https://bob.agent/v1/summarize?lang=en").unwrap()).unwrap().unwrap();
+        assert_eq!((route.agent_id.as_str(), route.url.as_str()), ("Bob", "http://127.0.0.1:9001/api/v1/summarize?lang=en"));
+        assert!(registry.route(&Url::parse("https://carol.agent/").unwrap()).unwrap().is_err());
+        assert!(registry.route(&Url::parse("https://api.openai.com/").unwrap()).is_none());
+    }
+
+    #[test]
+    fn services_are_validated_and_unique_per_agent() {
+        let registry = ServiceRegistry::new();
+        assert!(registry.register(service("bob.agent", "http://127.0.0.1:9001")).is_err());
+        assert!(registry.register(service("bob", "ftp://127.0.0.1")).is_err());
+        let twice = ServiceConfig { services: vec![service("bob", "http://a"), service("BOB", "http://b")] };
+        assert!(registry.set_config(twice).is_err());
+
+        registry.register(service("bob", "http://a")).unwrap();
+        registry.register(service("BOB", "http://b")).unwrap();
+        assert_eq!(registry.get_config().services.len(), 1);
+        assert!(registry.remove("bob"));
+        assert!(!registry.remove("bob"));
+    }
+}
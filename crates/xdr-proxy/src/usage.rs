@@ -0,0 +1,389 @@
+//! Token usage metering for AI responses.
+//!
+//! Reads the `usage` block an OpenAI or Anthropic response reports, from a
+//! plain JSON body or from an SSE stream (OpenAI's final usage chunk,
+//! Anthropic's `message_start` / `message_delta` events). The body is tapped
+//! as it streams through, so metering never holds a response back.
+
+use axum::body::Body;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use serde_json::Value;
+
+/// Longest SSE line read for usage; longer ones are skipped (usage events are small).
+const MAX_LINE_BYTES: usize = 1024 * 1024;
+/// Largest top-level `model` or `usage` value read from a JSON body.
+const MAX_VALUE_BYTES: usize = 64 * 1024;
+
+/// Token counts from a response's `usage`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+/// Usage and model as reported by the upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportedUsage {
+    pub model: Option<String>,
+    pub tokens: TokenUsage,
+}
+
+/// Parses the usage out of a complete response body. None when the body
+/// reports none (e.g. an OpenAI stream without `include_usage`).
+pub fn parse(body: &[u8]) -> Option<ReportedUsage> {
+    let mut reader = UsageReader::default();
+    reader.push(body);
+    reader.finish()
+}
+
+/// Reads the usage out of a body chunk by chunk, holding on to no more than
+/// it needs: the current SSE line, or the top-level `model` and `usage` of a
+/// JSON body.
+#[derive(Default)]
+pub struct UsageReader {
+    format: Format,
+    model: Option<String>,
+    input: Option<u64>,
+    output: Option<u64>,
+}
+
+#[derive(Default)]
+enum Format {
+    /// Nothing but whitespace seen yet
+    #[default]
+    Unknown,
+    Json(JsonScan),
+    /// Bytes of the current line; None while skipping an overlong one
+    Sse(Option<Vec<u8>>),
+}
+
+impl UsageReader {
+    pub fn push(&mut self, mut chunk: &[u8]) {
+        if let Format::Unknown = self.format {
+            let Some(start) = chunk.iter().position(|b| !b.is_ascii_whitespace()) else { return };
+            chunk = &chunk[start..];
+            self.format = match chunk[0] {
+                b'{' => Format::Json(JsonScan::default()),
+                _ => Format::Sse(Some(Vec::new())),
+            };
+        }
+        match &mut self.format {
+            Format::Unknown => {}
+            Format::Json(scan) => {
+                for &byte in chunk {
+                    if let Some((key, value)) = scan.push(byte) {
+                        match key {
+                            JsonKey::Model => self.model = value.as_str().map(str::to_string),
+                            JsonKey::Usage => {
+                                if let Some(tokens) = read_usage(&value) {
+                                    self.input = Some(tokens.input_tokens);
+                                    self.output = Some(tokens.output_tokens);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Format::Sse(line) => {
+                let mut lines = Vec::new();
+                for piece in chunk.split_inclusive(|b| *b == b'\n') {
+                    let ends = piece.ends_with(b"\n");
+                    match line {
+                        Some(buf) if buf.len() + piece.len() > MAX_LINE_BYTES => *line = None,
+                        Some(buf) => buf.extend_from_slice(piece),
+                        None => {}
+                    }
+                    if ends {
+                        lines.extend(line.replace(Vec::new()));
+                    }
+                }
+                for line in lines {
+                    self.read_sse_line(&line);
+                }
+            }
+        }
+    }
+
+    /// The usage reported by everything pushed so far.
+    pub fn finish(mut self) -> Option<ReportedUsage> {
+        if let Format::Sse(Some(line)) = std::mem::take(&mut self.format) {
+            self.read_sse_line(&line);
+        }
+        (self.input.is_some() || self.output.is_some()).then(|| ReportedUsage {
+            model: self.model,
+            tokens: TokenUsage { input_tokens: self.input.unwrap_or(0), output_tokens: self.output.unwrap_or(0) },
+        })
+    }
+
+    // SSE: later events refine earlier ones (Anthropic reports input tokens
+    // up front and the running output count in each message_delta)
+    fn read_sse_line(&mut self, line: &[u8]) {
+        let Ok(line) = std::str::from_utf8(line) else { return };
+        let Some(data) = line.trim_end().strip_prefix("data:") else { return };
+        let Ok(event) = serde_json::from_str::<Value>(data.trim()) else { return };
+        let message = event.get("message").unwrap_or(&event);
+        self.model = model_of(message).or(self.model.take());
+        let Some(usage) = message.get("usage").filter(|u| u.is_object()) else { return };
+        if let Some(tokens) = usage.get("prompt_tokens").or_else(|| usage.get("input_tokens")).and_then(Value::as_u64) {
+            self.input = Some(tokens);
+        }
+        if let Some(tokens) = usage.get("completion_tokens").or_else(|| usage.get("output_tokens")).and_then(Value::as_u64) {
+            self.output = Some(tokens);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum JsonKey {
+    Model,
+    Usage,
+}
+
+/// Walks a JSON object byte by byte and hands back the values of its
+/// top-level `model` and `usage` keys; everything else is only counted.
+#[derive(Default)]
+struct JsonScan {
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// At depth 1, the next string is a key
+    expect_key: bool,
+    /// The top-level key being read, or the last one read
+    key: Option<Vec<u8>>,
+    reading_key: bool,
+    /// The value being kept; dropped if it grows past `MAX_VALUE_BYTES`
+    value: Option<(JsonKey, Vec<u8>)>,
+}
+
+impl JsonScan {
+    fn push(&mut self, byte: u8) -> Option<(JsonKey, Value)> {
+        if self.in_string {
+            self.keep(byte);
+            if self.escaped {
+                self.escaped = false;
+            } else if byte == b'\\' {
+                self.escaped = true;
+            } else if byte == b'"' {
+                self.in_string = false;
+                self.reading_key = false;
+                return None;
+            }
+            if self.reading_key {
+                if let Some(key) = &mut self.key {
+                    key.push(byte);
+                }
+                // No key we look for is that long
+                if self.key.as_ref().is_some_and(|key| key.len() > 5) {
+                    self.key = None;
+                }
+            }
+            return None;
+        }
+        let mut done = None;
+        match byte {
+            b',' | b'}' if self.depth == 1 => {
+                done = self.value.take();
+                self.expect_key = true;
+            }
+            b':' if self.depth == 1 => {
+                self.expect_key = false;
+                self.value = match self.key.as_deref() {
+                    Some(b"model") => Some((JsonKey::Model, Vec::new())),
+                    Some(b"usage") => Some((JsonKey::Usage, Vec::new())),
+                    _ => None,
+                };
+                return None;
+            }
+            b'"' => {
+                self.in_string = true;
+                if self.depth == 1 && self.expect_key {
+                    self.reading_key = true;
+                    self.key = Some(Vec::new());
+                }
+            }
+            _ => {}
+        }
+        match byte {
+            b'{' | b'[' => {
+                self.depth += 1;
+                self.expect_key |= self.depth == 1;
+            }
+            b'}' | b']' => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+        if done.is_none() {
+            self.keep(byte);
+        }
+        let (key, value) = done?;
+        serde_json::from_slice(&value).ok().map(|value| (key, value))
+    }
+
+    fn keep(&mut self, byte: u8) {
+        if let Some((_, value)) = &mut self.value {
+            value.push(byte);
+            if value.len() > MAX_VALUE_BYTES {
+                self.value = None;
+            }
+        }
+    }
+}
+
+fn model_of(json: &Value) -> Option<String> {
+    json.get("model").and_then(Value::as_str).map(str::to_string)
+}
+
+fn read_usage(usage: &Value) -> Option<TokenUsage> {
+    let field = |openai: &str, anthropic: &str| usage.get(openai).or_else(|| usage.get(anthropic)).and_then(Value::as_u64);
+    let input = field("prompt_tokens", "input_tokens");
+    let output = field("completion_tokens", "output_tokens");
+    (input.is_some() || output.is_some()).then(|| TokenUsage {
+        input_tokens: input.unwrap_or(0),
+        output_tokens: output.unwrap_or(0),
+    })
+}
+
+type OnDone = Box<dyn FnOnce(Option<&[u8]>) + Send>;
+
+/// Copies the body aside as it streams and hands it to `on_done` once the
+/// stream ends or is dropped (an agent hanging up mid-stream still used
+/// whatever was generated). None when the body outgrew the copy's limit.
+struct Tap {
+    seen: Vec<u8>,
+    limit: usize,
+    overflow: bool,
+    on_done: Option<OnDone>,
+}
+
+impl Tap {
+    fn push(&mut self, chunk: &Bytes) {
+        if self.overflow || self.seen.len() + chunk.len() > self.limit {
+            self.overflow = true;
+            self.seen = Vec::new();
+        } else {
+            self.seen.extend_from_slice(chunk);
+        }
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done((!self.overflow).then_some(self.seen.as_slice()));
+        }
+    }
+}
+
+/// Passes `body` through untouched while keeping a copy of up to `limit` bytes.
+pub(crate) fn tap(body: Body, limit: usize, on_done: impl FnOnce(Option<&[u8]>) + Send + 'static) -> Body {
+    let mut tap = Tap { seen: Vec::new(), limit, overflow: false, on_done: Some(Box::new(on_done)) };
+    Body::from_stream(body.into_data_stream().map(move |chunk| {
+        if let Ok(bytes) = &chunk {
+            tap.push(bytes);
+        }
+        chunk
+    }))
+}
+
+type OnUsage = Box<dyn FnOnce(Option<ReportedUsage>) + Send>;
+
+/// Reads the usage as the body streams and hands it to `on_done` once the
+/// stream ends or is dropped, like `Tap`, without keeping the body.
+struct Meter {
+    reader: Option<UsageReader>,
+    on_done: Option<OnUsage>,
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        if let (Some(reader), Some(on_done)) = (self.reader.take(), self.on_done.take()) {
+            on_done(reader.finish());
+        }
+    }
+}
+
+/// Passes `body` through untouched and reports the usage it carried.
+pub(crate) fn meter(body: Body, on_done: impl FnOnce(Option<ReportedUsage>) + Send + 'static) -> Body {
+    let mut meter = Meter { reader: Some(UsageReader::default()), on_done: Some(Box::new(on_done)) };
+    Body::from_stream(body.into_data_stream().map(move |chunk| {
+        if let (Ok(bytes), Some(reader)) = (&chunk, &mut meter.reader) {
+            reader.push(bytes);
+        }
+        chunk
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn tap_passes_the_stream_through_and_keeps_a_copy() {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = vec![Ok(Bytes::from("data: a\n\n")), Ok(Bytes::from("data: b\n\n"))];
+        let seen = Arc::new(Mutex::new(None));
+        let copy = seen.clone();
+        let body = tap(Body::from_stream(futures_util::stream::iter(chunks)), 1024, move |body| {
+            *copy.lock().unwrap() = Some(body.map(<[u8]>::to_vec));
+        });
+        let out = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(&out[..], b"data: a\n\ndata: b\n\n");
+        assert_eq!(seen.lock().unwrap().clone(), Some(Some(out.to_vec())));
+    }
+
+    #[tokio::test]
+    async fn tap_gives_up_on_bodies_over_the_limit() {
+        let seen = Arc::new(Mutex::new(None));
+        let copy = seen.clone();
+        let body = tap(Body::from("0123456789"), 4, move |body| *copy.lock().unwrap() = Some(body.is_none()));
+        assert_eq!(axum::body::to_bytes(body, usize::MAX).await.unwrap().len(), 10);
+        assert_eq!(*seen.lock().unwrap(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn meter_reads_usage_at_the_end_of_large_bodies() {
+        let content = "x".repeat(20 * 1024 * 1024);
+        let json = format!(r#"{{"model":"gpt-4o","choices":[{{"message":{{"content":"{}"}}}}],"usage":{{"prompt_tokens":5,"completion_tokens":7}}}}"#, content);
+        let chunks: Vec<Result<Bytes, std::io::Error>> = json.as_bytes().chunks(64 * 1024).map(|c| Ok(Bytes::copy_from_slice(c))).collect();
+        let seen = Arc::new(Mutex::new(None));
+        let copy = seen.clone();
+        let body = meter(Body::from_stream(futures_util::stream::iter(chunks)), move |usage| *copy.lock().unwrap() = usage);
+        assert_eq!(axum::body::to_bytes(body, usize::MAX).await.unwrap().len(), json.len());
+        let usage = seen.lock().unwrap().clone().unwrap();
+        assert_eq!(usage.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(usage.tokens, TokenUsage { input_tokens: 5, output_tokens: 7 });
+    }
+
+    #[test]
+    fn usage_is_read_across_chunk_boundaries() {
+        let json = br#" {"id":"x","content":[{"text":"\"usage\": {}"}],"model":"claude","usage":{"input_tokens":3,"output_tokens":4}}"#;
+        let mut reader = UsageReader::default();
+        for byte in json {
+            reader.push(&[*byte]);
+        }
+        let usage = reader.finish().unwrap();
+        assert_eq!(usage.model.as_deref(), Some("claude"));
+        assert_eq!(usage.tokens, TokenUsage { input_tokens: 3, output_tokens: 4 });
+
+        let sse = b"data: {\"model\":\"gpt-4o\",\"choices\":[]}\n\ndata: {\"usage\":{\"prompt_tokens\":2,\"completion_tokens\":6}}";
+        let mut reader = UsageReader::default();
+        for chunk in sse.chunks(5) {
+            reader.push(chunk);
+        }
+        assert_eq!(reader.finish().unwrap().tokens, TokenUsage { input_tokens: 2, output_tokens: 6 });
+    }
+
+    #[test]
+    fn parses_usage_from_json_and_sse() {
+        let json = br#"{"model":"gpt-4o","usage":{"prompt_tokens":5,"completion_tokens":7}}"#;
+        let usage = parse(json).unwrap();
+        assert_eq!(usage.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(usage.tokens, TokenUsage { input_tokens: 5, output_tokens: 7 });
+
+        let sse = b"data: {\"type\":\"message_start\",\"message\":{\"model\":\"claude\",\"usage\":{\"input_tokens\":3,\"output_tokens\":1}}}\n\n\
+                    data: {\"type\":\"message_delta\",\"usage\":{\"output_tokens\":9}}\n\n";
+        let usage = parse(sse).unwrap();
+        assert_eq!(usage.model.as_deref(), Some("claude"));
+        assert_eq!(usage.tokens, TokenUsage { input_tokens: 3, output_tokens: 9 });
+    }
+}
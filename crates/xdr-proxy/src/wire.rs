@@ -0,0 +1,69 @@
+//! How amounts are written in the JSON the proxy sends back to clients.
+//!
+//! Amounts are persisted as decimal strings whatever the format; the format
+//! chosen with `xdr run --amount-format` only applies here, while a response
+//! body is serialized. Handlers answer with this module's `Json` instead of
+//! axum's, and build any `json!` value that embeds amounts with `value`.
+
+use axum::extract::{FromRequest, Request};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use xdr_ledger::amount::{self, AmountFormat};
+
+static AMOUNTS_AS_STRINGS: AtomicBool = AtomicBool::new(false);
+
+/// Selects how amounts are written in responses from now on.
+pub fn set_amount_format(format: AmountFormat) {
+    AMOUNTS_AS_STRINGS.store(format == AmountFormat::String, Ordering::Relaxed);
+}
+
+pub fn amount_format() -> AmountFormat {
+    match AMOUNTS_AS_STRINGS.load(Ordering::Relaxed) {
+        false => AmountFormat::Number,
+        true => AmountFormat::String,
+    }
+}
+
+/// `value` as JSON, with its amounts in the response format.
+pub fn value<T: Serialize>(value: &T) -> Value {
+    amount::with_format(amount_format(), || serde_json::to_value(value).unwrap_or(Value::Null))
+}
+
+/// axum's `Json`, except that a response writes its amounts in the response
+/// format. As an extractor it is axum's unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        amount::with_format(amount_format(), || axum::Json(self.0).into_response())
+    }
+}
+
+#[axum::async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    axum::Json<T>: FromRequest<S>,
+    S: Send + Sync,
+{
+    type Rejection = <axum::Json<T> as FromRequest<S>>::Rejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        axum::Json::from_request(req, state).await.map(|axum::Json(value)| Self(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xdr_ledger::Amount;
+
+    #[test]
+    fn responses_use_the_format_but_persistence_does_not() {
+        set_amount_format(AmountFormat::Number);
+        assert_eq!(value(&Amount::from_units(10_000)), serde_json::json!(0.01));
+        assert_eq!(serde_json::to_value(Amount::from_units(10_000)).unwrap(), serde_json::json!("0.01"));
+    }
+}
@@ -0,0 +1,313 @@
+//! x402 wire format (spec v1).
+//!
+//! A gated request without payment gets a 402 whose JSON body lists the
+//! accepted `PaymentRequirements`. The client retries with a base64 JSON
+//! `X-PAYMENT` header, and a settled request carries a base64 JSON
+//! `X-PAYMENT-RESPONSE` header back.
+//!
+//! A route priced in several assets lists one requirement per EIP-3009 token;
+//! the payment's signature domain tells which one the client chose.
+//!
+//! `upto` routes use the same payload, but `maxAmountRequired` is a ceiling
+//! paid into escrow and `payTo` is the escrow contract.
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+use xdr_ledger::tokens::Token;
+use xdr_ledger::PaymentError;
+
+use crate::eip3009::{self, VerifyError};
+use crate::pricing::{AssetPrice, PaymentScheme};
+
+pub const X402_VERSION: u32 = 1;
+pub const HEADER_PAYMENT: &str = "x-payment";
+pub const HEADER_PAYMENT_RESPONSE: &str = "x-payment-response";
+
+/// Mock settlement address (burned funds)
+pub const PAY_TO_ADDRESS: &str = xdr_ledger::BURN_ADDRESS;
+/// Longest validity window the server accepts for a signed payment
+const MAX_TIMEOUT_SECONDS: u64 = 60;
+
+/// Which payment challenge dialect the gate speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentProtocol {
+    /// `WWW-Authenticate: L402 token=<invoice>` / `Authorization: L402 <invoice>`
+    L402,
+    /// x402 `accepts` body / `X-PAYMENT` / `X-PAYMENT-RESPONSE`
+    X402,
+}
+
+impl FromStr for PaymentProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "l402" => Ok(Self::L402),
+            "x402" => Ok(Self::X402),
+            other => Err(format!("Unknown payment protocol '{}' (expected l402 or x402)", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentRequirements {
+    pub scheme: String,
+    pub network: String,
+    /// Atomic units of `asset`, as a decimal string
+    pub max_amount_required: String,
+    pub resource: String,
+    pub description: String,
+    pub mime_type: String,
+    pub pay_to: String,
+    pub max_timeout_seconds: u64,
+    pub asset: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentRequiredBody {
+    pub x402_version: u32,
+    pub error: String,
+    pub accepts: Vec<PaymentRequirements>,
+    /// Why the payment was rejected (XDR extension, omitted on first challenge)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentPayload {
+    pub x402_version: u32,
+    pub scheme: String,
+    pub network: String,
+    pub payload: ExactEvmPayload,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExactEvmPayload {
+    pub signature: String,
+    pub authorization: Authorization,
+}
+
+/// EIP-3009 `transferWithAuthorization` parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Authorization {
+    pub from: String,
+    pub to: String,
+    pub value: String,
+    pub valid_after: String,
+    pub valid_before: String,
+    pub nonce: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SettlementResponse {
+    pub success: bool,
+    pub transaction: String,
+    pub network: String,
+    pub payer: String,
+}
+
+/// EIP-155 chain id of the simulated network
+pub fn chain_id(network: &str) -> u64 {
+    if network == "cronos-mainnet" { 25 } else { 338 }
+}
+
+/// Builds the `exact` or `upto` scheme requirements for paying `option` in
+/// `token` (which must support EIP-3009).
+pub fn requirements(option: &AssetPrice, scheme: PaymentScheme, token: &Token, resource: &Url, network: &str) -> PaymentRequirements {
+    let (pay_to, description) = match scheme {
+        PaymentScheme::Exact => (PAY_TO_ADDRESS, format!("XDR simulated payment ({} {})", option.price, token.symbol)),
+        PaymentScheme::Upto => (xdr_ledger::escrow::ESCROW_ADDRESS, format!("XDR simulated payment (up to {} {}, unused part refunded)", option.price, token.symbol)),
+    };
+    PaymentRequirements {
+        scheme: scheme.as_str().to_string(),
+        network: network.to_string(),
+        max_amount_required: token.to_atomic(option.price).to_string(),
+        resource: resource.to_string(),
+        description,
+        mime_type: "application/json".to_string(),
+        pay_to: pay_to.to_string(),
+        max_timeout_seconds: MAX_TIMEOUT_SECONDS,
+        asset: token.address.clone().unwrap_or_default(),
+        extra: Some(serde_json::json!({
+            "name": token.name,
+            "version": token.eip712_version.clone().unwrap_or_default(),
+        })),
+    }
+}
+
+/// The spec'd 402 response: error code plus the list of accepted requirements.
+pub fn payment_required(error: &str, accepts: &[PaymentRequirements]) -> Response {
+    rejected(error, None, accepts)
+}
+
+/// A 402 for a payment that was attempted but refused, with the reason attached.
+pub fn rejected(error: &str, detail: Option<String>, accepts: &[PaymentRequirements]) -> Response {
+    let body = PaymentRequiredBody {
+        x402_version: X402_VERSION,
+        error: error.to_string(),
+        accepts: accepts.to_vec(),
+        detail,
+    };
+    (StatusCode::PAYMENT_REQUIRED, Json(body)).into_response()
+}
+
+/// Decodes the base64 JSON `X-PAYMENT` header.
+/// Any failure here maps to the `invalid_payload` error code.
+pub fn decode_payment(header: &str) -> Result<PaymentPayload, String> {
+    let raw = BASE64
+        .decode(header.trim())
+        .map_err(|_| "X-PAYMENT is not valid base64".to_string())?;
+    serde_json::from_slice(&raw).map_err(|e| format!("X-PAYMENT is not a valid payload: {}", e))
+}
+
+/// Encodes a settlement result for the `X-PAYMENT-RESPONSE` header.
+pub fn encode_settlement(settlement: &SettlementResponse) -> String {
+    BASE64.encode(serde_json::to_vec(settlement).unwrap_or_default())
+}
+
+/// Checks that a payment targets the requirements it claims to satisfy, then
+/// runs the scheme verifier. Returns the payer address on success.
+pub fn verify(payment: &PaymentPayload, requirements: &PaymentRequirements) -> Result<String, VerifyError> {
+    check_envelope(payment, requirements)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    eip3009::verify(payment, requirements, now)
+}
+
+/// The fields a payment must share with the requirement, before its signature is looked at.
+fn check_envelope(payment: &PaymentPayload, requirements: &PaymentRequirements) -> Result<(), VerifyError> {
+    if payment.x402_version != X402_VERSION {
+        return Err(VerifyError { code: "invalid_x402_version", detail: format!("x402Version {} is not supported", payment.x402_version) });
+    }
+    if payment.scheme != requirements.scheme {
+        return Err(VerifyError { code: "unsupported_scheme", detail: format!("scheme '{}' is not accepted here", payment.scheme) });
+    }
+    if payment.network != requirements.network {
+        return Err(VerifyError { code: "invalid_network", detail: format!("network '{}' does not match '{}'", payment.network, requirements.network) });
+    }
+    Ok(())
+}
+
+/// Finds the requirement a payment is meant for and verifies it. The
+/// authorization doesn't name its asset, but the EIP-712 domain it was signed
+/// under does. Returns the requirement's index and the payer.
+///
+/// With a single requirement that's the one verified, so its own checks say
+/// what is wrong. With several, a payment signed for none of them is refused
+/// rather than checked against an asset the client never chose.
+pub fn select(payment: &PaymentPayload, accepts: &[PaymentRequirements]) -> Result<(usize, String), VerifyError> {
+    match accepts {
+        [] => Err(VerifyError { code: "invalid_payment_requirements", detail: "no payment requirements to satisfy".to_string() }),
+        [requirements] => verify(payment, requirements).map(|payer| (0, payer)),
+        _ => match accepts.iter().position(|r| eip3009::signed_for(payment, r)) {
+            Some(idx) => verify(payment, &accepts[idx]).map(|payer| (idx, payer)),
+            None => {
+                // Every requirement of a route shares its scheme and network
+                check_envelope(payment, &accepts[0])?;
+                let assets: Vec<&str> = accepts.iter().map(|r| r.asset.as_str()).collect();
+                Err(VerifyError {
+                    code: "invalid_exact_evm_payload_signature",
+                    detail: format!("the authorization is not signed for any accepted asset ({})", assets.join(", ")),
+                })
+            }
+        },
+    }
+}
+
+/// Maps a ledger settlement error onto an x402 error code. Refusals x402 has
+/// no code for (budget, policies) keep XDR's own code.
+pub fn settle_error_code(error: &PaymentError) -> &'static str {
+    match error {
+        PaymentError::InsufficientFunds { .. } => "insufficient_funds",
+        PaymentError::BudgetExceeded
+        | PaymentError::GroupBudgetExceeded { .. }
+        | PaymentError::Policy(_)
+        | PaymentError::InvoiceExpired { .. }
+        | PaymentError::InvoiceCancelled
+        | PaymentError::ResourceMismatch { .. }
+        | PaymentError::AmountOverflow { .. } => error.code(),
+        _ => "unexpected_settle_error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eip3009::{Domain, TransferAuthorization};
+    use k256::ecdsa::SigningKey;
+    use xdr_ledger::tokens::TokenRegistry;
+    use xdr_ledger::Amount;
+
+    /// Requirements for paying $0.01 in each of `symbols` on the testnet.
+    fn accepts(symbols: &[&str]) -> Vec<PaymentRequirements> {
+        let tokens = TokenRegistry::default().tokens("cronos-testnet");
+        let resource = Url::parse("https://api.openai.com/v1/chat/completions").unwrap();
+        symbols.iter().map(|symbol| {
+            let token = tokens.iter().find(|t| t.symbol == *symbol).unwrap();
+            let option = AssetPrice { currency: symbol.to_string(), price: Amount::from_units(10_000) };
+            requirements(&option, PaymentScheme::Exact, token, &resource, "cronos-testnet")
+        }).collect()
+    }
+
+    /// A payment of the required amount, signed under the domain of `requirements`.
+    fn signed(requirements: &PaymentRequirements) -> PaymentPayload {
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let authorization = Authorization {
+            from: eip3009::format_address(&eip3009::address_of(key.verifying_key())),
+            to: requirements.pay_to.clone(),
+            value: requirements.max_amount_required.clone(),
+            valid_after: "0".to_string(),
+            valid_before: (now + 60).to_string(),
+            nonce: format!("0x{}", "ab".repeat(32)),
+        };
+        let domain = Domain::from_requirements(requirements).unwrap();
+        let digest = TransferAuthorization::parse(&authorization).unwrap().signing_hash(&domain);
+        let (signature, recid) = key.sign_prehash_recoverable(&digest).unwrap();
+        let mut bytes = signature.to_bytes().to_vec();
+        bytes.push(27 + recid.to_byte());
+        PaymentPayload {
+            x402_version: X402_VERSION,
+            scheme: requirements.scheme.clone(),
+            network: requirements.network.clone(),
+            payload: ExactEvmPayload { signature: format!("0x{}", hex::encode(bytes)), authorization },
+        }
+    }
+
+    #[test]
+    fn select_picks_the_asset_the_payment_was_signed_for() {
+        let accepts = accepts(&["USDC", "USDT"]);
+        assert_eq!(select(&signed(&accepts[1]), &accepts).unwrap().0, 1);
+        assert_eq!(select(&signed(&accepts[0]), &accepts).unwrap().0, 0);
+    }
+
+    #[test]
+    fn select_refuses_payments_signed_for_no_accepted_asset() {
+        let offered = accepts(&["USDC", "USDT"]);
+        // Signed for a token contract the route doesn't take
+        let mut elsewhere = offered.clone();
+        elsewhere[0].asset = format!("0x{}", "11".repeat(20));
+        let err = select(&signed(&elsewhere[0]), &offered).unwrap_err();
+        assert_eq!(err.code, "invalid_exact_evm_payload_signature");
+        assert!(err.detail.contains(&offered[0].asset) && err.detail.contains(&offered[1].asset), "{}", err.detail);
+
+        // Envelope errors still win over the asset mismatch
+        let mut wrong_network = signed(&elsewhere[0]);
+        wrong_network.network = "cronos-mainnet".to_string();
+        assert_eq!(select(&wrong_network, &offered).unwrap_err().code, "invalid_network");
+        assert_eq!(select(&signed(&offered[0]), &[]).unwrap_err().code, "invalid_payment_requirements");
+    }
+}
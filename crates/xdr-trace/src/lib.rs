@@ -13,6 +13,12 @@ pub struct Trace {
     pub duration_ms: Option<u64>,
     pub status_code: Option<u16>,
     pub events: Vec<TraceEvent>,
+    /// Every chaos roll made for this request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chaos_decisions: Vec<ChaosDecision>,
+    /// Tokens an AI request used and the post-paid charge for them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<UsageCharge>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,12 +28,53 @@ pub struct TraceEvent {
     pub message: String,
 }
 
+/// One chaos roll and the key its RNG stream was derived from. The same key
+/// always rolls the same way, however requests from other agents interleave.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChaosDecision {
+    pub seed: u64,
+    pub agent_id: String,
+    /// The agent's request number since chaos was last configured
+    pub seq: u64,
+    /// What was rolled for (`network_failure`, `latency`, `response`, ...)
+    pub fault: String,
+    pub hit: bool,
+}
+
+impl ChaosDecision {
+    /// "42/agent-007/3/network_failure"
+    pub fn key(&self) -> String {
+        format!("{}/{}/{}/{}", self.seed, self.agent_id, self.seq, self.fault)
+    }
+}
+
+/// Per-token cost breakdown of one AI response. Costs are decimal strings in
+/// `currency` (e.g. "0.000125").
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageCharge {
+    pub model: String,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub input_cost: String,
+    pub output_cost: String,
+    pub total_cost: String,
+    pub currency: String,
+    /// Settlement transaction (None when the charge was rejected or free)
+    #[serde(default)]
+    pub tx_hash: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum EventCategory {
     Info,
     Chaos,
     Payment,
     Upstream,
+    Replay,
+    /// A spending policy blocked a payment
+    Policy,
+    /// A payment held for (or given) an operator's approval
+    Approval,
     Error,
 }
 
@@ -43,6 +90,8 @@ impl Trace {
             duration_ms: None,
             status_code: None,
             events: Vec::new(),
+            chaos_decisions: Vec::new(),
+            usage: None,
         }
     }
 
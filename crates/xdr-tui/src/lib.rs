@@ -7,13 +7,16 @@ use crossterm::{
 };
 use ratatui::{prelude::*, widgets::*};
 use std::{error::Error, io, sync::{Arc, Mutex}, collections::VecDeque, time::Duration};
-use xdr_ledger::Ledger;
+use xdr_ledger::groups::BudgetGroup;
+use xdr_ledger::{AgentState, Amount, Ledger};
 use xdr_chaos::ChaosEngine;
+use xdr_proxy::approvals::{ApprovalQueue, Decision};
 use xdr_trace::Trace;
 
 pub struct App {
     pub ledger: Ledger,
     pub chaos: ChaosEngine,
+    pub approvals: ApprovalQueue,
     pub traces: Arc<Mutex<VecDeque<Trace>>>,
 }
 
@@ -37,11 +40,18 @@ pub async fn run_tui(app_state: App) -> Result<(), Box<dyn Error>> {
 }
 
 async fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: App) -> io::Result<()> {
+    // Index into the pending approvals (oldest first)
+    let mut selected: usize = 0;
+    // Why the last key's action failed, shown in the footer until the next key
+    let mut error: Option<String> = None;
     loop {
-        terminal.draw(|f| ui(f, &app))?;
+        let pending = app.approvals.pending();
+        selected = selected.min(pending.len().saturating_sub(1));
+        terminal.draw(|f| ui(f, &app, selected, error.as_deref()))?;
 
         if event::poll(Duration::from_millis(100))? {
             if let Event::Key(key) = event::read()? {
+                error = None;
                 match key.code {
                     KeyCode::Char('q') => return Ok(()),
                     KeyCode::Char('c') => {
@@ -52,14 +62,28 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: App) -> io::Result
                             new_cfg.global_failure_rate = 0.2;
                             new_cfg.min_latency_ms = 200;
                         }
-                        app.chaos.set_config(new_cfg);
+                        if let Err(e) = app.chaos.set_config(new_cfg) {
+                            error = Some(format!("Chaos not toggled: {}", e));
+                        }
                     },
                     KeyCode::Char('f') => {
                         let agent_id = "agent-007";
-                        if let Some(state) = app.ledger.get_state(agent_id) {
-                            app.ledger.set_balance(agent_id, state.balance_usdc + 50.0);
-                        } else {
-                            app.ledger.set_balance(agent_id, 100.0);
+                        let amount = match app.ledger.get_state(agent_id) {
+                            Some(state) => state.balance("USDC") + Amount::from_whole(50),
+                            None => Amount::from_whole(100),
+                        };
+                        if let Err(e) = app.ledger.set_balance(agent_id, "USDC", amount) {
+                            error = Some(format!("Funding {} failed: {}", agent_id, e));
+                        }
+                    },
+                    KeyCode::Up => selected = selected.saturating_sub(1),
+                    KeyCode::Down => selected += 1,
+                    KeyCode::Char('y') | KeyCode::Char('n') => {
+                        if let Some(approval) = pending.get(selected) {
+                            let decision = if key.code == KeyCode::Char('y') { Decision::Approve } else { Decision::Deny };
+                            if let Err(e) = app.approvals.decide(&approval.id, decision, Some("decided in the TUI".to_string())) {
+                                error = Some(format!("Approval {} not decided: {}", approval.id, e));
+                            }
                         }
                     },
                     _ => {}
@@ -69,7 +93,7 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: App) -> io::Result
     }
 }
 
-fn ui(f: &mut Frame, app: &App) {
+fn ui(f: &mut Frame, app: &App, selected: usize, error: Option<&str>) {
     let area = f.size();
     
     // Clear with dark background
@@ -95,13 +119,33 @@ fn ui(f: &mut Frame, app: &App) {
         .split(main_layout[1]);
 
     render_agent_panel(f, app, content_layout[0]);
-    render_traffic_panel(f, app, content_layout[1]);
-    render_footer(f, main_layout[2]);
+
+    // Approval queue under the request log, while approvals are in use
+    let pending = app.approvals.pending();
+    if app.approvals.get_config().is_enabled() || !pending.is_empty() {
+        let right = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(5),
+                Constraint::Length(pending.len().clamp(1, 5) as u16 + 2),
+            ])
+            .split(content_layout[1]);
+        render_traffic_panel(f, app, right[0]);
+        render_approval_panel(f, &pending, selected, right[1]);
+    } else {
+        render_traffic_panel(f, app, content_layout[1]);
+    }
+    render_footer(f, main_layout[2], !pending.is_empty(), error);
 }
 
 fn render_header(f: &mut Frame, app: &App, area: Rect) {
     let chaos = app.chaos.get_config();
-    let chaos_text = if chaos.enabled {
+    let chaos_text = if let Some(status) = app.chaos.scenario_status() {
+        let color = if status.finished { Color::DarkGray } else { Color::Magenta };
+        Span::styled(format!(" SCENARIO: {} ", status.label()), Style::default().bg(color).fg(Color::White).add_modifier(Modifier::BOLD))
+    } else if chaos.enabled && !chaos.rules.is_empty() {
+        Span::styled(format!(" CHAOS ON ({} rules) ", chaos.rules.len()), Style::default().bg(Color::Red).fg(Color::White).add_modifier(Modifier::BOLD))
+    } else if chaos.enabled {
         Span::styled(" CHAOS ON ", Style::default().bg(Color::Red).fg(Color::White).add_modifier(Modifier::BOLD))
     } else {
         Span::styled(" CHAOS OFF ", Style::default().bg(Color::Green).fg(Color::Black).add_modifier(Modifier::BOLD))
@@ -109,13 +153,22 @@ fn render_header(f: &mut Frame, app: &App, area: Rect) {
     
     let agents = app.ledger.list_agents();
     let trace_count = app.traces.lock().map(|t| t.len()).unwrap_or(0);
+    let pending = app.approvals.pending().len();
     
-    let header = Paragraph::new(Line::from(vec![
+    let mut spans = vec![
         Span::styled(" XDR ", Style::default().bg(Color::Cyan).fg(Color::Black).add_modifier(Modifier::BOLD)),
         Span::raw(" Control Plane | "),
         chaos_text,
         Span::raw(format!(" | Agents: {} | Requests: {} ", agents.len(), trace_count)),
-    ]))
+    ];
+    if pending > 0 {
+        spans.push(Span::raw("| "));
+        spans.push(Span::styled(
+            format!(" {} AWAITING APPROVAL ", pending),
+            Style::default().bg(Color::Yellow).fg(Color::Black).add_modifier(Modifier::BOLD),
+        ));
+    }
+    let header = Paragraph::new(Line::from(spans))
     .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(Color::Cyan)));
     
     f.render_widget(header, area);
@@ -123,10 +176,11 @@ fn render_header(f: &mut Frame, app: &App, area: Rect) {
 
 fn render_agent_panel(f: &mut Frame, app: &App, area: Rect) {
     let agents = app.ledger.list_agents();
+    let groups = app.ledger.list_groups();
     
     let mut text_lines: Vec<Line> = Vec::new();
     
-    if agents.is_empty() {
+    if agents.is_empty() && groups.is_empty() {
         text_lines.push(Line::from(""));
         text_lines.push(Line::from(Span::styled(
             "  No agents connected",
@@ -141,87 +195,13 @@ fn render_agent_panel(f: &mut Frame, app: &App, area: Rect) {
         text_lines.push(Line::from(""));
         text_lines.push(Line::from("  Or press [F] to pre-fund"));
     } else {
-        for agent in &agents {
-            // Agent ID header
-            text_lines.push(Line::from(vec![
-                Span::styled(
-                    format!(" {} ", agent.id),
-                    Style::default().bg(Color::Blue).fg(Color::White).add_modifier(Modifier::BOLD)
-                ),
-            ]));
-            text_lines.push(Line::from(""));
-            
-            // Balance - prominent display
-            let balance_color = if agent.balance_usdc < 5.0 { 
-                Color::Red 
-            } else if agent.balance_usdc < 20.0 { 
-                Color::Yellow 
-            } else { 
-                Color::Green 
-            };
-            
-            text_lines.push(Line::from(vec![
-                Span::raw("  Balance: "),
-                Span::styled(
-                    format!("${:.2}", agent.balance_usdc),
-                    Style::default().fg(balance_color).add_modifier(Modifier::BOLD)
-                ),
-                Span::styled(" USDC", Style::default().fg(Color::DarkGray)),
-            ]));
-            
-            // Spend info
-            text_lines.push(Line::from(vec![
-                Span::raw("  Spent:   "),
-                Span::styled(
-                    format!("${:.2}", agent.total_spend),
-                    Style::default().fg(Color::Yellow)
-                ),
-                Span::styled(
-                    format!(" / ${:.0} limit", agent.budget_limit),
-                    Style::default().fg(Color::DarkGray)
-                ),
-            ]));
-            
-            // Payment count
-            text_lines.push(Line::from(vec![
-                Span::raw("  Payments: "),
-                Span::styled(
-                    format!("{}", agent.payment_count),
-                    Style::default().fg(Color::Cyan)
-                ),
-            ]));
-            
-            // Budget usage bar
-            let pct = if agent.budget_limit > 0.0 {
-                (agent.total_spend / agent.budget_limit * 100.0).min(100.0)
-            } else { 0.0 };
-            
-            let bar_width = 20;
-            let filled = (pct / 100.0 * bar_width as f64) as usize;
-            let empty = bar_width - filled;
-            
-            let bar_color = if pct > 80.0 { Color::Red } 
-                           else if pct > 50.0 { Color::Yellow } 
-                           else { Color::Green };
-            
-            text_lines.push(Line::from(""));
-            text_lines.push(Line::from(vec![
-                Span::raw("  "),
-                Span::styled(
-                    "=".repeat(filled),
-                    Style::default().fg(bar_color)
-                ),
-                Span::styled(
-                    "-".repeat(empty),
-                    Style::default().fg(Color::DarkGray)
-                ),
-                Span::styled(
-                    format!(" {:.0}%", pct),
-                    Style::default().fg(bar_color)
-                ),
-            ]));
-            
-            text_lines.push(Line::from(""));
+        // Budget groups first, as trees: shared wallet, then each level's spend and members
+        for root in groups.iter().filter(|g| g.is_root()) {
+            push_group_lines(app, &groups, &agents, root, 0, &mut text_lines);
+        }
+        // Agents on their own wallets (including those whose group is gone)
+        for agent in agents.iter().filter(|a| a.group.as_ref().is_none_or(|g| !groups.iter().any(|grp| grp.id == *g))) {
+            push_agent_lines(app, agent, &mut text_lines);
         }
     }
     
@@ -234,6 +214,177 @@ fn render_agent_panel(f: &mut Frame, app: &App, area: Rect) {
     f.render_widget(panel, area);
 }
 
+/// Color of a balance by its USD value.
+fn balance_color(usd: Amount) -> Color {
+    if usd < Amount::from_whole(5) { 
+        Color::Red 
+    } else if usd < Amount::from_whole(20) { 
+        Color::Yellow 
+    } else { 
+        Color::Green 
+    }
+}
+
+/// "=====--------------- 25%" for `spent` out of `limit`.
+fn usage_bar(indent: &str, spent: Amount, limit: Amount) -> Line<'static> {
+    let pct = if !limit.is_zero() {
+        (spent.as_f64() / limit.as_f64() * 100.0).min(100.0)
+    } else { 0.0 };
+    
+    let bar_width = 20;
+    let filled = (pct / 100.0 * bar_width as f64) as usize;
+    let empty = bar_width - filled;
+    
+    let bar_color = if pct > 80.0 { Color::Red } 
+                   else if pct > 50.0 { Color::Yellow } 
+                   else { Color::Green };
+    
+    Line::from(vec![
+        Span::raw(format!("  {}", indent)),
+        Span::styled(
+            "=".repeat(filled),
+            Style::default().fg(bar_color)
+        ),
+        Span::styled(
+            "-".repeat(empty),
+            Style::default().fg(Color::DarkGray)
+        ),
+        Span::styled(
+            format!(" {:.0}%", pct),
+            Style::default().fg(bar_color)
+        ),
+    ])
+}
+
+/// One budget group (wallet if it holds one, spend against its cap, member
+/// agents), then its subgroups indented below it.
+fn push_group_lines(
+    app: &App,
+    groups: &[BudgetGroup],
+    agents: &[AgentState],
+    group: &BudgetGroup,
+    depth: usize,
+    text_lines: &mut Vec<Line>,
+) {
+    let indent = "  ".repeat(depth);
+    let header_style = if depth == 0 {
+        Style::default().bg(Color::Magenta).fg(Color::White).add_modifier(Modifier::BOLD)
+    } else {
+        Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD)
+    };
+    text_lines.push(Line::from(vec![
+        Span::raw(indent.clone()),
+        Span::styled(format!(" {} ", group.id), header_style),
+        Span::styled(if depth == 0 { " group" } else { " team" }, Style::default().fg(Color::DarkGray)),
+    ]));
+
+    // Shared wallet (top-level groups only)
+    for (i, (symbol, balance)) in group.balances.iter().enumerate() {
+        let usd = app.ledger.token(symbol).map_or(*balance, |t| t.usd_value(*balance));
+        text_lines.push(Line::from(vec![
+            Span::raw(if i == 0 { format!("{}  Wallet:  ", indent) } else { format!("{}           ", indent) }),
+            Span::styled(
+                format!("{}", balance),
+                Style::default().fg(balance_color(usd)).add_modifier(Modifier::BOLD)
+            ),
+            Span::styled(format!(" {}", symbol), Style::default().fg(Color::DarkGray)),
+        ]));
+    }
+
+    let cap = group.budget_limit.map_or(" (no cap)".to_string(), |limit| format!(" / ${} cap", limit));
+    text_lines.push(Line::from(vec![
+        Span::raw(format!("{}  Spent:   ", indent)),
+        Span::styled(format!("${}", group.total_spend), Style::default().fg(Color::Yellow)),
+        Span::styled(cap, Style::default().fg(Color::DarkGray)),
+        Span::styled(format!("  {} payments", group.payment_count), Style::default().fg(Color::Cyan)),
+    ]));
+    if let Some(limit) = group.budget_limit {
+        text_lines.push(usage_bar(&indent, group.total_spend, limit));
+    }
+
+    // Member agents: their own sub-limit within the group
+    for agent in agents.iter().filter(|a| a.group.as_deref() == Some(group.id.as_str())) {
+        let pct_color = if agent.total_spend.as_f64() > agent.budget_limit.as_f64() * 0.8 { Color::Red } else { Color::Yellow };
+        let mut spans = vec![
+            Span::raw(format!("{}  • ", indent)),
+            Span::styled(agent.id.clone(), Style::default().fg(Color::White).add_modifier(Modifier::BOLD)),
+            Span::raw("  "),
+            Span::styled(format!("${}", agent.total_spend), Style::default().fg(pct_color)),
+            Span::styled(format!(" / ${} limit", agent.budget_limit), Style::default().fg(Color::DarkGray)),
+            Span::styled(format!("  {} payments", agent.payment_count), Style::default().fg(Color::Cyan)),
+        ];
+        if !agent.policy.is_empty() {
+            spans.push(Span::styled(format!("  {}", agent.policy.summary()), Style::default().fg(Color::Magenta)));
+        }
+        text_lines.push(Line::from(spans));
+    }
+    text_lines.push(Line::from(""));
+
+    for child in groups.iter().filter(|g| g.parent.as_deref() == Some(group.id.as_str())) {
+        push_group_lines(app, groups, agents, child, depth + 1, text_lines);
+    }
+}
+
+/// An agent paying from its own wallet.
+fn push_agent_lines(app: &App, agent: &AgentState, text_lines: &mut Vec<Line>) {
+    // Agent ID header
+    text_lines.push(Line::from(vec![
+        Span::styled(
+            format!(" {} ", agent.id),
+            Style::default().bg(Color::Blue).fg(Color::White).add_modifier(Modifier::BOLD)
+        ),
+    ]));
+    text_lines.push(Line::from(""));
+    
+    // Balances - prominent display, one line per asset (colored by USD value)
+    for (i, (symbol, balance)) in agent.balances.iter().enumerate() {
+        let usd = app.ledger.token(symbol).map_or(*balance, |t| t.usd_value(*balance));
+        text_lines.push(Line::from(vec![
+            Span::raw(if i == 0 { "  Balance: " } else { "           " }),
+            Span::styled(
+                format!("{}", balance),
+                Style::default().fg(balance_color(usd)).add_modifier(Modifier::BOLD)
+            ),
+            Span::styled(format!(" {}", symbol), Style::default().fg(Color::DarkGray)),
+        ]));
+    }
+    
+    // Spend info
+    text_lines.push(Line::from(vec![
+        Span::raw("  Spent:   "),
+        Span::styled(
+            format!("${}", agent.total_spend),
+            Style::default().fg(Color::Yellow)
+        ),
+        Span::styled(
+            format!(" / ${} limit", agent.budget_limit),
+            Style::default().fg(Color::DarkGray)
+        ),
+    ]));
+    
+    // Payment count
+    text_lines.push(Line::from(vec![
+        Span::raw("  Payments: "),
+        Span::styled(
+            format!("{}", agent.payment_count),
+            Style::default().fg(Color::Cyan)
+        ),
+    ]));
+
+    // Spending policy (if any)
+    if !agent.policy.is_empty() {
+        text_lines.push(Line::from(vec![
+            Span::raw("  Policy:  "),
+            Span::styled(agent.policy.summary(), Style::default().fg(Color::Magenta)),
+        ]));
+    }
+    
+    // Budget usage bar
+    text_lines.push(Line::from(""));
+    text_lines.push(usage_bar("", agent.total_spend, agent.budget_limit));
+    text_lines.push(Line::from(""));
+}
+
 fn render_traffic_panel(f: &mut Frame, app: &App, area: Rect) {
     let traces = app.traces.lock().unwrap();
     
@@ -249,11 +400,6 @@ fn render_traffic_panel(f: &mut Frame, app: &App, area: Rect) {
             Style::default().fg(Color::DarkGray)
         )));
     } else {
-        // Get the agent's current balance for display
-        let current_balance = app.ledger.get_state("agent-007")
-            .map(|a| a.balance_usdc)
-            .unwrap_or(0.0);
-        
         // Show most recent traces
         for trace in traces.iter().rev().take(visible_rows) {
             let status = trace.status_code.unwrap_or(0);
@@ -290,10 +436,11 @@ fn render_traffic_panel(f: &mut Frame, app: &App, area: Rect) {
                 Span::styled(format!("{:>4}ms", latency), latency_style),
             ]));
             
-            // Show balance change for payment events
-            if status == 200 || status == 402 {
-                for event in &trace.events {
-                    if matches!(event.category, xdr_trace::EventCategory::Payment) {
+            // Show balance change for payment events (or the policy / approval that held it)
+            if status == 200 || status == 202 || status == 402 {
+                let held = trace.events.iter().rev().find(|e| matches!(e.category, xdr_trace::EventCategory::Policy | xdr_trace::EventCategory::Approval));
+                for event in held.into_iter().chain(&trace.events) {
+                    if matches!(event.category, xdr_trace::EventCategory::Payment | xdr_trace::EventCategory::Policy | xdr_trace::EventCategory::Approval) {
                         // Truncate message if needed
                         let msg = if event.message.len() > 50 {
                             format!("{}...", &event.message[..47])
@@ -321,15 +468,68 @@ fn render_traffic_panel(f: &mut Frame, app: &App, area: Rect) {
     f.render_widget(panel, area);
 }
 
-fn render_footer(f: &mut Frame, area: Rect) {
-    let footer = Paragraph::new(Line::from(vec![
+fn render_approval_panel(f: &mut Frame, pending: &[xdr_proxy::approvals::Approval], selected: usize, area: Rect) {
+    let mut text_lines: Vec<Line> = Vec::new();
+
+    if pending.is_empty() {
+        text_lines.push(Line::from(Span::styled(
+            "  No payments awaiting approval",
+            Style::default().fg(Color::DarkGray)
+        )));
+    } else {
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        for (i, approval) in pending.iter().enumerate() {
+            let marker = if i == selected { ">" } else { " " };
+            let style = if i == selected {
+                Style::default().fg(Color::Black).bg(Color::Yellow)
+            } else {
+                Style::default().fg(Color::Yellow)
+            };
+            text_lines.push(Line::from(vec![
+                Span::styled(format!("{} {} ", marker, approval.id), style.add_modifier(Modifier::BOLD)),
+                Span::styled(format!(" {} ", approval.agent_id), Style::default().fg(Color::Cyan)),
+                Span::raw(format!("{} {} ", approval.amount, approval.currency)),
+                Span::styled(format!("-> {} ", approval.payee), Style::default().fg(Color::DarkGray)),
+                Span::styled(
+                    format!("{}s left", approval.expires_at.saturating_sub(now)),
+                    Style::default().fg(Color::Red)
+                ),
+            ]));
+        }
+    }
+
+    let panel = Paragraph::new(text_lines)
+        .block(Block::default()
+            .title(" Approvals ")
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Yellow)));
+
+    f.render_widget(panel, area);
+}
+
+fn render_footer(f: &mut Frame, area: Rect, approvals_pending: bool, error: Option<&str>) {
+    let mut spans = vec![
         Span::styled(" [Q] ", Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)),
         Span::raw("Quit  "),
         Span::styled(" [C] ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
         Span::raw("Toggle Chaos  "),
         Span::styled(" [F] ", Style::default().fg(Color::Green).add_modifier(Modifier::BOLD)),
         Span::raw("Fund +$50  "),
-    ]))
+    ];
+    if approvals_pending {
+        spans.extend([
+            Span::styled(" [Y] ", Style::default().fg(Color::Green).add_modifier(Modifier::BOLD)),
+            Span::raw("Approve  "),
+            Span::styled(" [N] ", Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)),
+            Span::raw("Deny  "),
+            Span::styled(" [↑↓] ", Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)),
+            Span::raw("Select  "),
+        ]);
+    }
+    if let Some(error) = error {
+        spans.push(Span::styled(format!(" ❌ {}", error), Style::default().fg(Color::Red)));
+    }
+    let footer = Paragraph::new(Line::from(spans))
     .alignment(Alignment::Center)
     .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(Color::DarkGray)));
     
@@ -1,7 +1,27 @@
 use clap::{Parser, Subcommand};
 use anyhow::Result;
 use serde_json::json;
+use xdr_chaos::response::ResponseFault;
+use xdr_chaos::rules::{ChaosRule, RequestType, Target};
+use xdr_chaos::scenario::{Scenario, ScenarioStatus};
 use xdr_chaos::ChaosConfig;
+use xdr_ledger::amount::{self, AmountFormat};
+use xdr_ledger::escrow::Hold;
+use xdr_ledger::groups::BudgetGroup;
+use xdr_ledger::journal::{JournalEntry, JournalPage, MAX_PAGE_SIZE};
+use xdr_ledger::policy::{AssetCap, PayeeCap, RateLimit, SpendLimit, SpendingPolicy};
+use xdr_ledger::storage::FileStorage;
+use xdr_ledger::tokens::{Token, TokenRegistry};
+use xdr_ledger::{Amount, Invoice, InvoiceStatus};
+use xdr_proxy::auth::{AgentCredential, AuthConfig, Credential};
+use xdr_proxy::control::{AuditEntry, ControlConfig, ControlToken, Role};
+use xdr_proxy::approvals::{Approval, ApprovalConfig, ApprovalMode, ApprovalQueue, ApprovalStatus, Decision};
+use xdr_proxy::mock_ai::MockAiConfig;
+use xdr_proxy::pricing::{PricingConfig, PricingTable};
+use xdr_proxy::ratelimit::{HeaderStyle, RateLimitConfig, RateLimitRule};
+use xdr_proxy::services::ServiceConfig;
+use xdr_proxy::x402::PaymentProtocol;
+use xdr_proxy::{ServerConfig, SessionMode};
 use xdr_trace::Trace;
 use std::sync::{Arc, Mutex};
 use std::collections::VecDeque;
@@ -18,6 +38,18 @@ struct Cli {
     #[arg(short, long, default_value_t = 4002, global = true)]
     port: u16,
 
+    /// Control plane URL for client commands (defaults to the port after the proxy's)
+    #[arg(long, env = "XDR_CONTROL_URL", global = true)]
+    control_url: Option<String>,
+
+    /// Operator token sent to the control plane. On `run`, registered as an admin token (as is the config file's)
+    #[arg(long, env = "XDR_ADMIN_TOKEN", global = true, hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// TOML file with `control_url` and `admin_token` (default: ~/.xdr/config.toml)
+    #[arg(long, env = "XDR_CONFIG", global = true)]
+    config: Option<String>,
+
     /// Enable verbose logging
     #[arg(short, long, global = true)]
     verbose: bool,
@@ -30,12 +62,178 @@ enum Commands {
         /// Select Network Environment
         #[arg(long, default_value = "cronos-testnet")]
         network: String,
+
+        /// Payment challenge dialect: "l402" or "x402"
+        #[arg(long, default_value = "l402")]
+        protocol: PaymentProtocol,
+
+        /// Path to a JSON pricing table (defaults to $0.01 on "paid" routes)
+        #[arg(long)]
+        pricing: Option<String>,
+
+        /// Persist the ledger (and snapshots) in this directory instead of memory
+        #[arg(long)]
+        data_dir: Option<String>,
+
+        /// Record every interaction to this cassette file
+        #[arg(long, conflicts_with = "replay")]
+        record: Option<String>,
+
+        /// Replay a recorded cassette offline, flagging divergences
+        #[arg(long)]
+        replay: Option<String>,
+
+        /// Answer RPC traffic from the built-in mock Cronos node instead of the real network
+        #[arg(long)]
+        mock_rpc: bool,
+
+        /// Answer OpenAI/Anthropic traffic (chat, messages, embeddings) from built-in mocks, fully offline
+        #[arg(long)]
+        mock_ai: bool,
+
+        /// Path to a JSON file of mock AI reply templates (implies --mock-ai)
+        #[arg(long)]
+        mock_replies: Option<String>,
+
+        /// Mine a block every N seconds instead of one per payment (0 = only on `xdr mine`)
+        #[arg(long)]
+        block_time: Option<u64>,
+
+        /// How amounts are written in JSON: "number" (0.01) or "string" ("0.01")
+        #[arg(long, default_value = "number")]
+        amount_format: AmountFormat,
+
+        /// Path to a JSON token registry (network -> tokens), merged over the built-in USDC/USDT/CRO
+        #[arg(long)]
+        tokens: Option<String>,
+
+        /// Path to a JSON approval policy (threshold, rules, mode, timeout)
+        #[arg(long)]
+        approvals: Option<String>,
+
+        /// Path to a JSON file of simulated upstream quotas (token buckets per agent and host)
+        #[arg(long)]
+        rate_limits: Option<String>,
+
+        /// Path to a JSON list of agent services (agent_id, url) other agents can pay for
+        #[arg(long)]
+        services: Option<String>,
+
+        /// Seconds an L402 invoice stays payable (0 = never expires)
+        #[arg(long, default_value_t = xdr_ledger::DEFAULT_INVOICE_TTL_SECS)]
+        invoice_ttl: u64,
+
+        /// Path to a JSON agent registry (per-agent bearer key, HMAC secret or Ethereum address)
+        #[arg(long)]
+        agents: Option<String>,
+
+        /// Reject agents without a registered credential with 401
+        #[arg(long)]
+        strict_auth: bool,
+
+        /// Address of the /_xdr control plane [default: 127.0.0.1:<port + 1>]
+        #[arg(long)]
+        control_bind: Option<std::net::SocketAddr>,
+
+        /// Serve the control plane without operator tokens: anyone who can reach it gets full admin access
+        #[arg(long)]
+        insecure_open_control_plane: bool,
+
+        /// Path to a JSON list of operator tokens (name, token, role)
+        #[arg(long)]
+        control_tokens: Option<String>,
+
+        /// Append every control-plane mutation to this JSON-lines file
+        #[arg(long)]
+        audit_log: Option<String>,
     },
     /// Manage Chaos engineering settings
     Chaos {
         #[command(subcommand)]
         action: ChaosAction,
     },
+    /// Save, load or list ledger snapshots
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+    /// Mine blocks on the simulated chain (confirms pending payments)
+    Mine {
+        /// Number of blocks to mine
+        #[arg(short, long, default_value_t = 1)]
+        blocks: u64,
+    },
+    /// Inspect or replace the per-route pricing table
+    Pricing {
+        #[command(subcommand)]
+        action: PricingAction,
+    },
+    /// Manage agent credentials: who may spend which agent's wallet
+    Auth {
+        #[command(subcommand)]
+        action: AuthAction,
+    },
+    /// Simulate upstream 429s: token-bucket quotas per agent and host
+    RateLimits {
+        #[command(subcommand)]
+        action: RateLimitAction,
+    },
+    /// Show or set an agent's spending policy (rolling caps, rate limits, payee/asset caps)
+    Policy {
+        #[command(subcommand)]
+        action: PolicyAction,
+    },
+    /// Review payments waiting for approval, or configure when payments need one
+    Approvals {
+        #[command(subcommand)]
+        action: ApprovalsAction,
+    },
+    /// List or register the assets agents can hold and pay with
+    Tokens {
+        #[command(subcommand)]
+        action: TokensAction,
+    },
+    /// List invoices or cancel an open one
+    Invoices {
+        #[command(subcommand)]
+        action: InvoicesAction,
+    },
+    /// Manage budget groups: shared wallets and spend caps for swarms of agents
+    Groups {
+        #[command(subcommand)]
+        action: GroupsAction,
+    },
+    /// Agent-to-agent marketplace: services agents sell each other at <agent>.agent
+    Services {
+        #[command(subcommand)]
+        action: ServicesAction,
+    },
+    /// Show the audit log of control-plane changes (who changed what)
+    Audit {
+        /// Output Raw JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List escrow holds of `upto` payments (what was reserved, captured and refunded)
+    Holds {
+        /// Only this agent's holds
+        #[arg(short, long)]
+        agent: Option<String>,
+    },
+    /// Print an agent's statement: every balance change as a line item
+    Statement {
+        #[arg(short, long)]
+        agent: String,
+        /// "csv" or "json"
+        #[arg(long, default_value = "csv")]
+        format: StatementFormat,
+        /// Start of the period: unix seconds, RFC 3339 or YYYY-MM-DD (inclusive)
+        #[arg(long)]
+        from: Option<String>,
+        /// End of the period, same formats (exclusive)
+        #[arg(long)]
+        to: Option<String>,
+    },
     /// Show current status of the runtime
     Status{
         /// The Agent ID to query
@@ -46,7 +244,10 @@ enum Commands {
         #[arg(short, long)]
         agent: String,
         #[arg(long)]
-        set: f64,
+        set: Amount,
+        /// Asset whose balance is set
+        #[arg(long, default_value = "USDC")]
+        currency: String,
     },
     Logs {
         /// Filter by Agent ID
@@ -85,120 +286,1434 @@ enum ChaosAction {
         
         #[arg(long, default_value_t = 0)]
         max_latency: u64,
+
+        /// Chance of a chain reorg after each mined block (0.0 - 1.0)
+        #[arg(long, default_value_t = 0.0)]
+        reorg_rate: f64,
+
+        /// Deepest reorg, in blocks
+        #[arg(long, default_value_t = 2)]
+        max_reorg_depth: u64,
+
+        /// Only hit this agent (glob). Any target flag turns the rates above
+        /// (except reorgs) into a rule for the matching requests
+        #[arg(long)]
+        agent: Option<String>,
+
+        /// Only hit this upstream host (glob; a plain domain includes subdomains)
+        #[arg(long)]
+        host: Option<String>,
+
+        /// Only hit this request path (glob)
+        #[arg(long)]
+        path: Option<String>,
+
+        /// Only hit this HTTP method
+        #[arg(long)]
+        method: Option<String>,
+
+        /// Only hit this request type (ai_inference, rpc, unknown)
+        #[arg(long)]
+        request_type: Option<RequestType>,
+
+        /// Add the targeted rules and response faults in a YAML, TOML or JSON
+        /// file (`rules: [...]`, `response_faults: [...]`)
+        #[arg(long)]
+        rules: Option<String>,
+    },
+    /// Print the active chaos configuration and rules
+    Show,
+    /// Play a scripted scenario (YAML, TOML or JSON) of timed chaos phases
+    Load {
+        file: String,
+    },
+    /// Show the running scenario's current phase
+    Status,
+}
+
+#[derive(Subcommand)]
+enum PricingAction {
+    /// Print the active pricing table
+    Show,
+    /// Replace the pricing table with the rules in a JSON file
+    Load {
+        file: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum InvoicesAction {
+    /// List invoices with their status (open, paid, expired, cancelled)
+    List {
+        /// Only this agent's invoices
+        #[arg(short, long)]
+        agent: Option<String>,
+    },
+    /// Cancel an open invoice so it can no longer be paid
+    Cancel {
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum GroupsAction {
+    /// Print the group trees with wallets, spend, caps and members
+    Show,
+    /// Create a group, or change an existing group's parent or cap
+    Create {
+        id: String,
+        /// Nest under this group as one of its allocations (a new group without one is top-level and holds the wallet)
+        #[arg(long, conflicts_with = "top_level")]
+        parent: Option<String>,
+        /// Move an existing group out of its parent, to the top of its own tree
+        #[arg(long)]
+        top_level: bool,
+        /// USD cap on everything the group's agents and subgroups spend
+        #[arg(long, conflicts_with = "no_cap")]
+        budget: Option<Amount>,
+        /// Lift an existing group's cap
+        #[arg(long)]
+        no_cap: bool,
     },
+    /// Delete a group with no members and no subgroups
+    Delete {
+        id: String,
+    },
+    /// Set a balance of a top-level group's shared wallet
+    Fund {
+        id: String,
+        #[arg(long)]
+        set: Amount,
+        #[arg(long, default_value = "USDC")]
+        currency: String,
+    },
+    /// Make an agent pay through a group
+    Assign {
+        #[arg(short, long)]
+        agent: String,
+        #[arg(short, long)]
+        group: String,
+    },
+    /// Take an agent out of its group (back to its own wallet)
+    Unassign {
+        #[arg(short, long)]
+        agent: String,
+        #[arg(short, long)]
+        group: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServicesAction {
+    /// List registered services with their hosts and payee wallets
+    List,
+    /// Register (or move) the service an agent sells; payments for it go to the agent's wallet
+    Register {
+        #[arg(short, long)]
+        agent: String,
+        /// Where the agent serves requests, e.g. http://127.0.0.1:9001
+        #[arg(long)]
+        url: String,
+        /// One line shown in the listing
+        #[arg(long)]
+        description: Option<String>,
+    },
+    /// Stop routing to an agent's service
+    Remove {
+        #[arg(short, long)]
+        agent: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum AuthAction {
+    /// List registered agents and their credential kind (secrets are not shown)
+    Show,
+    /// Register or replace an agent's credential
+    #[command(group(clap::ArgGroup::new("credential").required(true)))]
+    Add {
+        #[arg(short, long)]
+        agent: String,
+        /// Static key the agent sends as X-Agent-Key
+        #[arg(long, group = "credential")]
+        key: Option<String>,
+        /// Shared secret the agent signs requests with (HMAC-SHA256)
+        #[arg(long, group = "credential")]
+        hmac_secret: Option<String>,
+        /// Wallet address the agent signs nonces with
+        #[arg(long, group = "credential")]
+        address: Option<String>,
+    },
+    /// Remove an agent's credential
+    Remove {
+        #[arg(short, long)]
+        agent: String,
+    },
+    /// Turn strict mode on or off (reject agents without a credential)
+    Strict {
+        #[arg(action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: bool,
+    },
+    /// Replace the registry with the one in a JSON file
+    Load {
+        file: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum RateLimitAction {
+    /// Print the active quotas
+    Show,
+    /// Add a quota (rules are matched in order, first match wins)
+    Add {
+        /// Upstream host glob, e.g. api.openai.com (any host when omitted)
+        #[arg(long)]
+        host: Option<String>,
+        /// Agent id glob (every agent when omitted)
+        #[arg(long)]
+        agent: Option<String>,
+        /// Burst size: requests allowed back to back
+        #[arg(long)]
+        capacity: u32,
+        /// Requests added back to the bucket per second
+        #[arg(long)]
+        refill: f64,
+        /// Headers to imitate: "openai" or "anthropic" (picked from the host when omitted)
+        #[arg(long)]
+        style: Option<HeaderStyle>,
+    },
+    /// Replace all quotas with the rules in a JSON file
+    Load {
+        file: String,
+    },
+    /// Remove every quota
+    Clear,
+}
+
+#[derive(Subcommand)]
+enum PolicyAction {
+    /// Print an agent's policy
+    Show {
+        #[arg(short, long)]
+        agent: String,
+    },
+    /// Replace an agent's policy with the given rules
+    Set {
+        #[arg(short, long)]
+        agent: String,
+        /// Max USD spend in any rolling minute
+        #[arg(long)]
+        per_minute: Option<Amount>,
+        /// Max USD spend in any rolling hour
+        #[arg(long)]
+        per_hour: Option<Amount>,
+        /// Max USD spend in any rolling day
+        #[arg(long)]
+        per_day: Option<Amount>,
+        /// Max number of payments in any rolling minute
+        #[arg(long)]
+        payments_per_minute: Option<u32>,
+        /// Max number of payments in any rolling hour
+        #[arg(long)]
+        payments_per_hour: Option<u32>,
+        /// Lifetime USD cap for one payee, as HOST=AMOUNT (repeatable)
+        #[arg(long, value_parser = parse_cap)]
+        payee_cap: Vec<(String, Amount)>,
+        /// Lifetime cap on one asset, as SYMBOL=AMOUNT (repeatable)
+        #[arg(long, value_parser = parse_cap)]
+        asset_cap: Vec<(String, Amount)>,
+        /// Read the full policy (with windowed payee/asset caps) from a JSON file instead
+        #[arg(long, conflicts_with_all = ["per_minute", "per_hour", "per_day", "payments_per_minute", "payments_per_hour", "payee_cap", "asset_cap"])]
+        file: Option<String>,
+    },
+    /// Remove all policy rules for an agent
+    Clear {
+        #[arg(short, long)]
+        agent: String,
+    },
+}
+
+/// "api.openai.com=5" -> ("api.openai.com", 5.00)
+fn parse_cap(s: &str) -> Result<(String, Amount), String> {
+    let (key, amount) = s.rsplit_once('=').ok_or_else(|| format!("expected KEY=AMOUNT, got '{}'", s))?;
+    Ok((key.to_string(), amount.parse()?))
+}
+
+#[derive(Subcommand)]
+enum ApprovalsAction {
+    /// List payments awaiting approval
+    List {
+        /// Include decided items (newest first)
+        #[arg(long)]
+        all: bool,
+    },
+    /// Let a held payment settle
+    Approve {
+        id: String,
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Reject a held payment
+    Deny {
+        id: String,
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Show the approval policy
+    Show,
+    /// Replace the approval policy
+    Set {
+        /// Payments worth more than this (USD) need approval
+        #[arg(long)]
+        threshold: Option<Amount>,
+        /// "hold" keeps the request open; "defer" answers 202 with Retry-After
+        #[arg(long, default_value = "hold")]
+        mode: ApprovalMode,
+        /// Seconds before an undecided payment falls back to --on-timeout
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+        /// Decision applied on timeout: "approve" or "deny"
+        #[arg(long, default_value = "deny")]
+        on_timeout: Decision,
+        /// Read the full policy (with agent/host/path rules) from a JSON file instead
+        #[arg(long, conflicts_with_all = ["threshold", "mode", "timeout", "on_timeout"])]
+        file: Option<String>,
+    },
+    /// Turn approvals off (every payment settles at once)
+    Disable,
+}
+
+#[derive(Subcommand)]
+enum TokensAction {
+    /// Print the active network's tokens
+    List,
+    /// Register a custom ERC-20 (or replace a token with the same symbol)
+    Add {
+        #[arg(long)]
+        symbol: String,
+        /// Contract name (the EIP-712 domain name); defaults to the symbol
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long, default_value_t = 18)]
+        decimals: u32,
+        /// Contract address on the simulated network
+        #[arg(long)]
+        address: String,
+        /// EIP-712 domain version; set it if the token supports EIP-3009 (x402 payments)
+        #[arg(long)]
+        eip712_version: Option<String>,
+        /// Mock USD price of one token (for the budget cap)
+        #[arg(long, default_value = "1")]
+        usd_rate: Amount,
+        /// Balance new agents start with
+        #[arg(long, default_value = "0")]
+        faucet: Amount,
+    },
+}
+
+#[derive(Subcommand)]
+enum SnapshotAction {
+    /// Save the current ledger state under a name
+    Save {
+        name: String,
+    },
+    /// Replace the ledger state with a saved snapshot
+    Load {
+        name: String,
+    },
+    /// List saved snapshots
+    List,
+}
+
+#[derive(Clone, Copy)]
+enum StatementFormat {
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for StatementFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            other => Err(format!("Unknown format '{}' (expected csv or json)", other)),
+        }
+    }
+}
+
+/// Quotes a CSV field when it needs it.
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+fn print_statement_csv(entries: &[JournalEntry]) {
+    println!("seq,time,agent,kind,currency,debit,credit,balance_after,usd_value,tx_hash,invoice_id,payee,memo,wallet");
+    for e in entries {
+        let time = chrono::DateTime::from_timestamp(e.timestamp as i64, 0)
+            .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+            .unwrap_or_default();
+        let fields = [
+            e.seq.to_string(),
+            time,
+            e.agent_id.clone(),
+            e.kind.as_str().to_string(),
+            e.currency.clone(),
+            e.debit.to_string(),
+            e.credit.to_string(),
+            e.balance_after.to_string(),
+            e.usd_value.to_string(),
+            e.tx_hash.clone().unwrap_or_default(),
+            e.invoice_id.clone().unwrap_or_default(),
+            e.payee.clone().unwrap_or_default(),
+            e.memo.clone().unwrap_or_default(),
+            e.wallet.clone().unwrap_or_default(),
+        ];
+        println!("{}", fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(","));
+    }
+}
+
+fn read_pricing_file(path: &str) -> Result<PricingConfig> {
+    let raw = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&raw)?)
+}
+
+/// An invoice as the control plane lists it, with its status at listing time.
+#[derive(serde::Deserialize)]
+struct InvoiceRow {
+    #[serde(flatten)]
+    invoice: Invoice,
+    status: InvoiceStatus,
+}
+
+/// A budget group as the control plane lists it, with its direct members.
+#[derive(serde::Deserialize)]
+struct GroupRow {
+    #[serde(flatten)]
+    group: BudgetGroup,
+    members: Vec<String>,
+}
+
+/// Prints `id` and everything below it, indented by depth.
+fn print_group_tree(rows: &[GroupRow], id: &str, depth: usize) {
+    let Some(row) = rows.iter().find(|r| r.group.id == id) else { return };
+    let group = &row.group;
+    let indent = "   ".repeat(depth);
+    let cap = group.budget_limit.map(|limit| format!(" of ${}", limit)).unwrap_or_default();
+    let wallet = match group.is_root() {
+        true if group.balances.is_empty() => " | wallet: empty".to_string(),
+        true => {
+            let balances: Vec<String> = group.balances.iter().map(|(symbol, amount)| format!("{} {}", amount, symbol)).collect();
+            format!(" | wallet: {}", balances.join(", "))
+        }
+        false => String::new(),
+    };
+    let icon = if depth == 0 { "👥" } else { "└" };
+    println!("{}{} {} | spent ${}{} ({} payments){}", indent, icon, group.id, group.total_spend, cap, group.payment_count, wallet);
+    if !row.members.is_empty() {
+        println!("{}   agents: {}", indent, row.members.join(", "));
+    }
+    for child in rows.iter().filter(|r| r.group.parent.as_deref() == Some(id)) {
+        print_group_tree(rows, &child.group.id, depth + 1);
+    }
+}
+
+/// Targeted chaos rules and response faults, as written in a `--rules` file.
+#[derive(serde::Deserialize)]
+struct ChaosRulesFile {
+    #[serde(default)]
+    rules: Vec<ChaosRule>,
+    #[serde(default)]
+    response_faults: Vec<ResponseFault>,
+}
+
+/// Reads a chaos scenario or rules file, picking the format from the file extension.
+fn read_chaos_file<T: serde::de::DeserializeOwned>(path: &str) -> Result<T> {
+    let raw = std::fs::read_to_string(path)?;
+    let extension = std::path::Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or_default();
+    Ok(match extension.to_ascii_lowercase().as_str() {
+        "yaml" | "yml" => serde_yaml::from_str(&raw)?,
+        "toml" => toml::from_str(&raw)?,
+        _ => serde_json::from_str(&raw)?,
+    })
+}
+
+fn read_approvals_file(path: &str) -> Result<ApprovalConfig> {
+    let raw = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&raw)?)
+}
+
+fn read_mock_replies_file(path: &str) -> Result<MockAiConfig> {
+    let raw = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&raw)?)
+}
+
+fn read_rate_limits_file(path: &str) -> Result<RateLimitConfig> {
+    let raw = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&raw)?)
+}
+
+fn read_services_file(path: &str) -> Result<ServiceConfig> {
+    let raw = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&raw)?)
+}
+
+fn read_auth_file(path: &str) -> Result<AuthConfig> {
+    let raw = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&raw)?)
+}
+
+fn read_control_tokens_file(path: &str) -> Result<Vec<ControlToken>> {
+    let raw = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&raw)?)
+}
+
+/// Client-side settings read from the config file.
+#[derive(serde::Deserialize, Default)]
+struct CliConfig {
+    control_url: Option<String>,
+    admin_token: Option<String>,
+}
+
+impl CliConfig {
+    /// The config file, with flags and env vars winning over it.
+    fn load(cli: &Cli) -> Result<Self> {
+        let path = cli.config.clone().or_else(|| {
+            std::env::var("HOME").ok()
+                .map(|home| format!("{}/.xdr/config.toml", home))
+                .filter(|p| std::path::Path::new(p).exists())
+        });
+        let file: CliConfig = match path {
+            Some(path) => toml::from_str(&std::fs::read_to_string(&path)?)?,
+            None => CliConfig::default(),
+        };
+        Ok(Self {
+            control_url: cli.control_url.clone().or(file.control_url),
+            admin_token: cli.admin_token.clone().or(file.admin_token),
+        })
+    }
+}
+
+/// The control plane listens on the port after the proxy's unless told otherwise.
+fn control_port(proxy_port: u16) -> Result<u16> {
+    proxy_port.checked_add(1)
+        .ok_or_else(|| anyhow::anyhow!("No port after {} for the control plane; pass its address explicitly", proxy_port))
+}
+
+/// HTTP client for the control plane, carrying the operator token.
+struct ControlClient {
+    /// e.g. http://localhost:4003
+    base: String,
+    client: reqwest::Client,
+}
+
+impl ControlClient {
+    fn new(cli: &Cli, settings: CliConfig) -> Result<Self> {
+        let base = match settings.control_url {
+            Some(url) => url,
+            None => format!("http://localhost:{}", control_port(cli.port)?),
+        };
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(token) = settings.admin_token {
+            headers.insert(reqwest::header::AUTHORIZATION, format!("Bearer {}", token).parse()?);
+        }
+        Ok(Self {
+            base: base.trim_end_matches('/').to_string(),
+            client: reqwest::Client::builder().default_headers(headers).build()?,
+        })
+    }
+}
+
+/// What to print for a control request the proxy answered with an error.
+async fn refusal(resp: reqwest::Response) -> String {
+    let status = resp.status();
+    match status {
+        reqwest::StatusCode::UNAUTHORIZED => {
+            format!("Unauthorized [{}]: set XDR_ADMIN_TOKEN (or admin_token in the config file) to an operator token", status)
+        }
+        reqwest::StatusCode::FORBIDDEN => format!("Forbidden [{}]: the operator token's role doesn't allow this", status),
+        _ => match resp.text().await {
+            Ok(body) if !body.is_empty() => format!("Error [{}]: {}", status, body),
+            _ => format!("Error [{}]", status),
+        },
+    }
+}
+
+/// Pretty JSON for the terminal, with amounts as numbers like the API's default.
+fn pretty_json<T: serde::Serialize>(value: &T) -> serde_json::Result<String> {
+    amount::with_format(AmountFormat::Number, || serde_json::to_string_pretty(value))
+}
+
+fn read_tokens_file(path: &str) -> Result<TokenRegistry> {
+    let raw = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&raw)?)
+}
+
+/// Runs the proxy and the TUI until the TUI quits.
+async fn run_runtime(cli: &Cli, settings: CliConfig) -> Result<()> {
+    let Commands::Run{network, protocol, pricing, data_dir, record, replay, mock_rpc, mock_ai, mock_replies, block_time, amount_format, tokens, approvals: approvals_file, rate_limits, services, invoice_ttl, agents, strict_auth, control_bind, insecure_open_control_plane, control_tokens, audit_log} = &cli.command else {
+        unreachable!("run_runtime is only called for `run`");
+    };
+    // NOTE: No tracing subscriber when running TUI - it corrupts the display
+    // Tracing is only used for non-TUI commands
+    
+    xdr_proxy::wire::set_amount_format(*amount_format);
+
+    // 1. Create Shared State (owned by main, shared with proxy and TUI)
+    let ledger = match data_dir {
+        Some(dir) => xdr_ledger::Ledger::with_storage(Arc::new(FileStorage::open(dir)?))?,
+        None => xdr_ledger::Ledger::new(),
+    };
+    let chaos = xdr_chaos::ChaosEngine::new();
+    let pricing = match pricing {
+        Some(path) => PricingTable::from_config(read_pricing_file(path)?)
+            .map_err(anyhow::Error::msg)?,
+        None => PricingTable::new(),
+    };
+    let approvals = ApprovalQueue::new();
+    if let Some(path) = approvals_file {
+        approvals.set_config(read_approvals_file(path)?).map_err(anyhow::Error::msg)?;
+    }
+    let mut registry = TokenRegistry::default();
+    if let Some(path) = tokens {
+        registry.merge(read_tokens_file(path)?).map_err(anyhow::Error::msg)?;
+    }
+    let traces: Arc<Mutex<VecDeque<Trace>>> = Arc::new(Mutex::new(VecDeque::with_capacity(1000)));
+
+    // 2. Clone for Proxy (runs in background task)
+    let proxy_ledger = ledger.clone();
+    let proxy_chaos = chaos.clone();
+    let proxy_pricing = pricing.clone();
+    let proxy_approvals = approvals.clone();
+    let proxy_traces = traces.clone();
+    let proxy_config = ServerConfig {
+        port: cli.port,
+        network: network.clone(),
+        protocol: *protocol,
+        session: match (record, replay) {
+            (Some(path), _) => Some(SessionMode::Record(path.clone())),
+            (_, Some(path)) => Some(SessionMode::Replay(path.clone())),
+            _ => None,
+        },
+        mock_rpc: *mock_rpc,
+        mock_ai: match mock_replies {
+            Some(path) => Some(read_mock_replies_file(path)?),
+            None => mock_ai.then(MockAiConfig::default),
+        },
+        block_time: block_time.map(std::time::Duration::from_secs),
+        tokens: registry,
+        rate_limits: match rate_limits {
+            Some(path) => read_rate_limits_file(path)?,
+            None => RateLimitConfig::default(),
+        },
+        services: match services {
+            Some(path) => read_services_file(path)?,
+            None => ServiceConfig::default(),
+        },
+        invoice_ttl: (*invoice_ttl > 0).then_some(*invoice_ttl),
+        auth: {
+            let mut auth = match agents {
+                Some(path) => read_auth_file(path)?,
+                None => AuthConfig::default(),
+            };
+            auth.strict |= *strict_auth;
+            auth
+        },
+        control: ControlConfig {
+            bind: match control_bind {
+                Some(addr) => *addr,
+                None => std::net::SocketAddr::from(([127, 0, 0, 1], control_port(cli.port)?)),
+            },
+            tokens: {
+                let mut tokens = match control_tokens {
+                    Some(path) => read_control_tokens_file(path)?,
+                    None => Vec::new(),
+                };
+                // A token the tokens file already names keeps its role there
+                if let Some(token) = settings.admin_token.filter(|t| !tokens.iter().any(|named| &named.token == t)) {
+                    tokens.push(ControlToken { name: "admin".to_string(), token, role: Role::Admin });
+                }
+                tokens
+            },
+            insecure_open: *insecure_open_control_plane,
+            audit_log: audit_log.as_ref().map(Into::into),
+        },
+    };
+    // Refused before the TUI takes over the terminal
+    proxy_config.control.validate().map_err(anyhow::Error::msg)?;
+
+    // 3. Spawn Proxy in Background Task
+    tokio::spawn(async move {
+        if let Err(e) = xdr_proxy::run_server(
+            proxy_config,
+            proxy_ledger, 
+            proxy_chaos, 
+            proxy_pricing,
+            proxy_approvals,
+            proxy_traces,
+        ).await {
+            eprintln!("Proxy crashed: {}", e);
+        }
+    });
+
+    // 4. Run TUI in Foreground (Main Thread)
+    // Brief delay to let proxy bind to port
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    
+    let tui_app = xdr_tui::App {
+        ledger,
+        chaos,
+        approvals,
+        traces,
+    };
+
+    if let Err(e) = xdr_tui::run_tui(tui_app).await {
+         eprintln!("TUI Error: {}", e);
+    }
+    
+    // When TUI quits (user hits 'q'), the program exits
+    println!("Shutting down XDR...");
+    Ok(())
 }
 
 // 2. Main Entry Point
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    let settings = CliConfig::load(&cli)?;
+    // `run` serves the control plane; only the client commands call it
+    if let Commands::Run { .. } = cli.command {
+        return run_runtime(&cli, settings).await;
+    }
+    let control = ControlClient::new(&cli, settings)?;
 
     // 4. Command Router
     match &cli.command {
-        Commands::Run{network} => {
-            // NOTE: No tracing subscriber when running TUI - it corrupts the display
-            // Tracing is only used for non-TUI commands
-            
-            // 1. Create Shared State (owned by main, shared with proxy and TUI)
-            let ledger = xdr_ledger::Ledger::new();
-            let chaos = xdr_chaos::ChaosEngine::new();
-            let traces: Arc<Mutex<VecDeque<Trace>>> = Arc::new(Mutex::new(VecDeque::with_capacity(1000)));
-
-            // 2. Clone for Proxy (runs in background task)
-            let proxy_ledger = ledger.clone();
-            let proxy_chaos = chaos.clone();
-            let proxy_traces = traces.clone();
-            let proxy_network = network.clone();
-            let proxy_port = cli.port;
-
-            // 3. Spawn Proxy in Background Task
-            tokio::spawn(async move {
-                if let Err(e) = xdr_proxy::run_server(
-                    proxy_port, 
-                    proxy_network, 
-                    proxy_ledger, 
-                    proxy_chaos, 
-                    proxy_traces
-                ).await {
-                    eprintln!("Proxy crashed: {}", e);
-                }
-            });
-
-            // 4. Run TUI in Foreground (Main Thread)
-            // Brief delay to let proxy bind to port
-            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-            
-            let tui_app = xdr_tui::App {
-                ledger,
-                chaos,
-                traces,
-            };
-
-            if let Err(e) = xdr_tui::run_tui(tui_app).await {
-                 eprintln!("TUI Error: {}", e);
-            }
-            
-            // When TUI quits (user hits 'q'), the program exits
-            println!("Shutting down XDR...");
-        }
+        Commands::Run { .. } => unreachable!("`run` is handled before the client commands"),
         Commands::Status { agent } => {
-            let url = format!("http://localhost:{}/_xdr/status/{}", cli.port, agent);
-            match reqwest::get(&url).await {
-                Ok(resp) => {
-                    if resp.status().is_success() {
-                        let body = resp.text().await.unwrap_or_default();
-                        println!("{}", body);
-                    } else {
-                        // NEW: Print the actual status code (e.g., 404 or 400)
-                        eprintln!("❌ Error [{}]: Agent '{}' not found.", resp.status(), agent);
-                    }
+            let url = format!("{}/_xdr/status/{}", control.base, agent);
+            match control.client.get(&url).send().await {
+                Ok(resp) if resp.status().is_success() => match resp.text().await {
+                    Ok(body) => println!("{}", body),
+                    Err(e) => eprintln!("❌ Could not read the status: {}", e),
+                },
+                Ok(resp) if resp.status() == reqwest::StatusCode::NOT_FOUND => {
+                    eprintln!("❌ Error [{}]: Agent '{}' not found.", resp.status(), agent);
                 }
+                Ok(resp) => eprintln!("❌ {}", refusal(resp).await),
                 Err(e) => eprintln!("❌ Connection failed: {}", e),
             }
         }
-        Commands::Budget { agent, set } => {
-            let client = reqwest::Client::new();
-            let url = format!("http://localhost:{}/_xdr/budget/{}", cli.port, agent);
+        Commands::Budget { agent, set, currency } => {
+            let client = control.client.clone();
+            let url = format!("{}/_xdr/budget/{}", control.base, agent);
             
             let res = client.post(&url)
-                .json(&json!({ "amount": set }))
+                .json(&json!({ "amount": set, "currency": currency }))
                 .send()
                 .await;
 
             match res {
-                Ok(r) if r.status().is_success() => println!("✅ Budget updated for {}", agent),
-                Ok(r) => eprintln!("❌ Failed: {}", r.status()),
+                Ok(r) if r.status().is_success() => println!("✅ {} balance updated for {}", currency, agent),
+                Ok(r) => eprintln!("❌ Failed [{}]: {}", r.status(), r.text().await.unwrap_or_default()),
                 Err(e) => eprintln!("❌ Connection failed: {}", e),
             }
         }
         Commands::Chaos { action } => {
+            let client = control.client.clone();
+            let scenario_url = format!("{}/_xdr/chaos/scenario", control.base);
+
             let config = match action {
+                ChaosAction::Load { file } => {
+                    let scenario: Scenario = read_chaos_file(file)?;
+                    match client.post(&scenario_url).json(&scenario).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            println!("🎬 Scenario '{}' loaded:", scenario.name.as_deref().unwrap_or(file));
+                            for (i, phase) in scenario.phases.iter().enumerate() {
+                                let length = match (phase.duration_secs, phase.requests) {
+                                    (Some(secs), Some(reqs)) => format!("{}s or {} requests", secs, reqs),
+                                    (Some(secs), None) => format!("{}s", secs),
+                                    (None, Some(reqs)) => format!("{} requests", reqs),
+                                    (None, None) => "until stopped".to_string(),
+                                };
+                                let faults: Vec<String> = phase.faults.iter().map(|f| f.describe()).collect();
+                                let faults = if faults.is_empty() { "clean".to_string() } else { faults.join(", ") };
+                                println!("   {}. {} [{}]: {}", i + 1, phase.name, length, faults);
+                            }
+                        }
+                        Ok(r) => eprintln!("❌ Rejected [{}]: {}", r.status(), r.text().await.unwrap_or_default()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                    return Ok(());
+                }
+                ChaosAction::Status => {
+                    match client.get(&scenario_url).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            let body: serde_json::Value = r.json().await?;
+                            let status: ScenarioStatus = serde_json::from_value(body["status"].clone())?;
+                            println!("🎬 {} ({}s in)", status.label(), status.elapsed_secs);
+                        }
+                        Ok(r) if r.status() == reqwest::StatusCode::NOT_FOUND => println!("No chaos scenario loaded."),
+                        Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                    return Ok(());
+                }
+                ChaosAction::Show => {
+                    match client.get(format!("{}/_xdr/chaos", control.base)).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            let config: ChaosConfig = r.json().await?;
+                            if !config.enabled {
+                                println!("🌪️ Chaos is off.");
+                                return Ok(());
+                            }
+                            println!("🌪️ Chaos on (seed {})", config.seed);
+                            println!("   all requests: {}% failures, {}% payment failures, {}% rugs, {}-{}ms latency, {}% reorgs",
+                                config.global_failure_rate * 100.0, config.payment_failure_rate * 100.0, config.rug_rate * 100.0,
+                                config.min_latency_ms, config.max_latency_ms, config.reorg_rate * 100.0);
+                            for rule in &config.rules {
+                                println!("   {}{}: {}% failures, {}% payment failures, {}% rugs, {}-{}ms latency",
+                                    rule.label(), match rule.name { Some(_) => rule.target.describe(), None => String::new() },
+                                    rule.failure_rate * 100.0, rule.payment_failure_rate * 100.0, rule.rug_rate * 100.0,
+                                    rule.min_latency_ms, rule.max_latency_ms);
+                            }
+                            for fault in &config.response_faults {
+                                println!("   response: {}% {}", fault.rate * 100.0, fault.describe());
+                            }
+                        }
+                        Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                    return Ok(());
+                }
                 ChaosAction::Disable => ChaosConfig::default(),
-                ChaosAction::Enable { seed, failure_rate, payment_failure, rug_rate, min_latency, max_latency } => ChaosConfig {
-                    enabled: true,
-                    seed: *seed,
-                    global_failure_rate: *failure_rate,
-                    payment_failure_rate: *payment_failure,
-                    rug_rate: *rug_rate,
-                    min_latency_ms: *min_latency,
-                    max_latency_ms: *max_latency,
-                },
+                ChaosAction::Enable { seed, failure_rate, payment_failure, rug_rate, min_latency, max_latency, reorg_rate, max_reorg_depth, agent, host, path, method, request_type, rules } => {
+                    let mut config = ChaosConfig {
+                        enabled: true,
+                        seed: *seed,
+                        global_failure_rate: *failure_rate,
+                        payment_failure_rate: *payment_failure,
+                        rug_rate: *rug_rate,
+                        min_latency_ms: *min_latency,
+                        max_latency_ms: *max_latency,
+                        reorg_rate: *reorg_rate,
+                        max_reorg_depth: *max_reorg_depth,
+                        rules: Vec::new(),
+                        response_faults: Vec::new(),
+                    };
+                    let target = Target {
+                        agent: agent.clone(),
+                        host: host.clone(),
+                        path: path.clone(),
+                        method: method.clone(),
+                        request_type: *request_type,
+                    };
+                    if !target.is_any() {
+                        config.rules.push(ChaosRule {
+                            name: None,
+                            target,
+                            failure_rate: std::mem::take(&mut config.global_failure_rate),
+                            status: Vec::new(),
+                            payment_failure_rate: std::mem::take(&mut config.payment_failure_rate),
+                            rug_rate: std::mem::take(&mut config.rug_rate),
+                            min_latency_ms: std::mem::take(&mut config.min_latency_ms),
+                            max_latency_ms: std::mem::take(&mut config.max_latency_ms),
+                        });
+                    }
+                    if let Some(file) = rules {
+                        let file: ChaosRulesFile = read_chaos_file(file)?;
+                        config.rules.extend(file.rules);
+                        config.response_faults.extend(file.response_faults);
+                    }
+                    config
+                }
             };
 
-            let client = reqwest::Client::new();
-            let url = format!("http://localhost:{}/_xdr/chaos", cli.port);
+            let url = format!("{}/_xdr/chaos", control.base);
             
             match client.post(&url).json(&config).send().await {
-                Ok(r) if r.status().is_success() => println!("🌪️ Chaos configuration updated."),
+                Ok(r) if r.status().is_success() => match (config.rules.len(), config.response_faults.len()) {
+                    (0, 0) => println!("🌪️ Chaos configuration updated."),
+                    (rules, faults) => println!("🌪️ Chaos configuration updated ({} targeted rule(s), {} response fault(s)).", rules, faults),
+                },
+                Ok(r) => eprintln!("❌ Rejected [{}]: {}", r.status(), r.text().await.unwrap_or_default()),
+                Err(e) => eprintln!("❌ Connection failed: {}", e),
+            }
+        }
+        Commands::Snapshot { action } => {
+            let client = control.client.clone();
+            let base = format!("{}/_xdr/snapshot", control.base);
+
+            let res = match action {
+                SnapshotAction::Save { name } => client.post(format!("{}/save/{}", base, name)).send().await,
+                SnapshotAction::Load { name } => client.post(format!("{}/load/{}", base, name)).send().await,
+                SnapshotAction::List => client.get(&base).send().await,
+            };
+
+            match res {
+                Ok(r) if r.status().is_success() => {
+                    let body: serde_json::Value = r.json().await?;
+                    match action {
+                        SnapshotAction::Save { name } => println!("💾 Snapshot '{}' saved ({} agents).", name, body["agents"]),
+                        SnapshotAction::Load { name } => println!("📂 Snapshot '{}' loaded ({} agents).", name, body["agents"]),
+                        SnapshotAction::List => {
+                            for name in body.as_array().into_iter().flatten() {
+                                println!("{}", name.as_str().unwrap_or_default());
+                            }
+                        }
+                    }
+                }
+                Ok(r) => eprintln!("❌ Failed [{}]: {}", r.status(), r.text().await.unwrap_or_default()),
+                Err(e) => eprintln!("❌ Connection failed: {}", e),
+            }
+        }
+        Commands::Statement { agent, format, from, to } => {
+            let client = control.client.clone();
+            let url = format!("{}/_xdr/agents/{}/transactions", control.base, agent);
+            let mut params = vec![("limit", MAX_PAGE_SIZE.to_string())];
+            params.extend(from.clone().map(|v| ("from", v)));
+            params.extend(to.clone().map(|v| ("to", v)));
+
+            // Walk every page of the journal
+            let mut entries = Vec::new();
+            let mut after: Option<u64> = None;
+            loop {
+                let mut query = params.clone();
+                query.extend(after.map(|a| ("after", a.to_string())));
+                match client.get(&url).query(&query).send().await {
+                    Ok(r) if r.status().is_success() => {
+                        let page: JournalPage = r.json().await?;
+                        entries.extend(page.entries);
+                        match page.next {
+                            Some(next) => after = Some(next),
+                            None => break,
+                        }
+                    }
+                    Ok(r) => {
+                        eprintln!("❌ Failed [{}]: {}", r.status(), r.text().await.unwrap_or_default());
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        eprintln!("❌ Connection failed: {}", e);
+                        return Ok(());
+                    }
+                }
+            }
+            match format {
+                StatementFormat::Csv => print_statement_csv(&entries),
+                StatementFormat::Json => println!("{}", pretty_json(&entries)?),
+            }
+        }
+        Commands::Invoices { action } => {
+            let client = control.client.clone();
+            match action {
+                InvoicesAction::List { agent } => {
+                    let url = format!("{}/_xdr/invoices", control.base);
+                    let query: Vec<(&str, &str)> = agent.iter().map(|a| ("agent", a.as_str())).collect();
+                    match client.get(&url).query(&query).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            let rows: Vec<InvoiceRow> = r.json().await?;
+                            if rows.is_empty() {
+                                println!("No invoices.");
+                            }
+                            for InvoiceRow { invoice, status } in rows {
+                                let resource = invoice.resource.map(|r| format!(" | {}", r)).unwrap_or_default();
+                                println!("🧾 {} [{:?}] {} → {}: {} {}{}",
+                                    invoice.id, status, invoice.agent_id, invoice.payee, invoice.amount, invoice.currency, resource);
+                            }
+                        }
+                        Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                }
+                InvoicesAction::Cancel { id } => {
+                    let url = format!("{}/_xdr/invoices/{}/cancel", control.base, id);
+                    match client.post(&url).send().await {
+                        Ok(r) if r.status().is_success() => println!("🚫 Invoice {} cancelled", id),
+                        Ok(r) => eprintln!("❌ {}", r.text().await.unwrap_or_default()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                }
+            }
+        }
+        Commands::Groups { action } => {
+            let client = control.client.clone();
+            let (request, done) = match action {
+                GroupsAction::Show => {
+                    let url = format!("{}/_xdr/groups", control.base);
+                    match client.get(&url).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            let rows: Vec<GroupRow> = r.json().await?;
+                            if rows.is_empty() {
+                                println!("No budget groups.");
+                            }
+                            for root in rows.iter().filter(|r| r.group.is_root()) {
+                                print_group_tree(&rows, &root.group.id, 0);
+                            }
+                        }
+                        Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                    return Ok(());
+                }
+                GroupsAction::Create { id, parent, top_level, budget, no_cap } => {
+                    // Only what was asked for is sent, so the group keeps everything else
+                    let mut body = json!({ "id": id });
+                    if parent.is_some() || *top_level {
+                        body["parent"] = json!(parent);
+                    }
+                    if budget.is_some() || *no_cap {
+                        body["budget_limit"] = json!(budget);
+                    }
+                    (client.post(format!("{}/_xdr/groups", control.base)).json(&body), format!("👥 Group {} saved", id))
+                }
+                GroupsAction::Delete { id } => (
+                    client.delete(format!("{}/_xdr/groups/{}", control.base, id)),
+                    format!("👥 Group {} deleted", id),
+                ),
+                GroupsAction::Fund { id, set, currency } => (
+                    client.post(format!("{}/_xdr/groups/{}/balance", control.base, id))
+                        .json(&json!({ "amount": set, "currency": currency })),
+                    format!("💰 {} wallet of group {} set to {}", currency, id, set),
+                ),
+                GroupsAction::Assign { agent, group } => (
+                    client.post(format!("{}/_xdr/groups/{}/members", control.base, group))
+                        .json(&json!({ "agent_id": agent })),
+                    format!("👥 {} now pays through group {}", agent, group),
+                ),
+                GroupsAction::Unassign { agent, group } => (
+                    client.delete(format!("{}/_xdr/groups/{}/members/{}", control.base, group, agent)),
+                    format!("👥 {} left group {}", agent, group),
+                ),
+            };
+            match request.send().await {
+                Ok(r) if r.status().is_success() => println!("{}", done),
+                Ok(r) => eprintln!("❌ {}", r.text().await.unwrap_or_default()),
+                Err(e) => eprintln!("❌ Connection failed: {}", e),
+            }
+        }
+        Commands::Services { action } => {
+            let client = control.client.clone();
+            let (request, done) = match action {
+                ServicesAction::List => {
+                    let url = format!("{}/_xdr/services", control.base);
+                    match client.get(&url).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            let rows: Vec<serde_json::Value> = r.json().await?;
+                            if rows.is_empty() {
+                                println!("No agent services registered.");
+                            }
+                            for row in rows {
+                                let field = |name: &str| row[name].as_str().unwrap_or_default().to_string();
+                                println!("🏪 {:<20} {:<28} -> {}", field("agent_id"), field("host"), field("url"));
+                                if let Some(description) = row["description"].as_str() {
+                                    println!("   {}", description);
+                                }
+                                println!("   wallet: {}", field("wallet"));
+                            }
+                        }
+                        Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                    return Ok(());
+                }
+                ServicesAction::Register { agent, url, description } => (
+                    client.post(format!("{}/_xdr/services", control.base))
+                        .json(&json!({ "agent_id": agent, "url": url, "description": description })),
+                    format!("🏪 {} now sells at {}.agent (served from {})", agent, agent.to_ascii_lowercase(), url),
+                ),
+                ServicesAction::Remove { agent } => (
+                    client.delete(format!("{}/_xdr/services/{}", control.base, agent)),
+                    format!("🏪 {} no longer sells a service", agent),
+                ),
+            };
+            match request.send().await {
+                Ok(r) if r.status().is_success() => println!("{}", done),
+                Ok(r) => eprintln!("❌ {}", r.text().await.unwrap_or_default()),
+                Err(e) => eprintln!("❌ Connection failed: {}", e),
+            }
+        }
+        Commands::Audit { json } => {
+            let url = format!("{}/_xdr/audit", control.base);
+            match control.client.get(&url).send().await {
+                Ok(r) if r.status().is_success() => {
+                    let entries: Vec<AuditEntry> = r.json().await?;
+                    if *json {
+                        println!("{}", pretty_json(&entries)?);
+                        return Ok(());
+                    }
+                    if entries.is_empty() {
+                        println!("No control-plane changes yet.");
+                    }
+                    for entry in entries {
+                        let icon = if entry.status < 400 { "📝" } else { "⛔" };
+                        let role = entry.role.map(|r| format!(" ({})", r.as_str())).unwrap_or_default();
+                        let body = entry.body.map(|b| format!(" {}", b)).unwrap_or_default();
+                        let suppressed = entry.suppressed.map(|n| format!(" (+{} more refused since the last)", n)).unwrap_or_default();
+                        println!("{} {} {}{} {} {} → {}{}{}", icon, entry.timestamp, entry.operator, role, entry.method, entry.path, entry.status, body, suppressed);
+                    }
+                }
+                Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                Err(e) => eprintln!("❌ Connection failed: {}", e),
+            }
+        }
+        Commands::Holds { agent } => {
+            let url = match agent {
+                Some(agent) => format!("{}/_xdr/holds/{}", control.base, agent),
+                None => format!("{}/_xdr/holds", control.base),
+            };
+            match control.client.get(&url).send().await {
+                Ok(r) if r.status().is_success() => {
+                    let holds: Vec<Hold> = r.json().await?;
+                    if holds.is_empty() {
+                        println!("No escrow holds.");
+                    }
+                    for hold in holds {
+                        let refund = hold.refund_tx.map(|tx| format!(" | Refund Tx: {}", tx)).unwrap_or_default();
+                        println!("🔒 {} [{:?}] {} → {}: up to {} {}, captured {}, refunded {}{}",
+                            hold.id, hold.status, hold.agent_id, hold.payee, hold.amount, hold.currency, hold.captured, hold.refunded, refund);
+                    }
+                }
+                Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                Err(e) => eprintln!("❌ Connection failed: {}", e),
+            }
+        }
+        Commands::Mine { blocks } => {
+            let client = control.client.clone();
+            let url = format!("{}/_xdr/mine", control.base);
+
+            match client.post(&url).json(&json!({ "blocks": blocks })).send().await {
+                Ok(r) if r.status().is_success() => {
+                    let report: serde_json::Value = r.json().await?;
+                    let included: usize = report["blocks"].as_array().into_iter().flatten()
+                        .map(|b| b["transactions"].as_array().map_or(0, |t| t.len()))
+                        .sum();
+                    println!("⛏️  Mined {} block(s), {} payment(s) included. Height: {}", blocks, included, report["height"]);
+                    for reorg in report["reorgs"].as_array().into_iter().flatten() {
+                        println!("🔀 Reorg: {} block(s) from #{}, {} payment(s) orphaned",
+                            reorg["depth"], reorg["from_block"], reorg["orphaned"].as_array().map_or(0, |o| o.len()));
+                    }
+                }
                 Ok(r) => eprintln!("❌ Server error: {}", r.status()),
                 Err(e) => eprintln!("❌ Connection failed: {}", e),
             }
         }
+        Commands::Pricing { action } => {
+            let client = control.client.clone();
+            let url = format!("{}/_xdr/pricing", control.base);
+
+            match action {
+                PricingAction::Show => match client.get(&url).send().await {
+                    Ok(r) if r.status().is_success() => {
+                        let config: PricingConfig = r.json().await?;
+                        println!("{}", pretty_json(&config)?);
+                    }
+                    Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                    Err(e) => eprintln!("❌ Connection failed: {}", e),
+                },
+                PricingAction::Load { file } => {
+                    let config = read_pricing_file(file)?;
+                    match client.post(&url).json(&config).send().await {
+                        Ok(r) if r.status().is_success() => println!("🏷️  Pricing table loaded ({} rules).", config.rules.len()),
+                        Ok(r) => eprintln!("❌ Rejected [{}]: {}", r.status(), r.text().await.unwrap_or_default()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                }
+            }
+        }
+        Commands::Auth { action } => {
+            let client = control.client.clone();
+            let base = format!("{}/_xdr/auth", control.base);
+            let sent = match action {
+                AuthAction::Show => {
+                    match client.get(&base).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            let config: AuthConfig = r.json().await?;
+                            println!("Strict mode: {}", if config.strict { "on (unregistered agents get 401)" } else { "off" });
+                            if config.agents.is_empty() {
+                                println!("No agents registered: X-Agent-ID is trusted as sent.");
+                            }
+                            for agent in &config.agents {
+                                let detail = match &agent.credential {
+                                    Credential::Ethereum { address } => format!("  {}", address),
+                                    _ => String::new(),
+                                };
+                                println!("🔑 {:<20} {}{}", agent.agent_id, agent.credential.kind(), detail);
+                            }
+                        }
+                        Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                    return Ok(());
+                }
+                AuthAction::Add { agent, key, hmac_secret, address } => {
+                    let credential = match (key, hmac_secret, address) {
+                        (Some(key), _, _) => Credential::Bearer { key: key.clone() },
+                        (_, Some(secret), _) => Credential::Hmac { secret: secret.clone() },
+                        (_, _, Some(address)) => Credential::Ethereum { address: address.clone() },
+                        _ => unreachable!("clap requires one credential"),
+                    };
+                    let kind = credential.kind();
+                    let body = AgentCredential { agent_id: agent.clone(), credential };
+                    client.post(format!("{}/agents", base)).json(&body).send().await
+                        .map(|r| (r, format!("🔑 Agent {} registered with a {} credential.", agent, kind)))
+                }
+                AuthAction::Remove { agent } => {
+                    client.delete(format!("{}/agents/{}", base, agent)).send().await
+                        .map(|r| (r, format!("🔑 Agent {} credential removed.", agent)))
+                }
+                AuthAction::Strict { enabled } => {
+                    client.post(format!("{}/strict", base)).json(&json!({ "strict": enabled })).send().await
+                        .map(|r| (r, format!("🔑 Strict mode {}.", if *enabled { "on" } else { "off" })))
+                }
+                AuthAction::Load { file } => {
+                    let config = read_auth_file(file)?;
+                    let count = config.agents.len();
+                    client.post(&base).json(&config).send().await
+                        .map(|r| (r, format!("🔑 Agent registry loaded ({} agents).", count)))
+                }
+            };
+            match sent {
+                Ok((r, message)) if r.status().is_success() => println!("{}", message),
+                Ok((r, _)) => eprintln!("❌ Rejected [{}]: {}", r.status(), r.text().await.unwrap_or_default()),
+                Err(e) => eprintln!("❌ Connection failed: {}", e),
+            }
+        }
+        Commands::RateLimits { action } => {
+            let client = control.client.clone();
+            let url = format!("{}/_xdr/ratelimits", control.base);
+
+            let config = match action {
+                RateLimitAction::Show => {
+                    match client.get(&url).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            let config: RateLimitConfig = r.json().await?;
+                            if !config.is_enabled() {
+                                println!("No rate limits: every request is forwarded.");
+                            }
+                            for rule in &config.rules {
+                                println!(
+                                    "{:<24} agent {:<12} {} burst, {}/s refill{}",
+                                    rule.host.as_deref().unwrap_or("*"),
+                                    rule.agent.as_deref().unwrap_or("*"),
+                                    rule.capacity,
+                                    rule.refill_per_sec,
+                                    rule.style.map(|s| format!("  [{:?}]", s)).unwrap_or_default(),
+                                );
+                            }
+                        }
+                        Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                    return Ok(());
+                }
+                RateLimitAction::Add { host, agent, capacity, refill, style } => {
+                    let mut config: RateLimitConfig = match client.get(&url).send().await {
+                        Ok(r) if r.status().is_success() => r.json().await?,
+                        Ok(r) => {
+                            eprintln!("❌ Server error: {}", r.status());
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            eprintln!("❌ Connection failed: {}", e);
+                            return Ok(());
+                        }
+                    };
+                    config.rules.push(RateLimitRule {
+                        host: host.clone(),
+                        agent: agent.clone(),
+                        capacity: *capacity,
+                        refill_per_sec: *refill,
+                        style: *style,
+                    });
+                    config
+                }
+                RateLimitAction::Load { file } => read_rate_limits_file(file)?,
+                RateLimitAction::Clear => RateLimitConfig::default(),
+            };
+            match client.post(&url).json(&config).send().await {
+                Ok(r) if r.status().is_success() => match config.is_enabled() {
+                    true => println!("🚦 Rate limits set ({} rules).", config.rules.len()),
+                    false => println!("🚦 Rate limits cleared."),
+                },
+                Ok(r) => eprintln!("❌ Rejected [{}]: {}", r.status(), r.text().await.unwrap_or_default()),
+                Err(e) => eprintln!("❌ Connection failed: {}", e),
+            }
+        }
+        Commands::Policy { action } => {
+            let client = control.client.clone();
+            let base = format!("{}/_xdr/policy", control.base);
+
+            let (agent, policy) = match action {
+                PolicyAction::Show { agent } => {
+                    match client.get(format!("{}/{}", base, agent)).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            let policy: SpendingPolicy = r.json().await?;
+                            if policy.is_empty() {
+                                println!("No spending policy for {} (only the budget cap applies).", agent);
+                            } else {
+                                println!("{}", pretty_json(&policy)?);
+                            }
+                        }
+                        Ok(r) => eprintln!("❌ Error [{}]: Agent '{}' not found.", r.status(), agent),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                    return Ok(());
+                }
+                PolicyAction::Clear { agent } => (agent, SpendingPolicy::default()),
+                PolicyAction::Set { agent, file: Some(path), .. } => {
+                    let raw = std::fs::read_to_string(path)?;
+                    (agent, serde_json::from_str(&raw)?)
+                }
+                PolicyAction::Set { agent, per_minute, per_hour, per_day, payments_per_minute, payments_per_hour, payee_cap, asset_cap, file: None } => {
+                    let spend = [(60, per_minute), (3_600, per_hour), (86_400, per_day)];
+                    let rate = [(60, payments_per_minute), (3_600, payments_per_hour)];
+                    let policy = SpendingPolicy {
+                        spend_limits: spend.iter()
+                            .filter_map(|(window_secs, max)| max.map(|max_usd| SpendLimit { window_secs: *window_secs, max_usd }))
+                            .collect(),
+                        rate_limits: rate.iter()
+                            .filter_map(|(window_secs, max)| max.map(|max_payments| RateLimit { window_secs: *window_secs, max_payments }))
+                            .collect(),
+                        payee_caps: payee_cap.iter()
+                            .map(|(payee, max_usd)| PayeeCap { payee: payee.clone(), max_usd: *max_usd, window_secs: None })
+                            .collect(),
+                        asset_caps: asset_cap.iter()
+                            .map(|(currency, max_amount)| AssetCap { currency: currency.clone(), max_amount: *max_amount, window_secs: None })
+                            .collect(),
+                    };
+                    (agent, policy)
+                }
+            };
+
+            match client.post(format!("{}/{}", base, agent)).json(&policy).send().await {
+                Ok(r) if r.status().is_success() && policy.is_empty() => println!("🛡️  Spending policy cleared for {}.", agent),
+                Ok(r) if r.status().is_success() => println!("🛡️  Spending policy for {}: {}", agent, policy.summary()),
+                Ok(r) => eprintln!("❌ Rejected [{}]: {}", r.status(), r.text().await.unwrap_or_default()),
+                Err(e) => eprintln!("❌ Connection failed: {}", e),
+            }
+        }
+        Commands::Approvals { action } => {
+            let client = control.client.clone();
+            let base = format!("{}/_xdr/approvals", control.base);
+
+            let config = match action {
+                ApprovalsAction::List { all } => {
+                    match client.get(&base).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            let approvals: Vec<Approval> = r.json().await?;
+                            let shown: Vec<&Approval> = approvals.iter()
+                                .filter(|a| *all || a.status == ApprovalStatus::Pending)
+                                .collect();
+                            if shown.is_empty() {
+                                println!("No payments awaiting approval.");
+                            }
+                            for a in shown {
+                                println!(
+                                    "{:<8} {:<9} {:<12} {} {} (${}) to {}  {}",
+                                    a.id, a.status.label(), a.agent_id, a.amount, a.currency, a.usd_value, a.payee, a.reason,
+                                );
+                            }
+                        }
+                        Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                    return Ok(());
+                }
+                ApprovalsAction::Approve { id, note } | ApprovalsAction::Deny { id, note } => {
+                    let verb = if matches!(action, ApprovalsAction::Approve { .. }) { "approve" } else { "deny" };
+                    match client.post(format!("{}/{}/{}", base, id, verb)).json(&json!({ "note": note })).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            let a: Approval = r.json().await?;
+                            println!("✋ Payment {} {}: {} {} for {}.", a.id, a.status.label(), a.amount, a.currency, a.agent_id);
+                        }
+                        Ok(r) => eprintln!("❌ Failed [{}]: {}", r.status(), r.text().await.unwrap_or_default()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                    return Ok(());
+                }
+                ApprovalsAction::Show => {
+                    match client.get(format!("{}/config", base)).send().await {
+                        Ok(r) if r.status().is_success() => {
+                            let config: ApprovalConfig = r.json().await?;
+                            println!("{}", pretty_json(&config)?);
+                        }
+                        Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                    return Ok(());
+                }
+                ApprovalsAction::Disable => ApprovalConfig::default(),
+                ApprovalsAction::Set { file: Some(path), .. } => read_approvals_file(path)?,
+                ApprovalsAction::Set { threshold, mode, timeout, on_timeout, file: None } => ApprovalConfig {
+                    threshold_usd: *threshold,
+                    mode: *mode,
+                    timeout_secs: *timeout,
+                    on_timeout: *on_timeout,
+                    ..ApprovalConfig::default()
+                },
+            };
+
+            match client.post(format!("{}/config", base)).json(&config).send().await {
+                Ok(r) if r.status().is_success() && !config.is_enabled() => println!("✋ Approvals disabled."),
+                Ok(r) if r.status().is_success() => println!("✋ Approval policy updated ({:?} mode, {}s timeout, {:?} on timeout).", config.mode, config.timeout_secs, config.on_timeout),
+                Ok(r) => eprintln!("❌ Rejected [{}]: {}", r.status(), r.text().await.unwrap_or_default()),
+                Err(e) => eprintln!("❌ Connection failed: {}", e),
+            }
+        }
+        Commands::Tokens { action } => {
+            let client = control.client.clone();
+            let url = format!("{}/_xdr/tokens", control.base);
+
+            match action {
+                TokensAction::List => match client.get(&url).send().await {
+                    Ok(r) if r.status().is_success() => {
+                        let tokens: Vec<Token> = r.json().await?;
+                        for t in tokens {
+                            println!(
+                                "{:<8} {:<12} decimals: {:<2}  ${} each  {}{}",
+                                t.symbol, t.name, t.decimals, t.usd_rate,
+                                t.address.as_deref().unwrap_or("(native)"),
+                                if t.supports_eip3009() { "  [x402]" } else { "" },
+                            );
+                        }
+                    }
+                    Ok(r) => eprintln!("❌ Server error: {}", r.status()),
+                    Err(e) => eprintln!("❌ Connection failed: {}", e),
+                },
+                TokensAction::Add { symbol, name, decimals, address, eip712_version, usd_rate, faucet } => {
+                    let token = Token {
+                        symbol: symbol.clone(),
+                        name: name.clone().unwrap_or_else(|| symbol.clone()),
+                        decimals: *decimals,
+                        address: Some(address.clone()),
+                        eip712_version: eip712_version.clone(),
+                        usd_rate: *usd_rate,
+                        faucet: *faucet,
+                    };
+                    match client.post(&url).json(&token).send().await {
+                        Ok(r) if r.status().is_success() => println!("🪙 Token {} registered at {}.", symbol, address),
+                        Ok(r) => eprintln!("❌ Rejected [{}]: {}", r.status(), r.text().await.unwrap_or_default()),
+                        Err(e) => eprintln!("❌ Connection failed: {}", e),
+                    }
+                }
+            }
+        }
         Commands::Logs { agent, json } => {
-             let url = format!("http://localhost:{}/_xdr/traces", cli.port);
-             match reqwest::get(&url).await {
-                Ok(res) => {
-                    let traces: Vec<Trace> = res.json().await.unwrap_or_default();
+             let url = format!("{}/_xdr/traces", control.base);
+             match control.client.get(&url).send().await {
+                Ok(res) if res.status().is_success() => {
+                    let traces: Vec<Trace> = match res.json().await {
+                        Ok(traces) => traces,
+                        Err(e) => {
+                            eprintln!("❌ Could not read logs: {}", e);
+                            Vec::new()
+                        }
+                    };
                     
                     for trace in traces {
                         // Filter
@@ -213,13 +1728,24 @@ async fn main() -> Result<()> {
                             println!("------------------------------------------------");
                             println!("🆔 [{}] {} {}", trace.status_code.unwrap_or(0), trace.method, trace.url);
                             println!("   Agent: {} | Duration: {}ms", trace.agent_id, trace.duration_ms.unwrap_or(0));
+                            if let Some(usage) = &trace.usage {
+                                println!(
+                                    "   Usage: {} | {} in ({}) + {} out ({}) = {} {}",
+                                    usage.model, usage.input_tokens, usage.input_cost,
+                                    usage.output_tokens, usage.output_cost, usage.total_cost, usage.currency
+                                );
+                            }
                             for event in trace.events {
                                 println!("   - [{:?}] {}", event.category, event.message);
                             }
+                            for decision in trace.chaos_decisions {
+                                println!("   - [Roll] {} {}", decision.key(), if decision.hit { "HIT" } else { "pass" });
+                            }
                         }
                     }
                 },
-                Err(_) => eprintln!("❌ Could not fetch logs"),
+                Ok(res) => eprintln!("❌ {}", refusal(res).await),
+                Err(e) => eprintln!("❌ Could not fetch logs: {}", e),
              }
         }
     }